@@ -0,0 +1,132 @@
+/*
+   JSON against protobuf decoding of list and watch payloads, run with cargo bench.
+   Payloads were recorded from fake-api-server seeded with 40 Deployments and 40 Services
+   owned by ExposedApps, with Accept: application/json and application/vnd.kubernetes.protobuf.
+   They only hold the fields mapped in k8s_types, responses of a real API server carry
+   more, e.g. managedFields, which JSON has to parse and protobuf skips.
+*/
+use no_library::k8s_types::{Deployment, K8sListObject, List, Service, Watch};
+use no_library::protobuf::{decode_list, decode_watch_event, Frames, Protobuf};
+use serde::de::DeserializeOwned;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const PAYLOADS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/payloads");
+const MEASURED: Duration = Duration::from_secs(2);
+
+// Name, payload file and decoding returning the number of objects
+type Bench = (&'static str, &'static str, fn(&[u8]) -> usize);
+
+fn payload(name: &str) -> Vec<u8> {
+    std::fs::read(format!("{}/{}", PAYLOADS, name)).expect("Payload not found")
+}
+
+// Runs decode for MEASURED after a warm-up, prints the mean time per payload
+fn bench(name: &str, payload: &[u8], decode: impl Fn(&[u8]) -> usize) {
+    let warm_up = Instant::now();
+    while warm_up.elapsed() < MEASURED / 4 {
+        black_box(decode(black_box(payload)));
+    }
+    let mut iterations = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < MEASURED {
+        black_box(decode(black_box(payload)));
+        iterations += 1;
+    }
+    let mean = start.elapsed() / iterations;
+    let throughput = payload.len() as f64 / mean.as_secs_f64() / 1_000_000.0;
+    println!(
+        "{:<28} {:>7} bytes {:>10.1?}/payload {:>8.1} MB/s",
+        name,
+        payload.len(),
+        mean,
+        throughput
+    );
+}
+
+fn json_list<T: DeserializeOwned>(payload: &[u8]) -> usize {
+    serde_json::from_slice::<List<K8sListObject<T>>>(payload)
+        .unwrap()
+        .items
+        .len()
+}
+
+fn protobuf_list<T: Protobuf>(payload: &[u8]) -> usize {
+    decode_list(payload, T::decoder().unwrap())
+        .unwrap()
+        .items
+        .len()
+}
+
+// One event per line, as the operator reads them
+fn json_watch<T: DeserializeOwned>(payload: &[u8]) -> usize {
+    payload
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .fold(0, |events, line| {
+            serde_json::from_slice::<Watch<K8sListObject<T>>>(line).unwrap();
+            events + 1
+        })
+}
+
+fn protobuf_watch<T: Protobuf>(payload: &[u8]) -> usize {
+    let mut frames = Frames::default();
+    frames.push(payload);
+    let mut events = 0;
+    while let Some(frame) = frames.next_frame().unwrap() {
+        decode_watch_event(frame.as_slice(), T::decoder().unwrap()).unwrap();
+        events += 1;
+    }
+    events
+}
+
+fn main() {
+    // cargo bench passes --bench
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let benches: [Bench; 8] = [
+        (
+            "deployments list json",
+            "deployments.json",
+            json_list::<Deployment>,
+        ),
+        (
+            "deployments list protobuf",
+            "deployments.pb",
+            protobuf_list::<Deployment>,
+        ),
+        (
+            "deployments watch json",
+            "deployments-watch.jsonl",
+            json_watch::<Deployment>,
+        ),
+        (
+            "deployments watch protobuf",
+            "deployments-watch.pb",
+            protobuf_watch::<Deployment>,
+        ),
+        ("services list json", "services.json", json_list::<Service>),
+        (
+            "services list protobuf",
+            "services.pb",
+            protobuf_list::<Service>,
+        ),
+        (
+            "services watch json",
+            "services-watch.jsonl",
+            json_watch::<Service>,
+        ),
+        (
+            "services watch protobuf",
+            "services-watch.pb",
+            protobuf_watch::<Service>,
+        ),
+    ];
+    for (name, file, decode) in benches {
+        if filter
+            .as_ref()
+            .is_none_or(|filter| name.contains(filter.as_str()))
+        {
+            bench(name, payload(file).as_slice(), decode);
+        }
+    }
+}
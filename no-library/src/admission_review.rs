@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// https://kubernetes.io/docs/reference/access-authn-authz/extensible-admission-controllers/#request
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionReview {
+    pub api_version: String,
+    pub kind: String,
+    pub request: Option<AdmissionRequest>,
+    pub response: Option<AdmissionResponse>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionRequest {
+    pub uid: String,
+    // CREATE, UPDATE, DELETE or CONNECT
+    pub operation: String,
+    pub name: Option<String>,
+    pub namespace: Option<String>,
+    pub object: Option<Value>,
+    pub old_object: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionResponse {
+    pub uid: String,
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<AdmissionStatus>,
+}
+
+// metav1.Status, returned to the client as is when the request is denied
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionStatus {
+    pub code: u16,
+    pub reason: String,
+    pub message: String,
+    pub details: Option<StatusDetails>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusDetails {
+    pub name: Option<String>,
+    pub group: String,
+    pub kind: String,
+    pub causes: Vec<StatusCause>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusCause {
+    pub reason: String,
+    pub message: String,
+    pub field: String,
+}
+
+impl AdmissionReview {
+    fn response(response: AdmissionResponse) -> Self {
+        AdmissionReview {
+            api_version: String::from("admission.k8s.io/v1"),
+            kind: String::from("AdmissionReview"),
+            request: None,
+            response: Some(response),
+        }
+    }
+
+    pub fn allowed(uid: &str) -> Self {
+        Self::response(AdmissionResponse {
+            uid: String::from(uid),
+            allowed: true,
+            status: None,
+        })
+    }
+
+    pub fn denied(uid: &str, status: AdmissionStatus) -> Self {
+        Self::response(AdmissionResponse {
+            uid: String::from(uid),
+            allowed: false,
+            status: Some(status),
+        })
+    }
+}
@@ -0,0 +1,328 @@
+use crate::config::Config;
+use crate::discovery::{Discovery, Requirement};
+use crate::k8s_client::client::{K8sClient, K8sClientError};
+use crate::k8s_types::{
+    CustomResourceDefinition, K8sObject, Lease, LeaseSpec, Metadata, Resource, ResourceAttributes,
+    SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use crate::operator::operator::required_resources;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use tracing::info;
+
+// Same CRDs the kustomize bundle applies, regenerated with crdgen
+const CRDS: &str = include_str!("../config/custom_resource_definition.yaml");
+const ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(60);
+// Same as config/lease.yaml
+const LEASE_DURATION_SECONDS: u32 = 30;
+
+struct EmbeddedCrd {
+    document: Value,
+    name: String,
+    group: String,
+    kind: String,
+    plural: String,
+}
+
+impl EmbeddedCrd {
+    fn defines(&self, requirement: &Requirement) -> bool {
+        self.kind == requirement.kind && self.group == requirement.group()
+    }
+}
+
+fn embedded_crds() -> Vec<EmbeddedCrd> {
+    serde_yaml::Deserializer::from_str(CRDS)
+        .map(|document| {
+            let document = Value::deserialize(document).expect("Embedded CRD is not valid YAML");
+            let field = |pointer: &str| {
+                document
+                    .pointer(pointer)
+                    .and_then(Value::as_str)
+                    .map(String::from)
+                    .unwrap_or_else(|| panic!("Embedded CRD has no {}", pointer))
+            };
+            EmbeddedCrd {
+                name: field("/metadata/name"),
+                group: field("/spec/group"),
+                kind: field("/spec/names/kind"),
+                plural: field("/spec/names/plural"),
+                document,
+            }
+        })
+        .collect()
+}
+
+fn attributes(
+    group: &str,
+    resource: &str,
+    verb: &str,
+    namespace: Option<&str>,
+    subresource: Option<&str>,
+) -> ResourceAttributes {
+    ResourceAttributes {
+        namespace: namespace.map(String::from),
+        verb: String::from(verb),
+        group: String::from(group),
+        resource: String::from(resource),
+        subresource: subresource.map(String::from),
+    }
+}
+
+// In kubectl auth can-i terms, e.g. "create leases.coordination.k8s.io in namespace no-library"
+fn describe(attributes: &ResourceAttributes) -> String {
+    let mut resource = attributes.resource.clone();
+    if let Some(subresource) = &attributes.subresource {
+        resource = format!("{}/{}", resource, subresource);
+    }
+    if !attributes.group.is_empty() {
+        resource = format!("{}.{}", resource, attributes.group);
+    }
+    match &attributes.namespace {
+        Some(namespace) => format!(
+            "{} {} in namespace {}",
+            attributes.verb, resource, namespace
+        ),
+        None => format!("{} {} cluster-wide", attributes.verb, resource),
+    }
+}
+
+/*
+   Everything the bootstrap and the controllers do, checked before anything is modified.
+   Kinds of the embedded CRD are not served yet, their names are read from the CRD.
+*/
+fn permissions(
+    client: &K8sClient,
+    crds: &[EmbeddedCrd],
+    config: &Config,
+    required: &[Requirement],
+) -> Vec<ResourceAttributes> {
+    let discovery = client.discovery().expect("Discovery has to run first");
+    let lease_namespace = Some(config.lease_namespace.as_str());
+    let mut permissions = Vec::new();
+    for requirement in required {
+        let (group, plural) = match discovery.get(requirement.api_version, requirement.kind) {
+            Some(resource) => (resource.group.clone(), resource.plural.clone()),
+            None => {
+                let crd = crds
+                    .iter()
+                    .find(|crd| crd.defines(requirement))
+                    .unwrap_or_else(|| {
+                        panic!("{} is neither served nor embedded", requirement.kind)
+                    });
+                (crd.group.clone(), crd.plural.clone())
+            }
+        };
+        let namespace = match requirement.kind {
+            Lease::KIND => lease_namespace,
+            _ => None,
+        };
+        for verb in requirement.verbs {
+            permissions.push(attributes(&group, &plural, verb, namespace, None));
+        }
+    }
+    for crd in crds {
+        permissions.push(attributes(
+            &crd.group,
+            &crd.plural,
+            "update",
+            None,
+            Some("status"),
+        ));
+    }
+    permissions
+}
+
+async fn denied(
+    client: &mut K8sClient,
+    permissions: Vec<ResourceAttributes>,
+) -> Result<Vec<String>, String> {
+    let mut denied = Vec::new();
+    for resource_attributes in permissions {
+        let review = K8sObject {
+            api_version: String::from(SelfSubjectAccessReview::API_VERSION),
+            kind: String::from(SelfSubjectAccessReview::KIND),
+            metadata: Metadata::default(),
+            object: SelfSubjectAccessReview {
+                spec: SelfSubjectAccessReviewSpec {
+                    resource_attributes: resource_attributes.clone(),
+                },
+                status: None,
+            },
+        };
+        let review = client
+            .post(&review)
+            .await
+            .map_err(|e| format!("Access review failed: {:?}", e))?;
+        let status = review.object.status;
+        if !status.as_ref().is_some_and(|status| status.allowed) {
+            let reason = status.and_then(|status| status.reason);
+            denied.push(match reason {
+                Some(reason) if !reason.is_empty() => {
+                    format!("{} ({})", describe(&resource_attributes), reason)
+                }
+                _ => describe(&resource_attributes),
+            });
+        }
+    }
+    Ok(denied)
+}
+
+// Merge patch keeps fields set by others, e.g. the CA bundle injected by cert-manager
+async fn apply_crd(client: &mut K8sClient, crd: &EmbeddedCrd) -> Result<(), String> {
+    let object =
+        serde_json::from_value::<K8sObject<CustomResourceDefinition>>(crd.document.clone())
+            .map_err(|e| format!("Embedded CRD is invalid: {}", e))?;
+    match client.post(&object).await {
+        Ok(_) => {
+            info!("CustomResourceDefinition {} created", crd.name);
+            Ok(())
+        }
+        Err(K8sClientError::Conflict) => {
+            client
+                .merge_patch::<CustomResourceDefinition>("", crd.name.as_str(), &crd.document)
+                .await
+                .map_err(|e| format!("Unable to update CRD {}: {:?}", crd.name, e))?;
+            info!("CustomResourceDefinition {} updated", crd.name);
+            Ok(())
+        }
+        Err(e) => Err(format!("Unable to create CRD {}: {:?}", crd.name, e)),
+    }
+}
+
+fn condition(crd: &K8sObject<CustomResourceDefinition>, condition_type: &str) -> Option<String> {
+    crd.object
+        .status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .and_then(|conditions| {
+            conditions
+                .iter()
+                .find(|condition| condition.condition_type == condition_type)
+        })
+        .map(|condition| condition.status.clone())
+}
+
+// Established alone is not enough, discovery of the new kinds lags behind it
+async fn wait_established(
+    client: &mut K8sClient,
+    crd: &EmbeddedCrd,
+    served: &[Requirement],
+) -> Result<(), String> {
+    let uri = client
+        .api_resource::<CustomResourceDefinition>()
+        .object_uri("", crd.name.as_str());
+    let deadline = Instant::now() + ESTABLISHED_TIMEOUT;
+    loop {
+        let current = client
+            .get::<CustomResourceDefinition>(uri.as_str())
+            .await
+            .map_err(|e| format!("Unable to read CRD {}: {:?}", crd.name, e))?;
+        if condition(&current, "NamesAccepted").as_deref() == Some("False") {
+            return Err(format!(
+                "CRD {} names were not accepted, do they conflict with another CRD?",
+                crd.name
+            ));
+        }
+        if condition(&current, "Established").as_deref() == Some("True")
+            && Discovery::load(client, served).await.is_ok()
+        {
+            info!("CustomResourceDefinition {} established", crd.name);
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "CRD {} not established after {:?}",
+                crd.name, ESTABLISHED_TIMEOUT
+            ));
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn ensure_lease(client: &mut K8sClient, config: &Config) -> Result<(), String> {
+    let namespace = config.lease_namespace.as_str();
+    let name = config.lease_name.as_str();
+    match client.get_lease(namespace, name).await {
+        Ok(_) => return Ok(()),
+        Err(K8sClientError::NotFound) => {}
+        Err(e) => return Err(format!("Unable to read Lease {}: {:?}", name, e)),
+    }
+    let lease = K8sObject {
+        api_version: String::from(Lease::API_VERSION),
+        kind: String::from(Lease::KIND),
+        metadata: Metadata {
+            name: Some(String::from(name)),
+            namespace: Some(String::from(namespace)),
+            ..Metadata::default()
+        },
+        object: Lease {
+            spec: LeaseSpec {
+                lease_duration_seconds: LEASE_DURATION_SECONDS,
+                holder_identity: None,
+                acquire_time: None,
+                renew_time: None,
+            },
+        },
+    };
+    match client.post(&lease).await {
+        Ok(_) => {
+            info!("Lease {} created", name);
+            Ok(())
+        }
+        // Another replica was faster
+        Err(K8sClientError::Conflict) => Ok(()),
+        Err(e) => Err(format!("Unable to create Lease {}: {:?}", name, e)),
+    }
+}
+
+/*
+   Optional startup phase for installs without the kustomize bundle.
+   Checks the RBAC of the operator with SelfSubjectAccessReviews, applies the embedded
+   ExposedApp and ExposedAppTemplate CRDs, waits until they are served and creates
+   the leader election Lease.
+   Runs before discovery of the operator kinds, the CRDs may not exist yet.
+*/
+pub async fn bootstrap(client: &K8sClient, config: &Config) -> Result<(), String> {
+    let crds = embedded_crds();
+    let embedded = |requirement: &Requirement| crds.iter().any(|crd| crd.defines(requirement));
+    let mut required = vec![
+        Requirement::of::<CustomResourceDefinition>(&["get", "create", "patch"]),
+        Requirement::of::<SelfSubjectAccessReview>(&["create"]),
+    ];
+    required.extend(
+        required_resources()
+            .into_iter()
+            .filter(|requirement| !embedded(requirement)),
+    );
+    let mut bootstrap_client = client.clone();
+    let discovery = Discovery::load(&mut bootstrap_client, &required).await?;
+    let mut client = client.with_discovery(discovery);
+    // Everyone may review their own access
+    let checked: Vec<Requirement> = required
+        .into_iter()
+        .filter(|requirement| requirement.kind != SelfSubjectAccessReview::KIND)
+        .chain(required_resources().into_iter().filter(embedded))
+        .collect();
+    let permissions = permissions(&client, &crds, config, &checked);
+    info!("Checking {} permissions", permissions.len());
+    let denied = denied(&mut client, permissions).await?;
+    if !denied.is_empty() {
+        return Err(format!(
+            "The operator is not allowed to:\n  {}",
+            denied.join("\n  ")
+        ));
+    }
+    let served: Vec<Requirement> = required_resources().into_iter().filter(embedded).collect();
+    for crd in &crds {
+        apply_crd(&mut client, crd).await?;
+    }
+    for crd in &crds {
+        wait_established(&mut client, crd, &served).await?;
+    }
+    if !config.sharding {
+        ensure_lease(&mut client, config).await?;
+    }
+    Ok(())
+}
@@ -21,6 +21,8 @@ impl NamespacedName {
 pub struct CacheEntry {
     pub resource_version: String,
     pub generation: Option<u64>,
+    // Only tracked for objects whose annotations change how they are reconciled
+    pub annotations: Option<HashMap<String, String>>,
 }
 
 impl CacheEntry {
@@ -28,6 +30,14 @@ impl CacheEntry {
         CacheEntry {
             resource_version: String::from(resource_version),
             generation: Some(generation),
+            annotations: None,
+        }
+    }
+
+    pub fn with_annotations(self, annotations: Option<HashMap<String, String>>) -> Self {
+        CacheEntry {
+            annotations: Some(annotations.unwrap_or_default()),
+            ..self
         }
     }
 
@@ -35,6 +45,7 @@ impl CacheEntry {
         CacheEntry {
             resource_version: String::from(resource_version),
             generation: None,
+            annotations: None,
         }
     }
 }
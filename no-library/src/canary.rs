@@ -0,0 +1,582 @@
+use crate::k8s_time::Time;
+use crate::k8s_types::EventType::{Normal, Warning};
+use crate::k8s_types::{
+    CanaryPhase, CanaryStatus, CanaryStrategy, Deployment, EventType, ExposedAppSpec, K8sObject,
+};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+// Tells the stable and canary pods apart, both are selected by the Service
+pub const TRACK_LABEL: &str = "rollout.no-library.com/track";
+// Skips the current step, "full" skips all of them
+pub const PROMOTE_ANNOTATION: &str = "rollout.no-library.com/promote";
+pub const ABORT_ANNOTATION: &str = "rollout.no-library.com/abort";
+/*
+   Neither Deployment status updates nor annotation changes bump a generation,
+   so the controller skips them. Rollouts in progress are polled instead.
+*/
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Images and replicas of the stable and canary Deployments
+pub struct RolloutPlan {
+    pub stable_image: String,
+    pub stable_replicas: u32,
+    // None when there is no canary Deployment
+    pub canary_replicas: Option<u32>,
+    pub status: Option<CanaryStatus>,
+    pub requeue: Option<Duration>,
+    // Type, reason and note of the event to send
+    pub event: Option<(EventType, &'static str, String)>,
+}
+
+pub struct RolloutInput<'a> {
+    pub spec: &'a ExposedAppSpec,
+    pub previous: Option<&'a CanaryStatus>,
+    pub live_stable_image: Option<String>,
+    pub canary: Option<&'a K8sObject<Deployment>>,
+    // Why the canary pods are failing, if they are
+    pub canary_failure: Option<String>,
+    pub promote: Option<&'a str>,
+    pub abort: bool,
+}
+
+pub fn canary_strategy(spec: &ExposedAppSpec) -> Option<&CanaryStrategy> {
+    spec.rollout
+        .as_ref()
+        .and_then(|rollout| rollout.canary.as_ref())
+}
+
+// Rounded up, so every step runs at least one canary replica
+pub fn canary_replicas(replicas: u32, weight: u32) -> u32 {
+    (replicas * weight).div_ceil(100)
+}
+
+pub fn image(deployment: &K8sObject<Deployment>) -> Option<&str> {
+    deployment
+        .object
+        .spec
+        .template
+        .spec
+        .containers
+        .first()
+        .map(|container| container.image.as_str())
+}
+
+fn is_ready(deployment: &K8sObject<Deployment>, replicas: u32, canary_image: &str) -> bool {
+    let status = match &deployment.object.status {
+        Some(status) => status,
+        None => return false,
+    };
+    deployment.object.spec.replicas == replicas
+        && image(deployment) == Some(canary_image)
+        && status.observed_generation >= deployment.metadata.generation
+        && status.updated_replicas >= replicas
+        && status.ready_replicas >= replicas
+}
+
+pub fn progress_deadline_exceeded(deployment: &K8sObject<Deployment>) -> bool {
+    deployment.object.status.as_ref().is_some_and(|status| {
+        status.conditions.iter().any(|condition| {
+            condition.condition_type == "Progressing"
+                && condition.status == "False"
+                && condition.reason.as_deref() == Some("ProgressDeadlineExceeded")
+        })
+    })
+}
+
+impl RolloutPlan {
+    fn direct(spec: &ExposedAppSpec, image: &str, status: Option<CanaryStatus>) -> Self {
+        RolloutPlan {
+            stable_image: String::from(image),
+            stable_replicas: spec.replicas,
+            canary_replicas: None,
+            status,
+            requeue: None,
+            event: None,
+        }
+    }
+
+    fn promote(spec: &ExposedAppSpec, mut status: CanaryStatus) -> Self {
+        status.phase = CanaryPhase::Promoted;
+        status.weight = 100;
+        status.step_ready_time = None;
+        status.message = format!("{} promoted", status.image);
+        let note = format!("Canary {} promoted to stable", status.image);
+        RolloutPlan {
+            event: Some((Normal, "CanaryPromoted", note)),
+            ..Self::direct(spec, spec.image.as_str(), Some(status))
+        }
+    }
+
+    // Rolls back to the stable image
+    fn abort(spec: &ExposedAppSpec, mut status: CanaryStatus, message: String) -> Self {
+        status.phase = CanaryPhase::Aborted;
+        status.weight = 0;
+        status.step_ready_time = None;
+        status.message = message;
+        let note = format!(
+            "Canary {} aborted, rolled back to {}: {}",
+            status.image, status.stable_image, status.message
+        );
+        let stable_image = status.stable_image.clone();
+        RolloutPlan {
+            event: Some((Warning, "CanaryAborted", note)),
+            ..Self::direct(spec, stable_image.as_str(), Some(status))
+        }
+    }
+}
+
+/*
+   Next state of a canary rollout. A new image first runs on the canary Deployment next to
+   the stable one, the canary replicas grow step by step and the stable ones shrink accordingly.
+   A step is complete once its canary replicas are ready and its pause is over,
+   after the last one the stable Deployment takes the new image and the canary is removed.
+*/
+pub fn plan(input: RolloutInput) -> RolloutPlan {
+    let spec = input.spec;
+    let strategy = match canary_strategy(spec) {
+        Some(strategy) => strategy,
+        None => return RolloutPlan::direct(spec, spec.image.as_str(), None),
+    };
+    let in_progress = input.previous.filter(|previous| previous.is_in_progress());
+    let stable_image = in_progress
+        .map(|previous| previous.stable_image.clone())
+        .or(input.live_stable_image)
+        .unwrap_or_else(|| spec.image.clone());
+    if stable_image == spec.image {
+        let finished = input.previous.filter(|previous| !previous.is_in_progress());
+        return RolloutPlan::direct(spec, spec.image.as_str(), finished.cloned());
+    }
+    let mut event = None;
+    let mut status = match input.previous {
+        Some(previous) if previous.image == spec.image => previous.clone(),
+        _ => {
+            let note = format!("Canary rollout of {} started", spec.image);
+            event = Some((Normal, "CanaryStarted", note));
+            CanaryStatus {
+                image: spec.image.clone(),
+                stable_image,
+                step: 0,
+                weight: 0,
+                phase: CanaryPhase::Progressing,
+                message: String::new(),
+                step_ready_time: None,
+            }
+        }
+    };
+    match status.phase {
+        CanaryPhase::Aborted => {
+            let stable_image = status.stable_image.clone();
+            return RolloutPlan::direct(spec, stable_image.as_str(), Some(status));
+        }
+        CanaryPhase::Promoted => {
+            return RolloutPlan::direct(spec, spec.image.as_str(), Some(status))
+        }
+        CanaryPhase::Progressing | CanaryPhase::Paused => {}
+    }
+    if input.abort {
+        let message = format!("aborted with the {} annotation", ABORT_ANNOTATION);
+        return RolloutPlan::abort(spec, status, message);
+    }
+    // Failures of a replaced canary do not count against a rollout that just started
+    if let Some(failure) = input.canary_failure.filter(|_| event.is_none()) {
+        return RolloutPlan::abort(spec, status, failure);
+    }
+    if input.promote == Some("full") || strategy.steps.is_empty() {
+        return RolloutPlan::promote(spec, status);
+    }
+    // Steps might have been removed since the rollout started
+    status.step = status.step.min(strategy.steps.len() - 1);
+    let step = &strategy.steps[status.step];
+    let replicas = canary_replicas(spec.replicas, step.weight);
+    let ready = input
+        .canary
+        .is_some_and(|canary| is_ready(canary, replicas, spec.image.as_str()));
+    let mut requeue = None;
+    let advance = if input.promote.is_some() {
+        true
+    } else if !ready {
+        status.phase = CanaryPhase::Progressing;
+        status.message = format!(
+            "step {}/{}: waiting for {} canary replicas to be ready",
+            status.step + 1,
+            strategy.steps.len(),
+            replicas
+        );
+        false
+    } else {
+        let ready_time = *status.step_ready_time.get_or_insert_with(Time::now);
+        match step.pause_seconds {
+            Some(pause) => {
+                let pause = time::Duration::seconds(pause as i64);
+                let remaining = ready_time.value() + pause - OffsetDateTime::now_utc();
+                if remaining.is_positive() {
+                    status.phase = CanaryPhase::Progressing;
+                    status.message = format!(
+                        "step {}/{}: pausing until {}",
+                        status.step + 1,
+                        strategy.steps.len(),
+                        Time::new(ready_time.value() + pause)
+                    );
+                    requeue = Some(remaining.unsigned_abs().min(POLL_INTERVAL));
+                    false
+                } else {
+                    true
+                }
+            }
+            None => {
+                status.phase = CanaryPhase::Paused;
+                status.message = format!(
+                    "step {}/{}: waiting for the {} annotation",
+                    status.step + 1,
+                    strategy.steps.len(),
+                    PROMOTE_ANNOTATION
+                );
+                false
+            }
+        }
+    };
+    if advance {
+        status.step += 1;
+        status.step_ready_time = None;
+        if status.step == strategy.steps.len() {
+            return RolloutPlan::promote(spec, status);
+        }
+        status.phase = CanaryPhase::Progressing;
+        status.message = format!(
+            "step {}/{}: scaling the canary",
+            status.step + 1,
+            strategy.steps.len()
+        );
+    }
+    status.weight = strategy.steps[status.step].weight;
+    let replicas = canary_replicas(spec.replicas, status.weight);
+    RolloutPlan {
+        stable_image: status.stable_image.clone(),
+        stable_replicas: spec.replicas.saturating_sub(replicas),
+        canary_replicas: Some(replicas),
+        status: Some(status),
+        requeue: Some(requeue.unwrap_or(POLL_INTERVAL)),
+        event,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const STABLE: &str = "registry.example.com/app:1";
+    const CANARY: &str = "registry.example.com/app:2";
+
+    // 4 replicas, a timed pause at 25% then a manual promotion at 50%
+    fn spec() -> ExposedAppSpec {
+        serde_json::from_value(json!({
+            "replicas": 4,
+            "image": CANARY,
+            "ports": [{"name": "http", "port": 80, "containerPort": 8080}],
+            "rollout": {"canary": {"steps": [
+                {"weight": 25, "pauseSeconds": 60},
+                {"weight": 50},
+            ]}},
+        }))
+        .unwrap()
+    }
+
+    fn deployment(
+        replicas: u32,
+        ready: u32,
+        conditions: serde_json::Value,
+    ) -> K8sObject<Deployment> {
+        serde_json::from_value(json!({
+            "apiVersion": "apps/v1",
+            "kind": "Deployment",
+            "metadata": {"name": "demo-canary", "generation": 2},
+            "spec": {
+                "replicas": replicas,
+                "selector": {"matchLabels": {TRACK_LABEL: "canary"}},
+                "template": {
+                    "metadata": {"labels": {TRACK_LABEL: "canary"}},
+                    "spec": {"containers": [{"name": "demo", "image": CANARY}]},
+                },
+            },
+            "status": {
+                "observedGeneration": 2,
+                "updatedReplicas": ready,
+                "readyReplicas": ready,
+                "conditions": conditions,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn ready(replicas: u32) -> K8sObject<Deployment> {
+        deployment(replicas, replicas, json!([]))
+    }
+
+    fn status(step: usize, phase: CanaryPhase, ready_since: Option<i64>) -> CanaryStatus {
+        CanaryStatus {
+            image: String::from(CANARY),
+            stable_image: String::from(STABLE),
+            step,
+            weight: [25, 50][step],
+            phase,
+            message: String::new(),
+            step_ready_time: ready_since.map(|seconds| {
+                Time::new(OffsetDateTime::now_utc() - time::Duration::seconds(seconds))
+            }),
+        }
+    }
+
+    struct Case {
+        name: &'static str,
+        previous: Option<CanaryStatus>,
+        canary: Option<K8sObject<Deployment>>,
+        failure: Option<&'static str>,
+        promote: Option<&'static str>,
+        abort: bool,
+        // Phase, step, stable image, stable replicas, canary replicas and event reason
+        expected: (
+            CanaryPhase,
+            usize,
+            &'static str,
+            u32,
+            Option<u32>,
+            Option<&'static str>,
+        ),
+    }
+
+    impl Default for Case {
+        fn default() -> Self {
+            Case {
+                name: "",
+                previous: Some(status(0, CanaryPhase::Progressing, None)),
+                canary: None,
+                failure: None,
+                promote: None,
+                abort: false,
+                expected: (CanaryPhase::Progressing, 0, STABLE, 3, Some(1), None),
+            }
+        }
+    }
+
+    fn planned(case: &Case, spec: &ExposedAppSpec) -> RolloutPlan {
+        plan(RolloutInput {
+            spec,
+            previous: case.previous.as_ref(),
+            live_stable_image: Some(String::from(STABLE)),
+            canary: case.canary.as_ref(),
+            canary_failure: case.failure.map(String::from),
+            promote: case.promote,
+            abort: case.abort,
+        })
+    }
+
+    #[test]
+    fn rollout_transitions() {
+        use CanaryPhase::{Aborted, Paused, Progressing, Promoted};
+        let cases = [
+            Case {
+                name: "new image starts the first step",
+                previous: None,
+                expected: (Progressing, 0, STABLE, 3, Some(1), Some("CanaryStarted")),
+                ..Case::default()
+            },
+            Case {
+                name: "step waits for the canary replicas",
+                canary: Some(deployment(1, 0, json!([]))),
+                ..Case::default()
+            },
+            Case {
+                name: "ready step holds during its pause",
+                previous: Some(status(0, Progressing, Some(10))),
+                canary: Some(ready(1)),
+                ..Case::default()
+            },
+            Case {
+                name: "ready step starts its pause",
+                canary: Some(ready(1)),
+                ..Case::default()
+            },
+            Case {
+                name: "step advances once ready and paused long enough",
+                previous: Some(status(0, Progressing, Some(120))),
+                canary: Some(ready(1)),
+                expected: (Progressing, 1, STABLE, 2, Some(2), None),
+                ..Case::default()
+            },
+            Case {
+                name: "step without pause waits for the promote annotation",
+                previous: Some(status(1, Progressing, None)),
+                canary: Some(ready(2)),
+                expected: (Paused, 1, STABLE, 2, Some(2), None),
+                ..Case::default()
+            },
+            Case {
+                name: "promote annotation skips the current step",
+                promote: Some("true"),
+                expected: (Progressing, 1, STABLE, 2, Some(2), None),
+                ..Case::default()
+            },
+            Case {
+                name: "promote annotation after the last step promotes",
+                previous: Some(status(1, Paused, None)),
+                canary: Some(ready(2)),
+                promote: Some("true"),
+                expected: (Promoted, 2, CANARY, 4, None, Some("CanaryPromoted")),
+                ..Case::default()
+            },
+            Case {
+                name: "full promotion skips every step",
+                promote: Some("full"),
+                expected: (Promoted, 0, CANARY, 4, None, Some("CanaryPromoted")),
+                ..Case::default()
+            },
+            Case {
+                name: "abort annotation rolls back",
+                canary: Some(ready(1)),
+                abort: true,
+                expected: (Aborted, 0, STABLE, 4, None, Some("CanaryAborted")),
+                ..Case::default()
+            },
+            Case {
+                name: "failing canary pods roll back",
+                canary: Some(deployment(1, 0, json!([]))),
+                failure: Some("canary pod demo-canary-1 is in CrashLoopBackOff"),
+                expected: (Aborted, 0, STABLE, 4, None, Some("CanaryAborted")),
+                ..Case::default()
+            },
+            Case {
+                name: "failures of the replaced canary are ignored",
+                previous: None,
+                failure: Some("canary pod demo-canary-1 is in CrashLoopBackOff"),
+                expected: (Progressing, 0, STABLE, 3, Some(1), Some("CanaryStarted")),
+                ..Case::default()
+            },
+            Case {
+                name: "aborted rollout stays on the stable image",
+                previous: Some(status(0, Aborted, None)),
+                expected: (Aborted, 0, STABLE, 4, None, None),
+                ..Case::default()
+            },
+        ];
+        let spec = spec();
+        for case in cases {
+            let plan = planned(&case, &spec);
+            let status = plan.status.as_ref().unwrap();
+            let (phase, step, stable_image, stable_replicas, canary_replicas, reason) =
+                case.expected;
+            assert_eq!(status.phase, phase, "{}", case.name);
+            assert_eq!(status.step, step, "{}", case.name);
+            assert_eq!(plan.stable_image, stable_image, "{}", case.name);
+            assert_eq!(plan.stable_replicas, stable_replicas, "{}", case.name);
+            assert_eq!(plan.canary_replicas, canary_replicas, "{}", case.name);
+            assert_eq!(
+                plan.event.as_ref().map(|(_, reason, _)| *reason),
+                reason,
+                "{}",
+                case.name
+            );
+            if let Some(failure) = case.failure.filter(|_| phase == Aborted) {
+                assert_eq!(status.message, failure, "{}", case.name);
+            }
+        }
+    }
+
+    #[test]
+    fn pause_is_polled_until_it_ends() {
+        let spec = spec();
+        let holding = planned(
+            &Case {
+                previous: Some(status(0, CanaryPhase::Progressing, Some(58))),
+                canary: Some(ready(1)),
+                ..Case::default()
+            },
+            &spec,
+        );
+        let requeue = holding.requeue.unwrap();
+        assert!(requeue <= Duration::from_secs(2), "{:?}", requeue);
+        assert!(holding.status.unwrap().message.contains("pausing until"));
+        // The pause starts when the step is first seen ready
+        let started = planned(
+            &Case {
+                canary: Some(ready(1)),
+                ..Case::default()
+            },
+            &spec,
+        );
+        assert!(started.status.unwrap().step_ready_time.is_some());
+        assert_eq!(started.requeue, Some(POLL_INTERVAL));
+    }
+
+    #[test]
+    fn exceeded_progress_deadline_is_detected() {
+        let exceeded = json!([{
+            "type": "Progressing",
+            "status": "False",
+            "reason": "ProgressDeadlineExceeded",
+        }]);
+        assert!(progress_deadline_exceeded(&deployment(1, 0, exceeded)));
+        let progressing = json!([{
+            "type": "Progressing",
+            "status": "True",
+            "reason": "ReplicaSetUpdated",
+        }]);
+        assert!(!progress_deadline_exceeded(&deployment(1, 0, progressing)));
+        assert!(!progress_deadline_exceeded(&ready(1)));
+        // Reported by the reconciler as the canary failure
+        let plan = planned(
+            &Case {
+                failure: Some("canary Deployment exceeded its progress deadline"),
+                ..Case::default()
+            },
+            &spec(),
+        );
+        assert_eq!(plan.status.unwrap().phase, CanaryPhase::Aborted);
+    }
+
+    #[test]
+    fn readiness_needs_the_canary_image_and_generation() {
+        assert!(is_ready(&ready(2), 2, CANARY));
+        assert!(!is_ready(&ready(2), 3, CANARY));
+        assert!(!is_ready(&ready(2), 2, STABLE));
+        assert!(!is_ready(&deployment(2, 1, json!([])), 2, CANARY));
+        let mut stale = ready(2);
+        stale.metadata.generation = Some(3);
+        assert!(!is_ready(&stale, 2, CANARY));
+    }
+
+    #[test]
+    fn without_canary_strategy_the_image_is_rolled_directly() {
+        let mut spec = spec();
+        spec.rollout = None;
+        let plan = planned(&Case::default(), &spec);
+        assert_eq!(plan.stable_image, CANARY);
+        assert_eq!(plan.stable_replicas, 4);
+        assert_eq!(plan.canary_replicas, None);
+        assert!(plan.status.is_none());
+    }
+
+    #[test]
+    fn canary_replicas_are_rounded_up() {
+        for (replicas, weight, expected) in [
+            (4, 0, 0),
+            (4, 100, 4),
+            (1, 1, 1),
+            (1, 50, 1),
+            (1, 100, 1),
+            (0, 50, 0),
+            (3, 50, 2),
+            (10, 25, 3),
+            (10, 10, 1),
+        ] {
+            assert_eq!(
+                canary_replicas(replicas, weight),
+                expected,
+                "{} replicas at {}%",
+                replicas,
+                weight
+            );
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, Value};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::error;
+
+/*
+   API traffic of the operator, one JSON record per line.
+   Written by K8sClient when --record is set, served back by replay-api-server.
+   Bodies are kept as JSON when they parse, as strings otherwise.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Record {
+    #[serde(rename_all = "camelCase")]
+    Exchange {
+        sequence: u64,
+        time: String,
+        method: String,
+        // Path and query, e.g. /api/v1/pods?watch=1&resourceVersion=5
+        uri: String,
+        request: Option<Value>,
+        status: u16,
+        // Empty for watches, their events are recorded separately
+        response: Value,
+    },
+    // Chunk of the watch started by the Exchange with the given sequence
+    #[serde(rename_all = "camelCase")]
+    WatchEvent {
+        sequence: u64,
+        time: String,
+        watch: u64,
+        event: Value,
+    },
+    // The API server closed the watch, the operator lists again
+    #[serde(rename_all = "camelCase")]
+    WatchEnd {
+        sequence: u64,
+        time: String,
+        watch: u64,
+    },
+}
+
+const REDACTED: &str = "<redacted>";
+const LAST_APPLIED: &str = "kubectl.kubernetes.io/last-applied-configuration";
+
+fn redact_secret(object: &mut Value) {
+    for field in ["data", "stringData"] {
+        if let Some(values) = object.get_mut(field).and_then(Value::as_object_mut) {
+            for value in values.values_mut() {
+                *value = Value::from(REDACTED);
+            }
+        }
+    }
+    // Holds the whole Secret when it was created with kubectl apply
+    if let Some(annotation) = object.pointer_mut(&format!(
+        "/metadata/annotations/{}",
+        LAST_APPLIED.replace('/', "~1")
+    )) {
+        *annotation = Value::from(REDACTED);
+    }
+}
+
+/*
+   Secret values are replaced, keys are kept so the shape of the traffic stays the same.
+   Secrets are recognized by their URI, list items and watch events carry no kind.
+*/
+pub fn redact(uri: &str, body: &mut Value) {
+    let path = uri.split('?').next().unwrap_or_default();
+    if !path.split('/').any(|segment| segment == "secrets") {
+        return;
+    }
+    if let Some(items) = body.get_mut("items").and_then(Value::as_array_mut) {
+        items.iter_mut().for_each(redact_secret);
+    }
+    if let Some(object) = body.get_mut("object") {
+        redact_secret(object);
+    }
+    redact_secret(body);
+}
+
+pub fn parse_body(body: &str) -> Value {
+    from_str(body).unwrap_or_else(|_| Value::from(body))
+}
+
+fn now() -> String {
+    OffsetDateTime::now_utc().format(&Rfc3339).unwrap()
+}
+
+pub struct Recorder {
+    file: Mutex<File>,
+    sequence: AtomicU64,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Unable to create cassette {}: {}", path.display(), e))?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    fn next(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Every line is flushed, a crashed operator still leaves a usable cassette
+    fn write(&self, record: &Record) {
+        let mut line = serde_json::to_string(record).unwrap();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!("Unable to write cassette: {}", e);
+        }
+    }
+
+    // Returns the sequence, watch events refer to it
+    pub fn exchange(
+        &self,
+        method: &str,
+        uri: &str,
+        request: Option<&str>,
+        status: u16,
+        response: &str,
+    ) -> u64 {
+        let sequence = self.next();
+        let mut request = request.map(parse_body);
+        if let Some(request) = request.as_mut() {
+            redact(uri, request);
+        }
+        let mut response = parse_body(response);
+        redact(uri, &mut response);
+        self.write(&Record::Exchange {
+            sequence,
+            time: now(),
+            method: String::from(method),
+            uri: String::from(uri),
+            request,
+            status,
+            response,
+        });
+        sequence
+    }
+
+    pub fn watch_event(&self, watch: u64, uri: &str, event: &str) {
+        let mut event = parse_body(event);
+        redact(uri, &mut event);
+        self.write(&Record::WatchEvent {
+            sequence: self.next(),
+            time: now(),
+            watch,
+            event,
+        });
+    }
+
+    pub fn watch_end(&self, watch: u64) {
+        self.write(&Record::WatchEnd {
+            sequence: self.next(),
+            time: now(),
+            watch,
+        });
+    }
+}
+
+// Read by the replay server
+pub fn load(path: &Path) -> Result<Vec<Record>, String> {
+    let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|(number, line)| {
+            let line = line.map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+            from_str::<Record>(line.as_str())
+                .map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))
+        })
+        .collect()
+}
@@ -0,0 +1,94 @@
+use crate::k8s_types::{Metadata, ObjectReference};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+type Owned = BTreeMap<(String, String, String), ObjectReference>;
+
+/*
+   Objects owned by each primary object, keyed by the UID of their controller owner.
+   Kept up to date by the watches of owned kinds, read by the reconciler to delete
+   the children it no longer provisions, e.g. after a naming change.
+*/
+#[derive(Clone, Default)]
+pub struct Children {
+    owned: Arc<Mutex<HashMap<String, Owned>>>,
+}
+
+fn controller_uid(owner_kind: &str, metadata: &Metadata) -> Option<String> {
+    metadata
+        .owner_references
+        .as_ref()?
+        .iter()
+        .find(|owner| owner.controller && owner.kind == owner_kind)
+        .map(|owner| owner.uid.clone())
+}
+
+fn reference(api_version: &str, kind: &str, metadata: Metadata) -> ObjectReference {
+    ObjectReference {
+        api_version: String::from(api_version),
+        kind: String::from(kind),
+        name: metadata.name.unwrap_or_default(),
+        namespace: metadata.namespace.unwrap_or_default(),
+        uid: metadata.uid.unwrap_or_default(),
+    }
+}
+
+fn key(child: &ObjectReference) -> (String, String, String) {
+    (
+        child.kind.clone(),
+        child.namespace.clone(),
+        child.name.clone(),
+    )
+}
+
+impl Children {
+    // Replaces every known child of the kind after a list
+    pub fn listed(&self, owner_kind: &str, api_version: &str, kind: &str, objects: Vec<Metadata>) {
+        let mut owned = self.owned.lock().unwrap();
+        for children in owned.values_mut() {
+            children.retain(|(child_kind, _, _), _| child_kind != kind);
+        }
+        for metadata in objects {
+            if let Some(uid) = controller_uid(owner_kind, &metadata) {
+                let child = reference(api_version, kind, metadata);
+                owned.entry(uid).or_default().insert(key(&child), child);
+            }
+        }
+        owned.retain(|_, children| !children.is_empty());
+    }
+
+    pub fn received(
+        &self,
+        owner_kind: &str,
+        api_version: &str,
+        kind: &str,
+        metadata: Metadata,
+        deleted: bool,
+    ) {
+        let uid = match controller_uid(owner_kind, &metadata) {
+            Some(uid) => uid,
+            None => return,
+        };
+        let child = reference(api_version, kind, metadata);
+        let mut owned = self.owned.lock().unwrap();
+        if deleted {
+            if let Some(children) = owned.get_mut(&uid) {
+                children.remove(&key(&child));
+                if children.is_empty() {
+                    owned.remove(&uid);
+                }
+            }
+        } else {
+            owned.entry(uid).or_default().insert(key(&child), child);
+        }
+    }
+
+    pub fn of(&self, owner_uid: &str) -> Vec<ObjectReference> {
+        self.owned
+            .lock()
+            .unwrap()
+            .get(owner_uid)
+            .map(|children| children.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
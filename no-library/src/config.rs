@@ -0,0 +1,445 @@
+use clap::builder::BoolishValueParser;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/*
+   Operator configuration.
+   Every value is taken from the first source that defines it:
+   command line flag, environment variable, YAML config file, built-in default.
+   Switches take an optional value, e.g. --dry-run=false or DRY_RUN=false turns off
+   dry-run enabled by the config file.
+*/
+#[derive(Parser)]
+#[command(version, about = "ExposedApp operator")]
+struct Args {
+    /// YAML config file
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// Health check port
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
+    /// HTTPS port of the conversion webhook
+    #[arg(long, env = "WEBHOOK_PORT")]
+    webhook_port: Option<u16>,
+    /// Directory holding tls.crt and tls.key, webhooks are served only when set
+    #[arg(long, env = "CERT_DIR")]
+    cert_dir: Option<PathBuf>,
+    /// Identity used in the Lease and reported in Events
+    #[arg(long, env = "POD_NAME")]
+    pod_name: Option<String>,
+    #[arg(long, env = "LEASE_NAME")]
+    lease_name: Option<String>,
+    #[arg(long, env = "LEASE_NAMESPACE")]
+    lease_namespace: Option<String>,
+    /// Delay before a closed or failed watch is restarted
+    #[arg(long, env = "WATCH_RETRY_SECONDS")]
+    watch_retry_seconds: Option<u64>,
+    /// First reconcile retry delay, doubled on every failure
+    #[arg(long, env = "INITIAL_BACKOFF_SECONDS")]
+    initial_backoff_seconds: Option<u64>,
+    #[arg(long, env = "MAX_BACKOFF_SECONDS")]
+    max_backoff_seconds: Option<u64>,
+    /// Consecutive reconcile failures after which an ExposedApp is reported Stalled
+    #[arg(long, env = "STALL_AFTER_FAILURES")]
+    stall_after_failures: Option<u32>,
+    /// Fixed retry delay of Stalled ExposedApps
+    #[arg(long, env = "STALLED_RETRY_SECONDS")]
+    stalled_retry_seconds: Option<u64>,
+    /// Reconcile requests buffered between watches and the reconciler
+    #[arg(long, env = "CHANNEL_SIZE")]
+    channel_size: Option<usize>,
+    #[arg(long, env = "USER_AGENT")]
+    user_agent: Option<String>,
+    /// API server URL, in-cluster service account is used when not set
+    #[arg(long, env = "KUBERNETES_API_URL")]
+    api_server_url: Option<String>,
+    /// PEM bundle trusted in addition to the system roots, used together with --api-server-url
+    #[arg(long, env = "KUBERNETES_CA_FILE")]
+    ca_file: Option<PathBuf>,
+    /// Bearer token used together with --api-server-url
+    #[arg(long, env = "KUBERNETES_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Bearer token of the /debug routes, they are served only when set
+    #[arg(long, env = "DEBUG_TOKEN", hide_env_values = true)]
+    debug_token: Option<String>,
+    #[arg(long, env = "K8S_CLIENT_QPS")]
+    client_qps: Option<f64>,
+    #[arg(long, env = "K8S_CLIENT_BURST")]
+    client_burst: Option<u32>,
+    #[arg(long, env = "K8S_CLIENT_MAX_RETRIES")]
+    client_max_retries: Option<u32>,
+    /// Records API requests and responses to this JSONL cassette, Secret data is redacted
+    #[arg(long, env = "RECORD_FILE")]
+    record: Option<PathBuf>,
+    /// Split ExposedApps between replicas instead of electing a single leader
+    #[arg(long, env = "SHARDING", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    sharding: Option<bool>,
+    /// Shard member Lease duration, a replica not renewing it for that long loses its shard
+    #[arg(long, env = "SHARD_LEASE_SECONDS")]
+    shard_lease_seconds: Option<u64>,
+    /// Log what would change without modifying anything, leader election is skipped
+    #[arg(long, env = "DRY_RUN", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    dry_run: Option<bool>,
+    /// Leave the children of every ExposedApp untouched, status still reports the pause
+    #[arg(long, env = "PAUSED", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    paused: Option<bool>,
+    /// Check RBAC, install the CRD and create the Lease before starting
+    #[arg(long, env = "BOOTSTRAP", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    bootstrap: Option<bool>,
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+    /// Base URL of an OTLP/HTTP collector, spans are exported to {url}/v1/traces when set
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct FileConfig {
+    port: Option<u16>,
+    webhook_port: Option<u16>,
+    cert_dir: Option<PathBuf>,
+    pod_name: Option<String>,
+    lease_name: Option<String>,
+    lease_namespace: Option<String>,
+    watch_retry_seconds: Option<u64>,
+    initial_backoff_seconds: Option<u64>,
+    max_backoff_seconds: Option<u64>,
+    stall_after_failures: Option<u32>,
+    stalled_retry_seconds: Option<u64>,
+    channel_size: Option<usize>,
+    user_agent: Option<String>,
+    api_server_url: Option<String>,
+    ca_file: Option<PathBuf>,
+    client_qps: Option<f64>,
+    client_burst: Option<u32>,
+    client_max_retries: Option<u32>,
+    record: Option<PathBuf>,
+    dry_run: Option<bool>,
+    paused: Option<bool>,
+    bootstrap: Option<bool>,
+    sharding: Option<bool>,
+    shard_lease_seconds: Option<u64>,
+    log_format: Option<LogFormat>,
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One object per line, with the fields of the current span and its parents
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub api_server_url: Option<String>,
+    pub ca_file: Option<PathBuf>,
+    pub token: Option<String>,
+    pub user_agent: String,
+    pub qps: f64,
+    pub burst: u32,
+    pub max_retries: u32,
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub port: u16,
+    pub webhook_port: u16,
+    pub cert_dir: Option<PathBuf>,
+    pub pod_name: String,
+    pub lease_name: String,
+    pub lease_namespace: String,
+    pub watch_retry: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub stall_after: u32,
+    pub stalled_retry: Duration,
+    pub channel_size: usize,
+    pub dry_run: bool,
+    pub paused: bool,
+    pub bootstrap: bool,
+    pub sharding: bool,
+    pub shard_lease_duration: Duration,
+    pub debug_token: Option<String>,
+    pub log_format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+    pub client: ClientConfig,
+}
+
+// https://kubernetes.io/docs/concepts/overview/working-with-objects/names/#dns-subdomain-names
+fn validate_name(field: &str, value: &str) -> Result<(), String> {
+    let valid = !value.is_empty()
+        && value.len() <= 253
+        && value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+        && value.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && value.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("{} '{}' is not a valid object name", field, value))
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, String> {
+        Self::from_args(Args::parse())
+    }
+
+    // Command line given explicitly, e.g. by tests, the environment is still read
+    pub fn parse_from<I, T>(args: I) -> Result<Config, String>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args = Args::try_parse_from(args).map_err(|e| e.to_string())?;
+        Self::from_args(args)
+    }
+
+    fn from_args(args: Args) -> Result<Config, String> {
+        let file = match &args.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+                serde_yaml::from_str::<FileConfig>(content.as_str())
+                    .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?
+            }
+            None => FileConfig::default(),
+        };
+        let config = Config {
+            port: args.port.or(file.port).unwrap_or(3000),
+            webhook_port: args.webhook_port.or(file.webhook_port).unwrap_or(8443),
+            cert_dir: args.cert_dir.or(file.cert_dir),
+            pod_name: args
+                .pod_name
+                .or(file.pod_name)
+                .ok_or("Pod name expected, set --pod-name or POD_NAME")?,
+            lease_name: args
+                .lease_name
+                .or(file.lease_name)
+                .unwrap_or(String::from("no-library")),
+            lease_namespace: args
+                .lease_namespace
+                .or(file.lease_namespace)
+                .unwrap_or(String::from("no-library")),
+            watch_retry: Duration::from_secs(
+                args.watch_retry_seconds
+                    .or(file.watch_retry_seconds)
+                    .unwrap_or(2),
+            ),
+            initial_backoff: Duration::from_secs(
+                args.initial_backoff_seconds
+                    .or(file.initial_backoff_seconds)
+                    .unwrap_or(2),
+            ),
+            max_backoff: Duration::from_secs(
+                args.max_backoff_seconds
+                    .or(file.max_backoff_seconds)
+                    .unwrap_or(128),
+            ),
+            stall_after: args
+                .stall_after_failures
+                .or(file.stall_after_failures)
+                .unwrap_or(10),
+            stalled_retry: Duration::from_secs(
+                args.stalled_retry_seconds
+                    .or(file.stalled_retry_seconds)
+                    .unwrap_or(900),
+            ),
+            channel_size: args.channel_size.or(file.channel_size).unwrap_or(64),
+            dry_run: args.dry_run.or(file.dry_run).unwrap_or(false),
+            paused: args.paused.or(file.paused).unwrap_or(false),
+            bootstrap: args.bootstrap.or(file.bootstrap).unwrap_or(false),
+            sharding: args.sharding.or(file.sharding).unwrap_or(false),
+            shard_lease_duration: Duration::from_secs(
+                args.shard_lease_seconds
+                    .or(file.shard_lease_seconds)
+                    .unwrap_or(30),
+            ),
+            debug_token: args.debug_token,
+            log_format: args.log_format.or(file.log_format).unwrap_or_default(),
+            otlp_endpoint: args.otlp_endpoint.or(file.otlp_endpoint),
+            client: ClientConfig {
+                api_server_url: args.api_server_url.or(file.api_server_url),
+                ca_file: args.ca_file.or(file.ca_file),
+                token: args.token,
+                user_agent: args
+                    .user_agent
+                    .or(file.user_agent)
+                    .unwrap_or(String::from("exposed-apps-controller")),
+                // controller-runtime defaults
+                qps: args.client_qps.or(file.client_qps).unwrap_or(20.0),
+                burst: args.client_burst.or(file.client_burst).unwrap_or(30),
+                max_retries: args
+                    .client_max_retries
+                    .or(file.client_max_retries)
+                    .unwrap_or(5),
+                record: args.record.or(file.record),
+            },
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.pod_name.is_empty() {
+            return Err(String::from("Pod name must not be empty"));
+        }
+        if self.cert_dir.is_some() && self.webhook_port == self.port {
+            return Err(format!(
+                "Webhook port {} is already used by the health check",
+                self.port
+            ));
+        }
+        validate_name("Lease name", self.lease_name.as_str())?;
+        validate_name("Lease namespace", self.lease_namespace.as_str())?;
+        if self.watch_retry.is_zero() {
+            return Err(String::from("Watch retry interval must be positive"));
+        }
+        if self.initial_backoff.is_zero() {
+            return Err(String::from("Initial backoff must be positive"));
+        }
+        if self.max_backoff < self.initial_backoff {
+            return Err(format!(
+                "Max backoff {:?} is lower than initial backoff {:?}",
+                self.max_backoff, self.initial_backoff
+            ));
+        }
+        if self.stall_after == 0 {
+            return Err(String::from("Stall threshold must be positive"));
+        }
+        if self.stalled_retry < self.max_backoff {
+            return Err(format!(
+                "Stalled retry interval {:?} is lower than max backoff {:?}",
+                self.stalled_retry, self.max_backoff
+            ));
+        }
+        if self.bootstrap && self.dry_run {
+            return Err(String::from(
+                "Bootstrap installs the CRD and Lease, it can't be combined with dry-run",
+            ));
+        }
+        if self.sharding && self.shard_lease_duration.as_secs() < 3 {
+            return Err(String::from(
+                "Shard lease duration must be at least 3 seconds",
+            ));
+        }
+        if self
+            .debug_token
+            .as_ref()
+            .is_some_and(|token| token.len() < 16)
+        {
+            return Err(String::from("Debug token must be at least 16 characters"));
+        }
+        if self.channel_size == 0 {
+            return Err(String::from("Channel size must be positive"));
+        }
+        if self.client.user_agent.is_empty() {
+            return Err(String::from("User agent must not be empty"));
+        }
+        if !self.client.qps.is_finite() || self.client.qps < 0.0 {
+            return Err(format!(
+                "Client QPS {} must be a non-negative number, 0 disables rate limiting",
+                self.client.qps
+            ));
+        }
+        if self.client.burst == 0 {
+            return Err(String::from("Client burst must be positive"));
+        }
+        if let Some(url) = &self.client.api_server_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(format!("API server URL {} must be http(s)", url));
+            }
+        }
+        if self.client.ca_file.is_some() && self.client.api_server_url.is_none() {
+            return Err(String::from(
+                "CA file is only used with an API server URL, in-cluster the service account CA is",
+            ));
+        }
+        if let Some(url) = &self.otlp_endpoint {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(format!("OTLP endpoint {} must be http(s)", url));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    // Held while parsing, the environment is shared by the test threads
+    static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("no-library-{}-{}.yaml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn parse(args: &[&str]) -> Config {
+        let _environment = ENVIRONMENT.lock().unwrap();
+        Config::parse_from(["no-library", "--pod-name", "test"].iter().chain(args)).unwrap()
+    }
+
+    fn with_config(path: &Path, args: &[&str]) -> Config {
+        let path = path.to_str().unwrap();
+        parse(&[&["--config", path], args].concat())
+    }
+
+    #[test]
+    fn switches_default_to_off() {
+        let config = parse(&[]);
+        assert!(!config.dry_run && !config.paused && !config.bootstrap && !config.sharding);
+    }
+
+    #[test]
+    fn switch_without_value_is_on() {
+        let config = parse(&["--paused", "--sharding"]);
+        assert!(config.paused && config.sharding);
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let path = config_file(
+            "command-line",
+            "dryRun: true\npaused: true\nsharding: true\n",
+        );
+        let config = with_config(&path, &["--paused=false", "--sharding", "false"]);
+        assert!(config.dry_run);
+        assert!(!config.paused);
+        assert!(!config.sharding);
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let path = config_file("environment", "dryRun: true\n");
+        let config = {
+            let _environment = ENVIRONMENT.lock().unwrap();
+            std::env::set_var("DRY_RUN", "false");
+            let config = Config::parse_from([
+                "no-library",
+                "--pod-name",
+                "test",
+                "--config",
+                path.to_str().unwrap(),
+            ]);
+            std::env::remove_var("DRY_RUN");
+            config.unwrap()
+        };
+        assert!(!config.dry_run);
+        assert!(with_config(&path, &[]).dry_run);
+    }
+}
@@ -0,0 +1,592 @@
+use crate::cache::{clone_cache, new_cache, Cache, NamespacedName};
+use crate::children::Children;
+use crate::config::Config;
+use crate::discovery::with_query;
+use crate::introspection::Introspection;
+use crate::k8s_client::client::{K8sClient, K8sClientError};
+use crate::k8s_types::{K8sListObject, MetadataAware, Resource, WatchEventType};
+use crate::protobuf::Protobuf;
+use crate::sharding::Sharding;
+use futures::future::BoxFuture;
+use futures::{pin_mut, FutureExt, StreamExt};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+use tokio_util::time::delay_queue::Key;
+use tokio_util::time::DelayQueue;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+pub enum Action {
+    Done,
+    Requeue(Duration),
+}
+
+impl Action {
+    // The earliest of both requeues
+    pub fn merge(self, other: Action) -> Action {
+        match (self, other) {
+            (Action::Requeue(a), Action::Requeue(b)) => Action::Requeue(a.min(b)),
+            (Action::Requeue(a), Action::Done) | (Action::Done, Action::Requeue(a)) => {
+                Action::Requeue(a)
+            }
+            (Action::Done, Action::Done) => Action::Done,
+        }
+    }
+}
+
+pub trait Reconcile: Send {
+    type Error: Display + Send;
+
+    fn reconcile(
+        &mut self,
+        request: NamespacedName,
+    ) -> impl Future<Output = Result<Action, Self::Error>> + Send;
+
+    // Called once the error policy decided when to retry, None when it gave up
+    fn failed(
+        &mut self,
+        _request: &NamespacedName,
+        _error: &Self::Error,
+        _failures: u32,
+        _retry: Option<Duration>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+// Decides when a failed request is retried, failures counts consecutive errors
+pub trait ErrorPolicy: Send + Sync {
+    fn on_error(&self, request: &NamespacedName, error: &str, failures: u32) -> Action;
+}
+
+// Default policy, the delay doubles on every failure up to max
+pub struct ExponentialBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl ErrorPolicy for ExponentialBackoff {
+    fn on_error(&self, _request: &NamespacedName, _error: &str, failures: u32) -> Action {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        Action::Requeue(self.initial.saturating_mul(factor).min(self.max))
+    }
+}
+
+// Retries at the fixed stalled interval once stall_after consecutive failures are reached
+pub struct StalledRetry {
+    pub backoff: ExponentialBackoff,
+    pub stall_after: u32,
+    pub stalled_retry: Duration,
+}
+
+impl StalledRetry {
+    pub fn new(config: &Config) -> Self {
+        StalledRetry {
+            backoff: ExponentialBackoff {
+                initial: config.initial_backoff,
+                max: config.max_backoff,
+            },
+            stall_after: config.stall_after,
+            stalled_retry: config.stalled_retry,
+        }
+    }
+}
+
+impl ErrorPolicy for StalledRetry {
+    fn on_error(&self, request: &NamespacedName, error: &str, failures: u32) -> Action {
+        if failures >= self.stall_after {
+            return Action::Requeue(self.stalled_retry);
+        }
+        self.backoff.on_error(request, error, failures)
+    }
+}
+
+// Maps a watched object to the primary objects that have to be reconciled
+pub type Mapper<T> = Arc<dyn Fn(&K8sListObject<T>) -> Vec<NamespacedName> + Send + Sync>;
+
+enum CacheCheck {
+    Always,
+    // Deleted child keeps the cached version, it has to be recreated
+    ExceptDeleted,
+    Never,
+}
+
+struct WatchContext {
+    client: K8sClient,
+    sender: Sender<NamespacedName>,
+    cache: Cache,
+    children: Children,
+    watch_retry: Duration,
+    introspection: Introspection,
+}
+
+type WatchTask = Box<dyn FnOnce(WatchContext) -> BoxFuture<'static, ()> + Send>;
+
+type ListTask = Box<
+    dyn Fn(K8sClient) -> BoxFuture<'static, Result<Vec<NamespacedName>, K8sClientError>> + Send,
+>;
+
+/*
+   List-watch-reconcile loop for one primary kind.
+   Every watch maps its events to primary NamespacedNames which are queued,
+   deduplicated and handed to the Reconcile implementation one at a time.
+
+   Controller::new(client, &config)
+       .for_resource::<ExposedApp>()
+       .owns::<Deployment>()
+       .watches::<ConfigMap>(mapper)
+       .run(reconciler)
+*/
+pub struct Controller {
+    client: K8sClient,
+    config: Config,
+    cache: Cache,
+    children: Children,
+    primary_kind: Option<&'static str>,
+    // Children of cluster scoped primaries are mapped to names without namespace
+    primary_namespaced: bool,
+    primary_list: Option<ListTask>,
+    watches: Vec<WatchTask>,
+    sharding: Option<Arc<Sharding>>,
+    leader: Option<Arc<Notify>>,
+    error_policy: Box<dyn ErrorPolicy>,
+    introspection: Introspection,
+}
+
+fn namespaced_name(object: &impl MetadataAware) -> NamespacedName {
+    let metadata = object.metadata();
+    NamespacedName::new(
+        metadata.name.unwrap_or_default().as_str(),
+        metadata.namespace.unwrap_or_default().as_str(),
+    )
+}
+
+async fn can_skip_reconcile(object: &impl MetadataAware, cache: &Cache) -> bool {
+    let metadata = object.metadata();
+    let name = metadata.name.unwrap_or_default();
+    let namespace = metadata.namespace.unwrap_or_default();
+    let namespaced_name = NamespacedName::new(name.as_str(), namespace.as_str());
+    let resource_version = metadata.resource_version.unwrap_or_default();
+    let map = cache.lock().await;
+    if let Some(value) = map.get(&namespaced_name) {
+        let same_annotations = value.annotations.as_ref().is_none_or(|annotations| {
+            *annotations == metadata.annotations.clone().unwrap_or_default()
+        });
+        if let (Some(left), Some(right)) = (metadata.generation, value.generation) {
+            if left == right && same_annotations {
+                info!("Object {} generation {}. Status updated, skip.", name, left);
+                return true;
+            }
+        }
+        if resource_version == value.resource_version {
+            info!(
+                "Object {} version {} already handled, skip",
+                name, resource_version
+            );
+            return true;
+        }
+    }
+    false
+}
+
+// Objects controlled by an owner of the given kind are recorded as its children
+async fn watch<T: Resource + Protobuf + DeserializeOwned + Send + Sync + 'static>(
+    mut context: WatchContext,
+    uri: String,
+    mapper: Mapper<T>,
+    cache_check: CacheCheck,
+    owner_kind: Option<&'static str>,
+) {
+    loop {
+        info!("Watching {}", T::KIND);
+        match context.client.get_all::<T>(uri.as_str()).await {
+            Ok(list) => {
+                let resource_version = list.metadata.resource_version.clone().unwrap();
+                let objects = list
+                    .items
+                    .iter()
+                    .map(|item| {
+                        let version = item.metadata.resource_version.clone().unwrap_or_default();
+                        (namespaced_name(item), version)
+                    })
+                    .collect();
+                context.introspection.listed(
+                    T::KIND,
+                    uri.as_str(),
+                    resource_version.as_str(),
+                    objects,
+                );
+                if let Some(owner_kind) = owner_kind {
+                    let objects = list.items.iter().map(|item| item.metadata()).collect();
+                    context
+                        .children
+                        .listed(owner_kind, T::API_VERSION, T::KIND, objects);
+                }
+                let requests: Vec<NamespacedName> =
+                    list.items.iter().flat_map(|item| mapper(item)).collect();
+                for request in requests {
+                    context.sender.send(request).await.unwrap();
+                }
+                match context
+                    .client
+                    .watch::<T>(uri.as_str(), resource_version.as_str())
+                    .await
+                {
+                    Ok(stream) => {
+                        pin_mut!(stream);
+                        while let Some(event) = stream.next().await {
+                            let metadata = event.object.metadata();
+                            info!(
+                                "Received {} {:?} event for {}, version {}",
+                                T::KIND,
+                                event.event_type,
+                                metadata.name.unwrap_or_default(),
+                                metadata.resource_version.clone().unwrap_or_default()
+                            );
+                            context.introspection.received(
+                                T::KIND,
+                                &namespaced_name(&event.object),
+                                metadata.resource_version.unwrap_or_default().as_str(),
+                                matches!(event.event_type, WatchEventType::Deleted),
+                            );
+                            if let Some(owner_kind) = owner_kind {
+                                context.children.received(
+                                    owner_kind,
+                                    T::API_VERSION,
+                                    T::KIND,
+                                    event.object.metadata(),
+                                    matches!(event.event_type, WatchEventType::Deleted),
+                                );
+                            }
+                            let check = match cache_check {
+                                CacheCheck::Always => true,
+                                CacheCheck::ExceptDeleted => {
+                                    !matches!(event.event_type, WatchEventType::Deleted)
+                                }
+                                CacheCheck::Never => false,
+                            };
+                            if check && can_skip_reconcile(&event.object, &context.cache).await {
+                                continue;
+                            }
+                            for request in mapper(&event.object) {
+                                info!("Sending reconcile request for {}", request.name);
+                                context.sender.send(request).await.unwrap();
+                            }
+                        }
+                        warn!("{} stream closed. Will retry", T::KIND);
+                    }
+                    Err(e) => {
+                        error!("Error occurred while trying to watch {}: {:?}", T::KIND, e);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Error occurred while trying to list {}: {:?}", T::KIND, e);
+            }
+        }
+        sleep(context.watch_retry).await;
+    }
+}
+
+// Objects moved to this replica are enqueued, the ones moved away are dropped by process
+async fn rebalance(
+    client: K8sClient,
+    list: ListTask,
+    sharding: Arc<Sharding>,
+    sender: Sender<NamespacedName>,
+) {
+    let mut changes = sharding.subscribe();
+    while changes.changed().await.is_ok() {
+        info!("Shard rebalanced, listing owned objects");
+        match list(client.clone()).await {
+            Ok(requests) => {
+                for request in requests.into_iter().filter(|r| sharding.owns(r)) {
+                    sender.send(request).await.unwrap();
+                }
+            }
+            Err(e) => error!("Unable to list objects after rebalance: {:?}", e),
+        }
+    }
+}
+
+fn schedule(
+    queue: &mut DelayQueue<NamespacedName>,
+    queued: &mut HashMap<NamespacedName, Key>,
+    request: NamespacedName,
+    delay: Duration,
+) {
+    match queued.get(&request) {
+        // An event wins over a pending retry
+        Some(key) if delay.is_zero() => queue.reset(key, delay),
+        Some(_) => {}
+        None => {
+            let key = queue.insert(request.clone(), delay);
+            queued.insert(request, key);
+        }
+    }
+}
+
+/*
+   Parent of every log line and API request of one reconcile.
+   The generation is recorded by the reconciler once the object is read.
+*/
+fn reconcile_span(kind: &str, request: &NamespacedName) -> Span {
+    let reconcile_id = Alphanumeric
+        .sample_string(&mut rand::rng(), 12)
+        .to_lowercase();
+    info_span!(
+        "reconcile",
+        kind,
+        namespace = request.namespace.as_str(),
+        object_name = request.name.as_str(),
+        reconcile_id,
+        generation = field::Empty,
+    )
+}
+
+async fn process<R: Reconcile>(
+    kind: &str,
+    mut reconciler: R,
+    mut receiver: Receiver<NamespacedName>,
+    error_policy: Box<dyn ErrorPolicy>,
+    sharding: Option<Arc<Sharding>>,
+    introspection: Introspection,
+) {
+    let owned = |request: &NamespacedName| sharding.as_ref().is_none_or(|s| s.owns(request));
+    let mut queue: DelayQueue<NamespacedName> = DelayQueue::with_capacity(32);
+    let mut queued: HashMap<NamespacedName, Key> = HashMap::new();
+    let mut failures: HashMap<NamespacedName, u32> = HashMap::new();
+    loop {
+        select! {
+            Some(request) = receiver.recv() => {
+                if !owned(&request) {
+                    debug!("{} {} belongs to another shard", kind, request.name);
+                    continue;
+                }
+                info!("{} {} enqueued", kind, request.name);
+                schedule(&mut queue, &mut queued, request.clone(), Duration::ZERO);
+                let failures = failures.get(&request).copied().unwrap_or(0);
+                introspection.queued(&request, queue.deadline(&queued[&request]), failures, None);
+            }
+            Some(expired) = queue.next() => {
+                let request = expired.into_inner();
+                queued.remove(&request);
+                introspection.dequeued(&request);
+                // Membership might have changed while the request was queued
+                if !owned(&request) {
+                    failures.remove(&request);
+                    continue;
+                }
+                let span = reconcile_span(kind, &request);
+                let action = async {
+                    info!("{} {} ready to reconcile", kind, request.name);
+                    match reconciler.reconcile(request.clone()).await {
+                        Ok(action) => {
+                            info!("{} {} successfully reconciled", kind, request.name);
+                            failures.remove(&request);
+                            action
+                        }
+                        Err(e) => {
+                            error!("{} {} reconcile failed: {}", kind, request.name, e);
+                            let count = failures.entry(request.clone()).or_insert(0);
+                            *count += 1;
+                            let action = error_policy.on_error(&request, e.to_string().as_str(), *count);
+                            let retry = match action {
+                                Action::Requeue(delay) => Some(delay),
+                                Action::Done => None,
+                            };
+                            reconciler.failed(&request, &e, *count, retry).await;
+                            action
+                        }
+                    }
+                }
+                .instrument(span)
+                .await;
+                if let Action::Requeue(delay) = action {
+                    info!("{} {} requeued in {:?}", kind, request.name, delay);
+                    schedule(&mut queue, &mut queued, request.clone(), delay);
+                    let failures = failures.get(&request).copied().unwrap_or(0);
+                    let backoff = (failures > 0).then_some(delay);
+                    introspection.queued(&request, queue.deadline(&queued[&request]), failures, backoff);
+                }
+            }
+            else => return,
+        }
+    }
+}
+
+impl Controller {
+    pub fn new(client: K8sClient, config: &Config) -> Self {
+        Controller {
+            client,
+            config: config.clone(),
+            cache: new_cache(),
+            children: Children::default(),
+            primary_kind: None,
+            primary_namespaced: true,
+            primary_list: None,
+            watches: Vec::new(),
+            sharding: None,
+            leader: None,
+            error_policy: Box::new(ExponentialBackoff {
+                initial: config.initial_backoff,
+                max: config.max_backoff,
+            }),
+            introspection: Introspection::default(),
+        }
+    }
+
+    fn add_watch<T: Resource + Protobuf + DeserializeOwned + Send + Sync + 'static>(
+        mut self,
+        uri: String,
+        mapper: Mapper<T>,
+        cache_check: CacheCheck,
+        owner_kind: Option<&'static str>,
+    ) -> Self {
+        self.watches.push(Box::new(move |context| {
+            watch::<T>(context, uri, mapper, cache_check, owner_kind).boxed()
+        }));
+        self
+    }
+
+    // Kind reconciled by this controller
+    pub fn for_resource<T: Resource + Protobuf + DeserializeOwned + Send + Sync + 'static>(
+        mut self,
+    ) -> Self {
+        self.primary_kind = Some(T::KIND);
+        self.primary_namespaced = self.client.api_resource::<T>().namespaced;
+        let uri = self.client.api_resource::<T>().list_uri();
+        let list_uri = uri.clone();
+        self.primary_list = Some(Box::new(move |mut client: K8sClient| {
+            let uri = list_uri.clone();
+            async move {
+                let list = client.get_all::<T>(uri.as_str()).await?;
+                Ok(list.items.iter().map(namespaced_name).collect())
+            }
+            .boxed()
+        }));
+        let mapper: Mapper<T> = Arc::new(|object| vec![namespaced_name(object)]);
+        self.add_watch(uri, mapper, CacheCheck::Always, None)
+    }
+
+    // Children are mapped to their controller owner of the primary kind
+    pub fn owns<T: Resource + Protobuf + DeserializeOwned + Send + Sync + 'static>(self) -> Self {
+        let owner_kind = self
+            .primary_kind
+            .expect("for_resource has to be called before owns");
+        let namespaced = self.primary_namespaced;
+        let mapper: Mapper<T> = Arc::new(move |object| {
+            let metadata = object.metadata();
+            let namespace = if namespaced {
+                metadata.namespace.unwrap_or_default()
+            } else {
+                String::new()
+            };
+            metadata
+                .owner_references
+                .unwrap_or_default()
+                .iter()
+                .filter(|owner| owner.kind == owner_kind)
+                .map(|owner| NamespacedName::new(owner.name.as_str(), namespace.as_str()))
+                .collect()
+        });
+        let uri = self.client.api_resource::<T>().list_uri();
+        self.add_watch(uri, mapper, CacheCheck::ExceptDeleted, Some(owner_kind))
+    }
+
+    // Related objects, optionally narrowed down by a label selector
+    pub fn watches<T: Resource + Protobuf + DeserializeOwned + Send + Sync + 'static>(
+        self,
+        label_selector: Option<&str>,
+        mapper: impl Fn(&K8sListObject<T>) -> Vec<NamespacedName> + Send + Sync + 'static,
+    ) -> Self {
+        let list_uri = self.client.api_resource::<T>().list_uri();
+        let uri = match label_selector {
+            Some(selector) => with_query(list_uri.as_str(), "labelSelector", selector),
+            None => list_uri,
+        };
+        self.add_watch(uri, Arc::new(mapper), CacheCheck::Never, None)
+    }
+
+    // Nothing is watched nor reconciled before the notification, controllers may share it
+    pub fn leader_gate(mut self, notify: Arc<Notify>) -> Self {
+        self.leader = Some(notify);
+        self
+    }
+
+    // Only objects hashed to this replica are reconciled
+    pub fn shard(mut self, sharding: Arc<Sharding>) -> Self {
+        self.sharding = Some(sharding);
+        self
+    }
+
+    pub fn error_policy(mut self, policy: impl ErrorPolicy + 'static) -> Self {
+        self.error_policy = Box::new(policy);
+        self
+    }
+
+    // Records watches and the queue in the given handle, served on the debug routes
+    pub fn introspect(mut self, introspection: Introspection) -> Self {
+        introspection.register_cache(self.cache());
+        self.introspection = introspection;
+        self
+    }
+
+    // Shared with the reconciler, records versions written by the operator itself
+    pub fn cache(&self) -> Cache {
+        clone_cache(&self.cache)
+    }
+
+    // Children of every primary object, as seen by the watches of owned kinds
+    pub fn children(&self) -> Children {
+        self.children.clone()
+    }
+
+    pub async fn run<R: Reconcile>(self, reconciler: R) {
+        let kind = self
+            .primary_kind
+            .expect("for_resource has to be called before run");
+        if let Some(leader) = &self.leader {
+            leader.notified().await;
+            // Notified once, the permit is handed on to the next gated controller
+            leader.notify_one();
+        }
+        info!("Starting {} controller", kind);
+        let (sender, receiver) = mpsc::channel(self.config.channel_size);
+        self.introspection.register_sender(sender.clone());
+        let mut tasks = JoinSet::new();
+        for watch in self.watches {
+            tasks.spawn(watch(WatchContext {
+                client: self.client.clone(),
+                sender: sender.clone(),
+                cache: clone_cache(&self.cache),
+                children: self.children.clone(),
+                watch_retry: self.config.watch_retry,
+                introspection: self.introspection.clone(),
+            }));
+        }
+        if let (Some(sharding), Some(list)) = (&self.sharding, self.primary_list) {
+            tasks.spawn(rebalance(
+                self.client.clone(),
+                list,
+                Arc::clone(sharding),
+                sender.clone(),
+            ));
+        }
+        select! {
+            _ = process(kind, reconciler, receiver, self.error_policy, self.sharding, self.introspection) => {
+                error!("{} reconcile loop stopped", kind)
+            }
+            _ = tasks.join_next() => { error!("{} watch stopped", kind) }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use crate::conversion_review::ConversionReview;
+use crate::k8s_types::{ExposedAppPort, ExposedAppSpec, ExposedAppSpecV1, EXPOSED_APP_API_VERSION};
+use axum::Json;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{from_value, to_value, Map, Value};
+use tracing::{error, info};
+
+const V1: &str = "stable.no-library.com/v1";
+/*
+   Hub fields v1 is not able to hold (additional ports, port names, envFrom, rollout)
+   are kept as JSON in these annotations, so v2 -> v1 -> v2 is lossless.
+   Fields neither version knows, e.g. written by a newer operator, are passed through.
+*/
+const PORTS_ANNOTATION: &str = "conversion.stable.no-library.com/ports";
+const ENV_FROM_ANNOTATION: &str = "conversion.stable.no-library.com/env-from";
+const ROLLOUT_ANNOTATION: &str = "conversion.stable.no-library.com/rollout";
+
+type Annotations = Map<String, Value>;
+
+fn save<T: Serialize>(annotations: &mut Annotations, key: &str, value: Option<&T>) {
+    match value {
+        Some(value) => {
+            annotations.insert(
+                String::from(key),
+                Value::String(serde_json::to_string(value).unwrap()),
+            );
+        }
+        None => {
+            annotations.remove(key);
+        }
+    }
+}
+
+fn restore<T: DeserializeOwned>(
+    annotations: &mut Annotations,
+    key: &str,
+) -> Result<Option<T>, String> {
+    match annotations.remove(key) {
+        Some(Value::String(saved)) => serde_json::from_str(saved.as_str())
+            .map(Some)
+            .map_err(|e| format!("Invalid {} annotation: {}", key, e)),
+        _ => Ok(None),
+    }
+}
+
+pub fn v1_to_hub(
+    spec: ExposedAppSpecV1,
+    annotations: &mut Annotations,
+) -> Result<ExposedAppSpec, String> {
+    let mut ports = restore::<Vec<ExposedAppPort>>(annotations, PORTS_ANNOTATION)?
+        .filter(|ports| !ports.is_empty())
+        .unwrap_or_else(|| {
+            vec![ExposedAppPort {
+                name: None,
+                port: spec.port,
+                container_port: spec.container_port,
+                protocol: spec.protocol,
+                node_port: spec.node_port,
+                extra: Map::new(),
+            }]
+        });
+    // v1 fields might have been updated after the annotation was written, name and extra are kept
+    let first = &mut ports[0];
+    first.port = spec.port;
+    first.container_port = spec.container_port;
+    first.protocol = spec.protocol;
+    first.node_port = spec.node_port;
+    let env_from = restore(annotations, ENV_FROM_ANNOTATION)?.unwrap_or_default();
+    let rollout = restore(annotations, ROLLOUT_ANNOTATION)?;
+    Ok(ExposedAppSpec {
+        replicas: spec.replicas,
+        image: spec.image,
+        ports,
+        service_type: spec.service_type,
+        env_from,
+        rollout,
+        extra: spec.extra,
+    })
+}
+
+pub fn hub_to_v1(
+    spec: ExposedAppSpec,
+    annotations: &mut Annotations,
+) -> Result<ExposedAppSpecV1, String> {
+    let first = spec
+        .ports
+        .first()
+        .cloned()
+        .ok_or("spec.ports must contain at least one port")?;
+    save(
+        annotations,
+        PORTS_ANNOTATION,
+        Some(&spec.ports)
+            .filter(|ports| ports.len() > 1 || first.name.is_some() || !first.extra.is_empty()),
+    );
+    save(
+        annotations,
+        ENV_FROM_ANNOTATION,
+        Some(&spec.env_from).filter(|env_from| !env_from.is_empty()),
+    );
+    save(annotations, ROLLOUT_ANNOTATION, spec.rollout.as_ref());
+    Ok(ExposedAppSpecV1 {
+        replicas: spec.replicas,
+        container_port: first.container_port,
+        image: spec.image,
+        port: first.port,
+        protocol: first.protocol,
+        node_port: first.node_port,
+        service_type: spec.service_type,
+        extra: spec.extra,
+    })
+}
+
+/*
+   Only spec differs between versions, metadata and status are passed through untouched
+   so fields this operator does not model (managedFields, labels...) are not lost.
+*/
+fn convert_object(mut object: Value, desired_api_version: &str) -> Result<Value, String> {
+    let api_version = object["apiVersion"]
+        .as_str()
+        .ok_or("apiVersion missing")?
+        .to_string();
+    if api_version == desired_api_version {
+        return Ok(object);
+    }
+    let root = object.as_object_mut().ok_or("Object expected")?;
+    let spec = root.remove("spec").ok_or("spec missing")?;
+    let metadata = root
+        .entry("metadata")
+        .or_insert_with(|| Value::Object(Map::new()))
+        .as_object_mut()
+        .ok_or("metadata must be an object")?;
+    let had_annotations = metadata.contains_key("annotations");
+    let mut annotations = match metadata.remove("annotations") {
+        Some(Value::Object(annotations)) => annotations,
+        _ => Map::new(),
+    };
+    let hub = match api_version.as_str() {
+        V1 => v1_to_hub(
+            from_value::<ExposedAppSpecV1>(spec).map_err(|e| e.to_string())?,
+            &mut annotations,
+        )?,
+        EXPOSED_APP_API_VERSION => from_value::<ExposedAppSpec>(spec).map_err(|e| e.to_string())?,
+        other => return Err(format!("Unsupported source version {}", other)),
+    };
+    let converted = match desired_api_version {
+        V1 => to_value(hub_to_v1(hub, &mut annotations)?).unwrap(),
+        EXPOSED_APP_API_VERSION => to_value(hub).unwrap(),
+        other => return Err(format!("Unsupported desired version {}", other)),
+    };
+    if had_annotations || !annotations.is_empty() {
+        metadata.insert(String::from("annotations"), Value::Object(annotations));
+    }
+    root.insert(String::from("spec"), converted);
+    root.insert(
+        String::from("apiVersion"),
+        Value::String(String::from(desired_api_version)),
+    );
+    Ok(object)
+}
+
+pub async fn convert(Json(review): Json<ConversionReview>) -> Json<ConversionReview> {
+    let request = match review.request {
+        Some(request) => request,
+        None => {
+            return Json(ConversionReview::failure(
+                "",
+                "ConversionReview request missing",
+            ))
+        }
+    };
+    let desired = request.desired_api_version;
+    info!(
+        "Converting {} ExposedApps to {}",
+        request.objects.len(),
+        desired
+    );
+    let converted: Result<Vec<Value>, String> = request
+        .objects
+        .into_iter()
+        .map(|object| convert_object(object, desired.as_str()))
+        .collect();
+    match converted {
+        Ok(objects) => Json(ConversionReview::success(request.uid.as_str(), objects)),
+        Err(e) => {
+            error!("Conversion to {} failed: {}", desired, e);
+            Json(ConversionReview::failure(request.uid.as_str(), e.as_str()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const V2: &str = EXPOSED_APP_API_VERSION;
+
+    fn object(api_version: &str, annotations: Option<Value>, spec: Value) -> Value {
+        let mut metadata = json!({
+            "name": "app",
+            "namespace": "default",
+            "uid": "6f0c5d2e",
+            "labels": { "team": "a" },
+        });
+        if let Some(annotations) = annotations {
+            metadata["annotations"] = annotations;
+        }
+        json!({
+            "apiVersion": api_version,
+            "kind": "ExposedApp",
+            "metadata": metadata,
+            "spec": spec,
+            "status": { "observedGeneration": 3 },
+        })
+    }
+
+    // Every v1 field set, plus one v1 does not know
+    fn v1_spec() -> Value {
+        json!({
+            "replicas": 3,
+            "containerPort": 8080,
+            "image": "nginx:1.25",
+            "port": 80,
+            "protocol": "UDP",
+            "nodePort": 30080,
+            "serviceType": "NodePort",
+            "sessionAffinity": "ClientIP",
+        })
+    }
+
+    // Every hub field set, plus some the hub does not know
+    fn hub_spec() -> Value {
+        json!({
+            "replicas": 0,
+            "image": "nginx:1.25",
+            "ports": [
+                {
+                    "name": "http",
+                    "port": 80,
+                    "containerPort": 8080,
+                    "protocol": "TCP",
+                    "nodePort": 30080,
+                    "appProtocol": "http",
+                },
+                {
+                    "name": "metrics",
+                    "port": 9090,
+                    "containerPort": 9090,
+                    "protocol": "SCTP",
+                },
+            ],
+            "serviceType": "LoadBalancer",
+            "envFrom": [
+                { "configMapRef": { "name": "settings" } },
+                { "secretRef": { "name": "credentials" } },
+            ],
+            "rollout": {
+                "canary": {
+                    "steps": [{ "weight": 10, "pauseSeconds": 60 }, { "weight": 50 }],
+                    "maxUnavailable": 1,
+                    "progressDeadlineSeconds": 300,
+                },
+            },
+            "sessionAffinity": "ClientIP",
+        })
+    }
+
+    #[test]
+    fn v1_to_hub_to_v1_is_lossless() {
+        let original = object(V1, Some(json!({ "owner": "team-a" })), v1_spec());
+        let hub = convert_object(original.clone(), V2).unwrap();
+        assert_eq!(
+            hub["spec"],
+            json!({
+                "replicas": 3,
+                "image": "nginx:1.25",
+                "ports": [{ "port": 80, "containerPort": 8080, "protocol": "UDP", "nodePort": 30080 }],
+                "serviceType": "NodePort",
+                "sessionAffinity": "ClientIP",
+            })
+        );
+        assert_eq!(hub["metadata"]["annotations"], json!({ "owner": "team-a" }));
+        assert_eq!(convert_object(hub, V1).unwrap(), original);
+    }
+
+    #[test]
+    fn v1_defaults_survive_the_round_trip() {
+        let original = object(
+            V1,
+            None,
+            json!({ "replicas": 1, "containerPort": 80, "image": "nginx:1.25", "port": 80, "protocol": "TCP" }),
+        );
+        let hub = convert_object(original.clone(), V2).unwrap();
+        assert!(hub["metadata"].get("annotations").is_none());
+        assert_eq!(convert_object(hub, V1).unwrap(), original);
+    }
+
+    #[test]
+    fn hub_to_v1_to_hub_is_lossless() {
+        let original = object(V2, Some(json!({ "owner": "team-a" })), hub_spec());
+        let v1 = convert_object(original.clone(), V1).unwrap();
+        assert_eq!(
+            v1["spec"],
+            json!({
+                "replicas": 0,
+                "containerPort": 8080,
+                "image": "nginx:1.25",
+                "port": 80,
+                "protocol": "TCP",
+                "nodePort": 30080,
+                "serviceType": "LoadBalancer",
+                "sessionAffinity": "ClientIP",
+            })
+        );
+        let annotations = v1["metadata"]["annotations"].as_object().unwrap();
+        assert_eq!(annotations["owner"], "team-a");
+        for key in [PORTS_ANNOTATION, ENV_FROM_ANNOTATION, ROLLOUT_ANNOTATION] {
+            assert!(annotations[key].is_string(), "{} missing", key);
+        }
+        assert_eq!(convert_object(v1, V2).unwrap(), original);
+    }
+
+    #[test]
+    fn single_unnamed_port_needs_no_annotation() {
+        let spec = json!({
+            "replicas": 2,
+            "image": "nginx:1.25",
+            "ports": [{ "port": 80, "containerPort": 8080, "protocol": "TCP" }],
+        });
+        let original = object(V2, None, spec);
+        let v1 = convert_object(original.clone(), V1).unwrap();
+        assert!(v1["metadata"].get("annotations").is_none());
+        assert_eq!(convert_object(v1, V2).unwrap(), original);
+    }
+
+    // Only the fields v1 holds are taken from it, name and unknown fields of the first port stay
+    #[test]
+    fn v1_edits_update_the_first_hub_port() {
+        let v1 = convert_object(object(V2, None, hub_spec()), V1).unwrap();
+        let mut edited = v1.clone();
+        edited["spec"]["port"] = json!(8000);
+        edited["spec"]["protocol"] = json!("UDP");
+        edited["spec"].as_object_mut().unwrap().remove("nodePort");
+        let hub = convert_object(edited, V2).unwrap();
+        assert_eq!(
+            hub["spec"]["ports"][0],
+            json!({
+                "name": "http",
+                "port": 8000,
+                "containerPort": 8080,
+                "protocol": "UDP",
+                "appProtocol": "http",
+            })
+        );
+        assert_eq!(hub["spec"]["ports"][1], hub_spec()["ports"][1]);
+    }
+
+    #[test]
+    fn invalid_annotation_fails_the_conversion() {
+        let original = object(V1, Some(json!({ PORTS_ANNOTATION: "[" })), v1_spec());
+        assert!(convert_object(original, V2)
+            .unwrap_err()
+            .contains(PORTS_ANNOTATION));
+    }
+
+    #[test]
+    fn hub_without_ports_can_not_be_served_as_v1() {
+        let mut spec = hub_spec();
+        spec["ports"] = json!([]);
+        assert!(convert_object(object(V2, None, spec), V1).is_err());
+    }
+}
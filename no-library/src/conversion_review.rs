@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// https://kubernetes.io/docs/tasks/extend-kubernetes/custom-resources/custom-resource-definition-versioning/#webhook-request-and-response
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionReview {
+    pub api_version: String,
+    pub kind: String,
+    pub request: Option<ConversionRequest>,
+    pub response: Option<ConversionResponse>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionRequest {
+    pub uid: String,
+    #[serde(rename = "desiredAPIVersion")]
+    pub desired_api_version: String,
+    pub objects: Vec<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionResponse {
+    pub uid: String,
+    pub converted_objects: Vec<Value>,
+    pub result: ConversionResult,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionResult {
+    pub status: String,
+    pub message: Option<String>,
+}
+
+impl ConversionReview {
+    pub fn success(uid: &str, converted_objects: Vec<Value>) -> Self {
+        ConversionReview {
+            api_version: String::from("apiextensions.k8s.io/v1"),
+            kind: String::from("ConversionReview"),
+            request: None,
+            response: Some(ConversionResponse {
+                uid: String::from(uid),
+                converted_objects,
+                result: ConversionResult {
+                    status: String::from("Success"),
+                    message: None,
+                },
+            }),
+        }
+    }
+
+    pub fn failure(uid: &str, message: &str) -> Self {
+        ConversionReview {
+            api_version: String::from("apiextensions.k8s.io/v1"),
+            kind: String::from("ConversionReview"),
+            request: None,
+            response: Some(ConversionResponse {
+                uid: String::from(uid),
+                converted_objects: Vec::new(),
+                result: ConversionResult {
+                    status: String::from("Failure"),
+                    message: Some(String::from(message)),
+                },
+            }),
+        }
+    }
+}
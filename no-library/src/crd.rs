@@ -0,0 +1,210 @@
+/*
+   ExposedApp and ExposedAppTemplate CRDs generated from k8s_types,
+   printed by crdgen and compared with the checked-in manifest by tests.
+*/
+use crate::k8s_types::{ExposedApp, ExposedAppTemplate, ExposedAppV1};
+use schemars::gen::SchemaSettings;
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+// https://kubernetes.io/docs/tasks/extend-kubernetes/custom-resources/custom-resource-definitions/#format
+const SUPPORTED_FORMATS: [&str; 8] = [
+    "int32",
+    "int64",
+    "float",
+    "double",
+    "byte",
+    "date",
+    "date-time",
+    "password",
+];
+
+/*
+   Turns the generated schema into a structural one:
+   drops formats the API server does not know (e.g. uint32)
+   and prints integral bounds as integers.
+*/
+fn normalize(schema: &mut Value) {
+    match schema {
+        Value::Object(map) => {
+            if let Some(Value::String(format)) = map.get("format") {
+                if !SUPPORTED_FORMATS.contains(&format.as_str()) {
+                    map.remove("format");
+                }
+            }
+            for bound in ["minimum", "maximum"] {
+                if let Some(value) = map.get(bound).and_then(|v| v.as_f64()) {
+                    if value.fract() == 0.0 {
+                        map.insert(String::from(bound), json!(value as i64));
+                    }
+                }
+            }
+            map.values_mut().for_each(normalize);
+        }
+        Value::Array(items) => items.iter_mut().for_each(normalize),
+        _ => {}
+    }
+}
+
+fn schema<T: JsonSchema>() -> Value {
+    let generator = SchemaSettings::openapi3()
+        .with(|settings| {
+            settings.inline_subschemas = true;
+            settings.option_nullable = false;
+            settings.option_add_null_type = false;
+            settings.meta_schema = None;
+        })
+        .into_generator();
+    let root = generator.into_root_schema_for::<T>();
+    let mut schema = serde_json::to_value(root.schema).unwrap();
+    schema.as_object_mut().unwrap().remove("title");
+    normalize(&mut schema);
+    schema
+}
+
+fn version(name: &str, storage: bool, schema: Value) -> Value {
+    json!({
+        "name": name,
+        "served": true,
+        "storage": storage,
+        "subresources": {
+            "status": {}
+        },
+        "additionalPrinterColumns": [
+            {
+                "jsonPath": ".status.deploymentName",
+                "name": "DeploymentName",
+                "type": "string"
+            },
+            {
+                "jsonPath": ".status.serviceName",
+                "name": "ServiceName",
+                "type": "string"
+            },
+            {
+                "jsonPath": ".spec.replicas",
+                "name": "Replicas",
+                "type": "integer"
+            },
+            {
+                "jsonPath": ".status.conditions[?(@.type==\"PodsHealthy\")].status",
+                "name": "Healthy",
+                "type": "string"
+            },
+            {
+                "jsonPath": ".status.conditions[?(@.type==\"Stalled\")].status",
+                "name": "Stalled",
+                "type": "string",
+                "priority": 1
+            },
+            {
+                "jsonPath": ".metadata.creationTimestamp",
+                "name": "Age",
+                "type": "date"
+            }
+        ],
+        "schema": {
+            "openAPIV3Schema": schema
+        }
+    })
+}
+
+// v1 stays the storage version, v2 is the hub the operator works on,
+// the API server converts between them with the operator conversion webhook.
+fn exposed_app_crd() -> Value {
+    json!({
+        "apiVersion": "apiextensions.k8s.io/v1",
+        "kind": "CustomResourceDefinition",
+        "metadata": {
+            "name": "exposedapps.stable.no-library.com",
+            "annotations": {
+                "cert-manager.io/inject-ca-from": "no-library/no-library-certificate"
+            }
+        },
+        "spec": {
+            "group": "stable.no-library.com",
+            "scope": "Namespaced",
+            "names": {
+                "kind": "ExposedApp",
+                "plural": "exposedapps",
+                "singular": "exposedapp",
+                "shortNames": ["ea"]
+            },
+            "conversion": {
+                "strategy": "Webhook",
+                "webhook": {
+                    "conversionReviewVersions": ["v1"],
+                    "clientConfig": {
+                        "service": {
+                            "name": "no-library-webhook",
+                            "namespace": "no-library",
+                            "path": "/convert",
+                            "port": 8443
+                        }
+                    }
+                }
+            },
+            "versions": [
+                version("v1", true, schema::<ExposedAppV1>()),
+                version("v2", false, schema::<ExposedApp>())
+            ]
+        }
+    })
+}
+
+// Single version, cluster scoped as it stamps ExposedApps into many namespaces
+fn exposed_app_template_crd() -> Value {
+    json!({
+        "apiVersion": "apiextensions.k8s.io/v1",
+        "kind": "CustomResourceDefinition",
+        "metadata": {
+            "name": "exposedapptemplates.stable.no-library.com"
+        },
+        "spec": {
+            "group": "stable.no-library.com",
+            "scope": "Cluster",
+            "names": {
+                "kind": "ExposedAppTemplate",
+                "plural": "exposedapptemplates",
+                "singular": "exposedapptemplate",
+                "shortNames": ["eat"]
+            },
+            "versions": [{
+                "name": "v1",
+                "served": true,
+                "storage": true,
+                "subresources": {
+                    "status": {}
+                },
+                "additionalPrinterColumns": [
+                    {
+                        "jsonPath": ".status.selectedNamespaces",
+                        "name": "Selected",
+                        "type": "integer"
+                    },
+                    {
+                        "jsonPath": ".status.readyNamespaces",
+                        "name": "Ready",
+                        "type": "integer"
+                    },
+                    {
+                        "jsonPath": ".metadata.creationTimestamp",
+                        "name": "Age",
+                        "type": "date"
+                    }
+                ],
+                "schema": {
+                    "openAPIV3Schema": schema::<ExposedAppTemplate>()
+                }
+            }]
+        }
+    })
+}
+
+pub fn generate() -> String {
+    let crds = [exposed_app_crd(), exposed_app_template_crd()]
+        .iter()
+        .map(|crd| serde_yaml::to_string(crd).unwrap())
+        .collect::<Vec<String>>();
+    crds.join("---\n")
+}
@@ -0,0 +1,9 @@
+use no_library::crd;
+
+/*
+   Prints the ExposedApp and ExposedAppTemplate CRDs generated from k8s_types.
+   Usage: cargo run --bin crdgen > config/custom_resource_definition.yaml
+*/
+fn main() {
+    print!("{}", crd::generate());
+}
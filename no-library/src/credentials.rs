@@ -0,0 +1,268 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::{Certificate, Client};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::fs;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+// Re-read the token this long before it expires, kubelet rotates it at 80% of its lifetime
+const EXPIRY_MARGIN: time::Duration = time::Duration::minutes(2);
+
+/*
+   Service account credentials shared by every K8sClient.
+   Bound tokens are rotated by kubelet, the projected files are polled
+   and re-read ahead of the token expiry, clients always get the current token
+   and an HTTP client built with the current CA bundle.
+*/
+pub struct CredentialProvider {
+    token_path: Option<PathBuf>,
+    ca_path: Option<PathBuf>,
+    state: RwLock<State>,
+}
+
+struct State {
+    token: String,
+    ca: Vec<u8>,
+    client: Client,
+    expires_at: Option<OffsetDateTime>,
+}
+
+fn build_client(ca: &[u8]) -> Client {
+    let mut builder = Client::builder();
+    if !ca.is_empty() {
+        let certificate = Certificate::from_pem(ca).expect("Unable to create Certificate");
+        builder = builder.add_root_certificate(certificate);
+    }
+    builder.build().expect("Unable to add root certificate")
+}
+
+// Only the payload is decoded, the signature is verified by the API server
+fn token_expiry(token: &str) -> Option<OffsetDateTime> {
+    let payload = token.split('.').nth(1)?;
+    let decoded = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: Value = serde_json::from_slice(decoded.as_slice()).ok()?;
+    OffsetDateTime::from_unix_timestamp(claims.get("exp")?.as_i64()?).ok()
+}
+
+async fn read_file(path: &Option<PathBuf>) -> Option<Vec<u8>> {
+    let path = path.as_ref()?;
+    match fs::read(path).await {
+        Ok(content) => Some(content),
+        Err(e) => {
+            error!("Unable to read {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+impl CredentialProvider {
+    pub async fn in_cluster(service_account_path: &str) -> Arc<Self> {
+        let token_path = PathBuf::from(format!("{}/token", service_account_path));
+        let ca_path = PathBuf::from(format!("{}/ca.crt", service_account_path));
+        let token = fs::read(&token_path).await.expect("Unable to read token");
+        let token = String::from_utf8(token).expect("Unable to convert token to UTF8");
+        let ca = fs::read(&ca_path)
+            .await
+            .expect("Unable to read certificate");
+        let provider = Arc::new(CredentialProvider {
+            token_path: Some(token_path),
+            ca_path: Some(ca_path),
+            state: RwLock::new(State {
+                client: build_client(ca.as_slice()),
+                expires_at: token_expiry(token.as_str()),
+                token,
+                ca,
+            }),
+        });
+        tokio::spawn(Self::watch(Arc::downgrade(&provider)));
+        provider
+    }
+
+    // Static token and CA bundle, empty when only the system roots are trusted, nothing to refresh
+    pub fn fixed(token: &str, ca: Vec<u8>) -> Arc<Self> {
+        Arc::new(CredentialProvider {
+            token_path: None,
+            ca_path: None,
+            state: RwLock::new(State {
+                token: String::from(token),
+                client: build_client(ca.as_slice()),
+                ca,
+                expires_at: None,
+            }),
+        })
+    }
+
+    pub fn token(&self) -> String {
+        self.state.read().unwrap().token.clone()
+    }
+
+    pub fn client(&self) -> Client {
+        self.state.read().unwrap().client.clone()
+    }
+
+    // Re-reads token and CA bundle, returns true when any of them changed
+    pub async fn reload(&self) -> bool {
+        let token = read_file(&self.token_path)
+            .await
+            .and_then(|content| String::from_utf8(content).ok());
+        let ca = read_file(&self.ca_path).await;
+        let mut state = self.state.write().unwrap();
+        let mut changed = false;
+        if let Some(token) = token.filter(|t| !t.is_empty() && *t != state.token) {
+            state.expires_at = token_expiry(token.as_str());
+            state.token = token;
+            info!(
+                "Service account token reloaded, expires at {:?}",
+                state.expires_at
+            );
+            changed = true;
+        }
+        if let Some(ca) = ca.filter(|ca| !ca.is_empty() && *ca != state.ca) {
+            state.client = build_client(ca.as_slice());
+            state.ca = ca;
+            info!("CA bundle reloaded");
+            changed = true;
+        }
+        changed
+    }
+
+    fn next_check(&self) -> Duration {
+        let expires_at = self.state.read().unwrap().expires_at;
+        match expires_at {
+            Some(expires_at) => {
+                let refresh_at = expires_at - EXPIRY_MARGIN;
+                let remaining = refresh_at - OffsetDateTime::now_utc();
+                if remaining.is_positive() {
+                    POLL_INTERVAL.min(remaining.unsigned_abs())
+                } else {
+                    warn!(
+                        "Service account token expires at {}, waiting for rotation",
+                        expires_at
+                    );
+                    Duration::from_secs(5)
+                }
+            }
+            None => POLL_INTERVAL,
+        }
+    }
+
+    async fn watch(provider: Weak<Self>) {
+        loop {
+            let wait = match provider.upgrade() {
+                Some(provider) => provider.next_check(),
+                None => return,
+            };
+            sleep(wait).await;
+            match provider.upgrade() {
+                Some(provider) => {
+                    provider.reload().await;
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_api::FakeApiServer;
+    use base64::engine::general_purpose::URL_SAFE;
+
+    // Unsigned, only the payload is read
+    fn jwt(claims: Value) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        format!("eyJhbGciOiJSUzI1NiJ9.{}.signature", payload)
+    }
+
+    fn service_account(name: &str, token: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("no-library-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("token"), token).unwrap();
+        std::fs::write(path.join("ca.crt"), "").unwrap();
+        path
+    }
+
+    fn expiring_in(provider: &CredentialProvider, remaining: time::Duration) {
+        provider.state.write().unwrap().expires_at = Some(OffsetDateTime::now_utc() + remaining);
+    }
+
+    #[test]
+    fn expiry_is_read_from_the_token_payload() {
+        let exp = 1_900_000_000;
+        let expected = OffsetDateTime::from_unix_timestamp(exp).ok();
+        assert_eq!(
+            token_expiry(jwt(serde_json::json!({"exp": exp})).as_str()),
+            expected
+        );
+        let padded = format!(
+            "header.{}.signature",
+            URL_SAFE.encode(serde_json::json!({"exp": exp, "sub": "a"}).to_string())
+        );
+        assert_eq!(token_expiry(padded.as_str()), expected);
+        assert_eq!(
+            token_expiry(jwt(serde_json::json!({"sub": "a"})).as_str()),
+            None
+        );
+        assert_eq!(token_expiry("static-token"), None);
+        assert_eq!(token_expiry("a.not base64.c"), None);
+    }
+
+    #[tokio::test]
+    async fn reload_picks_up_a_rotated_token() {
+        let path = service_account("rotated", "old");
+        let provider = CredentialProvider::in_cluster(path.to_str().unwrap()).await;
+        assert!(!provider.reload().await);
+        let rotated = jwt(serde_json::json!({"exp": 1_900_000_000}));
+        std::fs::write(path.join("token"), rotated.as_str()).unwrap();
+        assert!(provider.reload().await);
+        assert_eq!(provider.token(), rotated);
+        assert!(provider.state.read().unwrap().expires_at.is_some());
+        // Being rewritten by kubelet
+        std::fs::write(path.join("token"), "").unwrap();
+        assert!(!provider.reload().await);
+        assert_eq!(provider.token(), rotated);
+    }
+
+    #[tokio::test]
+    async fn reload_picks_up_a_new_ca_bundle() {
+        let server = FakeApiServer::new().tls().start().await;
+        let path = service_account("ca", "token");
+        let provider = CredentialProvider::in_cluster(path.to_str().unwrap()).await;
+        std::fs::copy(server.ca_file.as_ref().unwrap(), path.join("ca.crt")).unwrap();
+        assert!(provider.reload().await);
+        assert!(!provider.reload().await);
+        // The rebuilt client trusts the new bundle
+        let response = provider
+            .client()
+            .get(format!("{}/healthz", server.url))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[test]
+    fn checks_are_scheduled_ahead_of_the_expiry() {
+        let provider = CredentialProvider::fixed("token", Vec::new());
+        assert_eq!(provider.next_check(), POLL_INTERVAL);
+        expiring_in(&provider, time::Duration::hours(1));
+        assert_eq!(provider.next_check(), POLL_INTERVAL);
+        // Re-read 2 minutes before it expires
+        expiring_in(&provider, time::Duration::seconds(150));
+        let next = provider.next_check();
+        assert!(
+            next <= Duration::from_secs(30) && next > Duration::from_secs(25),
+            "{:?}",
+            next
+        );
+        expiring_in(&provider, time::Duration::seconds(60));
+        assert_eq!(provider.next_check(), Duration::from_secs(5));
+    }
+}
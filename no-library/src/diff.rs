@@ -0,0 +1,77 @@
+use serde_json::Value;
+
+// Set by the API server on every write, never a change made by the operator
+const IGNORED: [&str; 6] = [
+    "metadata.resourceVersion",
+    "metadata.generation",
+    "metadata.uid",
+    "metadata.creationTimestamp",
+    "metadata.managedFields",
+    "status",
+];
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn diff_at(path: &str, live: Option<&Value>, desired: Option<&Value>, changes: &mut Vec<String>) {
+    if IGNORED.contains(&path) {
+        return;
+    }
+    match (live, desired) {
+        (Some(Value::Object(live)), Some(Value::Object(desired))) => {
+            for (key, value) in desired {
+                diff_at(
+                    child_path(path, key).as_str(),
+                    live.get(key),
+                    Some(value),
+                    changes,
+                );
+            }
+            for (key, value) in live.iter().filter(|(key, _)| !desired.contains_key(*key)) {
+                diff_at(child_path(path, key).as_str(), Some(value), None, changes);
+            }
+        }
+        (Some(Value::Array(live)), Some(Value::Array(desired))) if live.len() == desired.len() => {
+            for (index, (live, desired)) in live.iter().zip(desired).enumerate() {
+                diff_at(
+                    format!("{}[{}]", path, index).as_str(),
+                    Some(live),
+                    Some(desired),
+                    changes,
+                );
+            }
+        }
+        (Some(live), Some(desired)) if live != desired => {
+            changes.push(format!("~ {}: {} -> {}", path, live, desired))
+        }
+        (None, Some(Value::Object(desired))) => {
+            for (key, value) in desired {
+                diff_at(child_path(path, key).as_str(), None, Some(value), changes);
+            }
+        }
+        (Some(Value::Object(live)), None) => {
+            for (key, value) in live {
+                diff_at(child_path(path, key).as_str(), Some(value), None, changes);
+            }
+        }
+        (None, Some(Value::Null)) | (Some(Value::Null), None) => {}
+        (None, Some(desired)) => changes.push(format!("+ {}: {}", path, desired)),
+        (Some(live), None) => changes.push(format!("- {}: {}", path, live)),
+        _ => {}
+    }
+}
+
+/*
+   Field by field difference between the live object and the one the API server
+   would store, one line per changed leaf: "+" added, "-" removed, "~" modified.
+*/
+pub fn diff(live: &Value, desired: &Value) -> Vec<String> {
+    let mut changes = Vec::new();
+    diff_at("", Some(live), Some(desired), &mut changes);
+    changes
+}
@@ -0,0 +1,462 @@
+use crate::k8s_client::client::{K8sClient, K8sClientError};
+use crate::k8s_types::Resource;
+use serde::Deserialize;
+use serde_json::{from_str, Value};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+// Servers without aggregated discovery ignore the first media type and answer with the legacy document
+const AGGREGATED: &str =
+    "application/json;g=apidiscovery.k8s.io;v=v2;as=APIGroupDiscoveryList,application/json";
+
+/*
+   Where a kind is served, resolved once at startup from /api and /apis.
+   URIs have no leading slash and no API server URL, like the ones built by hand before.
+*/
+#[derive(Clone, Debug)]
+pub struct ApiResource {
+    // Empty for the core group
+    pub group: String,
+    pub version: String,
+    pub kind: String,
+    pub plural: String,
+    pub namespaced: bool,
+    pub verbs: Vec<String>,
+    // Version the API server prefers for the group, may differ from the one used
+    pub preferred_version: String,
+}
+
+impl ApiResource {
+    fn prefix(&self) -> String {
+        if self.group.is_empty() {
+            format!("api/{}", self.version)
+        } else {
+            format!("apis/{}/{}", self.group, self.version)
+        }
+    }
+
+    // Across all namespaces
+    pub fn list_uri(&self) -> String {
+        format!("{}/{}", self.prefix(), self.plural)
+    }
+
+    pub fn namespaced_uri(&self, namespace: &str) -> String {
+        if self.namespaced {
+            format!("{}/namespaces/{}/{}", self.prefix(), namespace, self.plural)
+        } else {
+            self.list_uri()
+        }
+    }
+
+    pub fn object_uri(&self, namespace: &str, name: &str) -> String {
+        format!("{}/{}", self.namespaced_uri(namespace), name)
+    }
+}
+
+// Appends a query parameter, percent-encoded, e.g. a selector like "track in (stable,canary)"
+pub fn with_query(uri: &str, parameter: &str, value: &str) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair(parameter, value)
+        .finish();
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", uri, separator, query)
+}
+
+// A kind the operator cannot run without, with the verbs it uses on it
+pub struct Requirement {
+    pub api_version: &'static str,
+    pub kind: &'static str,
+    pub verbs: &'static [&'static str],
+}
+
+impl Requirement {
+    pub fn of<T: Resource>(verbs: &'static [&'static str]) -> Self {
+        Requirement {
+            api_version: T::API_VERSION,
+            kind: T::KIND,
+            verbs,
+        }
+    }
+
+    pub fn group(&self) -> &'static str {
+        self.api_version
+            .rsplit_once('/')
+            .map(|(group, _)| group)
+            .unwrap_or_default()
+    }
+}
+
+fn api_version(group: &str, version: &str) -> String {
+    if group.is_empty() {
+        String::from(version)
+    } else {
+        format!("{}/{}", group, version)
+    }
+}
+
+// apidiscovery.k8s.io/v2 APIGroupDiscoveryList, /api lists the core group with an empty name
+#[derive(Deserialize)]
+struct GroupDiscoveryList {
+    #[serde(default)]
+    items: Vec<GroupDiscovery>,
+}
+
+#[derive(Deserialize, Default)]
+struct DiscoveryMetadata {
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct GroupDiscovery {
+    #[serde(default)]
+    metadata: DiscoveryMetadata,
+    // Ordered by preference
+    #[serde(default)]
+    versions: Vec<VersionDiscovery>,
+}
+
+#[derive(Deserialize)]
+struct VersionDiscovery {
+    version: String,
+    #[serde(default)]
+    resources: Vec<ResourceDiscovery>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceDiscovery {
+    resource: String,
+    response_kind: Option<ResponseKind>,
+    scope: String,
+    #[serde(default)]
+    verbs: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseKind {
+    kind: String,
+}
+
+// Legacy documents, /api returns APIVersions and /apis an APIGroupList
+#[derive(Deserialize)]
+struct ApiVersions {
+    versions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiGroupList {
+    groups: Vec<ApiGroup>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiGroup {
+    name: String,
+    versions: Vec<GroupVersion>,
+    preferred_version: Option<GroupVersion>,
+}
+
+#[derive(Deserialize)]
+struct GroupVersion {
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct ApiResourceList {
+    resources: Vec<LegacyResource>,
+}
+
+#[derive(Deserialize)]
+struct LegacyResource {
+    name: String,
+    kind: String,
+    namespaced: bool,
+    #[serde(default)]
+    verbs: Vec<String>,
+}
+
+/*
+   Resource metadata of every group version read at startup, keyed by apiVersion and kind.
+   Served versions of each group are kept to explain why a required kind is missing.
+*/
+#[derive(Default)]
+pub struct Discovery {
+    resources: HashMap<(String, String), ApiResource>,
+    versions: HashMap<String, Vec<String>>,
+}
+
+fn unavailable(uri: &str, error: K8sClientError) -> String {
+    format!("Discovery of {} failed: {:?}", uri, error)
+}
+
+impl Discovery {
+    pub fn resource<T: Resource>(&self) -> Option<&ApiResource> {
+        self.get(T::API_VERSION, T::KIND)
+    }
+
+    pub fn get(&self, api_version: &str, kind: &str) -> Option<&ApiResource> {
+        self.resources
+            .get(&(String::from(api_version), String::from(kind)))
+    }
+
+    /*
+       Reads /api and /apis, aggregated discovery returns every resource in those two requests.
+       Older API servers answer with the group lists, then only the group versions
+       of required kinds are fetched. Fails with every missing kind at once.
+    */
+    pub async fn load(client: &mut K8sClient, required: &[Requirement]) -> Result<Self, String> {
+        let mut discovery = Discovery::default();
+        for root in ["api", "apis"] {
+            let text = client
+                .get_document(root, AGGREGATED)
+                .await
+                .map_err(|e| unavailable(root, e))?;
+            let document = from_str::<Value>(text.as_str())
+                .map_err(|e| format!("Discovery of {} failed: {}", root, e))?;
+            if document["kind"] == "APIGroupDiscoveryList" {
+                discovery.aggregated(document)?;
+            } else if root == "api" {
+                discovery.legacy_core(client, document, required).await?;
+            } else {
+                discovery.legacy_groups(client, document, required).await?;
+            }
+        }
+        discovery.check(required)?;
+        Ok(discovery)
+    }
+
+    fn aggregated(&mut self, document: Value) -> Result<(), String> {
+        let list = serde_json::from_value::<GroupDiscoveryList>(document)
+            .map_err(|e| format!("Invalid aggregated discovery document: {}", e))?;
+        for group in list.items {
+            let name = group.metadata.name;
+            let preferred = match group.versions.first() {
+                Some(version) => version.version.clone(),
+                None => continue,
+            };
+            for version in &group.versions {
+                for resource in &version.resources {
+                    // Kind is missing on resources that can't be read, e.g. bindings
+                    if let Some(kind) = &resource.response_kind {
+                        self.insert(ApiResource {
+                            group: name.clone(),
+                            version: version.version.clone(),
+                            kind: kind.kind.clone(),
+                            plural: resource.resource.clone(),
+                            namespaced: resource.scope == "Namespaced",
+                            verbs: resource.verbs.clone(),
+                            preferred_version: preferred.clone(),
+                        });
+                    }
+                }
+            }
+            let versions = group.versions.into_iter().map(|v| v.version).collect();
+            self.versions.insert(name, versions);
+        }
+        Ok(())
+    }
+
+    async fn legacy_core(
+        &mut self,
+        client: &mut K8sClient,
+        document: Value,
+        required: &[Requirement],
+    ) -> Result<(), String> {
+        let core = serde_json::from_value::<ApiVersions>(document)
+            .map_err(|e| format!("Invalid discovery document for /api: {}", e))?;
+        let preferred = core.versions.first().cloned().unwrap_or_default();
+        for version in &core.versions {
+            if required.iter().any(|r| r.api_version == version) {
+                self.group_version(client, "", version, preferred.as_str())
+                    .await?;
+            }
+        }
+        self.versions.insert(String::new(), core.versions);
+        Ok(())
+    }
+
+    async fn legacy_groups(
+        &mut self,
+        client: &mut K8sClient,
+        document: Value,
+        required: &[Requirement],
+    ) -> Result<(), String> {
+        let list = serde_json::from_value::<ApiGroupList>(document)
+            .map_err(|e| format!("Invalid discovery document for /apis: {}", e))?;
+        for group in list.groups {
+            let preferred = group
+                .preferred_version
+                .as_ref()
+                .or(group.versions.first())
+                .map(|v| v.version.clone())
+                .unwrap_or_default();
+            for version in &group.versions {
+                let needed = api_version(group.name.as_str(), version.version.as_str());
+                if required.iter().any(|r| r.api_version == needed) {
+                    self.group_version(
+                        client,
+                        group.name.as_str(),
+                        version.version.as_str(),
+                        preferred.as_str(),
+                    )
+                    .await?;
+                }
+            }
+            let versions = group.versions.into_iter().map(|v| v.version).collect();
+            self.versions.insert(group.name, versions);
+        }
+        Ok(())
+    }
+
+    // APIResourceList of one group version, api/v1 or apis/{group}/{version}
+    async fn group_version(
+        &mut self,
+        client: &mut K8sClient,
+        group: &str,
+        version: &str,
+        preferred: &str,
+    ) -> Result<(), String> {
+        let uri = if group.is_empty() {
+            format!("api/{}", version)
+        } else {
+            format!("apis/{}/{}", group, version)
+        };
+        let text = client
+            .get_document(uri.as_str(), "application/json")
+            .await
+            .map_err(|e| unavailable(uri.as_str(), e))?;
+        let list = from_str::<ApiResourceList>(text.as_str())
+            .map_err(|e| format!("Invalid discovery document for /{}: {}", uri, e))?;
+        // Subresources are listed as e.g. deployments/status
+        for resource in list.resources.into_iter().filter(|r| !r.name.contains('/')) {
+            self.insert(ApiResource {
+                group: String::from(group),
+                version: String::from(version),
+                kind: resource.kind,
+                plural: resource.name,
+                namespaced: resource.namespaced,
+                verbs: resource.verbs,
+                preferred_version: String::from(preferred),
+            });
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, resource: ApiResource) {
+        let key = (
+            api_version(resource.group.as_str(), resource.version.as_str()),
+            resource.kind.clone(),
+        );
+        // A group version may serve a kind under several names, the first one listed is kept
+        self.resources.entry(key).or_insert(resource);
+    }
+
+    fn check(&self, required: &[Requirement]) -> Result<(), String> {
+        let mut missing = Vec::new();
+        for requirement in required {
+            let key = (
+                String::from(requirement.api_version),
+                String::from(requirement.kind),
+            );
+            let resource = match self.resources.get(&key) {
+                Some(resource) => resource,
+                None => {
+                    let served = self
+                        .versions
+                        .get(requirement.group())
+                        .map(|versions| versions.join(", "))
+                        .unwrap_or_else(|| String::from("none"));
+                    missing.push(format!(
+                        "{} {} is not served (served versions of the group: {})",
+                        requirement.kind, requirement.api_version, served
+                    ));
+                    continue;
+                }
+            };
+            let unsupported: Vec<&str> = requirement
+                .verbs
+                .iter()
+                .filter(|verb| !resource.verbs.iter().any(|v| v == *verb))
+                .copied()
+                .collect();
+            if !unsupported.is_empty() {
+                missing.push(format!(
+                    "{} {} does not support {}",
+                    requirement.kind,
+                    requirement.api_version,
+                    unsupported.join(", ")
+                ));
+                continue;
+            }
+            // Groups prefer one version for all their kinds, some kinds may not be served in it
+            let preferred = (
+                api_version(resource.group.as_str(), resource.preferred_version.as_str()),
+                resource.kind.clone(),
+            );
+            if resource.version != resource.preferred_version
+                && self.resources.contains_key(&preferred)
+            {
+                warn!(
+                    "Using {} {}, the API server prefers version {}",
+                    resource.kind, requirement.api_version, resource.preferred_version
+                );
+            }
+            info!("Discovered {} at {}", resource.kind, resource.list_uri());
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Required API resources are missing, are the CRDs installed?\n  {}",
+                missing.join("\n  ")
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(uri: &str) -> Vec<(String, String)> {
+        let (_, query) = uri.split_once('?').unwrap();
+        form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    #[test]
+    fn selectors_are_percent_encoded() {
+        for selector in [
+            "app.kubernetes.io/instance=demo",
+            "track in (stable,canary),!paused",
+            "tier!=cache, env notin (dev)",
+            "metadata.name=a&b",
+        ] {
+            let uri = with_query("api/v1/pods", "labelSelector", selector);
+            assert!(!uri[uri.find('?').unwrap() + 1..].contains([' ', ',', '!', '(', ')', '/']));
+            assert_eq!(
+                decoded(uri.as_str()),
+                [(String::from("labelSelector"), String::from(selector))]
+            );
+        }
+        assert_eq!(
+            with_query("api/v1/pods", "labelSelector", "a in (x,y)"),
+            "api/v1/pods?labelSelector=a+in+%28x%2Cy%29"
+        );
+    }
+
+    #[test]
+    fn parameters_are_appended_to_an_existing_query() {
+        let uri = with_query(
+            "api/v1/pods?limit=10",
+            "fieldSelector",
+            "metadata.name=demo",
+        );
+        assert_eq!(
+            uri,
+            "api/v1/pods?limit=10&fieldSelector=metadata.name%3Ddemo"
+        );
+    }
+}
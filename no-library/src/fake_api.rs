@@ -0,0 +1,1437 @@
+/*
+   Minimal in-memory Kubernetes API server used to run the operator locally and in tests.
+   Run fake-api-server, then start the operator with KUBERNETES_API_URL pointing at the printed address.
+
+   Supported:
+   - HTTP, or HTTPS with a self-signed certificate written to KUBERNETES_CA_FILE
+   - get/list/watch, create (POST), update (PUT), patch (json-patch, merge-patch), delete
+   - resourceVersion conflicts on update and json-patch "test" operations (Lease handling)
+   - status subresource and generation bumps on spec changes
+   - finalizers, UID and resourceVersion delete preconditions and deletecollection
+   - labelSelector, and fieldSelector on metadata.name and metadata.namespace equality
+   - Background (default) and Orphan propagation, Foreground deletes the dependents
+     before the owner in the same request, gracePeriodSeconds is ignored
+   - aggregated and legacy discovery of the kinds the operator uses,
+     LEGACY_DISCOVERY=1 disables the aggregated documents,
+     DISABLED_GROUPS=stable.no-library.com hides groups, e.g. a CRD that is not installed
+   - CustomResourceDefinitions are Established as soon as they are created
+   - SelfSubjectAccessReviews allow everything but DENIED=verb:resource,... e.g. DENIED=create:leases
+   - protobuf lists and watches of Deployments, Services, Leases and Events when accepted
+*/
+use crate::k8s_types::{DeleteOptions, PropagationPolicy};
+use crate::protobuf::{encode_list, encode_watch_event, has_message, PROTOBUF};
+use async_stream::stream;
+use axum::body::{Body, Bytes};
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use axum_server::tls_openssl::{OpenSSLAcceptor, OpenSSLConfig};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectAlternativeName};
+use openssl::x509::{X509Builder, X509NameBuilder};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+// Resources served with a status subresource, main endpoint ignores status changes
+const STATUS_SUBRESOURCE: [&str; 5] = [
+    "deployments",
+    "services",
+    "exposedapps",
+    "exposedapptemplates",
+    "pods",
+];
+// Resources with metadata.generation incremented on spec change
+const GENERATION: [&str; 3] = ["deployments", "exposedapps", "exposedapptemplates"];
+// Resources created by PUT when missing
+const CREATE_ON_UPDATE: [&str; 1] = ["services"];
+
+// Group, version, kind, plural, namespaced. Versions of a group are listed by preference
+const DISCOVERY: [(&str, &str, &str, &str, bool); 14] = [
+    ("", "v1", "Namespace", "namespaces", false),
+    ("", "v1", "Pod", "pods", true),
+    ("", "v1", "Service", "services", true),
+    ("", "v1", "ConfigMap", "configmaps", true),
+    ("", "v1", "Secret", "secrets", true),
+    ("", "v1", "Event", "events", true),
+    ("apps", "v1", "Deployment", "deployments", true),
+    ("coordination.k8s.io", "v1", "Lease", "leases", true),
+    ("events.k8s.io", "v1", "Event", "events", true),
+    (
+        "stable.no-library.com",
+        "v2",
+        "ExposedApp",
+        "exposedapps",
+        true,
+    ),
+    (
+        "stable.no-library.com",
+        "v1",
+        "ExposedApp",
+        "exposedapps",
+        true,
+    ),
+    (
+        "stable.no-library.com",
+        "v1",
+        "ExposedAppTemplate",
+        "exposedapptemplates",
+        false,
+    ),
+    (
+        "apiextensions.k8s.io",
+        "v1",
+        "CustomResourceDefinition",
+        "customresourcedefinitions",
+        false,
+    ),
+    (
+        "authorization.k8s.io",
+        "v1",
+        "SelfSubjectAccessReview",
+        "selfsubjectaccessreviews",
+        false,
+    ),
+];
+const VERBS: [&str; 8] = [
+    "create",
+    "delete",
+    "deletecollection",
+    "get",
+    "list",
+    "patch",
+    "update",
+    "watch",
+];
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug)]
+struct ResourceKey {
+    // "api/v1" or "apis/{group}/{version}"
+    prefix: String,
+    resource: String,
+}
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug)]
+struct ObjectKey {
+    resource: ResourceKey,
+    namespace: Option<String>,
+    name: String,
+}
+
+#[derive(Clone)]
+struct StoredEvent {
+    resource_version: u64,
+    key: ObjectKey,
+    event_type: &'static str,
+    object: Value,
+}
+
+struct Store {
+    objects: BTreeMap<ObjectKey, Value>,
+    history: Vec<StoredEvent>,
+    resource_version: u64,
+    sender: broadcast::Sender<StoredEvent>,
+}
+
+type SharedStore = Arc<Mutex<Store>>;
+
+struct ApiPath {
+    resource: ResourceKey,
+    namespace: Option<String>,
+    name: Option<String>,
+    subresource: Option<String>,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    code: StatusCode,
+    reason: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(code: StatusCode, reason: &'static str, message: String) -> Self {
+        ApiError {
+            code,
+            reason,
+            message,
+        }
+    }
+
+    fn not_found(key: &ObjectKey) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NotFound",
+            format!("{} \"{}\" not found", key.resource.resource, key.name),
+        )
+    }
+
+    fn conflict(message: String) -> Self {
+        Self::new(StatusCode::CONFLICT, "Conflict", message)
+    }
+
+    fn already_exists(key: &ObjectKey) -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "AlreadyExists",
+            format!("{} \"{}\" already exists", key.resource.resource, key.name),
+        )
+    }
+
+    fn bad_request(message: String) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "BadRequest", message)
+    }
+
+    fn invalid(message: String) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, "Invalid", message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "metadata": {},
+            "status": "Failure",
+            "message": self.message,
+            "reason": self.reason,
+            "code": self.code.as_u16(),
+        });
+        (self.code, Json(body)).into_response()
+    }
+}
+
+fn parse_path(path: &str) -> Option<ApiPath> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let (prefix, rest) = match segments.as_slice() {
+        ["api", version, rest @ ..] => (format!("api/{}", version), rest),
+        ["apis", group, version, rest @ ..] => (format!("apis/{}/{}", group, version), rest),
+        _ => return None,
+    };
+    let (namespace, rest) = match rest {
+        ["namespaces", namespace, rest @ ..] if !rest.is_empty() => {
+            (Some(namespace.to_string()), rest)
+        }
+        _ => (None, rest),
+    };
+    let (resource, name, subresource) = match rest {
+        [resource] => (resource, None, None),
+        [resource, name] => (resource, Some(name.to_string()), None),
+        [resource, name, subresource] => (
+            resource,
+            Some(name.to_string()),
+            Some(subresource.to_string()),
+        ),
+        _ => return None,
+    };
+    Some(ApiPath {
+        resource: ResourceKey {
+            prefix,
+            resource: resource.to_string(),
+        },
+        namespace,
+        name,
+        subresource,
+    })
+}
+
+fn resource_name(kind: &str) -> String {
+    let lower = kind.to_lowercase();
+    if lower.ends_with('s') {
+        format!("{}es", lower)
+    } else {
+        format!("{}s", lower)
+    }
+}
+
+fn random_uid() -> String {
+    let raw = Alphanumeric
+        .sample_string(&mut rand::rng(), 32)
+        .to_lowercase();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &raw[0..8],
+        &raw[8..12],
+        &raw[12..16],
+        &raw[16..20],
+        &raw[20..32]
+    )
+}
+
+fn now() -> String {
+    OffsetDateTime::now_utc()
+        .replace_nanosecond(0)
+        .unwrap()
+        .format(&Rfc3339)
+        .unwrap()
+}
+
+fn metadata_mut(object: &mut Value) -> &mut Map<String, Value> {
+    let root = object.as_object_mut().unwrap();
+    root.entry("metadata")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .unwrap()
+}
+
+fn metadata_str(object: &Value, field: &str) -> Option<String> {
+    object
+        .get("metadata")
+        .and_then(|m| m.get(field))
+        .and_then(|v| v.as_str())
+        .map(String::from)
+}
+
+fn without(object: &Value, fields: &[&str]) -> Value {
+    let mut copy = object.clone();
+    if let Some(map) = copy.as_object_mut() {
+        for field in fields {
+            map.remove(*field);
+        }
+    }
+    copy
+}
+
+fn has_finalizers(object: &Value) -> bool {
+    object
+        .get("metadata")
+        .and_then(|m| m.get("finalizers"))
+        .and_then(|f| f.as_array())
+        .map(|f| !f.is_empty())
+        .unwrap_or(false)
+}
+
+fn matches_labels(object: &Value, selector: &str) -> bool {
+    let labels = object.get("metadata").and_then(|m| m.get("labels"));
+    selector
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .all(|requirement| match requirement.split_once('=') {
+            Some((key, value)) => {
+                let value = value.trim_start_matches('=');
+                labels.and_then(|l| l.get(key)).and_then(|v| v.as_str()) == Some(value)
+            }
+            None => labels.and_then(|l| l.get(requirement)).is_some(),
+        })
+}
+
+// Only metadata.name and metadata.namespace equality, e.g. metadata.name=demo
+fn matches_fields(object: &Value, selector: &str) -> bool {
+    selector
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .all(|requirement| match requirement.split_once('=') {
+            Some((field, value)) => {
+                let value = value.trim_start_matches('=');
+                let field = field.trim_start_matches("metadata.");
+                metadata_str(object, field).as_deref() == Some(value)
+            }
+            None => false,
+        })
+}
+
+fn valid_field_selector(selector: &str) -> bool {
+    selector
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .all(|requirement| {
+            requirement.starts_with("metadata.name=")
+                || requirement.starts_with("metadata.namespace=")
+        })
+}
+
+// labelSelector and fieldSelector of a list, watch or deletecollection
+#[derive(Clone, Default)]
+struct Selectors {
+    labels: String,
+    fields: String,
+}
+
+impl Selectors {
+    fn matches(&self, object: &Value) -> bool {
+        matches_labels(object, self.labels.as_str()) && matches_fields(object, self.fields.as_str())
+    }
+}
+
+impl Store {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(1024);
+        Store {
+            objects: BTreeMap::new(),
+            history: Vec::new(),
+            resource_version: 0,
+            sender,
+        }
+    }
+
+    // Copy used by dryRun requests, events go to a channel nobody listens to
+    fn scratch(&self) -> Self {
+        Store {
+            objects: self.objects.clone(),
+            history: Vec::new(),
+            resource_version: self.resource_version,
+            sender: broadcast::channel(1).0,
+        }
+    }
+
+    fn next_resource_version(&mut self) -> u64 {
+        self.resource_version += 1;
+        self.resource_version
+    }
+
+    fn record(&mut self, key: ObjectKey, event_type: &'static str, object: Value) {
+        let resource_version = object
+            .get("metadata")
+            .and_then(|m| m.get("resourceVersion"))
+            .and_then(|v| v.as_str())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(self.resource_version);
+        let event = StoredEvent {
+            resource_version,
+            key,
+            event_type,
+            object,
+        };
+        self.history.push(event.clone());
+        let _ = self.sender.send(event);
+    }
+
+    fn stamp(&mut self, object: &mut Value) -> u64 {
+        let version = self.next_resource_version();
+        metadata_mut(object).insert(
+            String::from("resourceVersion"),
+            Value::String(version.to_string()),
+        );
+        version
+    }
+
+    fn create(&mut self, key: &ObjectKey, mut object: Value) -> Result<Value, ApiError> {
+        if self.objects.contains_key(key) {
+            return Err(ApiError::already_exists(key));
+        }
+        let track_generation = GENERATION.contains(&key.resource.resource.as_str());
+        let metadata = metadata_mut(&mut object);
+        metadata.insert(String::from("name"), Value::String(key.name.clone()));
+        if let Some(namespace) = &key.namespace {
+            metadata.insert(String::from("namespace"), Value::String(namespace.clone()));
+        }
+        metadata.insert(String::from("uid"), Value::String(random_uid()));
+        metadata.insert(String::from("creationTimestamp"), Value::String(now()));
+        metadata.remove("deletionTimestamp");
+        if track_generation {
+            metadata.insert(String::from("generation"), json!(1));
+        }
+        if key.resource.resource == "customresourcedefinitions" {
+            object["status"] = established();
+        }
+        self.stamp(&mut object);
+        self.objects.insert(key.clone(), object.clone());
+        self.record(key.clone(), "ADDED", object.clone());
+        Ok(object)
+    }
+
+    fn update(
+        &mut self,
+        key: &ObjectKey,
+        mut object: Value,
+        subresource: Option<&str>,
+    ) -> Result<Value, ApiError> {
+        let existing = match self.objects.get(key) {
+            Some(existing) => existing.clone(),
+            None => {
+                return if subresource.is_none()
+                    && CREATE_ON_UPDATE.contains(&key.resource.resource.as_str())
+                {
+                    self.create(key, object)
+                } else {
+                    Err(ApiError::not_found(key))
+                }
+            }
+        };
+        let current_version = metadata_str(&existing, "resourceVersion");
+        match metadata_str(&object, "resourceVersion") {
+            Some(version) if !version.is_empty() && Some(&version) != current_version.as_ref() => {
+                return Err(ApiError::conflict(format!(
+                    "Operation cannot be fulfilled on {} \"{}\": the object has been modified",
+                    key.resource.resource, key.name
+                )));
+            }
+            _ => {}
+        }
+        let resource = key.resource.resource.as_str();
+        let with_status = STATUS_SUBRESOURCE.contains(&resource);
+        let mut updated = match subresource {
+            Some("status") => {
+                let mut updated = existing.clone();
+                let status = object
+                    .as_object_mut()
+                    .and_then(|o| o.remove("status"))
+                    .unwrap_or(Value::Null);
+                updated
+                    .as_object_mut()
+                    .unwrap()
+                    .insert(String::from("status"), status);
+                updated
+            }
+            Some(other) => {
+                return Err(ApiError::bad_request(format!(
+                    "Unsupported subresource {}",
+                    other
+                )))
+            }
+            None => {
+                if with_status {
+                    let root = object.as_object_mut().unwrap();
+                    match existing.get("status") {
+                        Some(status) => root.insert(String::from("status"), status.clone()),
+                        None => root.remove("status"),
+                    };
+                }
+                object
+            }
+        };
+        let existing_metadata = existing.get("metadata").cloned().unwrap_or(json!({}));
+        let spec_changed = without(&existing, &["metadata", "status"])
+            != without(&updated, &["metadata", "status"]);
+        let metadata = metadata_mut(&mut updated);
+        for field in [
+            "name",
+            "namespace",
+            "uid",
+            "creationTimestamp",
+            "deletionTimestamp",
+        ] {
+            match existing_metadata.get(field) {
+                Some(value) => metadata.insert(String::from(field), value.clone()),
+                None => metadata.remove(field),
+            };
+        }
+        if GENERATION.contains(&resource) {
+            let generation = existing_metadata
+                .get("generation")
+                .and_then(|g| g.as_u64())
+                .unwrap_or(1);
+            let generation = if spec_changed && subresource.is_none() {
+                generation + 1
+            } else {
+                generation
+            };
+            metadata.insert(String::from("generation"), json!(generation));
+        }
+        self.stamp(&mut updated);
+        let deleting = updated
+            .get("metadata")
+            .and_then(|m| m.get("deletionTimestamp"))
+            .is_some();
+        if deleting && !has_finalizers(&updated) {
+            self.objects.remove(key);
+            self.record(key.clone(), "DELETED", updated.clone());
+            self.collect_garbage(&updated);
+        } else {
+            self.objects.insert(key.clone(), updated.clone());
+            self.record(key.clone(), "MODIFIED", updated.clone());
+        }
+        Ok(updated)
+    }
+
+    fn delete(&mut self, key: &ObjectKey, options: &DeleteOptions) -> Result<Value, ApiError> {
+        let mut object = self
+            .objects
+            .get(key)
+            .cloned()
+            .ok_or_else(|| ApiError::not_found(key))?;
+        if let Some(preconditions) = &options.preconditions {
+            for (field, expected) in [
+                ("uid", &preconditions.uid),
+                ("resourceVersion", &preconditions.resource_version),
+            ] {
+                let actual = metadata_str(&object, field).unwrap_or_default();
+                if let Some(expected) = expected.as_ref().filter(|e| **e != actual) {
+                    return Err(ApiError::conflict(format!(
+                        "Precondition failed: {} in precondition: {}, {} in object meta: {}",
+                        field, expected, field, actual
+                    )));
+                }
+            }
+        }
+        match options.propagation_policy {
+            Some(PropagationPolicy::Foreground) => self.collect_garbage(&object),
+            Some(PropagationPolicy::Orphan) => self.orphan(&object),
+            _ => {}
+        }
+        if has_finalizers(&object) {
+            let metadata = metadata_mut(&mut object);
+            if metadata.contains_key("deletionTimestamp") {
+                return Ok(object);
+            }
+            metadata.insert(String::from("deletionTimestamp"), Value::String(now()));
+            self.stamp(&mut object);
+            self.objects.insert(key.clone(), object.clone());
+            self.record(key.clone(), "MODIFIED", object.clone());
+            return Ok(object);
+        }
+        self.objects.remove(key);
+        self.stamp(&mut object);
+        self.record(key.clone(), "DELETED", object.clone());
+        self.collect_garbage(&object);
+        Ok(object)
+    }
+
+    // Background propagation: dependents are removed once their owner is gone
+    fn collect_garbage(&mut self, owner: &Value) {
+        let uid = match metadata_str(owner, "uid") {
+            Some(uid) => uid,
+            None => return,
+        };
+        let dependents: Vec<ObjectKey> = self
+            .objects
+            .iter()
+            .filter(|(_, object)| {
+                object
+                    .get("metadata")
+                    .and_then(|m| m.get("ownerReferences"))
+                    .and_then(|r| r.as_array())
+                    .map(|r| {
+                        r.iter()
+                            .any(|r| r.get("uid") == Some(&Value::String(uid.clone())))
+                    })
+                    .unwrap_or(false)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for dependent in dependents {
+            info!("Garbage collecting {:?}", dependent);
+            let _ = self.delete(&dependent, &DeleteOptions::default());
+        }
+    }
+
+    // Owner references to the object are removed from its dependents
+    fn orphan(&mut self, owner: &Value) {
+        let uid = match metadata_str(owner, "uid") {
+            Some(uid) => Value::String(uid),
+            None => return,
+        };
+        let dependents: Vec<(ObjectKey, Value)> = self
+            .objects
+            .iter()
+            .filter_map(|(key, object)| {
+                let mut object = object.clone();
+                let metadata = metadata_mut(&mut object);
+                let references = metadata.get_mut("ownerReferences")?.as_array_mut()?;
+                let count = references.len();
+                references.retain(|r| r.get("uid") != Some(&uid));
+                if references.len() == count {
+                    return None;
+                }
+                if references.is_empty() {
+                    metadata.remove("ownerReferences");
+                }
+                Some((key.clone(), object))
+            })
+            .collect();
+        for (key, mut dependent) in dependents {
+            info!("Orphaning {:?}", key);
+            self.stamp(&mut dependent);
+            self.objects.insert(key.clone(), dependent.clone());
+            self.record(key, "MODIFIED", dependent);
+        }
+    }
+
+    fn list(
+        &self,
+        resource: &ResourceKey,
+        namespace: &Option<String>,
+        selectors: &Selectors,
+    ) -> Value {
+        let items: Vec<Value> = self
+            .objects
+            .iter()
+            .filter(|(key, _)| {
+                &key.resource == resource && (namespace.is_none() || &key.namespace == namespace)
+            })
+            .map(|(_, object)| object)
+            .filter(|object| selectors.matches(object))
+            .cloned()
+            .collect();
+        json!({
+            "kind": "List",
+            "apiVersion": "v1",
+            "metadata": { "resourceVersion": self.resource_version.to_string() },
+            "items": items,
+        })
+    }
+}
+
+fn apply_json_patch(target: &mut Value, patch: &Value) -> Result<(), ApiError> {
+    let operations = patch
+        .as_array()
+        .ok_or_else(|| ApiError::bad_request(String::from("JSON patch must be an array")))?;
+    for operation in operations {
+        let op = operation.get("op").and_then(|o| o.as_str()).unwrap_or("");
+        let path = operation.get("path").and_then(|p| p.as_str()).unwrap_or("");
+        let value = operation.get("value").cloned().unwrap_or(Value::Null);
+        let (parent_path, last) = path.rsplit_once('/').unwrap_or(("", path));
+        let last = last.replace("~1", "/").replace("~0", "~");
+        match op {
+            "test" => {
+                if target.pointer(path) != Some(&value) {
+                    return Err(ApiError::invalid(format!(
+                        "test operation failed for {}",
+                        path
+                    )));
+                }
+            }
+            "add" | "replace" => {
+                let parent = target
+                    .pointer_mut(parent_path)
+                    .ok_or_else(|| ApiError::invalid(format!("path {} not found", path)))?;
+                match parent {
+                    Value::Object(map) => {
+                        if op == "replace" && !map.contains_key(&last) {
+                            return Err(ApiError::invalid(format!("path {} not found", path)));
+                        }
+                        map.insert(last, value);
+                    }
+                    Value::Array(array) => {
+                        if last == "-" {
+                            array.push(value);
+                        } else {
+                            let index: usize = last.parse().map_err(|_| {
+                                ApiError::invalid(format!("invalid index in {}", path))
+                            })?;
+                            if op == "replace" && index < array.len() {
+                                array[index] = value;
+                            } else if op == "add" && index <= array.len() {
+                                array.insert(index, value);
+                            } else {
+                                return Err(ApiError::invalid(format!(
+                                    "index out of range {}",
+                                    path
+                                )));
+                            }
+                        }
+                    }
+                    _ => return Err(ApiError::invalid(format!("path {} not found", path))),
+                }
+            }
+            "remove" => {
+                let parent = target
+                    .pointer_mut(parent_path)
+                    .ok_or_else(|| ApiError::invalid(format!("path {} not found", path)))?;
+                let removed = match parent {
+                    Value::Object(map) => map.remove(&last).is_some(),
+                    Value::Array(array) => match last.parse::<usize>() {
+                        Ok(index) if index < array.len() => {
+                            array.remove(index);
+                            true
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                if !removed {
+                    return Err(ApiError::invalid(format!("path {} not found", path)));
+                }
+            }
+            other => {
+                return Err(ApiError::bad_request(format!(
+                    "Unsupported JSON patch operation {}",
+                    other
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7386
+fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    match (target.as_object_mut(), patch.as_object()) {
+        (Some(target), Some(patch)) => {
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    apply_merge_patch(target.entry(key).or_insert(Value::Null), value);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Params {
+    watch: Option<String>,
+    resource_version: Option<String>,
+    label_selector: Option<String>,
+    field_selector: Option<String>,
+    dry_run: Option<String>,
+}
+
+// apiVersion and kind served at the path, when it is accepted and encoded as protobuf
+fn protobuf_kind(headers: &HeaderMap, resource: &ResourceKey) -> Option<(String, &'static str)> {
+    let accepted = headers
+        .get("Accept")
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(PROTOBUF));
+    let api_version = resource
+        .prefix
+        .trim_start_matches("apis/")
+        .trim_start_matches("api/");
+    DISCOVERY
+        .into_iter()
+        .find(|(group, version, _, plural, _)| {
+            *plural == resource.resource && api_version == api_version_of(group, version)
+        })
+        .filter(|(_, _, kind, ..)| accepted && has_message(api_version, kind))
+        .map(|(_, _, kind, ..)| (String::from(api_version), kind))
+}
+
+fn api_version_of(group: &str, version: &str) -> String {
+    if group.is_empty() {
+        String::from(version)
+    } else {
+        format!("{}/{}", group, version)
+    }
+}
+
+fn list_response(list: Value, protobuf: Option<(String, &'static str)>) -> Response {
+    let encoded = protobuf.and_then(|(api_version, kind)| {
+        encode_list(
+            api_version.as_str(),
+            kind,
+            list["items"].as_array().unwrap(),
+            list["metadata"]["resourceVersion"].as_str().unwrap(),
+        )
+    });
+    match encoded {
+        Some(Ok(body)) => ([("Content-Type", PROTOBUF)], body).into_response(),
+        Some(Err(e)) => {
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", e).into_response()
+        }
+        None => Json(list).into_response(),
+    }
+}
+
+fn watch_response(
+    resource: ResourceKey,
+    namespace: Option<String>,
+    selectors: Selectors,
+    mut receiver: broadcast::Receiver<StoredEvent>,
+    backlog: Vec<StoredEvent>,
+    protobuf: Option<(String, &'static str)>,
+) -> Response {
+    let content_type = match protobuf {
+        Some(_) => format!("{};stream=watch", PROTOBUF),
+        None => String::from("application/json"),
+    };
+    let encode = move |event: &StoredEvent| match &protobuf {
+        // Objects failing to encode end the stream
+        Some((api_version, kind)) => {
+            encode_watch_event(api_version, kind, event.event_type, &event.object)
+                .unwrap()
+                .map(Bytes::from)
+        }
+        None => Ok(encode_event(event)),
+    };
+    let matches = move |event: &StoredEvent| {
+        event.key.resource == resource
+            && (namespace.is_none() || event.key.namespace == namespace)
+            && selectors.matches(&event.object)
+    };
+    let body = stream! {
+        let mut last_version = 0;
+        for event in backlog.into_iter().filter(|e| matches(e)) {
+            last_version = event.resource_version;
+            match encode(&event) {
+                Ok(bytes) => yield Ok::<Bytes, Infallible>(bytes),
+                Err(e) => {
+                    info!("Watch ended: {}", e);
+                    return;
+                }
+            }
+        }
+        // Lagging or closed channel ends the watch, client is expected to re-list
+        while let Ok(event) = receiver.recv().await {
+            if event.resource_version > last_version && matches(&event) {
+                match encode(&event) {
+                    Ok(bytes) => yield Ok(bytes),
+                    Err(e) => {
+                        info!("Watch ended: {}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .body(Body::from_stream(body))
+        .unwrap()
+}
+
+fn encode_event(event: &StoredEvent) -> Bytes {
+    let mut line = json!({ "type": event.event_type, "object": event.object }).to_string();
+    line.push('\n');
+    Bytes::from(line)
+}
+
+async fn handle(
+    State(store): State<SharedStore>,
+    method: Method,
+    uri: Uri,
+    Query(params): Query<Params>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let path = parse_path(uri.path())
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "NotFound", uri.path().to_string()))?;
+    info!("{} {}", method, uri);
+    let selectors = Selectors {
+        labels: params.label_selector.clone().unwrap_or_default(),
+        fields: params.field_selector.clone().unwrap_or_default(),
+    };
+    if !valid_field_selector(selectors.fields.as_str()) {
+        return Err(ApiError::bad_request(format!(
+            "Unsupported fieldSelector {}",
+            selectors.fields
+        )));
+    }
+    let watch = matches!(params.watch.as_deref(), Some("1") | Some("true"));
+    let mut store_guard = store.lock().await;
+    let mut scratch = (params.dry_run.as_deref() == Some("All")).then(|| store_guard.scratch());
+    let target: &mut Store = match scratch.as_mut() {
+        Some(scratch) => scratch,
+        None => &mut store_guard,
+    };
+    match (method, path.name) {
+        (Method::GET, None) if watch => {
+            let receiver = store_guard.sender.subscribe();
+            let backlog = match params.resource_version.as_deref() {
+                None | Some("") | Some("0") => {
+                    let current =
+                        store_guard.list(&path.resource, &path.namespace, &Selectors::default());
+                    current["items"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|object| StoredEvent {
+                            resource_version: store_guard.resource_version,
+                            key: ObjectKey {
+                                resource: path.resource.clone(),
+                                namespace: metadata_str(object, "namespace"),
+                                name: metadata_str(object, "name").unwrap_or_default(),
+                            },
+                            event_type: "ADDED",
+                            object: object.clone(),
+                        })
+                        .collect()
+                }
+                Some(version) => {
+                    let version: u64 = version.parse().map_err(|_| {
+                        ApiError::bad_request(format!("Invalid resourceVersion {}", version))
+                    })?;
+                    store_guard
+                        .history
+                        .iter()
+                        .filter(|e| e.resource_version > version)
+                        .cloned()
+                        .collect()
+                }
+            };
+            drop(store_guard);
+            let protobuf = protobuf_kind(&headers, &path.resource);
+            Ok(watch_response(
+                path.resource,
+                path.namespace,
+                selectors,
+                receiver,
+                backlog,
+                protobuf,
+            ))
+        }
+        (Method::GET, None) => {
+            let list = store_guard.list(&path.resource, &path.namespace, &selectors);
+            Ok(list_response(list, protobuf_kind(&headers, &path.resource)))
+        }
+        (Method::DELETE, None) => {
+            let options = delete_options(&body)?;
+            let list = target.list(&path.resource, &path.namespace, &selectors);
+            let mut deleted = Vec::new();
+            for object in list["items"].as_array().unwrap() {
+                let key = ObjectKey {
+                    resource: path.resource.clone(),
+                    namespace: metadata_str(object, "namespace"),
+                    name: metadata_str(object, "name").unwrap_or_default(),
+                };
+                // Collected as a dependent of an object deleted before
+                match target.delete(&key, &options) {
+                    Ok(object) => deleted.push(object),
+                    Err(e) if e.code == StatusCode::NOT_FOUND => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Json(json!({
+                "kind": "List",
+                "apiVersion": "v1",
+                "metadata": {},
+                "items": deleted,
+            }))
+            .into_response())
+        }
+        (Method::POST, None) if path.resource.resource == "selfsubjectaccessreviews" => {
+            let review: Value = serde_json::from_slice(&body)
+                .map_err(|e| ApiError::bad_request(format!("Invalid body: {}", e)))?;
+            Ok((StatusCode::CREATED, Json(review_access(review))).into_response())
+        }
+        (Method::POST, None) => {
+            let object: Value = serde_json::from_slice(&body)
+                .map_err(|e| ApiError::bad_request(format!("Invalid body: {}", e)))?;
+            let name = match metadata_str(&object, "name") {
+                Some(name) => name,
+                None => match metadata_str(&object, "generateName") {
+                    Some(prefix) => format!(
+                        "{}{}",
+                        prefix,
+                        Alphanumeric
+                            .sample_string(&mut rand::rng(), 5)
+                            .to_lowercase()
+                    ),
+                    None => return Err(ApiError::invalid(String::from("name is required"))),
+                },
+            };
+            let key = ObjectKey {
+                resource: path.resource,
+                namespace: path.namespace,
+                name,
+            };
+            let created = target.create(&key, object)?;
+            Ok((StatusCode::CREATED, Json(created)).into_response())
+        }
+        (method, Some(name)) => {
+            let key = ObjectKey {
+                resource: path.resource,
+                namespace: path.namespace,
+                name,
+            };
+            let subresource = path.subresource.as_deref();
+            match method {
+                Method::GET => store_guard
+                    .objects
+                    .get(&key)
+                    .map(|object| Json(object.clone()).into_response())
+                    .ok_or_else(|| ApiError::not_found(&key)),
+                Method::PUT => {
+                    let object: Value = serde_json::from_slice(&body)
+                        .map_err(|e| ApiError::bad_request(format!("Invalid body: {}", e)))?;
+                    let updated = target.update(&key, object, subresource)?;
+                    Ok(Json(updated).into_response())
+                }
+                Method::PATCH => {
+                    let patch: Value = serde_json::from_slice(&body)
+                        .map_err(|e| ApiError::bad_request(format!("Invalid body: {}", e)))?;
+                    let mut object = target
+                        .objects
+                        .get(&key)
+                        .cloned()
+                        .ok_or_else(|| ApiError::not_found(&key))?;
+                    let content_type = headers
+                        .get("Content-Type")
+                        .and_then(|c| c.to_str().ok())
+                        .unwrap_or("");
+                    match content_type {
+                        "application/json-patch+json" => apply_json_patch(&mut object, &patch)?,
+                        "application/merge-patch+json"
+                        | "application/strategic-merge-patch+json" => {
+                            apply_merge_patch(&mut object, &patch)
+                        }
+                        other => {
+                            return Err(ApiError::new(
+                                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                                "UnsupportedMediaType",
+                                format!("Unsupported patch type {}", other),
+                            ))
+                        }
+                    }
+                    // Patch was applied to the latest version, conflicts come only from "test"
+                    metadata_mut(&mut object).remove("resourceVersion");
+                    let updated = target.update(&key, object, subresource)?;
+                    Ok(Json(updated).into_response())
+                }
+                Method::DELETE => {
+                    let deleted = target.delete(&key, &delete_options(&body)?)?;
+                    Ok(Json(deleted).into_response())
+                }
+                other => Err(ApiError::new(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "MethodNotAllowed",
+                    format!("Method {} not allowed", other),
+                )),
+            }
+        }
+        (other, None) => Err(ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "MethodNotAllowed",
+            format!("Method {} not allowed", other),
+        )),
+    }
+}
+
+// Clients may send no body at all
+fn delete_options(body: &Bytes) -> Result<DeleteOptions, ApiError> {
+    if body.is_empty() {
+        return Ok(DeleteOptions::default());
+    }
+    serde_json::from_slice(body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid DeleteOptions: {}", e)))
+}
+
+// Names never conflict here, a real API server checks them first
+fn established() -> Value {
+    let condition = |condition_type: &str, reason: &str| {
+        json!({
+            "type": condition_type,
+            "status": "True",
+            "reason": reason,
+            "message": "the initial names have been accepted",
+            "lastTransitionTime": now(),
+        })
+    };
+    json!({ "conditions": [
+        condition("NamesAccepted", "NoConflicts"),
+        condition("Established", "InitialNamesAccepted"),
+    ] })
+}
+
+// Answered without being stored, like on a real API server
+fn review_access(mut review: Value) -> Value {
+    let attributes = &review["spec"]["resourceAttributes"];
+    let resource = match attributes["subresource"].as_str() {
+        Some(subresource) => format!(
+            "{}/{}",
+            attributes["resource"].as_str().unwrap_or_default(),
+            subresource
+        ),
+        None => attributes["resource"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+    };
+    let requested = format!(
+        "{}:{}",
+        attributes["verb"].as_str().unwrap_or_default(),
+        resource
+    );
+    let denied = env::var("DENIED").unwrap_or_default();
+    let allowed = !denied.split(',').any(|entry| entry == requested);
+    review["status"] = json!({
+        "allowed": allowed,
+        "reason": if allowed { "" } else { "denied by DENIED" },
+    });
+    review
+}
+
+fn served_resources() -> Vec<(&'static str, &'static str, &'static str, &'static str, bool)> {
+    let disabled = env::var("DISABLED_GROUPS").unwrap_or_default();
+    DISCOVERY
+        .into_iter()
+        .filter(|(group, ..)| !disabled.split(',').any(|d| !d.is_empty() && d == *group))
+        .collect()
+}
+
+// Versions of every group in order of preference, core group first
+fn served_groups() -> Vec<(&'static str, Vec<&'static str>)> {
+    let mut groups: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (group, version, ..) in served_resources() {
+        match groups.iter_mut().find(|(name, _)| *name == group) {
+            Some((_, versions)) if !versions.contains(&version) => versions.push(version),
+            Some(_) => {}
+            None => groups.push((group, vec![version])),
+        }
+    }
+    groups
+}
+
+fn aggregated_group(group: &str, versions: &[&str]) -> Value {
+    let versions: Vec<Value> = versions
+        .iter()
+        .map(|version| {
+            let resources: Vec<Value> = served_resources()
+                .into_iter()
+                .filter(|(g, v, ..)| *g == group && v == version)
+                .map(|(_, _, kind, plural, namespaced)| {
+                    json!({
+                        "resource": plural,
+                        "responseKind": { "group": group, "version": version, "kind": kind },
+                        "scope": if namespaced { "Namespaced" } else { "Cluster" },
+                        "verbs": VERBS,
+                    })
+                })
+                .collect();
+            json!({ "version": version, "resources": resources, "freshness": "Current" })
+        })
+        .collect();
+    json!({ "metadata": { "name": group }, "versions": versions })
+}
+
+/*
+   GET /api, /apis and their group versions.
+   Aggregated documents are returned when asked for in the Accept header, like kube-apiserver 1.30+.
+*/
+async fn discovery(uri: Uri, headers: HeaderMap) -> Response {
+    let aggregated = env::var("LEGACY_DISCOVERY").is_err()
+        && headers
+            .get("Accept")
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("as=APIGroupDiscoveryList"));
+    let segments: Vec<&str> = uri.path().split('/').filter(|s| !s.is_empty()).collect();
+    let groups = served_groups();
+    let (core, named): (Vec<_>, Vec<_>) =
+        groups.into_iter().partition(|(group, _)| group.is_empty());
+    let document = match segments.as_slice() {
+        ["api"] if aggregated => json!({
+            "apiVersion": "apidiscovery.k8s.io/v2",
+            "kind": "APIGroupDiscoveryList",
+            "items": core.iter().map(|(group, versions)| aggregated_group(group, versions)).collect::<Vec<_>>(),
+        }),
+        ["apis"] if aggregated => json!({
+            "apiVersion": "apidiscovery.k8s.io/v2",
+            "kind": "APIGroupDiscoveryList",
+            "items": named.iter().map(|(group, versions)| aggregated_group(group, versions)).collect::<Vec<_>>(),
+        }),
+        ["api"] => json!({
+            "kind": "APIVersions",
+            "versions": core.first().map(|(_, versions)| versions.clone()).unwrap_or_default(),
+        }),
+        ["apis"] => {
+            let groups: Vec<Value> = named
+                .iter()
+                .map(|(group, versions)| {
+                    let versions: Vec<Value> = versions
+                        .iter()
+                        .map(
+                            |v| json!({ "groupVersion": format!("{}/{}", group, v), "version": v }),
+                        )
+                        .collect();
+                    json!({ "name": group, "versions": versions, "preferredVersion": versions[0] })
+                })
+                .collect();
+            json!({ "kind": "APIGroupList", "apiVersion": "v1", "groups": groups })
+        }
+        ["api", version] | ["apis", _, version] => {
+            let group = if segments.len() == 3 { segments[1] } else { "" };
+            let resources: Vec<Value> = served_resources()
+                .into_iter()
+                .filter(|(g, v, ..)| *g == group && v == version)
+                .map(|(_, _, kind, plural, namespaced)| {
+                    json!({ "name": plural, "kind": kind, "namespaced": namespaced, "verbs": VERBS })
+                })
+                .collect();
+            if resources.is_empty() {
+                return ApiError::new(StatusCode::NOT_FOUND, "NotFound", uri.path().to_string())
+                    .into_response();
+            }
+            let group_version = api_version_of(group, version);
+            json!({ "kind": "APIResourceList", "groupVersion": group_version, "resources": resources })
+        }
+        _ => {
+            return ApiError::new(StatusCode::NOT_FOUND, "NotFound", uri.path().to_string())
+                .into_response()
+        }
+    };
+    info!("GET {} aggregated={}", uri, aggregated);
+    Json(document).into_response()
+}
+
+// YAML/JSON manifests, objects are created as if they were POSTed
+fn seed(store: &mut Store, manifests: &str) {
+    for document in serde_yaml::Deserializer::from_str(manifests) {
+        let object = Value::deserialize(document).expect("Unable to parse seed file");
+        if object.is_null() {
+            continue;
+        }
+        let api_version = object["apiVersion"].as_str().expect("apiVersion expected");
+        let kind = object["kind"].as_str().expect("kind expected");
+        let prefix = if api_version.contains('/') {
+            format!("apis/{}", api_version)
+        } else {
+            format!("api/{}", api_version)
+        };
+        let key = ObjectKey {
+            resource: ResourceKey {
+                prefix,
+                resource: resource_name(kind),
+            },
+            namespace: metadata_str(&object, "namespace"),
+            name: metadata_str(&object, "name").expect("metadata.name expected"),
+        };
+        info!("Seeding {} {}", kind, key.name);
+        store.create(&key, object).expect("Unable to seed object");
+    }
+}
+
+// Valid for 127.0.0.1 and localhost, it is its own CA
+fn self_signed_certificate() -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let key = PKey::from_ec_key(EcKey::generate(
+        EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?.as_ref(),
+    )?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "fake-api-server")?;
+    let name = name.build();
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(
+        BigNum::from_u32(rand::random())?
+            .to_asn1_integer()?
+            .as_ref(),
+    )?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(30)?.as_ref())?;
+    builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    builder.append_extension(
+        KeyUsage::new()
+            .critical()
+            .digital_signature()
+            .key_cert_sign()
+            .build()?,
+    )?;
+    let alternative_names = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(alternative_names)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+    Ok((builder.build().to_pem()?, key.private_key_to_pem_pkcs8()?))
+}
+
+/*
+   In-memory API server, started by the fake-api-server binary and by integration tests.
+   Listens on 127.0.0.1, a random port unless one is set.
+*/
+pub struct FakeApiServer {
+    store: Store,
+    port: u16,
+    tls: bool,
+}
+
+// Stops serving when dropped
+pub struct RunningApiServer {
+    pub url: String,
+    // Certificate of the server when serving HTTPS, to be trusted by clients
+    pub ca_file: Option<PathBuf>,
+    task: JoinHandle<()>,
+}
+
+impl Default for FakeApiServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeApiServer {
+    pub fn new() -> Self {
+        FakeApiServer {
+            store: Store::new(),
+            port: 0,
+            tls: false,
+        }
+    }
+
+    pub fn seed(mut self, manifests: &str) -> Self {
+        seed(&mut self.store, manifests);
+        self
+    }
+
+    pub fn seed_file(self, path: &Path) -> Self {
+        let content = std::fs::read_to_string(path).expect("Unable to read seed file");
+        self.seed(content.as_str())
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    // HTTPS with a self-signed certificate generated at startup
+    pub fn tls(mut self) -> Self {
+        self.tls = true;
+        self
+    }
+
+    pub async fn start(self) -> RunningApiServer {
+        let store: SharedStore = Arc::new(Mutex::new(self.store));
+        let app = Router::new()
+            .route("/healthz", axum::routing::get(async || "OK"))
+            .route("/api", axum::routing::get(discovery))
+            .route("/api/{version}", axum::routing::get(discovery))
+            .route("/apis", axum::routing::get(discovery))
+            .route("/apis/{group}/{version}", axum::routing::get(discovery))
+            .fallback(handle)
+            .with_state(store);
+        let listener = std::net::TcpListener::bind(("127.0.0.1", self.port))
+            .expect("Unable to bind fake API server");
+        let address = listener.local_addr().unwrap();
+        if !self.tls {
+            listener.set_nonblocking(true).unwrap();
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            return RunningApiServer {
+                url: format!("http://{}", address),
+                ca_file: None,
+                task: tokio::spawn(async move { axum::serve(listener, app).await.unwrap() }),
+            };
+        }
+        let (certificate, key) =
+            self_signed_certificate().expect("Unable to generate a certificate");
+        let ca_file = env::temp_dir().join(format!("fake-api-server-{}.crt", address.port()));
+        std::fs::write(&ca_file, &certificate).expect("Unable to write the certificate");
+        let tls = OpenSSLConfig::from_pem(&certificate, &key).expect("Invalid certificate");
+        let server = axum_server::from_tcp(listener).acceptor(OpenSSLAcceptor::new(tls));
+        RunningApiServer {
+            url: format!("https://{}", address),
+            ca_file: Some(ca_file),
+            task: tokio::spawn(async move { server.serve(app.into_make_service()).await.unwrap() }),
+        }
+    }
+}
+
+impl RunningApiServer {
+    // Until the server fails, it never stops on its own
+    pub async fn join(mut self) {
+        if let Err(e) = (&mut self.task).await {
+            error!("Fake API server failed: {}", e);
+        }
+    }
+}
+
+impl Drop for RunningApiServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Some(ca_file) = &self.ca_file {
+            let _ = std::fs::remove_file(ca_file);
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use no_library::fake_api::FakeApiServer;
+use std::env;
+use std::path::Path;
+
+/*
+   Usage: fake-api-server [SEED_FILE...]
+   Seed files are YAML/JSON manifests, e.g. config/lease.yaml examples/exposed_app.yaml
+   PORT sets the port, TLS=1 serves HTTPS with a self-signed certificate.
+*/
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let port = env::var("PORT").map_or(0, |port| port.parse().expect("Invalid PORT"));
+    let mut server = FakeApiServer::new().port(port);
+    for path in env::args().skip(1) {
+        server = server.seed_file(Path::new(path.as_str()));
+    }
+    if env::var("TLS").is_ok_and(|tls| tls == "1") {
+        server = server.tls();
+    }
+    let running = server.start().await;
+    println!("KUBERNETES_API_URL={}", running.url);
+    if let Some(ca_file) = &running.ca_file {
+        println!("KUBERNETES_CA_FILE={}", ca_file.display());
+    }
+    running.join().await;
+}
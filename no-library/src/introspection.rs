@@ -0,0 +1,322 @@
+use crate::cache::{Cache, NamespacedName};
+use crate::k8s_time::{MicroTime, Time};
+use crate::k8s_types::LeaseSpec;
+use crate::sharding::Sharding;
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{from_fn, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
+
+/*
+   Internal state of the operator served on the /debug routes.
+   Watches, the reconcile queue and the leader elector record what they do,
+   the handlers only read it. Objects are listed with their resourceVersion only,
+   so Secret data never leaves the operator.
+*/
+#[derive(Clone, Default)]
+pub struct Introspection {
+    state: Arc<Mutex<IntrospectionState>>,
+}
+
+#[derive(Default)]
+struct IntrospectionState {
+    cache: Option<Cache>,
+    // Set once the controller runs, before that nothing can be enqueued
+    sender: Option<Sender<NamespacedName>>,
+    watches: BTreeMap<&'static str, WatchState>,
+    queue: BTreeMap<String, QueueEntry>,
+    leader: Option<LeaderState>,
+}
+
+#[derive(Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct WatchState {
+    uri: String,
+    // Of the last list or event
+    resource_version: Option<String>,
+    restarts: u32,
+    last_event_time: Option<Time>,
+    // resourceVersion per namespace/name
+    objects: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct QueueEntry {
+    ready_time: Time,
+    failures: u32,
+    // Delay of the last retry, none for events and requeues
+    backoff_seconds: Option<f64>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct LeaderState {
+    identity: String,
+    leader: bool,
+    holder_identity: Option<String>,
+    acquire_time: Option<MicroTime>,
+    lease_duration_seconds: u32,
+    last_refresh_time: Option<MicroTime>,
+    last_error: Option<String>,
+}
+
+fn key(request: &NamespacedName) -> String {
+    format!("{}/{}", request.namespace, request.name)
+}
+
+impl Introspection {
+    pub fn register_cache(&self, cache: Cache) {
+        self.state.lock().unwrap().cache = Some(cache);
+    }
+
+    pub fn register_sender(&self, sender: Sender<NamespacedName>) {
+        self.state.lock().unwrap().sender = Some(sender);
+    }
+
+    // Replaces the known objects of the kind after a list
+    pub fn listed(
+        &self,
+        kind: &'static str,
+        uri: &str,
+        resource_version: &str,
+        objects: Vec<(NamespacedName, String)>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let watch = state.watches.entry(kind).or_default();
+        if watch.resource_version.is_some() {
+            watch.restarts += 1;
+        }
+        watch.uri = String::from(uri);
+        watch.resource_version = Some(String::from(resource_version));
+        watch.objects = objects
+            .iter()
+            .map(|(request, version)| (key(request), version.clone()))
+            .collect();
+    }
+
+    pub fn received(
+        &self,
+        kind: &'static str,
+        request: &NamespacedName,
+        resource_version: &str,
+        deleted: bool,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let watch = state.watches.entry(kind).or_default();
+        watch.resource_version = Some(String::from(resource_version));
+        watch.last_event_time = Some(Time::now());
+        if deleted {
+            watch.objects.remove(&key(request));
+        } else {
+            watch
+                .objects
+                .insert(key(request), String::from(resource_version));
+        }
+    }
+
+    pub fn queued(
+        &self,
+        request: &NamespacedName,
+        deadline: Instant,
+        failures: u32,
+        backoff: Option<Duration>,
+    ) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let entry = QueueEntry {
+            ready_time: Time::new(OffsetDateTime::now_utc() + remaining),
+            failures,
+            backoff_seconds: backoff.map(|backoff| backoff.as_secs_f64()),
+        };
+        self.state.lock().unwrap().queue.insert(key(request), entry);
+    }
+
+    pub fn dequeued(&self, request: &NamespacedName) {
+        self.state.lock().unwrap().queue.remove(&key(request));
+    }
+
+    pub fn lease(&self, identity: &str, spec: &LeaseSpec) {
+        let mut state = self.state.lock().unwrap();
+        let previous = state.leader.take();
+        state.leader = Some(LeaderState {
+            identity: String::from(identity),
+            leader: spec.holder_identity.as_deref() == Some(identity),
+            holder_identity: spec.holder_identity.clone(),
+            acquire_time: spec.acquire_time,
+            lease_duration_seconds: spec.lease_duration_seconds,
+            last_refresh_time: previous
+                .as_ref()
+                .and_then(|leader| leader.last_refresh_time),
+            last_error: previous.and_then(|leader| leader.last_error),
+        });
+    }
+
+    // Outcome of an acquire or refresh of the Lease by this replica
+    pub fn refreshed(&self, now: MicroTime, result: Result<(), String>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(leader) = state.leader.as_mut() {
+            match result {
+                Ok(()) => {
+                    leader.leader = true;
+                    leader.holder_identity = Some(leader.identity.clone());
+                    leader.acquire_time = Some(now);
+                    leader.last_refresh_time = Some(now);
+                    leader.last_error = None;
+                }
+                Err(e) => leader.last_error = Some(e),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct DebugState {
+    introspection: Introspection,
+    sharding: Option<Arc<Sharding>>,
+}
+
+#[derive(Deserialize)]
+struct EnqueueRequest {
+    namespace: String,
+    name: String,
+}
+
+// Compares every byte, so the response time doesn't tell how much of the token matched
+fn same_token(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |difference, (l, r)| difference | (l ^ r))
+            == 0
+}
+
+async fn cache(State(state): State<DebugState>) -> Json<Value> {
+    let (cache, watches) = {
+        let introspection = state.introspection.state.lock().unwrap();
+        (introspection.cache.clone(), introspection.watches.clone())
+    };
+    let mut written = BTreeMap::new();
+    if let Some(cache) = cache {
+        for (request, entry) in cache.lock().await.iter() {
+            written.insert(
+                key(request),
+                json!({
+                    "resourceVersion": entry.resource_version,
+                    "generation": entry.generation,
+                    "annotations": entry.annotations,
+                }),
+            );
+        }
+    }
+    let objects: BTreeMap<_, _> = watches
+        .into_iter()
+        .map(|(kind, watch)| (kind, watch.objects))
+        .collect();
+    Json(json!({ "written": written, "objects": objects }))
+}
+
+async fn watches(State(state): State<DebugState>) -> Json<Value> {
+    let introspection = state.introspection.state.lock().unwrap();
+    let watches: BTreeMap<_, _> = introspection
+        .watches
+        .iter()
+        .map(|(kind, watch)| {
+            let summary = json!({
+                "uri": watch.uri,
+                "resourceVersion": watch.resource_version,
+                "restarts": watch.restarts,
+                "lastEventTime": watch.last_event_time,
+                "objects": watch.objects.len(),
+            });
+            (*kind, summary)
+        })
+        .collect();
+    Json(json!(watches))
+}
+
+async fn queue(State(state): State<DebugState>) -> Json<Value> {
+    let introspection = state.introspection.state.lock().unwrap();
+    let channel = introspection.sender.as_ref().map(|sender| {
+        json!({
+            "pending": sender.max_capacity() - sender.capacity(),
+            "capacity": sender.max_capacity(),
+        })
+    });
+    Json(json!({ "channel": channel, "entries": introspection.queue }))
+}
+
+async fn leadership(State(state): State<DebugState>) -> Json<Value> {
+    let leader = state.introspection.state.lock().unwrap().leader.clone();
+    let shard = state.sharding.as_ref().map(|sharding| {
+        json!({
+            "identity": sharding.identity(),
+            "members": sharding.members(),
+        })
+    });
+    Json(json!({ "leader": leader, "shard": shard }))
+}
+
+async fn enqueue(State(state): State<DebugState>, Json(request): Json<EnqueueRequest>) -> Response {
+    let sender = state.introspection.state.lock().unwrap().sender.clone();
+    let sender = match sender {
+        Some(sender) => sender,
+        None => {
+            let message = "Controller not started, is this replica the leader?";
+            return (StatusCode::SERVICE_UNAVAILABLE, message).into_response();
+        }
+    };
+    let request = NamespacedName::new(request.name.as_str(), request.namespace.as_str());
+    match sender.send(request.clone()).await {
+        Ok(()) => (
+            StatusCode::ACCEPTED,
+            Json(json!({ "enqueued": key(&request) })),
+        )
+            .into_response(),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "Reconcile loop stopped").into_response(),
+    }
+}
+
+// Read-only JSON views of the operator state, plus POST /debug/enqueue {"namespace", "name"}
+pub fn routes(
+    introspection: Introspection,
+    sharding: Option<Arc<Sharding>>,
+    token: String,
+) -> Router {
+    let token = Arc::new(format!("Bearer {}", token));
+    let authenticate = move |request: Request, next: Next| {
+        let token = Arc::clone(&token);
+        async move {
+            let authorized = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .is_some_and(|value| same_token(value.as_bytes(), token.as_bytes()));
+            if authorized {
+                next.run(request).await
+            } else {
+                StatusCode::UNAUTHORIZED.into_response()
+            }
+        }
+    };
+    Router::new()
+        .route("/debug/cache", get(cache))
+        .route("/debug/watches", get(watches))
+        .route("/debug/queue", get(queue))
+        .route("/debug/leadership", get(leadership))
+        .route("/debug/enqueue", post(enqueue))
+        .layer(from_fn(authenticate))
+        .with_state(DebugState {
+            introspection,
+            sharding,
+        })
+}
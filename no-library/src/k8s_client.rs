@@ -1,46 +1,36 @@
 pub mod client {
+    use crate::cassette::Recorder;
+    use crate::config::ClientConfig;
+    use crate::credentials::CredentialProvider;
+    use crate::discovery::{with_query, ApiResource, Discovery};
     use crate::k8s_client::client::K8sClientError::{Conflict, Error, NotFound};
+    use crate::k8s_time::MicroTime;
     use crate::k8s_types::{
-        Deployment, Event, ExposedApp, K8sListObject, K8sObject, Lease, List, Service, Watch,
+        DeleteOptions, Deployment, Event, ExposedApp, K8sListObject, K8sObject, Lease, List,
+        Resource, Service, Watch, WatchEventType,
     };
-    use crate::offset_date_time_parser::format;
+    use crate::protobuf::{
+        decode_list, decode_watch_event, Decode, Frames, Protobuf, PROTOBUF, PROTOBUF_OR_JSON,
+    };
+    use crate::rate_limiter::RateLimiter;
     use async_stream::stream;
-    use futures::Stream;
-    use reqwest::header::{HeaderMap, HeaderValue};
-    use reqwest::{Certificate, Client, RequestBuilder, Response, StatusCode};
+    use futures::{pin_mut, Stream, StreamExt};
+    use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE, RETRY_AFTER};
+    use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
     use serde::de::DeserializeOwned;
     use serde::{Deserialize, Serialize};
-    use serde_json::{from_str, to_string};
+    use serde_json::{from_slice, from_str, json, to_string, Value};
     use std::env;
     use std::str::from_utf8;
+    use std::sync::Arc;
     use std::time::Duration;
+    use time::format_description::well_known::Rfc2822;
     use time::OffsetDateTime;
-    use tokio::fs;
-    use tokio::time::sleep;
-    use tracing::{error, info};
+    use tokio::time::{sleep, timeout};
+    use tracing::{error, field, info, info_span, warn, Instrument};
 
     const SERVICE_ACCOUNT_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
     const API_SERVER: &str = "https://kubernetes.default.svc";
-    const EXPOSED_APPS_LIST: &str = "apis/stable.no-library.com/v1/exposedapps";
-
-    async fn get_token() -> String {
-        let content = fs::read(format!("{}/token", SERVICE_ACCOUNT_PATH))
-            .await
-            .expect("Unable to read token");
-        String::from_utf8(content).expect("Unable to convert token to UTF8")
-    }
-
-    async fn get_client() -> Client {
-        let cert = fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT_PATH))
-            .await
-            .expect("Unable to read certificate");
-        let certificate =
-            Certificate::from_pem(cert.as_slice()).expect("Unable to create Certificate");
-        Client::builder()
-            .add_root_certificate(certificate)
-            .build()
-            .expect("Unable to add root certificate")
-    }
 
     #[derive(Debug, Clone)]
     pub enum K8sClientError {
@@ -66,12 +56,57 @@ pub mod client {
         }
     }
 
+    // Delay in seconds or an HTTP date, a date in the past means no delay
+    fn parse_retry_after(value: &str, now: OffsetDateTime) -> Option<Duration> {
+        let value = value.trim();
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        let date = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+        Some(Duration::try_from(date - now).unwrap_or(Duration::ZERO))
+    }
+
+    #[derive(Clone)]
+    pub struct RetryPolicy {
+        pub max_retries: u32,
+        pub base_delay: Duration,
+        pub max_delay: Duration,
+    }
+
+    impl Default for RetryPolicy {
+        fn default() -> Self {
+            RetryPolicy {
+                max_retries: 5,
+                base_delay: Duration::from_millis(200),
+                max_delay: Duration::from_secs(30),
+            }
+        }
+    }
+
+    impl RetryPolicy {
+        // Exponential backoff with full jitter
+        pub fn backoff(&self, attempt: u32) -> Duration {
+            let exponential = self
+                .base_delay
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(self.max_delay);
+            let millis = exponential.as_millis() as u64;
+            Duration::from_millis(rand::random_range(0..=millis))
+        }
+    }
+
     #[derive(Clone)]
     pub struct K8sClient {
-        client: Client,
-        token: String,
-        k8s_service_host: String,
-        k8s_service_port: u16,
+        credentials: Arc<CredentialProvider>,
+        api_server_url: String,
+        user_agent: String,
+        rate_limiter: Arc<RateLimiter>,
+        retry_policy: RetryPolicy,
+        dry_run: bool,
+        // Shared by every clone, requests are recorded in the order they complete
+        recorder: Option<Arc<Recorder>>,
+        // Set once at startup, every URI of a kind is resolved through it
+        discovery: Option<Arc<Discovery>>,
     }
 
     #[derive(Serialize, Deserialize)]
@@ -81,9 +116,21 @@ pub mod client {
         value: T,
     }
 
+    fn recorder(config: &ClientConfig) -> Option<Arc<Recorder>> {
+        config.record.as_ref().map(|path| {
+            info!("Recording API traffic to {}", path.display());
+            Arc::new(Recorder::create(path).expect("Unable to record API traffic"))
+        })
+    }
+
     impl K8sClient {
-        pub async fn new() -> Self {
-            let host = env::var("KUBERNETES_SERVICE_HOST").unwrap_or(String::new());
+        pub async fn new(config: &ClientConfig) -> Self {
+            if let Some(url) = &config.api_server_url {
+                info!("Using API server URL: {}", url);
+                let token = config.token.clone().unwrap_or_default();
+                return Self::with_api_server(url.as_str(), token.as_str(), config);
+            }
+            let host = env::var("KUBERNETES_SERVICE_HOST").unwrap_or_default();
             let port = env::var("KUBERNETES_SERVICE_PORT")
                 .map(|p| p.parse::<u16>().unwrap())
                 .unwrap_or(0);
@@ -93,71 +140,115 @@ pub mod client {
                     host, port
                 );
             }
+            let api_server_url = if host.is_empty() {
+                String::from(API_SERVER)
+            } else {
+                format!("https://{}:{}", host, port)
+            };
             K8sClient {
-                client: get_client().await,
-                token: get_token().await,
-                k8s_service_host: host,
-                k8s_service_port: port,
+                credentials: CredentialProvider::in_cluster(SERVICE_ACCOUNT_PATH).await,
+                api_server_url,
+                user_agent: config.user_agent.clone(),
+                rate_limiter: Arc::new(RateLimiter::new(config.qps, config.burst)),
+                retry_policy: RetryPolicy {
+                    max_retries: config.max_retries,
+                    ..RetryPolicy::default()
+                },
+                dry_run: false,
+                recorder: recorder(config),
+                discovery: None,
             }
         }
 
-        fn get_api_server_url(&self) -> String {
-            if self.k8s_service_host.is_empty() {
-                String::from(API_SERVER)
-            } else {
-                format!(
-                    "https://{}:{}",
-                    self.k8s_service_host, self.k8s_service_port
-                )
+        /*
+           Out of cluster client, e.g. pointed at fake-api-server.
+           Plain HTTP(S) trusting the configured CA file instead of the service account one,
+           token is never refreshed.
+        */
+        pub fn with_api_server(url: &str, token: &str, config: &ClientConfig) -> Self {
+            let ca = config
+                .ca_file
+                .as_ref()
+                .map(|path| std::fs::read(path).expect("Unable to read CA file"))
+                .unwrap_or_default();
+            K8sClient {
+                credentials: CredentialProvider::fixed(token, ca),
+                api_server_url: String::from(url.trim_end_matches('/')),
+                user_agent: config.user_agent.clone(),
+                rate_limiter: Arc::new(RateLimiter::new(config.qps, config.burst)),
+                retry_policy: RetryPolicy {
+                    max_retries: config.max_retries,
+                    ..RetryPolicy::default()
+                },
+                dry_run: false,
+                recorder: recorder(config),
+                discovery: None,
+            }
+        }
+
+        /*
+           Same client with dryRun=All added to every modifying request,
+           the API server runs admission and validation but persists nothing.
+        */
+        pub fn dry_run(&self) -> Self {
+            K8sClient {
+                dry_run: true,
+                ..self.clone()
+            }
+        }
+
+        pub fn with_discovery(&self, discovery: Discovery) -> Self {
+            K8sClient {
+                discovery: Some(Arc::new(discovery)),
+                ..self.clone()
             }
         }
 
+        // Kinds are checked at startup, a missing one is a bug in the required list
+        pub fn api_resource<T: Resource>(&self) -> &ApiResource {
+            self.discovery
+                .as_ref()
+                .and_then(|discovery| discovery.resource::<T>())
+                .unwrap_or_else(|| panic!("{} {} was not discovered", T::API_VERSION, T::KIND))
+        }
+
+        pub fn discovery(&self) -> Option<&Discovery> {
+            self.discovery.as_deref()
+        }
+
+        fn get_api_server_url(&self) -> String {
+            self.api_server_url.clone()
+        }
+
+        fn url(&self, uri: &str) -> String {
+            format!("{}/{}", self.get_api_server_url(), uri)
+        }
+
+        fn http(&self) -> Client {
+            self.credentials.client()
+        }
+
         fn get_headers(&self) -> HeaderMap {
             let mut headers = HeaderMap::new();
-            let value = HeaderValue::from_str(format!("Bearer {}", self.token).as_str()).unwrap();
+            let token = self.credentials.token();
+            let value = HeaderValue::from_str(format!("Bearer {}", token.trim()).as_str()).unwrap();
             headers.insert("Authorization", value);
             headers.insert(
                 "User-Agent",
-                HeaderValue::from_str("exposed-apps-controller").unwrap(),
+                HeaderValue::from_str(self.user_agent.as_str()).unwrap(),
             );
             headers
         }
 
-        pub async fn get_exposed_apps(
-            &mut self,
-        ) -> Result<List<K8sObject<ExposedApp>>, K8sClientError> {
-            let result = self
-                .send_with_retry(self.client.get(format!(
-                    "{}/{}",
-                    self.get_api_server_url(),
-                    EXPOSED_APPS_LIST
-                )))
-                .await;
-            let status = result.status();
-            let text = result.text().await.unwrap();
-            K8sClientError::from_status(status, text.as_str())
-                .map(Err)
-                .unwrap_or_else(|| {
-                    let object = from_str::<List<K8sObject<ExposedApp>>>(text.as_str()).unwrap();
-                    Ok(object)
-                })
-        }
-
         pub async fn get_exposed_app(
             &mut self,
             name: &str,
             namespace: &str,
         ) -> Result<K8sObject<ExposedApp>, K8sClientError> {
-            let response = self
-                .send_with_retry(self.client.get(format!(
-                    "{}/apis/stable.no-library.com/v1/namespaces/{}/exposedapps/{}",
-                    self.get_api_server_url(),
-                    namespace,
-                    name
-                )))
-                .await;
-            let status = response.status();
-            let text = response.text().await.unwrap();
+            let uri = self
+                .api_resource::<ExposedApp>()
+                .object_uri(namespace, name);
+            let (status, text) = self.send(self.http().get(self.url(uri.as_str()))).await?;
             K8sClientError::from_status(status, text.as_str())
                 .map(Err)
                 .unwrap_or_else(|| {
@@ -174,84 +265,177 @@ pub mod client {
           IT'S NOT TRUE FOR DEPLOYMENT
           WORKS FINE FOR SERVICE
         */
-        pub async fn put<T: Serialize + DeserializeOwned>(
+        // Namespace and name are taken from the metadata
+        pub async fn put<T: Resource + Serialize + DeserializeOwned>(
             &mut self,
             item: &K8sObject<T>,
-            namespace: &str,
-            resource_type: &str,
-            group: &str,
-            version: &str,
-            name: &str,
         ) -> Result<K8sObject<T>, K8sClientError> {
-            let url = format!(
-                "{}/apis/{}/{}/namespaces/{}/{}/{}",
-                self.get_api_server_url(),
-                group,
-                version,
-                namespace,
-                resource_type,
-                name
-            );
-            self.put_with_url(item, url.as_str()).await
+            let namespace = item.metadata.namespace.clone().unwrap_or_default();
+            let name = item.metadata.name.clone().unwrap();
+            let uri = self
+                .api_resource::<T>()
+                .object_uri(namespace.as_str(), name.as_str());
+            self.execute(self.http().put(self.url(uri.as_str())), item)
+                .await
         }
 
-        async fn put_with_url<T: Serialize + DeserializeOwned>(
+        pub async fn post<T: Resource + Serialize + DeserializeOwned>(
             &mut self,
             item: &K8sObject<T>,
-            url: &str,
         ) -> Result<K8sObject<T>, K8sClientError> {
-            self.execute(self.client.put(url), item).await
+            let namespace = item.metadata.namespace.clone().unwrap_or_default();
+            let uri = self.api_resource::<T>().namespaced_uri(namespace.as_str());
+            self.execute(self.http().post(self.url(uri.as_str())), item)
+                .await
         }
 
-        pub async fn post<T: Serialize + DeserializeOwned>(
-            &mut self,
-            item: &K8sObject<T>,
-            namespace: &str,
-            resource_type: &str,
-            group: &str,
-            version: &str,
-        ) -> Result<K8sObject<T>, K8sClientError> {
-            let url = format!(
-                "{}/apis/{}/{}/namespaces/{}/{}",
-                self.get_api_server_url(),
-                group,
-                version,
-                namespace,
-                resource_type,
-            );
-            self.execute(self.client.post(url), item).await
+        fn retry_after(response: &Response) -> Option<Duration> {
+            let value = response.headers().get(RETRY_AFTER)?;
+            let delay = value
+                .to_str()
+                .ok()
+                .and_then(|value| parse_retry_after(value, OffsetDateTime::now_utc()));
+            if delay.is_none() {
+                warn!("Ignoring invalid Retry-After header {:?}", value);
+            }
+            delay
         }
 
-        async fn refresh_token(&mut self) {
-            self.token = get_token().await;
+        /*
+           429 is returned by priority and fairness before the request is processed,
+           so it is safe to retry for every verb.
+           5xx may be returned after the request has been applied, only idempotent verbs are retried.
+        */
+        fn is_retriable(status: StatusCode, idempotent: bool) -> bool {
+            match status.as_u16() {
+                429 => true,
+                500 | 502 | 503 | 504 => idempotent,
+                _ => false,
+            }
         }
 
-        async fn send_with_retry(&mut self, builder: RequestBuilder) -> Response {
-            let mut result = builder
-                .try_clone()
-                .unwrap()
-                .headers(self.get_headers())
-                .send()
-                .await
-                .unwrap();
-            let mut last_status = result.status().as_u16();
-            let mut retries = 3;
-            while last_status == 401 && retries > 0 {
-                info!("Refreshing token");
-                sleep(Duration::from_secs(10)).await;
-                self.refresh_token().await;
-                let response = builder
+        // Child of the reconcile span, retries and token reloads included
+        async fn send_with_retry(
+            &mut self,
+            builder: RequestBuilder,
+        ) -> Result<Response, K8sClientError> {
+            let request = builder.try_clone().unwrap().build().unwrap();
+            let span = info_span!(
+                "k8s_request",
+                method = request.method().as_str(),
+                path = request.url().path(),
+                status = field::Empty,
+            );
+            let result = self.retry(builder).instrument(span.clone()).await;
+            if let Ok(response) = &result {
+                span.record("status", response.status().as_u16());
+            }
+            result
+        }
+
+        async fn retry(&mut self, mut builder: RequestBuilder) -> Result<Response, K8sClientError> {
+            let request = builder.try_clone().unwrap().build().unwrap();
+            if self.dry_run && !matches!(*request.method(), Method::GET | Method::HEAD) {
+                builder = builder.query(&[("dryRun", "All")]);
+            }
+            let idempotent = matches!(
+                *request.method(),
+                Method::GET | Method::HEAD | Method::PUT | Method::DELETE
+            );
+            let url = request.url().path().to_string();
+            let mut attempt = 0;
+            let mut token_refreshes = 3;
+            loop {
+                self.rate_limiter.acquire().await;
+                let result = builder
                     .try_clone()
                     .unwrap()
                     .headers(self.get_headers())
                     .send()
-                    .await
-                    .unwrap();
-                last_status = response.status().as_u16();
-                result = response;
-                retries = retries - 1;
+                    .await;
+                let delay = match result {
+                    Ok(response) => {
+                        let status = response.status();
+                        if status == StatusCode::UNAUTHORIZED && token_refreshes > 0 {
+                            token_refreshes -= 1;
+                            if !self.credentials.reload().await {
+                                // Rotated token not projected yet
+                                sleep(self.retry_policy.backoff(attempt)).await;
+                            }
+                            info!("Retrying {} with reloaded token", url);
+                            continue;
+                        }
+                        if !Self::is_retriable(status, idempotent)
+                            || attempt >= self.retry_policy.max_retries
+                        {
+                            return Ok(response);
+                        }
+                        let delay = Self::retry_after(&response)
+                            .unwrap_or_else(|| self.retry_policy.backoff(attempt));
+                        warn!(
+                            "Request {} {} failed with status {}, retrying in {:?}",
+                            request.method(),
+                            url,
+                            status,
+                            delay
+                        );
+                        delay
+                    }
+                    Err(e) => {
+                        // Connection was not established, request never reached the API server
+                        let safe = idempotent || e.is_connect();
+                        if !safe || attempt >= self.retry_policy.max_retries {
+                            return Err(Error(format!("Request {} failed: {}", url, e)));
+                        }
+                        let delay = self.retry_policy.backoff(attempt);
+                        warn!(
+                            "Request {} {} failed: {}, retrying in {:?}",
+                            request.method(),
+                            url,
+                            e,
+                            delay
+                        );
+                        delay
+                    }
+                };
+                attempt += 1;
+                sleep(delay).await;
+            }
+        }
+
+        // Reads the whole response, recorded when a cassette is set
+        async fn send(
+            &mut self,
+            builder: RequestBuilder,
+        ) -> Result<(StatusCode, String), K8sClientError> {
+            let request = builder.try_clone().unwrap().build().unwrap();
+            let response = self.send_with_retry(builder).await?;
+            let status = response.status();
+            let uri = Self::recorded_uri(&response);
+            let text = response.text().await.unwrap();
+            if let Some(recorder) = &self.recorder {
+                let body = request
+                    .body()
+                    .and_then(|body| body.as_bytes())
+                    .map(|bytes| String::from_utf8_lossy(bytes).into_owned());
+                recorder.exchange(
+                    request.method().as_str(),
+                    uri.as_str(),
+                    body.as_deref(),
+                    status.as_u16(),
+                    text.as_str(),
+                );
+            }
+            Ok((status, text))
+        }
+
+        // Path and query actually sent, dryRun included
+        fn recorded_uri(response: &Response) -> String {
+            let url = response.url();
+            match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => String::from(url.path()),
             }
-            result
         }
 
         async fn execute<I: Serialize, O: DeserializeOwned>(
@@ -260,9 +444,7 @@ pub mod client {
             item: &I,
         ) -> Result<O, K8sClientError> {
             let payload = to_string(&item).unwrap();
-            let result = self.send_with_retry(builder.body(payload)).await;
-            let status = result.status();
-            let text = result.text().await.unwrap();
+            let (status, text) = self.send(builder.body(payload)).await?;
             K8sClientError::from_status(status, text.as_str())
                 .map(Err)
                 .unwrap_or_else(|| {
@@ -275,17 +457,7 @@ pub mod client {
             &mut self,
             deployment: &K8sObject<Deployment>,
         ) -> Result<K8sObject<Deployment>, K8sClientError> {
-            let name = deployment.metadata.name.clone().unwrap();
-            let namespace = deployment.metadata.namespace.clone().unwrap();
-            self.put(
-                deployment,
-                namespace.as_str(),
-                "deployments",
-                "apps",
-                "v1",
-                name.as_str(),
-            )
-            .await
+            self.put(deployment).await
         }
 
         // Create or Update
@@ -311,82 +483,130 @@ pub mod client {
             &mut self,
             service: &K8sObject<Service>,
         ) -> Result<K8sObject<Service>, K8sClientError> {
-            let name = service.metadata.name.clone().unwrap();
-            let namespace = service.metadata.namespace.clone().unwrap();
-            let url = format!(
-                "{}/api/v1/namespaces/{}/services/{}",
-                self.get_api_server_url(),
-                namespace,
-                name,
-            );
-            self.put_with_url(service, url.as_str()).await
+            self.put(service).await
         }
 
         pub async fn post_deployment(
             &mut self,
             deployment: &K8sObject<Deployment>,
         ) -> Result<K8sObject<Deployment>, K8sClientError> {
-            let namespace = deployment.metadata.namespace.clone().unwrap();
-            self.post(deployment, namespace.as_str(), "deployments", "apps", "v1")
-                .await
+            self.post(deployment).await
+        }
+
+        // Recorded cassettes stay readable, the replay server only serves JSON
+        fn protobuf<T: Protobuf>(
+            &self,
+            builder: RequestBuilder,
+        ) -> (RequestBuilder, Option<Decode<T>>) {
+            match T::decoder() {
+                Some(decode) if self.recorder.is_none() => {
+                    (builder.header(ACCEPT, PROTOBUF_OR_JSON), Some(decode))
+                }
+                _ => (builder, None),
+            }
         }
 
-        pub async fn watch<T: DeserializeOwned>(
+        fn is_protobuf(response: &Response) -> bool {
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with(PROTOBUF))
+        }
+
+        pub async fn watch<T: Protobuf + DeserializeOwned>(
             &mut self,
             uri: &str,
             resource_version: &str,
         ) -> Result<impl Stream<Item = Watch<K8sListObject<T>>>, K8sClientError> {
-            let mut response = self
-                .send_with_retry(self.client.get(format!(
-                    "{}/{}?watch=1&resourceVersion={}",
-                    self.get_api_server_url(),
-                    uri,
-                    resource_version
-                )))
-                .await;
+            // uri may already carry a label selector
+            let separator = if uri.contains('?') { '&' } else { '?' };
+            let (builder, decode) = self.protobuf::<T>(self.http().get(format!(
+                "{}/{}{}watch=1&resourceVersion={}",
+                self.get_api_server_url(),
+                uri,
+                separator,
+                resource_version
+            )));
+            let mut response = self.send_with_retry(builder).await?;
+            let decode = decode.filter(|_| Self::is_protobuf(&response));
             let status = response.status();
+            let recorder = self.recorder.clone();
+            let uri = Self::recorded_uri(&response);
+            let watch = recorder
+                .as_ref()
+                .map(|recorder| recorder.exchange("GET", uri.as_str(), None, status.as_u16(), ""));
             if let Some(error) = K8sClientError::from_status(status, "") {
                 return Err(error);
             }
             Ok(stream! {
-                loop {
-                    if let Some(chunk) = response.chunk().await.unwrap() {
-                        let payload = from_utf8(chunk.as_ref()).unwrap();
-                        match from_str::<Watch<K8sListObject<T>>>(payload) {
-                            Ok(event) => {
-                                yield event;
-                            }
-                            Err(e) => {
-                                error!("Error occurred while trying to watch k8s object: {:?}", e);
-                                break;
+                let mut frames = Frames::default();
+                'chunks: while let Some(chunk) = response.chunk().await.unwrap() {
+                    if let Some(decode) = decode {
+                        frames.push(chunk.as_ref());
+                        loop {
+                            let decoded = match frames.next_frame() {
+                                Ok(Some(frame)) => decode_watch_event(frame.as_slice(), decode),
+                                Ok(None) => break,
+                                Err(e) => Err(e),
+                            };
+                            match decoded {
+                                Ok(event) => {
+                                    yield event;
+                                }
+                                Err(e) => {
+                                    error!("Error occurred while trying to watch k8s object: {}", e);
+                                    break 'chunks;
+                                }
                             }
                         }
-                    } else {
-                        break;
+                        continue;
+                    }
+                    let payload = from_utf8(chunk.as_ref()).unwrap();
+                    if let (Some(recorder), Some(watch)) = (&recorder, watch) {
+                        recorder.watch_event(watch, uri.as_str(), payload);
+                    }
+                    match from_str::<Watch<K8sListObject<T>>>(payload) {
+                        Ok(event) => {
+                            yield event;
+                        }
+                        Err(e) => {
+                            error!("Error occurred while trying to watch k8s object: {:?}", e);
+                            break 'chunks;
+                        }
                     }
                 }
+                if let (Some(recorder), Some(watch)) = (&recorder, watch) {
+                    recorder.watch_end(watch);
+                }
             })
         }
 
-        pub async fn watch_exposed_apps(
-            &mut self,
-            resource_version: &str,
-        ) -> Result<impl Stream<Item = Watch<K8sListObject<ExposedApp>>>, K8sClientError> {
-            self.watch(EXPOSED_APPS_LIST, resource_version).await
-        }
-
-        pub async fn get_all<T: DeserializeOwned>(
+        pub async fn get_all<T: Protobuf + DeserializeOwned>(
             &mut self,
             uri: &str,
         ) -> Result<List<K8sListObject<T>>, K8sClientError> {
-            let response = self
-                .send_with_retry(
-                    self.client
-                        .get(format!("{}/{}", self.get_api_server_url(), uri)),
-                )
-                .await;
-            let status = response.status();
-            let text = response.text().await.unwrap();
+            let (builder, decode) = self.protobuf::<T>(self.http().get(self.url(uri)));
+            if let Some(decode) = decode {
+                let response = self.send_with_retry(builder).await?;
+                let status = response.status();
+                let protobuf = Self::is_protobuf(&response);
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|e| Error(format!("Reading {} failed: {}", uri, e)))?;
+                if let Some(error) =
+                    K8sClientError::from_status(status, String::from_utf8_lossy(&body).as_ref())
+                {
+                    return Err(error);
+                }
+                if protobuf {
+                    return decode_list(body.as_ref(), decode)
+                        .map_err(|e| Error(format!("Invalid {} list: {}", uri, e)));
+                }
+                return Ok(from_slice::<List<K8sListObject<T>>>(body.as_ref()).unwrap());
+            }
+            let (status, text) = self.send(builder).await?;
             K8sClientError::from_status(status, text.as_str())
                 .map(Err)
                 .unwrap_or_else(|| {
@@ -395,20 +615,162 @@ pub mod client {
                 })
         }
 
+        pub async fn get<T: DeserializeOwned>(
+            &mut self,
+            uri: &str,
+        ) -> Result<K8sObject<T>, K8sClientError> {
+            let (status, text) = self.send(self.http().get(self.url(uri))).await?;
+            K8sClientError::from_status(status, text.as_str())
+                .map(Err)
+                .unwrap_or_else(|| Ok(from_str::<K8sObject<T>>(text.as_str()).unwrap()))
+        }
+
+        // Discovery documents, the Accept header selects aggregated or legacy discovery
+        pub async fn get_document(
+            &mut self,
+            uri: &str,
+            accept: &str,
+        ) -> Result<String, K8sClientError> {
+            let (status, text) = self
+                .send(self.http().get(self.url(uri)).header("Accept", accept))
+                .await?;
+            K8sClientError::from_status(status, text.as_str())
+                .map(Err)
+                .unwrap_or(Ok(text))
+        }
+
+        fn delete_request(&self, url: String, options: &DeleteOptions) -> RequestBuilder {
+            let builder = self
+                .http()
+                .delete(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(to_string(options).unwrap());
+            // A dry-run client already adds it to every write
+            if options.dry_run && !self.dry_run {
+                builder.query(&[("dryRun", "All")])
+            } else {
+                builder
+            }
+        }
+
+        // Failed preconditions are returned as Conflict
+        pub async fn delete(
+            &mut self,
+            uri: &str,
+            options: &DeleteOptions,
+        ) -> Result<(), K8sClientError> {
+            let (status, text) = self
+                .send(self.delete_request(self.url(uri), options))
+                .await?;
+            K8sClientError::from_status(status, text.as_str())
+                .map(Err)
+                .unwrap_or(Ok(()))
+        }
+
+        // Every object of the collection matching the label selector, all of them when empty
+        pub async fn delete_collection(
+            &mut self,
+            uri: &str,
+            label_selector: &str,
+            options: &DeleteOptions,
+        ) -> Result<(), K8sClientError> {
+            let mut builder = self.delete_request(self.url(uri), options);
+            if !label_selector.is_empty() {
+                builder = builder.query(&[("labelSelector", label_selector)]);
+            }
+            let (status, text) = self.send(builder).await?;
+            K8sClientError::from_status(status, text.as_str())
+                .map(Err)
+                .unwrap_or(Ok(()))
+        }
+
+        /*
+           Deletes the object and waits until it is gone, i.e. its finalizers were removed and,
+           with Foreground propagation, its dependents were deleted.
+           Gone on a dry-run since nothing was deleted.
+        */
+        pub async fn delete_and_wait<T: Resource + Protobuf + DeserializeOwned>(
+            &mut self,
+            namespace: &str,
+            name: &str,
+            options: &DeleteOptions,
+            wait: Duration,
+        ) -> Result<(), K8sClientError> {
+            let resource = self.api_resource::<T>().clone();
+            let uri = resource.object_uri(namespace, name);
+            match self.delete(uri.as_str(), options).await {
+                Ok(()) => {}
+                Err(NotFound) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            if options.dry_run || self.dry_run {
+                return Ok(());
+            }
+            timeout(
+                wait,
+                self.wait_until_deleted::<T>(&resource, namespace, name),
+            )
+            .await
+            .map_err(|_| {
+                Error(format!(
+                    "{} {} still exists after {:?}",
+                    T::KIND,
+                    name,
+                    wait
+                ))
+            })?
+        }
+
+        /*
+           Watches the object from the version read, the watch may be closed by the API server
+           before it is gone. An object without deletionTimestamp was recreated under the same name,
+           the deleted one is gone.
+        */
+        async fn wait_until_deleted<T: Protobuf + DeserializeOwned>(
+            &mut self,
+            resource: &ApiResource,
+            namespace: &str,
+            name: &str,
+        ) -> Result<(), K8sClientError> {
+            let uri = resource.object_uri(namespace, name);
+            let watch_uri = with_query(
+                resource.namespaced_uri(namespace).as_str(),
+                "fieldSelector",
+                format!("metadata.name={}", name).as_str(),
+            );
+            loop {
+                let live = match self.get::<T>(uri.as_str()).await {
+                    Ok(live) => live,
+                    Err(NotFound) => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                if live.metadata.deletion_timestamp.is_none() {
+                    return Ok(());
+                }
+                let resource_version = live.metadata.resource_version.unwrap_or_default();
+                let events = self
+                    .watch::<T>(watch_uri.as_str(), resource_version.as_str())
+                    .await?;
+                pin_mut!(events);
+                while let Some(event) = events.next().await {
+                    let metadata = &event.object.metadata;
+                    if metadata.name.as_deref() == Some(name)
+                        && metadata.uid == live.metadata.uid
+                        && matches!(event.event_type, WatchEventType::Deleted)
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
         pub async fn get_lease(
             &mut self,
             namespace: &str,
             name: &str,
         ) -> Result<K8sObject<Lease>, K8sClientError> {
-            let url = format!(
-                "{}/apis/coordination.k8s.io/v1/namespaces/{}/leases/{}",
-                self.get_api_server_url(),
-                namespace,
-                name
-            );
-            let result = self.send_with_retry(self.client.get(url)).await;
-            let status = result.status();
-            let text = result.text().await.unwrap();
+            let uri = self.api_resource::<Lease>().object_uri(namespace, name);
+            let (status, text) = self.send(self.http().get(self.url(uri.as_str()))).await?;
             K8sClientError::from_status(status, text.as_str())
                 .map(Err)
                 .unwrap_or_else(|| {
@@ -423,13 +785,55 @@ pub mod client {
             name: &str,
             app: &K8sObject<ExposedApp>,
         ) -> Result<K8sObject<ExposedApp>, K8sClientError> {
-            let url = format!(
-                "{}/apis/stable.no-library.com/v1/namespaces/{}/exposedapps/{}/status",
-                self.get_api_server_url(),
-                namespace,
-                name
-            );
-            self.execute(self.client.put(url), app).await
+            let uri = self
+                .api_resource::<ExposedApp>()
+                .object_uri(namespace, name);
+            let url = format!("{}/status", self.url(uri.as_str()));
+            self.execute(self.http().put(url), app).await
+        }
+
+        // Namespace and name are taken from the metadata
+        pub async fn put_status<T: Resource + Serialize + DeserializeOwned>(
+            &mut self,
+            item: &K8sObject<T>,
+        ) -> Result<K8sObject<T>, K8sClientError> {
+            let namespace = item.metadata.namespace.clone().unwrap_or_default();
+            let name = item.metadata.name.clone().unwrap();
+            let uri = self
+                .api_resource::<T>()
+                .object_uri(namespace.as_str(), name.as_str());
+            let url = format!("{}/status", self.url(uri.as_str()));
+            self.execute(self.http().put(url), item).await
+        }
+
+        // Namespace is ignored for cluster scoped kinds
+        pub async fn merge_patch<T: Resource + DeserializeOwned>(
+            &mut self,
+            namespace: &str,
+            name: &str,
+            patch: &Value,
+        ) -> Result<K8sObject<T>, K8sClientError> {
+            let uri = self.api_resource::<T>().object_uri(namespace, name);
+            let url = self.url(uri.as_str());
+            self.execute(
+                self.http()
+                    .patch(url)
+                    .header("Content-Type", "application/merge-patch+json"),
+                patch,
+            )
+            .await
+        }
+
+        // None removes the annotation
+        pub async fn annotate_exposed_app(
+            &mut self,
+            namespace: &str,
+            name: &str,
+            key: &str,
+            value: Option<&str>,
+        ) -> Result<K8sObject<ExposedApp>, K8sClientError> {
+            let patch = json!({ "metadata": { "annotations": { key: value } } });
+            self.merge_patch(namespace, name, &patch).await
         }
 
         pub async fn post_event(
@@ -437,12 +841,9 @@ pub mod client {
             namespace: &str,
             event: &K8sObject<Event>,
         ) -> Result<K8sObject<Event>, K8sClientError> {
-            let url = format!(
-                "{}/apis/events.k8s.io/v1/namespaces/{}/events",
-                self.get_api_server_url(),
-                namespace
-            );
-            self.execute(self.client.post(url), event).await
+            let uri = self.api_resource::<Event>().namespaced_uri(namespace);
+            self.execute(self.http().post(self.url(uri.as_str())), event)
+                .await
         }
 
         pub async fn patch_lease(
@@ -451,7 +852,7 @@ pub mod client {
             name: &str,
             resource_version: &str,
             holder_identity: &str,
-            acquire_time: OffsetDateTime,
+            acquire_time: MicroTime,
         ) -> Result<(), K8sClientError> {
             let entries = vec![
                 JsonPatchEntry {
@@ -467,29 +868,201 @@ pub mod client {
                 JsonPatchEntry {
                     op: String::from("add"),
                     path: String::from("/spec/acquireTime"),
-                    value: format(acquire_time).unwrap(),
+                    value: acquire_time.to_string(),
                 },
             ];
             let serialized = to_string(&entries).unwrap();
-            let url = format!(
-                "{}/apis/coordination.k8s.io/v1/namespaces/{}/leases/{}",
-                self.get_api_server_url(),
-                namespace,
-                name
-            );
-            let response = self
-                .send_with_retry(
-                    self.client
-                        .patch(url)
+            let uri = self.api_resource::<Lease>().object_uri(namespace, name);
+            let (status, text) = self
+                .send(
+                    self.http()
+                        .patch(self.url(uri.as_str()))
                         .body(serialized)
                         .header("Content-Type", "application/json-patch+json"),
                 )
-                .await;
-            let status = response.status();
-            let text = response.text().await.unwrap();
+                .await?;
             K8sClientError::from_status(status, text.as_str())
                 .map(Err)
                 .unwrap_or(Ok(()))
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use axum::extract::State;
+        use axum::response::IntoResponse;
+        use axum::Router;
+        use std::collections::VecDeque;
+        use std::path::PathBuf;
+        use std::sync::Mutex;
+
+        const VERBS: [Method; 5] = [
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ];
+
+        // Statuses answered in order, 200 once they are used up, with the Authorization headers received
+        #[derive(Default)]
+        struct Script {
+            statuses: VecDeque<u16>,
+            authorizations: Vec<String>,
+        }
+
+        type SharedScript = Arc<Mutex<Script>>;
+
+        async fn respond(
+            State(script): State<SharedScript>,
+            headers: axum::http::HeaderMap,
+        ) -> axum::response::Response {
+            let mut script = script.lock().unwrap();
+            let authorization = headers
+                .get("Authorization")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            script.authorizations.push(String::from(authorization));
+            let status = script.statuses.pop_front().unwrap_or(200);
+            let status = StatusCode::from_u16(status).unwrap();
+            (status, [(RETRY_AFTER, "0")], "{}").into_response()
+        }
+
+        async fn server(statuses: &[u16]) -> (String, SharedScript) {
+            let script = Arc::new(Mutex::new(Script {
+                statuses: statuses.iter().copied().collect(),
+                ..Script::default()
+            }));
+            let app = Router::new()
+                .fallback(respond)
+                .with_state(Arc::clone(&script));
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+                .await
+                .unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            (url, script)
+        }
+
+        fn client(url: &str, credentials: Arc<CredentialProvider>) -> K8sClient {
+            K8sClient {
+                credentials,
+                api_server_url: String::from(url),
+                user_agent: String::from("test"),
+                rate_limiter: Arc::new(RateLimiter::new(0.0, 1)),
+                retry_policy: RetryPolicy {
+                    max_retries: 3,
+                    base_delay: Duration::from_millis(1),
+                    max_delay: Duration::from_millis(10),
+                },
+                dry_run: false,
+                recorder: None,
+                discovery: None,
+            }
+        }
+
+        // Final status and number of requests sent
+        async fn send(method: Method, statuses: &[u16]) -> (u16, usize) {
+            let (url, script) = server(statuses).await;
+            let mut client = client(url.as_str(), CredentialProvider::fixed("token", Vec::new()));
+            let builder = client.http().request(method, client.url("api/v1/pods"));
+            let (status, _) = client.send(builder).await.unwrap();
+            let requests = script.lock().unwrap().authorizations.len();
+            (status.as_u16(), requests)
+        }
+
+        fn service_account(name: &str, token: &str) -> PathBuf {
+            let path =
+                std::env::temp_dir().join(format!("no-library-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(path.join("token"), token).unwrap();
+            std::fs::write(path.join("ca.crt"), "").unwrap();
+            path
+        }
+
+        #[tokio::test]
+        async fn too_many_requests_is_retried_for_every_verb() {
+            for method in VERBS {
+                assert_eq!(
+                    send(method.clone(), &[429, 429]).await,
+                    (200, 3),
+                    "{}",
+                    method
+                );
+            }
+        }
+
+        #[tokio::test]
+        async fn server_errors_are_retried_for_idempotent_verbs_only() {
+            for status in [500, 502, 503, 504] {
+                for method in VERBS {
+                    let expected = match method {
+                        Method::POST | Method::PATCH => (status, 1),
+                        _ => (200, 2),
+                    };
+                    assert_eq!(
+                        send(method.clone(), &[status]).await,
+                        expected,
+                        "{}",
+                        method
+                    );
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn client_errors_are_not_retried() {
+            for status in [400, 403, 404, 409, 422] {
+                assert_eq!(send(Method::GET, &[status]).await, (status, 1));
+            }
+        }
+
+        #[tokio::test]
+        async fn retries_stop_at_max_retries() {
+            assert_eq!(send(Method::GET, &[503; 10]).await, (503, 4));
+        }
+
+        #[tokio::test]
+        async fn unauthorized_reloads_the_token() {
+            let path = service_account("unauthorized", "old");
+            let credentials = CredentialProvider::in_cluster(path.to_str().unwrap()).await;
+            // Rotated by kubelet after it was read
+            std::fs::write(path.join("token"), "new").unwrap();
+            let (url, script) = server(&[401]).await;
+            let mut client = client(url.as_str(), credentials);
+            let builder = client.http().post(client.url("api/v1/pods"));
+            let (status, _) = client.send(builder).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                script.lock().unwrap().authorizations,
+                ["Bearer old", "Bearer new"]
+            );
+        }
+
+        #[tokio::test]
+        async fn unauthorized_is_returned_once_reloads_are_exhausted() {
+            assert_eq!(send(Method::GET, &[401; 10]).await, (401, 4));
+        }
+
+        #[test]
+        fn retry_after_is_seconds_or_an_http_date() {
+            let now = OffsetDateTime::parse("Wed, 21 Oct 2015 07:28:00 GMT", &Rfc2822).unwrap();
+            assert_eq!(
+                parse_retry_after("120", now),
+                Some(Duration::from_secs(120))
+            );
+            assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+            assert_eq!(
+                parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+                Some(Duration::from_secs(30))
+            );
+            assert_eq!(
+                parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+                Some(Duration::ZERO)
+            );
+            assert_eq!(parse_retry_after("-1", now), None);
+            assert_eq!(parse_retry_after("soon", now), None);
+        }
+    }
 }
@@ -0,0 +1,293 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use time::error::Parse;
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, UtcOffset};
+
+/*
+   Kubernetes Time and MicroTime.
+   Any RFC3339 value is accepted (any number of fractional digits, any offset),
+   the canonical UTC form is always emitted:
+   Time      2006-01-02T15:04:05Z
+   MicroTime 2006-01-02T15:04:05.000000Z
+   Values are truncated to the precision of the type, so parsing the emitted form gives the same value.
+   The fraction is written from the integer nanoseconds, the ISO 8601 formatter of time rounds it through f64.
+*/
+fn write_utc(f: &mut Formatter<'_>, datetime: OffsetDateTime) -> std::fmt::Result {
+    write!(
+        f,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        datetime.year(),
+        datetime.month() as u8,
+        datetime.day(),
+        datetime.hour(),
+        datetime.minute(),
+        datetime.second()
+    )
+}
+
+fn truncate(datetime: OffsetDateTime, nanos_precision: u32) -> OffsetDateTime {
+    let nanos = datetime.nanosecond() / nanos_precision * nanos_precision;
+    datetime
+        .to_offset(UtcOffset::UTC)
+        .replace_nanosecond(nanos)
+        .unwrap()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Time(OffsetDateTime);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct MicroTime(OffsetDateTime);
+
+impl Time {
+    pub fn new(datetime: OffsetDateTime) -> Self {
+        Time(truncate(datetime, 1_000_000_000))
+    }
+
+    pub fn now() -> Self {
+        Self::new(OffsetDateTime::now_utc())
+    }
+
+    pub fn value(&self) -> OffsetDateTime {
+        self.0
+    }
+}
+
+impl MicroTime {
+    pub fn new(datetime: OffsetDateTime) -> Self {
+        MicroTime(truncate(datetime, 1_000))
+    }
+
+    pub fn now() -> Self {
+        Self::new(OffsetDateTime::now_utc())
+    }
+
+    pub fn value(&self) -> OffsetDateTime {
+        self.0
+    }
+}
+
+impl FromStr for Time {
+    type Err = Parse;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        OffsetDateTime::parse(input, &Rfc3339).map(Self::new)
+    }
+}
+
+impl FromStr for MicroTime {
+    type Err = Parse;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        OffsetDateTime::parse(input, &Rfc3339).map(Self::new)
+    }
+}
+
+impl Display for Time {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write_utc(f, self.0)?;
+        f.write_str("Z")
+    }
+}
+
+impl Display for MicroTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write_utc(f, self.0)?;
+        write!(f, ".{:06}Z", self.0.microsecond())
+    }
+}
+
+impl Serialize for Time {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Serialize for MicroTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Time {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        input
+            .parse()
+            .map_err(|e| D::Error::custom(format!("invalid Time {}: {}", input, e)))
+    }
+}
+
+impl<'de> Deserialize<'de> for MicroTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        input
+            .parse()
+            .map_err(|e| D::Error::custom(format!("invalid MicroTime {}: {}", input, e)))
+    }
+}
+
+impl JsonSchema for Time {
+    fn schema_name() -> String {
+        String::from("Time")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            format: Some(String::from("date-time")),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // One day away from the year bounds of RFC3339, any offset keeps the local date in range
+    const MIN: i64 = 86_400;
+    const MAX: i64 = 253_402_300_799 - 86_400;
+
+    // RFC3339 input with the given number of fractional digits, the offset is written as Z when zero
+    fn rfc3339(datetime: OffsetDateTime, digits: usize) -> String {
+        let fraction = match digits {
+            0 => String::new(),
+            _ => format!(".{}", &format!("{:09}", datetime.nanosecond())[..digits]),
+        };
+        let offset = datetime.offset();
+        let offset = if offset.is_utc() {
+            String::from("Z")
+        } else {
+            let sign = if offset.is_negative() { '-' } else { '+' };
+            format!(
+                "{}{:02}:{:02}",
+                sign,
+                offset.whole_hours().abs(),
+                offset.minutes_past_hour().abs()
+            )
+        };
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{}",
+            datetime.year(),
+            datetime.month() as u8,
+            datetime.day(),
+            datetime.hour(),
+            datetime.minute(),
+            datetime.second(),
+            fraction,
+            offset
+        )
+    }
+
+    fn input() -> impl Strategy<Value = (OffsetDateTime, usize)> {
+        (MIN..MAX, 0..1_000_000_000u32, -1439..=1439i32, 0..=9usize).prop_map(
+            |(seconds, nanos, minutes, digits)| {
+                let offset = UtcOffset::from_whole_seconds(minutes * 60).unwrap();
+                let datetime = OffsetDateTime::from_unix_timestamp(seconds)
+                    .unwrap()
+                    .replace_nanosecond(nanos)
+                    .unwrap()
+                    .to_offset(offset);
+                (datetime, digits)
+            },
+        )
+    }
+
+    // The value actually written in the input, before the truncation to the type precision
+    fn written(datetime: OffsetDateTime, digits: usize) -> OffsetDateTime {
+        let precision = 10u32.pow(9 - digits as u32);
+        datetime
+            .replace_nanosecond(datetime.nanosecond() / precision * precision)
+            .unwrap()
+    }
+
+    fn assert_utc(output: &str, digits: usize) {
+        let length = if digits == 0 { 20 } else { 21 + digits };
+        assert_eq!(output.len(), length, "{}", output);
+        assert!(output.ends_with('Z'), "{}", output);
+        if digits > 0 {
+            assert_eq!(&output[19..20], ".", "{}", output);
+            assert!(output[20..20 + digits].bytes().all(|b| b.is_ascii_digit()));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn time_round_trip((datetime, digits) in input()) {
+            let input = rfc3339(datetime, digits);
+            let parsed: Time = input.parse().unwrap();
+            let output = parsed.to_string();
+            assert_utc(output.as_str(), 0);
+            prop_assert_eq!(output.parse::<Time>().unwrap(), parsed);
+            prop_assert_eq!(parsed.value().offset(), UtcOffset::UTC);
+            prop_assert_eq!(parsed.value().unix_timestamp(), datetime.unix_timestamp());
+            prop_assert_eq!(parsed.value().nanosecond(), 0);
+        }
+
+        #[test]
+        fn micro_time_round_trip((datetime, digits) in input()) {
+            let input = rfc3339(datetime, digits);
+            let parsed: MicroTime = input.parse().unwrap();
+            let output = parsed.to_string();
+            assert_utc(output.as_str(), 6);
+            prop_assert_eq!(output.parse::<MicroTime>().unwrap(), parsed);
+            prop_assert_eq!(parsed.value().offset(), UtcOffset::UTC);
+            let written = written(datetime, digits);
+            prop_assert_eq!(parsed.value().unix_timestamp(), written.unix_timestamp());
+            prop_assert_eq!(
+                parsed.value().nanosecond(),
+                written.nanosecond() / 1_000 * 1_000
+            );
+        }
+
+        #[test]
+        fn serde_round_trip((datetime, digits) in input()) {
+            let input = serde_json::Value::String(rfc3339(datetime, digits));
+            let time: Time = serde_json::from_value(input.clone()).unwrap();
+            let micro_time: MicroTime = serde_json::from_value(input).unwrap();
+            let time_json = serde_json::to_value(time).unwrap();
+            let micro_time_json = serde_json::to_value(micro_time).unwrap();
+            prop_assert_eq!(serde_json::from_value::<Time>(time_json.clone()).unwrap(), time);
+            prop_assert_eq!(
+                serde_json::from_value::<MicroTime>(micro_time_json.clone()).unwrap(),
+                micro_time
+            );
+            prop_assert_eq!(time_json, serde_json::Value::String(time.to_string()));
+            prop_assert_eq!(micro_time_json, serde_json::Value::String(micro_time.to_string()));
+        }
+    }
+
+    #[test]
+    fn offsets_are_converted_to_utc() {
+        let time: Time = "2024-03-01T01:30:00+02:00".parse().unwrap();
+        assert_eq!(time.to_string(), "2024-02-29T23:30:00Z");
+        let micro_time: MicroTime = "2024-02-29T20:30:00.5-03:00".parse().unwrap();
+        assert_eq!(micro_time.to_string(), "2024-02-29T23:30:00.500000Z");
+    }
+
+    #[test]
+    fn fractional_digits_are_truncated() {
+        let time: Time = "2006-01-02T15:04:05.999999999Z".parse().unwrap();
+        assert_eq!(time.to_string(), "2006-01-02T15:04:05Z");
+        let micro_time: MicroTime = "2006-01-02T15:04:05.123456789Z".parse().unwrap();
+        assert_eq!(micro_time.to_string(), "2006-01-02T15:04:05.123456Z");
+        let micro_time: MicroTime = "2006-01-02T15:04:05Z".parse().unwrap();
+        assert_eq!(micro_time.to_string(), "2006-01-02T15:04:05.000000Z");
+    }
+
+    #[test]
+    fn invalid_input_is_rejected() {
+        assert!("2006-01-02T15:04Z".parse::<Time>().is_err());
+        assert!("2006-01-02T15:04:05".parse::<MicroTime>().is_err());
+        assert!(serde_json::from_str::<Time>("\"yesterday\"").is_err());
+    }
+}
@@ -1,5 +1,14 @@
+use crate::k8s_time::{MicroTime, Time};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+// Hub version the operator reads and writes
+pub const EXPOSED_APP_API_VERSION: &str = "stable.no-library.com/v2";
+pub const EXPOSED_APP_TEMPLATE_API_VERSION: &str = "stable.no-library.com/v1";
 
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -22,7 +31,7 @@ pub struct ObjectReference {
     pub uid: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Copy)]
 pub enum EventType {
     Normal,
     Warning,
@@ -31,7 +40,7 @@ pub enum EventType {
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Event {
-    pub event_time: String,
+    pub event_time: MicroTime,
     pub action: String,
     pub note: Option<String>,
     pub reason: String,
@@ -43,21 +52,15 @@ pub struct Event {
     pub event_type: EventType,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct ManageFields {
-    pub manager: String,
-}
-
 // https://pkg.go.dev/k8s.io/apimachinery/pkg/apis/meta/v1#ObjectMeta
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Metadata {
     pub labels: Option<HashMap<String, String>>,
     pub annotations: Option<HashMap<String, String>>,
     pub resource_version: Option<String>,
     pub finalizers: Option<Vec<String>>,
-    pub deletion_timestamp: Option<String>,
+    pub deletion_timestamp: Option<Time>,
     pub name: Option<String>,
     pub uid: Option<String>,
     pub namespace: Option<String>,
@@ -65,19 +68,54 @@ pub struct Metadata {
     pub generation: Option<u64>,
 }
 
-impl Default for Metadata {
-    fn default() -> Self {
-        Metadata {
-            labels: None,
-            annotations: None,
-            resource_version: None,
-            finalizers: None,
-            deletion_timestamp: None,
-            name: None,
-            namespace: None,
-            owner_references: None,
-            uid: None,
-            generation: None,
+// https://pkg.go.dev/k8s.io/apimachinery/pkg/apis/meta/v1#DeletionPropagation
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum PropagationPolicy {
+    // Dependents are deleted before the owner is gone
+    Foreground,
+    // Dependents are deleted by the garbage collector once the owner is gone
+    Background,
+    // Dependents are kept, their owner references are removed
+    Orphan,
+}
+
+// https://pkg.go.dev/k8s.io/apimachinery/pkg/apis/meta/v1#Preconditions
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Preconditions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<String>,
+}
+
+/*
+   https://pkg.go.dev/k8s.io/apimachinery/pkg/apis/meta/v1#DeleteOptions
+   Sent as the body of a delete, dryRun goes to the query like for the other verbs.
+   The API server picks the propagation policy of the kind when none is set.
+*/
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub propagation_policy: Option<PropagationPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grace_period_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preconditions: Option<Preconditions>,
+    #[serde(skip)]
+    pub dry_run: bool,
+}
+
+impl DeleteOptions {
+    // Fails with a conflict when the name was taken by another object in the meantime
+    pub fn with_uid(uid: &str) -> Self {
+        DeleteOptions {
+            preconditions: Some(Preconditions {
+                uid: Some(String::from(uid)),
+                resource_version: None,
+            }),
+            ..Default::default()
         }
     }
 }
@@ -90,16 +128,175 @@ pub enum WatchEventType {
     Deleted,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, Default)]
+pub enum Protocol {
+    #[default]
+    #[serde(rename = "TCP")]
+    Tcp,
+    #[serde(rename = "UDP")]
+    Udp,
+    #[serde(rename = "SCTP")]
+    Sctp,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+            Protocol::Sctp => "SCTP",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+pub enum ServiceType {
+    ClusterIP,
+    NodePort,
+    LoadBalancer,
+    ExternalName,
+}
+
+fn default_replicas() -> u32 {
+    1
+}
+
+// CEL rules, not expressible with plain OpenAPI
+fn image_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema: SchemaObject = String::json_schema(gen).into();
+    schema.extensions.insert(
+        String::from("x-kubernetes-validations"),
+        json!([
+            {
+                "rule": "self.split(':').size() == 2",
+                "message": "Image must include a tag (e.g. 'nginx:1.25')"
+            },
+            {
+                "rule": "self.split(':').size() == 2 ? self.split(':')[1] != 'latest' : true",
+                "message": "'latest' tag is not allowed"
+            }
+        ]),
+    );
+    schema.into()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExposedAppPort {
+    // Required by the Service when more than one port is exposed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[schemars(range(min = 1, max = 65535))]
+    pub port: u32,
+    #[schemars(range(min = 1, max = 65535))]
+    pub container_port: u32,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[schemars(range(min = 1, max = 65535))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_port: Option<u32>,
+    // Fields of a newer version, kept through conversions
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct LocalObjectReference {
+    pub name: String,
+}
+
+// Exactly one of the references is set
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvFromSource {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_map_ref: Option<LocalObjectReference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_ref: Option<LocalObjectReference>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CanaryStep {
+    // Share of the replicas running the new image
+    #[schemars(range(min = 1, max = 100))]
+    pub weight: u32,
+    // Without a pause the rollout waits for the promote annotation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pause_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CanaryStrategy {
+    #[schemars(length(min = 1))]
+    pub steps: Vec<CanaryStep>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_unavailable: Option<u32>,
+    // Canary pods not ready within the deadline abort the rollout
+    #[schemars(range(min = 1))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_deadline_seconds: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct Rollout {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canary: Option<CanaryStrategy>,
+}
+
+/*
+   Hub version, served as stable.no-library.com/v2 and used by the reconciler.
+   Other versions are converted to and from it by the conversion webhook.
+   Schema of the CRD is generated from these types with `cargo run --bin crdgen`
+*/
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ExposedAppSpec {
+    #[serde(default = "default_replicas")]
+    #[schemars(range(min = 0))]
+    pub replicas: u32,
+    #[schemars(schema_with = "image_schema")]
+    pub image: String,
+    #[schemars(length(min = 1))]
+    pub ports: Vec<ExposedAppPort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_type: Option<ServiceType>,
+    // Deployments roll when referenced ConfigMaps and Secrets change
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env_from: Vec<EnvFromSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollout: Option<Rollout>,
+    // Fields of a newer version, kept through conversions
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: Map<String, Value>,
+}
+
+// stable.no-library.com/v1, single port
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExposedAppSpecV1 {
+    #[serde(default = "default_replicas")]
+    #[schemars(range(min = 0))]
     pub replicas: u32,
+    #[schemars(range(min = 1, max = 65535))]
     pub container_port: u32,
+    #[schemars(schema_with = "image_schema")]
     pub image: String,
+    #[schemars(range(min = 1, max = 65535))]
     pub port: u32,
-    pub protocol: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[schemars(range(min = 1, max = 65535))]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub node_port: Option<u32>,
-    pub service_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_type: Option<ServiceType>,
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -120,20 +317,227 @@ pub struct K8sListObject<T> {
     pub object: T,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ExposedAppStatus {
     pub deployment_name: String,
     pub service_name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canary: Option<CanaryStatus>,
+    // Last reconcile that changed the status or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_reconcile_time: Option<Time>,
+    // Cleared by the next successful reconcile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<ReconcileError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_retry_time: Option<Time>,
+}
+
+// Step of the reconcile that failed
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+pub enum ReconcilePhase {
+    Fetch,
+    Config,
+    Rollout,
+    Deployment,
+    Service,
+    Prune,
+    Sharding,
+    Pods,
+    Status,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileError {
+    pub message: String,
+    pub phase: ReconcilePhase,
+    // Consecutive failures
+    pub attempts: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+pub enum CanaryPhase {
+    Progressing,
+    Paused,
+    Promoted,
+    Aborted,
+}
+
+// Last canary rollout, kept after it is promoted or aborted
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CanaryStatus {
+    pub image: String,
+    pub stable_image: String,
+    pub step: usize,
+    pub weight: u32,
+    pub phase: CanaryPhase,
+    pub message: String,
+    // When the canary replicas of the current step became ready
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step_ready_time: Option<Time>,
+}
+
+impl CanaryStatus {
+    pub fn is_in_progress(&self) -> bool {
+        matches!(self.phase, CanaryPhase::Progressing | CanaryPhase::Paused)
+    }
+}
+
+// https://pkg.go.dev/k8s.io/apimachinery/pkg/apis/meta/v1#Condition
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub condition_type: String,
+    // True, False or Unknown
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    pub last_transition_time: Time,
+}
+
+impl Condition {
+    // lastTransitionTime is kept from the previous condition of the same type unless the status flips
+    pub fn new(
+        condition_type: &str,
+        status: &str,
+        reason: impl Into<String>,
+        message: impl Into<String>,
+        previous: Option<&Condition>,
+    ) -> Self {
+        let last_transition_time = previous
+            .filter(|previous| previous.status == status)
+            .map(|previous| previous.last_transition_time)
+            .unwrap_or_else(Time::now);
+        Condition {
+            condition_type: String::from(condition_type),
+            status: String::from(status),
+            reason: reason.into(),
+            message: message.into(),
+            last_transition_time,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ExposedApp {
     pub spec: ExposedAppSpec,
     pub status: Option<ExposedAppStatus>,
 }
 
+// Only used to generate the v1 schema, the operator works on the hub version
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExposedAppV1 {
+    pub spec: ExposedAppSpecV1,
+    pub status: Option<ExposedAppStatus>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+pub enum LabelSelectorOperator {
+    In,
+    NotIn,
+    Exists,
+    DoesNotExist,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct LabelSelectorRequirement {
+    pub key: String,
+    pub operator: LabelSelectorOperator,
+    // Empty for Exists and DoesNotExist
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+}
+
+// https://pkg.go.dev/k8s.io/apimachinery/pkg/apis/meta/v1#LabelSelector, empty selects everything
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelSelector {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub match_labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub match_expressions: Vec<LabelSelectorRequirement>,
+}
+
+// Replaces the matching fields of the template spec in one namespace
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceOverride {
+    pub namespace: String,
+    #[schemars(range(min = 0))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replicas: Option<u32>,
+    #[schemars(schema_with = "image_schema")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_type: Option<ServiceType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_from: Option<Vec<EnvFromSource>>,
+}
+
+/*
+   Stamps an ExposedApp named after the template into every namespace matching the selector.
+   Served as stable.no-library.com/v1, cluster scoped, the generated ExposedApps are owned by it.
+*/
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExposedAppTemplateSpec {
+    pub namespace_selector: LabelSelector,
+    pub template: ExposedAppSpec,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<NamespaceOverride>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+pub enum NamespaceRolloutPhase {
+    // Created, not reconciled yet
+    Pending,
+    Progressing,
+    Ready,
+    Failed,
+    // An ExposedApp with the same name exists and is not owned by the template
+    Conflict,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceRolloutStatus {
+    pub namespace: String,
+    pub phase: NamespaceRolloutPhase,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExposedAppTemplateStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<u64>,
+    pub selected_namespaces: u32,
+    pub ready_namespaces: u32,
+    // Sorted by namespace
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespaces: Vec<NamespaceRolloutStatus>,
+    // Last reconcile that changed the status
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_reconcile_time: Option<Time>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExposedAppTemplate {
+    pub spec: ExposedAppTemplateSpec,
+    pub status: Option<ExposedAppTemplateStatus>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Watch<T> {
     #[serde(rename = "type")]
@@ -150,13 +554,61 @@ pub struct List<T> {
 #[derive(Serialize, Deserialize)]
 pub struct Deployment {
     pub spec: DeploymentSpec,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<DeploymentStatus>,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DeploymentSpec {
     pub replicas: u32,
     pub template: PodTemplate,
     pub selector: Selector,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<DeploymentStrategy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_deadline_seconds: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentStrategy {
+    #[serde(rename = "type")]
+    pub strategy_type: String,
+    pub rolling_update: Option<RollingUpdate>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RollingUpdate {
+    pub max_unavailable: Option<IntOrString>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IntOrString {
+    Int(u32),
+    String(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentStatus {
+    pub observed_generation: Option<u64>,
+    #[serde(default)]
+    pub updated_replicas: u32,
+    #[serde(default)]
+    pub ready_replicas: u32,
+    #[serde(default)]
+    pub conditions: Vec<DeploymentCondition>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeploymentCondition {
+    #[serde(rename = "type")]
+    pub condition_type: String,
+    pub status: String,
+    pub reason: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -180,15 +632,75 @@ pub struct PodSpec {
 #[serde(rename_all = "camelCase")]
 pub struct ContainerPort {
     pub container_port: u32,
+    pub protocol: Protocol,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Container {
     pub name: String,
     pub image: String,
     pub ports: Option<Vec<ContainerPort>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env_from: Vec<EnvFromSource>,
+}
+
+// Only the parts of the Pod status the operator reports on
+#[derive(Serialize, Deserialize)]
+pub struct Pod {
+    pub status: Option<PodStatus>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodStatus {
+    pub container_statuses: Option<Vec<ContainerStatus>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerStatus {
+    pub name: String,
+    pub restart_count: u32,
+    pub state: Option<ContainerState>,
+    pub last_state: Option<ContainerState>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ContainerState {
+    pub waiting: Option<ContainerStateReason>,
+    pub terminated: Option<ContainerStateReason>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ContainerStateReason {
+    pub reason: Option<String>,
+    pub message: Option<String>,
+}
+
+// Only the content, used to detect changes
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigMap {
+    #[serde(default)]
+    pub data: BTreeMap<String, String>,
+    #[serde(default)]
+    pub binary_data: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Secret {
+    #[serde(default)]
+    pub data: BTreeMap<String, String>,
+}
+
+// Payload-less views, watched and patched without decoding data nor keeping it around
+#[derive(Deserialize)]
+pub struct ConfigMapMetadata {}
+
+#[derive(Deserialize)]
+pub struct SecretMetadata {}
+
 #[derive(Serialize, Deserialize)]
 pub struct Service {
     pub spec: ServiceSpec,
@@ -197,7 +709,7 @@ pub struct Service {
 #[derive(Serialize, Deserialize)]
 pub struct ServiceSpec {
     #[serde(rename = "type")]
-    pub service_type: Option<String>,
+    pub service_type: Option<ServiceType>,
     pub selector: Option<HashMap<String, String>>,
     pub ports: Vec<ServicePort>,
 }
@@ -205,18 +717,34 @@ pub struct ServiceSpec {
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ServicePort {
-    pub protocol: String,
+    pub name: Option<String>,
+    pub protocol: Protocol,
     pub port: u32,
     pub target_port: u32,
     pub node_port: Option<u32>,
 }
 
+// Labels select the namespaces ExposedAppTemplates stamp into
+#[derive(Serialize, Deserialize)]
+pub struct Namespace {
+    pub status: Option<NamespaceStatus>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NamespaceStatus {
+    // Active or Terminating
+    pub phase: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LeaseSpec {
     pub lease_duration_seconds: u32,
     pub holder_identity: Option<String>,
-    pub acquire_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acquire_time: Option<MicroTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renew_time: Option<MicroTime>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -224,26 +752,70 @@ pub struct Lease {
     pub spec: LeaseSpec,
 }
 
-impl<'a, T> Into<ObjectReference> for &'a K8sObject<T> {
-    fn into(self) -> ObjectReference {
+// Spec is applied as embedded, only the conditions are read back
+#[derive(Serialize, Deserialize)]
+pub struct CustomResourceDefinition {
+    pub spec: Value,
+    pub status: Option<CustomResourceDefinitionStatus>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CustomResourceDefinitionStatus {
+    pub conditions: Option<Vec<Condition>>,
+}
+
+// https://kubernetes.io/docs/reference/kubernetes-api/authorization-resources/self-subject-access-review-v1/
+#[derive(Serialize, Deserialize)]
+pub struct SelfSubjectAccessReview {
+    pub spec: SelfSubjectAccessReviewSpec,
+    pub status: Option<SubjectAccessReviewStatus>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfSubjectAccessReviewSpec {
+    pub resource_attributes: ResourceAttributes,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceAttributes {
+    // Cluster-wide when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub verb: String,
+    pub group: String,
+    pub resource: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subresource: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubjectAccessReviewStatus {
+    pub allowed: bool,
+    pub reason: Option<String>,
+}
+
+impl<T> From<&K8sObject<T>> for ObjectReference {
+    fn from(value: &K8sObject<T>) -> Self {
         ObjectReference {
-            api_version: self.api_version.clone(),
-            kind: self.kind.clone(),
-            name: self.metadata.name.clone().unwrap(),
-            namespace: self.metadata.namespace.clone().unwrap(),
-            uid: self.metadata.uid.clone().unwrap(),
+            api_version: value.api_version.clone(),
+            kind: value.kind.clone(),
+            name: value.metadata.name.clone().unwrap(),
+            namespace: value.metadata.namespace.clone().unwrap(),
+            uid: value.metadata.uid.clone().unwrap(),
         }
     }
 }
 
-impl<T> Into<ObjectReference> for K8sObject<T> {
-    fn into(self) -> ObjectReference {
+impl<T> From<K8sObject<T>> for ObjectReference {
+    fn from(value: K8sObject<T>) -> Self {
         ObjectReference {
-            api_version: self.api_version,
-            kind: self.kind,
-            name: self.metadata.name.unwrap(),
-            namespace: self.metadata.namespace.unwrap(),
-            uid: self.metadata.uid.unwrap(),
+            api_version: value.api_version,
+            kind: value.kind,
+            name: value.metadata.name.unwrap(),
+            namespace: value.metadata.namespace.unwrap(),
+            uid: value.metadata.uid.unwrap(),
         }
     }
 }
@@ -263,3 +835,102 @@ impl<T> MetadataAware for K8sListObject<T> {
         self.metadata.clone()
     }
 }
+
+// Kind served by the API server, its URIs are resolved through discovery
+pub trait Resource {
+    const API_VERSION: &'static str;
+    const KIND: &'static str;
+}
+
+impl Resource for ExposedApp {
+    const API_VERSION: &'static str = EXPOSED_APP_API_VERSION;
+    const KIND: &'static str = "ExposedApp";
+}
+
+impl Resource for ExposedAppTemplate {
+    const API_VERSION: &'static str = EXPOSED_APP_TEMPLATE_API_VERSION;
+    const KIND: &'static str = "ExposedAppTemplate";
+}
+
+impl Resource for Deployment {
+    const API_VERSION: &'static str = "apps/v1";
+    const KIND: &'static str = "Deployment";
+}
+
+impl Resource for Pod {
+    const API_VERSION: &'static str = "v1";
+    const KIND: &'static str = "Pod";
+}
+
+impl Resource for Service {
+    const API_VERSION: &'static str = "v1";
+    const KIND: &'static str = "Service";
+}
+
+impl Resource for ConfigMap {
+    const API_VERSION: &'static str = "v1";
+    const KIND: &'static str = "ConfigMap";
+}
+
+impl Resource for Secret {
+    const API_VERSION: &'static str = "v1";
+    const KIND: &'static str = "Secret";
+}
+
+impl Resource for ConfigMapMetadata {
+    const API_VERSION: &'static str = ConfigMap::API_VERSION;
+    const KIND: &'static str = ConfigMap::KIND;
+}
+
+impl Resource for SecretMetadata {
+    const API_VERSION: &'static str = Secret::API_VERSION;
+    const KIND: &'static str = Secret::KIND;
+}
+
+impl Resource for Namespace {
+    const API_VERSION: &'static str = "v1";
+    const KIND: &'static str = "Namespace";
+}
+
+impl Resource for Lease {
+    const API_VERSION: &'static str = "coordination.k8s.io/v1";
+    const KIND: &'static str = "Lease";
+}
+
+impl Resource for Event {
+    const API_VERSION: &'static str = "events.k8s.io/v1";
+    const KIND: &'static str = "Event";
+}
+
+impl Resource for CustomResourceDefinition {
+    const API_VERSION: &'static str = "apiextensions.k8s.io/v1";
+    const KIND: &'static str = "CustomResourceDefinition";
+}
+
+impl Resource for SelfSubjectAccessReview {
+    const API_VERSION: &'static str = "authorization.k8s.io/v1";
+    const KIND: &'static str = "SelfSubjectAccessReview";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn condition_keeps_the_transition_time_while_the_status_holds() {
+        let since: Time = "2006-01-02T15:04:05Z".parse().unwrap();
+        let previous = Condition {
+            last_transition_time: since,
+            ..Condition::new("Ready", "True", "Started", "started", None)
+        };
+        let same = Condition::new("Ready", "True", "Running", "running", Some(&previous));
+        assert_eq!(same.last_transition_time, since);
+        assert_eq!(same.reason, "Running");
+        assert_eq!(same.message, "running");
+        let flipped = Condition::new("Ready", "False", "Failed", "failed", Some(&previous));
+        assert!(flipped.last_transition_time > since);
+        let first = Condition::new("Ready", "False", "Failed", "failed", None);
+        assert_eq!(first.condition_type, "Ready");
+        assert!(first.last_transition_time > since);
+    }
+}
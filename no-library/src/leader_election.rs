@@ -1,58 +1,72 @@
+use crate::config::Config;
+use crate::introspection::Introspection;
 use crate::k8s_client::client::{K8sClient, K8sClientError};
+use crate::k8s_time::MicroTime;
 use crate::k8s_types::{K8sObject, Lease};
-use crate::offset_date_time_parser::parse;
 use std::sync::Arc;
-use time::{Duration, OffsetDateTime};
+use time::Duration;
 use tokio::sync::Notify;
 use tokio::time::sleep;
 use tracing::{error, info};
 
-const LEASE_NAME: &str = "no-library";
-const LEASE_NAMESPACE: &str = "no-library";
-
 pub struct LeaderElector {
     client: K8sClient,
     pod_id: String,
+    lease_name: String,
+    lease_namespace: String,
     is_leader_sender: Arc<Notify>,
+    introspection: Introspection,
 }
 
 impl LeaderElector {
-    pub fn new(client: K8sClient, pod_id: &str, is_leader_sender: Arc<Notify>) -> Self {
+    pub fn new(
+        client: K8sClient,
+        config: &Config,
+        is_leader_sender: Arc<Notify>,
+        introspection: Introspection,
+    ) -> Self {
         LeaderElector {
             client,
-            pod_id: String::from(pod_id),
+            pod_id: config.pod_name.clone(),
+            lease_name: config.lease_name.clone(),
+            lease_namespace: config.lease_namespace.clone(),
             is_leader_sender,
+            introspection,
         }
     }
 
     async fn get_lease(&mut self) -> K8sObject<Lease> {
-        self.client
-            .get_lease(LEASE_NAME, LEASE_NAMESPACE)
+        let lease = self
+            .client
+            .get_lease(self.lease_namespace.as_str(), self.lease_name.as_str())
             .await
-            .expect("Failed to get lease, should be available. Check yaml config")
+            .expect("Failed to get lease, should be available. Check yaml config");
+        self.introspection
+            .lease(self.pod_id.as_str(), &lease.object.spec);
+        lease
     }
 
-    async fn patch_lease(
-        &mut self,
-        version: &str,
-        now: &OffsetDateTime,
-    ) -> Result<(), K8sClientError> {
-        self.client
+    async fn patch_lease(&mut self, version: &str, now: &MicroTime) -> Result<(), K8sClientError> {
+        let result = self
+            .client
             .patch_lease(
-                LEASE_NAMESPACE,
-                LEASE_NAME,
+                self.lease_namespace.as_str(),
+                self.lease_name.as_str(),
                 version,
                 self.pod_id.as_str(),
-                now.clone(),
+                *now,
             )
-            .await
+            .await;
+        let outcome = result.as_ref().map(|_| ()).map_err(|e| format!("{:?}", e));
+        self.introspection.refreshed(*now, outcome);
+        result
     }
 
     async fn acquire_lease(&mut self) {
         loop {
             let lease = self.get_lease().await;
             let duration = lease.object.spec.lease_duration_seconds;
-            let now = OffsetDateTime::now_utc();
+            let now = MicroTime::now();
             let resource_version = lease.metadata.resource_version.clone().unwrap();
             let acquired_by = lease.object.spec.holder_identity.clone();
             let can_acquire = lease
@@ -60,14 +74,14 @@ impl LeaderElector {
                 .spec
                 .acquire_time
                 .map(|t| {
-                    now > parse(t.as_str())
-                        .unwrap()
-                        .checked_add(Duration::seconds(duration as i64))
-                        .unwrap()
+                    now.value()
+                        > t.value()
+                            .checked_add(Duration::seconds(duration as i64))
+                            .unwrap()
                 })
                 .unwrap_or(true);
             if can_acquire {
-                info!("Trying to acquire lease {}", LEASE_NAME);
+                info!("Trying to acquire lease {}", self.lease_name);
                 match self.patch_lease(resource_version.as_str(), &now).await {
                     Ok(_) => {
                         info!("Lease acquired, became a leader");
@@ -94,8 +108,8 @@ impl LeaderElector {
             let duration = lease.object.spec.lease_duration_seconds;
             let wait_time = duration >> 1;
             let resource_version = lease.metadata.resource_version.clone().unwrap();
-            info!("Refreshing lease {}", LEASE_NAME);
-            let now = OffsetDateTime::now_utc();
+            info!("Refreshing lease {}", self.lease_name);
+            let now = MicroTime::now();
             match self.patch_lease(resource_version.as_str(), &now).await {
                 Ok(_) => {
                     info!("Lease refreshed");
@@ -0,0 +1,37 @@
+/*
+   Operator library, the no-library binary wires it together.
+   The fake and replay API servers live here too, integration tests start them in-process.
+*/
+pub mod admission_review;
+pub mod bootstrap;
+pub mod cache;
+pub mod canary;
+pub mod cassette;
+pub mod children;
+pub mod config;
+pub mod controller;
+pub mod conversion;
+pub mod conversion_review;
+pub mod crd;
+pub mod credentials;
+pub mod diff;
+pub mod discovery;
+pub mod fake_api;
+pub mod introspection;
+pub mod k8s_client;
+pub mod k8s_time;
+pub mod k8s_types;
+pub mod leader_election;
+pub mod operator;
+pub mod outcome;
+pub mod pause;
+pub mod pod_health;
+pub mod protobuf;
+pub mod rate_limiter;
+pub mod reconciler;
+pub mod references;
+pub mod replay;
+pub mod sharding;
+pub mod telemetry;
+pub mod templates;
+pub mod validation;
@@ -1,34 +1,107 @@
-mod cache;
-mod k8s_client;
-mod k8s_types;
-mod leader_election;
-mod offset_date_time_parser;
-mod operator;
-mod reconciler;
-
-use crate::operator::operator::elect_leader;
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::Router;
-use operator::operator::handle_owned_resources;
-use std::env;
+use axum_server::tls_openssl::OpenSSLConfig;
+use no_library::bootstrap::bootstrap;
+use no_library::config::Config;
+use no_library::discovery::Discovery;
+use no_library::introspection::Introspection;
+use no_library::k8s_client::client::K8sClient;
+use no_library::operator::operator::{elect_leader, handle_owned_resources, required_resources};
+use no_library::sharding::Sharding;
+use no_library::{conversion, introspection, telemetry, validation};
+use std::net::SocketAddr;
+use std::process::exit;
 use std::sync::Arc;
 use tokio::select;
 use tokio::sync::Notify;
-use tracing::error;
+use tracing::{error, info};
+
+/*
+   Webhooks called by the API server, HTTPS only.
+   Certificates are issued by cert-manager and mounted in cert_dir.
+*/
+async fn serve_webhooks(config: Config) {
+    let cert_dir = match config.cert_dir {
+        Some(cert_dir) => cert_dir,
+        None => return futures::future::pending().await,
+    };
+    let tls = OpenSSLConfig::from_pem_file(cert_dir.join("tls.crt"), cert_dir.join("tls.key"))
+        .expect("Unable to load webhook certificate");
+    let app = Router::new()
+        .route("/convert", post(conversion::convert))
+        .route("/validate", post(validation::validate));
+    let address = SocketAddr::from(([0, 0, 0, 0], config.webhook_port));
+    info!("Serving webhooks on {}", address);
+    if let Err(e) = axum_server::bind_openssl(address, tls)
+        .serve(app.into_make_service())
+        .await
+    {
+        error!("Webhook server failed: {}", e);
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
-    let port = env::var("PORT").unwrap_or("3000".to_string());
-    let pod_name = env::var("POD_NAME").expect("Pod name expected");
-    let app = Router::new().route("/healthz", get(async || "OK"));
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        exit(2);
+    });
+    // Kept until main returns, buffered spans are exported when it is dropped
+    let _telemetry = telemetry::init(&config).unwrap_or_else(|e| {
+        eprintln!("Telemetry setup failed: {}", e);
+        exit(2);
+    });
     let notify = Arc::new(Notify::new());
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
+    // Clones share credentials and rate limiter
+    let mut client = K8sClient::new(&config.client).await;
+    if config.bootstrap {
+        bootstrap(&client, &config).await.unwrap_or_else(|e| {
+            error!("Bootstrap failed: {}", e);
+            exit(1);
+        });
+    }
+    let discovery = Discovery::load(&mut client, &required_resources())
+        .await
+        .unwrap_or_else(|e| {
+            error!("API discovery failed: {}", e);
+            exit(1);
+        });
+    let client = client.with_discovery(discovery);
+    let sharding = config
+        .sharding
+        .then(|| Arc::new(Sharding::new(client.clone(), &config)));
+    let mut app = Router::new().route("/healthz", get(async || "OK"));
+    if let Some(sharding) = &sharding {
+        let sharding = Arc::clone(sharding);
+        app = app.route("/metrics", get(async move || sharding.metrics()));
+    }
+    let introspection = Introspection::default();
+    if let Some(token) = config.debug_token.clone() {
+        app = app.merge(introspection::routes(
+            introspection.clone(),
+            sharding.clone(),
+            token,
+        ));
+    }
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port))
         .await
         .unwrap();
+    // Every shard member works at the same time, there is no leader
+    let elect = !config.dry_run && sharding.is_none();
+    if !elect {
+        info!("Dry-run or sharding mode, leader election skipped");
+        // Without the members every replica would reconcile every ExposedApp until the first rebalance
+        if let Some(sharding) = &sharding {
+            sharding.join().await;
+        }
+        notify.notify_one();
+    }
+    let membership = sharding.clone();
     select! {
         _ = axum::serve(listener, app) => { error!("HTTP server stopped working") }
-        _ = tokio::spawn(elect_leader(pod_name.clone(), Arc::clone(&notify))) => { error!("Leader elector stopped working") }
-        _ = tokio::spawn(handle_owned_resources(Arc::clone(&notify), pod_name)) => { error!("Resources handler stopped working") }
+        _ = serve_webhooks(config.clone()) => { error!("Webhook server stopped working") }
+        _ = tokio::spawn(elect_leader(client.clone(), config.clone(), Arc::clone(&notify), introspection.clone())), if elect => { error!("Leader elector stopped working") }
+        _ = async { membership.unwrap().run().await }, if membership.is_some() => { error!("Shard membership stopped working") }
+        _ = tokio::spawn(handle_owned_resources(client, Arc::clone(&notify), config, sharding, introspection)) => { error!("Resources handler stopped working") }
     }
 }
@@ -0,0 +1,92 @@
+use crate::k8s_time::Time;
+use crate::k8s_types::{Condition, ExposedAppStatus, ReconcileError, ReconcilePhase};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+// Reconciles keep failing, they are only retried at the stalled interval
+pub const STALLED: &str = "Stalled";
+
+// Error of a failed reconcile, tagged with the step that failed
+pub struct Failure {
+    pub phase: ReconcilePhase,
+    pub message: String,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.phase, self.message)
+    }
+}
+
+// map_err adapter, e.g. .map_err(failed_in(ReconcilePhase::Service))?
+pub fn failed_in(phase: ReconcilePhase) -> impl FnOnce(String) -> Failure {
+    move |message| Failure { phase, message }
+}
+
+fn replace_condition(status: &mut ExposedAppStatus, condition: Condition) {
+    status
+        .conditions
+        .retain(|existing| existing.condition_type != condition.condition_type);
+    status.conditions.push(condition);
+}
+
+fn stalled_condition(status: &ExposedAppStatus) -> Option<&Condition> {
+    status
+        .conditions
+        .iter()
+        .find(|condition| condition.condition_type == STALLED)
+}
+
+// Clears the last error, a Stalled condition once reported stays False from then on
+pub fn succeeded(status: &mut ExposedAppStatus) {
+    status.last_reconcile_time = Some(Time::now());
+    status.last_error = None;
+    status.next_retry_time = None;
+    if let Some(previous) = stalled_condition(status).cloned() {
+        let recovered = Condition::new(
+            STALLED,
+            "False",
+            "Reconciled",
+            "Reconciled successfully",
+            Some(&previous),
+        );
+        replace_condition(status, recovered);
+    }
+}
+
+/*
+   Records a failure, the ExposedApp is Stalled once attempts reaches stall_after.
+   Returns true when the Stalled condition has just become True.
+*/
+pub fn failed(
+    status: &mut ExposedAppStatus,
+    failure: &Failure,
+    attempts: u32,
+    retry: Option<Duration>,
+    stall_after: u32,
+) -> bool {
+    let now = Time::now();
+    status.last_reconcile_time = Some(now);
+    status.last_error = Some(ReconcileError {
+        message: failure.message.clone(),
+        phase: failure.phase,
+        attempts,
+    });
+    status.next_retry_time = retry.map(|delay| Time::new(now.value() + delay));
+    if attempts < stall_after {
+        return false;
+    }
+    let previous = stalled_condition(status).cloned();
+    let stalled = Condition::new(
+        STALLED,
+        "True",
+        format!("{:?}Failed", failure.phase),
+        format!(
+            "{} consecutive failures, last one: {}",
+            attempts, failure.message
+        ),
+        previous.as_ref(),
+    );
+    replace_condition(status, stalled);
+    previous.is_none_or(|previous| previous.status != "True")
+}
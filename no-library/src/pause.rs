@@ -0,0 +1,93 @@
+use crate::k8s_time::Time;
+use crate::k8s_types::Condition;
+use std::collections::HashMap;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+pub const PAUSED: &str = "Paused";
+/*
+   "true" pauses until the annotation is removed, an RFC3339 timestamp until that time,
+   so a forgotten pause still ends. "false" is the same as no annotation.
+*/
+pub const PAUSE_ANNOTATION: &str = "no-library.com/paused";
+
+pub enum Pause {
+    Config,
+    Annotation { until: Option<Time> },
+}
+
+// None when the ExposedApp is reconciled normally
+pub fn pause(
+    annotations: &Option<HashMap<String, String>>,
+    paused_by_config: bool,
+) -> Option<Pause> {
+    if paused_by_config {
+        return Some(Pause::Config);
+    }
+    let value = annotations.as_ref()?.get(PAUSE_ANNOTATION)?;
+    match value.as_str() {
+        "false" => None,
+        "true" => Some(Pause::Annotation { until: None }),
+        // Anything unexpected keeps the hands off, better than reverting an incident fix
+        other => match other.parse::<Time>() {
+            Ok(until) if until.value() <= OffsetDateTime::now_utc() => None,
+            Ok(until) => Some(Pause::Annotation { until: Some(until) }),
+            Err(_) => Some(Pause::Annotation { until: None }),
+        },
+    }
+}
+
+// Condition once reconciliation resumed
+pub fn resumed(previous: Option<&Condition>) -> Condition {
+    Condition::new(
+        PAUSED,
+        "False",
+        "Resumed",
+        "Reconciliation resumed",
+        previous,
+    )
+}
+
+impl Pause {
+    pub fn condition(&self, previous: Option<&Condition>) -> Condition {
+        match self {
+            Pause::Config => Condition::new(
+                PAUSED,
+                "True",
+                "PausedByConfig",
+                "Reconciliation of every ExposedApp is paused by the operator configuration",
+                previous,
+            ),
+            Pause::Annotation { until: None } => Condition::new(
+                PAUSED,
+                "True",
+                "PausedByAnnotation",
+                format!(
+                    "Reconciliation paused by the {} annotation",
+                    PAUSE_ANNOTATION
+                ),
+                previous,
+            ),
+            Pause::Annotation { until: Some(until) } => Condition::new(
+                PAUSED,
+                "True",
+                "PausedByAnnotation",
+                format!(
+                    "Reconciliation paused by the {} annotation until {}",
+                    PAUSE_ANNOTATION, until
+                ),
+                previous,
+            ),
+        }
+    }
+
+    // Left until the pause expires
+    pub fn remaining(&self) -> Option<Duration> {
+        match self {
+            Pause::Annotation { until: Some(until) } => {
+                Some((until.value() - OffsetDateTime::now_utc()).unsigned_abs())
+            }
+            _ => None,
+        }
+    }
+}
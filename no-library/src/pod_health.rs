@@ -0,0 +1,102 @@
+use crate::k8s_types::{Condition, ContainerState, K8sListObject, Pod};
+
+pub const PODS_HEALTHY: &str = "PodsHealthy";
+// Waiting reasons a container does not recover from by itself
+const FAILING_WAITING_REASONS: [&str; 6] = [
+    "ImagePullBackOff",
+    "ErrImagePull",
+    "CrashLoopBackOff",
+    "CreateContainerConfigError",
+    "CreateContainerError",
+    "InvalidImageName",
+];
+// Failures listed in the condition message, the rest is only counted
+const MAX_LISTED_FAILURES: usize = 5;
+
+pub struct PodHealth {
+    containers: usize,
+    restarts: u32,
+    failures: Vec<(String, String)>,
+}
+
+fn waiting_reason(state: &Option<ContainerState>) -> Option<&str> {
+    state.as_ref()?.waiting.as_ref()?.reason.as_deref()
+}
+
+fn terminated_reason(state: &Option<ContainerState>) -> Option<&str> {
+    state.as_ref()?.terminated.as_ref()?.reason.as_deref()
+}
+
+pub fn summarize(pods: &[K8sListObject<Pod>]) -> PodHealth {
+    let mut health = PodHealth {
+        containers: 0,
+        restarts: 0,
+        failures: Vec::new(),
+    };
+    for pod in pods {
+        let pod_name = pod.metadata.name.clone().unwrap_or_default();
+        let statuses = pod
+            .object
+            .status
+            .as_ref()
+            .and_then(|status| status.container_statuses.as_ref());
+        for container in statuses.into_iter().flatten() {
+            health.containers += 1;
+            health.restarts += container.restart_count;
+            let reason = waiting_reason(&container.state)
+                .filter(|reason| FAILING_WAITING_REASONS.contains(reason))
+                .or(terminated_reason(&container.state).filter(|reason| *reason != "Completed"));
+            // CrashLoopBackOff hides why the container died
+            let reason = match (reason, terminated_reason(&container.last_state)) {
+                (Some("CrashLoopBackOff"), Some("OOMKilled")) => Some("OOMKilled"),
+                (reason, _) => reason,
+            };
+            if let Some(reason) = reason {
+                let description = format!(
+                    "{}/{} {} (restarts {})",
+                    pod_name, container.name, reason, container.restart_count
+                );
+                health.failures.push((String::from(reason), description));
+            }
+        }
+    }
+    health
+}
+
+impl PodHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    pub fn condition(&self, previous: Option<&Condition>) -> Condition {
+        let status = if self.is_healthy() { "True" } else { "False" };
+        let (reason, message) = match self.failures.first() {
+            None => (
+                String::from("PodsRunning"),
+                format!(
+                    "{} containers running, {} restarts",
+                    self.containers, self.restarts
+                ),
+            ),
+            Some((reason, _)) => {
+                let listed: Vec<&str> = self
+                    .failures
+                    .iter()
+                    .take(MAX_LISTED_FAILURES)
+                    .map(|(_, description)| description.as_str())
+                    .collect();
+                (
+                    reason.clone(),
+                    format!(
+                        "{} of {} containers failing, {} restarts: {}",
+                        self.failures.len(),
+                        self.containers,
+                        self.restarts,
+                        listed.join(", ")
+                    ),
+                )
+            }
+        };
+        Condition::new(PODS_HEALTHY, status, reason, message, previous)
+    }
+}
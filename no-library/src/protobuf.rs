@@ -0,0 +1,1386 @@
+use crate::k8s_time::{MicroTime, Time};
+use crate::k8s_types::{
+    ConfigMap, ConfigMapMetadata, Container, ContainerPort, Deployment, DeploymentCondition,
+    DeploymentSpec, DeploymentStatus, DeploymentStrategy, EnvFromSource, Event, ExposedApp,
+    ExposedAppTemplate, IntOrString, K8sListObject, Lease, LeaseSpec, List, LocalObjectReference,
+    Metadata, Namespace, ObjectReference, OwnerReference, Pod, PodSpec, PodTemplate, Resource,
+    RollingUpdate, Secret, SecretMetadata, Selector, Service, ServicePort, ServiceSpec, Watch,
+};
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use time::OffsetDateTime;
+
+/*
+   Kubernetes protobuf encoding of the built-in kinds the operator lists and watches.
+   https://kubernetes.io/docs/reference/using-api/api-concepts/#protobuf-encoding
+   Objects are wrapped in a runtime.Unknown envelope prefixed with "k8s\0",
+   watch streams are WatchEvents preceded by their length as a 4 bytes big-endian integer.
+   Messages declare only the fields mapped to k8s_types, with the tags of the upstream
+   generated.proto, every other field is skipped while decoding.
+*/
+pub const PROTOBUF: &str = "application/vnd.kubernetes.protobuf";
+// Kinds without protobuf support, e.g. custom resources, are answered with JSON
+pub const PROTOBUF_OR_JSON: &str = "application/vnd.kubernetes.protobuf, application/json";
+const MAGIC: &[u8] = b"k8s\0";
+
+mod proto {
+    use prost::Message;
+    use std::collections::HashMap;
+
+    // k8s.io.apimachinery.pkg.runtime
+    #[derive(Clone, PartialEq, Message)]
+    pub struct TypeMeta {
+        #[prost(string, tag = "1")]
+        pub api_version: String,
+        #[prost(string, tag = "2")]
+        pub kind: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Unknown {
+        #[prost(message, optional, tag = "1")]
+        pub type_meta: Option<TypeMeta>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub raw: Vec<u8>,
+        #[prost(string, tag = "3")]
+        pub content_encoding: String,
+        #[prost(string, tag = "4")]
+        pub content_type: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct RawExtension {
+        #[prost(bytes = "vec", tag = "1")]
+        pub raw: Vec<u8>,
+    }
+
+    // k8s.io.apimachinery.pkg.apis.meta.v1, MicroTime has the same fields
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Time {
+        #[prost(int64, tag = "1")]
+        pub seconds: i64,
+        #[prost(int32, tag = "2")]
+        pub nanos: i32,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct OwnerReference {
+        #[prost(string, tag = "1")]
+        pub kind: String,
+        #[prost(string, tag = "3")]
+        pub name: String,
+        #[prost(string, tag = "4")]
+        pub uid: String,
+        #[prost(string, tag = "5")]
+        pub api_version: String,
+        #[prost(bool, optional, tag = "6")]
+        pub controller: Option<bool>,
+        #[prost(bool, optional, tag = "7")]
+        pub block_owner_deletion: Option<bool>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct ObjectMeta {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "3")]
+        pub namespace: String,
+        #[prost(string, tag = "5")]
+        pub uid: String,
+        #[prost(string, tag = "6")]
+        pub resource_version: String,
+        #[prost(int64, tag = "7")]
+        pub generation: i64,
+        #[prost(message, optional, tag = "9")]
+        pub deletion_timestamp: Option<Time>,
+        #[prost(map = "string, string", tag = "11")]
+        pub labels: HashMap<String, String>,
+        #[prost(map = "string, string", tag = "12")]
+        pub annotations: HashMap<String, String>,
+        #[prost(message, repeated, tag = "13")]
+        pub owner_references: Vec<OwnerReference>,
+        #[prost(string, repeated, tag = "14")]
+        pub finalizers: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct ListMeta {
+        #[prost(string, tag = "2")]
+        pub resource_version: String,
+    }
+
+    // Any *List kind, items are kept encoded and decoded as the listed kind
+    #[derive(Clone, PartialEq, Message)]
+    pub struct List {
+        #[prost(message, optional, tag = "1")]
+        pub metadata: Option<ListMeta>,
+        #[prost(bytes = "vec", repeated, tag = "2")]
+        pub items: Vec<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct WatchEvent {
+        #[prost(string, tag = "1")]
+        pub r#type: String,
+        #[prost(message, optional, tag = "2")]
+        pub object: Option<RawExtension>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct LabelSelector {
+        #[prost(map = "string, string", tag = "1")]
+        pub match_labels: HashMap<String, String>,
+    }
+
+    // k8s.io.apimachinery.pkg.util.intstr, type 0 is an int, 1 a string
+    #[derive(Clone, PartialEq, Message)]
+    pub struct IntOrString {
+        #[prost(int64, tag = "1")]
+        pub r#type: i64,
+        #[prost(int32, tag = "2")]
+        pub int_val: i32,
+        #[prost(string, tag = "3")]
+        pub str_val: String,
+    }
+
+    // k8s.io.api.core.v1
+    #[derive(Clone, PartialEq, Message)]
+    pub struct ObjectReference {
+        #[prost(string, tag = "1")]
+        pub kind: String,
+        #[prost(string, tag = "2")]
+        pub namespace: String,
+        #[prost(string, tag = "3")]
+        pub name: String,
+        #[prost(string, tag = "4")]
+        pub uid: String,
+        #[prost(string, tag = "5")]
+        pub api_version: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct LocalObjectReference {
+        #[prost(string, tag = "1")]
+        pub name: String,
+    }
+
+    // ConfigMapEnvSource and SecretEnvSource
+    #[derive(Clone, PartialEq, Message)]
+    pub struct EnvSource {
+        #[prost(message, optional, tag = "1")]
+        pub local_object_reference: Option<LocalObjectReference>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct EnvFromSource {
+        #[prost(message, optional, tag = "2")]
+        pub config_map_ref: Option<EnvSource>,
+        #[prost(message, optional, tag = "3")]
+        pub secret_ref: Option<EnvSource>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct ContainerPort {
+        #[prost(int32, tag = "3")]
+        pub container_port: i32,
+        #[prost(string, tag = "4")]
+        pub protocol: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Container {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub image: String,
+        #[prost(message, repeated, tag = "6")]
+        pub ports: Vec<ContainerPort>,
+        #[prost(message, repeated, tag = "19")]
+        pub env_from: Vec<EnvFromSource>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct PodSpec {
+        #[prost(message, repeated, tag = "2")]
+        pub containers: Vec<Container>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct PodTemplateSpec {
+        #[prost(message, optional, tag = "1")]
+        pub metadata: Option<ObjectMeta>,
+        #[prost(message, optional, tag = "2")]
+        pub spec: Option<PodSpec>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct ServicePort {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub protocol: String,
+        #[prost(int32, tag = "3")]
+        pub port: i32,
+        #[prost(message, optional, tag = "4")]
+        pub target_port: Option<IntOrString>,
+        #[prost(int32, tag = "5")]
+        pub node_port: i32,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct ServiceSpec {
+        #[prost(message, repeated, tag = "1")]
+        pub ports: Vec<ServicePort>,
+        #[prost(map = "string, string", tag = "2")]
+        pub selector: HashMap<String, String>,
+        #[prost(string, tag = "4")]
+        pub r#type: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Service {
+        #[prost(message, optional, tag = "1")]
+        pub metadata: Option<ObjectMeta>,
+        #[prost(message, optional, tag = "2")]
+        pub spec: Option<ServiceSpec>,
+    }
+
+    // k8s.io.api.apps.v1
+    #[derive(Clone, PartialEq, Message)]
+    pub struct RollingUpdateDeployment {
+        #[prost(message, optional, tag = "1")]
+        pub max_unavailable: Option<IntOrString>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct DeploymentStrategy {
+        #[prost(string, tag = "1")]
+        pub r#type: String,
+        #[prost(message, optional, tag = "2")]
+        pub rolling_update: Option<RollingUpdateDeployment>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct DeploymentSpec {
+        #[prost(int32, optional, tag = "1")]
+        pub replicas: Option<i32>,
+        #[prost(message, optional, tag = "2")]
+        pub selector: Option<LabelSelector>,
+        #[prost(message, optional, tag = "3")]
+        pub template: Option<PodTemplateSpec>,
+        #[prost(message, optional, tag = "4")]
+        pub strategy: Option<DeploymentStrategy>,
+        #[prost(int32, optional, tag = "9")]
+        pub progress_deadline_seconds: Option<i32>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct DeploymentCondition {
+        #[prost(string, tag = "1")]
+        pub r#type: String,
+        #[prost(string, tag = "2")]
+        pub status: String,
+        #[prost(string, tag = "4")]
+        pub reason: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct DeploymentStatus {
+        #[prost(int64, tag = "1")]
+        pub observed_generation: i64,
+        #[prost(int32, tag = "3")]
+        pub updated_replicas: i32,
+        #[prost(message, repeated, tag = "6")]
+        pub conditions: Vec<DeploymentCondition>,
+        #[prost(int32, tag = "7")]
+        pub ready_replicas: i32,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Deployment {
+        #[prost(message, optional, tag = "1")]
+        pub metadata: Option<ObjectMeta>,
+        #[prost(message, optional, tag = "2")]
+        pub spec: Option<DeploymentSpec>,
+        #[prost(message, optional, tag = "3")]
+        pub status: Option<DeploymentStatus>,
+    }
+
+    // k8s.io.api.coordination.v1
+    #[derive(Clone, PartialEq, Message)]
+    pub struct LeaseSpec {
+        #[prost(string, optional, tag = "1")]
+        pub holder_identity: Option<String>,
+        #[prost(int32, optional, tag = "2")]
+        pub lease_duration_seconds: Option<i32>,
+        #[prost(message, optional, tag = "3")]
+        pub acquire_time: Option<Time>,
+        #[prost(message, optional, tag = "4")]
+        pub renew_time: Option<Time>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Lease {
+        #[prost(message, optional, tag = "1")]
+        pub metadata: Option<ObjectMeta>,
+        #[prost(message, optional, tag = "2")]
+        pub spec: Option<LeaseSpec>,
+    }
+
+    // k8s.io.api.events.v1
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Event {
+        #[prost(message, optional, tag = "1")]
+        pub metadata: Option<ObjectMeta>,
+        #[prost(message, optional, tag = "2")]
+        pub event_time: Option<Time>,
+        #[prost(string, tag = "4")]
+        pub reporting_controller: String,
+        #[prost(string, tag = "5")]
+        pub reporting_instance: String,
+        #[prost(string, tag = "6")]
+        pub action: String,
+        #[prost(string, tag = "7")]
+        pub reason: String,
+        #[prost(message, optional, tag = "8")]
+        pub regarding: Option<ObjectReference>,
+        #[prost(message, optional, tag = "9")]
+        pub related: Option<ObjectReference>,
+        #[prost(string, tag = "10")]
+        pub note: String,
+        #[prost(string, tag = "11")]
+        pub r#type: String,
+    }
+}
+
+pub type Decode<T> = fn(&[u8]) -> Result<K8sListObject<T>, String>;
+
+// Kinds listed and watched as protobuf, the default keeps JSON
+pub trait Protobuf: Sized {
+    fn decoder() -> Option<Decode<Self>> {
+        None
+    }
+}
+
+fn decode<M, T>(message: &[u8]) -> Result<K8sListObject<T>, String>
+where
+    M: Message + Default,
+    K8sListObject<T>: TryFrom<M, Error = String>,
+{
+    let message = M::decode(message).map_err(|e| e.to_string())?;
+    K8sListObject::try_from(message)
+}
+
+impl Protobuf for Deployment {
+    fn decoder() -> Option<Decode<Self>> {
+        Some(decode::<proto::Deployment, Deployment>)
+    }
+}
+
+impl Protobuf for Service {
+    fn decoder() -> Option<Decode<Self>> {
+        Some(decode::<proto::Service, Service>)
+    }
+}
+
+impl Protobuf for Lease {
+    fn decoder() -> Option<Decode<Self>> {
+        Some(decode::<proto::Lease, Lease>)
+    }
+}
+
+impl Protobuf for Event {
+    fn decoder() -> Option<Decode<Self>> {
+        Some(decode::<proto::Event, Event>)
+    }
+}
+
+// Custom resources are only served as JSON, the others are not mapped yet
+impl Protobuf for ExposedApp {}
+impl Protobuf for ExposedAppTemplate {}
+impl Protobuf for Pod {}
+impl Protobuf for ConfigMap {}
+impl Protobuf for Secret {}
+impl Protobuf for ConfigMapMetadata {}
+impl Protobuf for SecretMetadata {}
+impl Protobuf for Namespace {}
+
+// Body of a list response
+pub fn decode_list<T>(body: &[u8], decode: Decode<T>) -> Result<List<K8sListObject<T>>, String> {
+    let list = proto::List::decode(unwrap(body)?.as_slice())
+        .map_err(|e| format!("Invalid list: {}", e))?;
+    let items = list
+        .items
+        .iter()
+        .map(|item| decode(item))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(List {
+        items,
+        metadata: Metadata {
+            resource_version: list
+                .metadata
+                .map(|metadata| metadata.resource_version)
+                .and_then(non_empty),
+            ..Metadata::default()
+        },
+    })
+}
+
+// One frame of a watch stream, the object is enveloped on its own
+pub fn decode_watch_event<T>(
+    frame: &[u8],
+    decode: Decode<T>,
+) -> Result<Watch<K8sListObject<T>>, String> {
+    let event = proto::WatchEvent::decode(unwrap(frame)?.as_slice())
+        .map_err(|e| format!("Invalid watch event: {}", e))?;
+    let raw = event
+        .object
+        .ok_or_else(|| format!("{} watch event without object", event.r#type))?
+        .raw;
+    Ok(Watch {
+        event_type: from_name(event.r#type.as_str())?,
+        object: decode(unwrap(raw.as_slice())?.as_slice())?,
+    })
+}
+
+// Limit of the client-go watch decoder, a larger length is a corrupted stream
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+// Splits a watch response into frames, a frame may span several chunks
+#[derive(Default)]
+pub struct Frames {
+    buffer: Vec<u8>,
+}
+
+impl Frames {
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    // None until the whole frame was pushed
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let Some(header) = self.buffer.get(..4) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+        if length > MAX_FRAME {
+            return Err(format!(
+                "Watch frame of {} bytes exceeds {} bytes",
+                length, MAX_FRAME
+            ));
+        }
+        let end = 4 + length;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+        let frame = self.buffer[4..end].to_vec();
+        self.buffer.drain(..end);
+        Ok(Some(frame))
+    }
+}
+
+// Raw message of an enveloped object, frames of a watch stream are not enveloped
+fn unwrap(body: &[u8]) -> Result<Vec<u8>, String> {
+    let Some(enveloped) = body.strip_prefix(MAGIC) else {
+        return Ok(body.to_vec());
+    };
+    let unknown =
+        proto::Unknown::decode(enveloped).map_err(|e| format!("Invalid envelope: {}", e))?;
+    if !unknown.content_encoding.is_empty() {
+        return Err(format!(
+            "Unsupported content encoding {}",
+            unknown.content_encoding
+        ));
+    }
+    Ok(unknown.raw)
+}
+
+// Encoding serves fake-api-server and the decoding benchmark
+fn envelope(api_version: &str, kind: &str, raw: Vec<u8>) -> Vec<u8> {
+    let unknown = proto::Unknown {
+        type_meta: Some(proto::TypeMeta {
+            api_version: String::from(api_version),
+            kind: String::from(kind),
+        }),
+        raw,
+        ..proto::Unknown::default()
+    };
+    [MAGIC, unknown.encode_to_vec().as_slice()].concat()
+}
+
+fn encode<T, M>(object: &Value) -> Result<Vec<u8>, String>
+where
+    T: DeserializeOwned,
+    M: Message + for<'a> From<&'a K8sListObject<T>>,
+{
+    let object =
+        serde_json::from_value::<K8sListObject<T>>(object.clone()).map_err(|e| e.to_string())?;
+    Ok(M::from(&object).encode_to_vec())
+}
+
+fn is<T: Resource>(api_version: &str, kind: &str) -> bool {
+    api_version == T::API_VERSION && kind == T::KIND
+}
+
+// Built-in kinds with a message, the others are only served as JSON
+pub fn has_message(api_version: &str, kind: &str) -> bool {
+    is::<Deployment>(api_version, kind)
+        || is::<Service>(api_version, kind)
+        || is::<Lease>(api_version, kind)
+        || is::<Event>(api_version, kind)
+}
+
+// Message of a built-in kind, None for kinds only served as JSON
+fn encode_object(api_version: &str, kind: &str, object: &Value) -> Option<Result<Vec<u8>, String>> {
+    if is::<Deployment>(api_version, kind) {
+        Some(encode::<Deployment, proto::Deployment>(object))
+    } else if is::<Service>(api_version, kind) {
+        Some(encode::<Service, proto::Service>(object))
+    } else if is::<Lease>(api_version, kind) {
+        Some(encode::<Lease, proto::Lease>(object))
+    } else if is::<Event>(api_version, kind) {
+        Some(encode::<Event, proto::Event>(object))
+    } else {
+        None
+    }
+}
+
+// Protobuf list response, as sent by the API server
+pub fn encode_list(
+    api_version: &str,
+    kind: &str,
+    items: &[Value],
+    resource_version: &str,
+) -> Option<Result<Vec<u8>, String>> {
+    let items = items
+        .iter()
+        .map(|item| encode_object(api_version, kind, item))
+        .collect::<Option<Result<Vec<_>, _>>>()?;
+    Some(items.map(|items| {
+        let list = proto::List {
+            metadata: Some(proto::ListMeta {
+                resource_version: String::from(resource_version),
+            }),
+            items,
+        };
+        envelope(
+            api_version,
+            format!("{}List", kind).as_str(),
+            list.encode_to_vec(),
+        )
+    }))
+}
+
+// Length prefixed watch event, as sent by the API server
+pub fn encode_watch_event(
+    api_version: &str,
+    kind: &str,
+    event_type: &str,
+    object: &Value,
+) -> Option<Result<Vec<u8>, String>> {
+    let raw = encode_object(api_version, kind, object)?;
+    Some(raw.map(|raw| {
+        let event = proto::WatchEvent {
+            r#type: String::from(event_type),
+            object: Some(proto::RawExtension {
+                raw: envelope(api_version, kind, raw),
+            }),
+        };
+        let message = event.encode_to_vec();
+        [(message.len() as u32).to_be_bytes().as_slice(), &message].concat()
+    }))
+}
+
+// Enums are mapped by their serde names, e.g. "TCP" or "NodePort"
+fn from_name<T: DeserializeOwned>(name: &str) -> Result<T, String> {
+    serde_json::from_value(Value::from(name)).map_err(|e| format!("{}: {}", name, e))
+}
+
+fn name_of<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+// Protobuf has no absent strings, empty ones are omitted in JSON
+fn non_empty(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
+}
+
+fn non_empty_vec<T>(values: Vec<T>) -> Option<Vec<T>> {
+    (!values.is_empty()).then_some(values)
+}
+
+fn required<T>(value: Option<T>, field: &str) -> Result<T, String> {
+    value.ok_or_else(|| format!("{} is missing", field))
+}
+
+fn datetime(time: proto::Time) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::from_unix_timestamp(time.seconds)
+        .and_then(|datetime| datetime.replace_nanosecond(time.nanos as u32))
+        .map_err(|e| format!("Invalid time: {}", e))
+}
+
+fn timestamp(datetime: OffsetDateTime) -> proto::Time {
+    proto::Time {
+        seconds: datetime.unix_timestamp(),
+        nanos: datetime.nanosecond() as i32,
+    }
+}
+
+fn int_or_string(value: proto::IntOrString) -> IntOrString {
+    match value.r#type {
+        1 => IntOrString::String(value.str_val),
+        _ => IntOrString::Int(value.int_val as u32),
+    }
+}
+
+impl From<&IntOrString> for proto::IntOrString {
+    fn from(value: &IntOrString) -> Self {
+        match value {
+            IntOrString::Int(int_val) => proto::IntOrString {
+                int_val: *int_val as i32,
+                ..proto::IntOrString::default()
+            },
+            IntOrString::String(str_val) => proto::IntOrString {
+                r#type: 1,
+                str_val: str_val.clone(),
+                ..proto::IntOrString::default()
+            },
+        }
+    }
+}
+
+impl TryFrom<proto::ObjectMeta> for Metadata {
+    type Error = String;
+
+    fn try_from(metadata: proto::ObjectMeta) -> Result<Self, String> {
+        Ok(Metadata {
+            labels: (!metadata.labels.is_empty()).then_some(metadata.labels),
+            annotations: (!metadata.annotations.is_empty()).then_some(metadata.annotations),
+            resource_version: non_empty(metadata.resource_version),
+            finalizers: non_empty_vec(metadata.finalizers),
+            deletion_timestamp: metadata
+                .deletion_timestamp
+                .map(datetime)
+                .transpose()?
+                .map(Time::new),
+            name: non_empty(metadata.name),
+            uid: non_empty(metadata.uid),
+            namespace: non_empty(metadata.namespace),
+            owner_references: non_empty_vec(
+                metadata
+                    .owner_references
+                    .into_iter()
+                    .map(|owner| OwnerReference {
+                        api_version: owner.api_version,
+                        kind: owner.kind,
+                        name: owner.name,
+                        uid: owner.uid,
+                        block_owner_deletion: owner.block_owner_deletion.unwrap_or_default(),
+                        controller: owner.controller.unwrap_or_default(),
+                    })
+                    .collect(),
+            ),
+            generation: (metadata.generation > 0).then_some(metadata.generation as u64),
+        })
+    }
+}
+
+impl From<&Metadata> for proto::ObjectMeta {
+    fn from(metadata: &Metadata) -> Self {
+        proto::ObjectMeta {
+            name: metadata.name.clone().unwrap_or_default(),
+            namespace: metadata.namespace.clone().unwrap_or_default(),
+            uid: metadata.uid.clone().unwrap_or_default(),
+            resource_version: metadata.resource_version.clone().unwrap_or_default(),
+            generation: metadata.generation.unwrap_or_default() as i64,
+            deletion_timestamp: metadata
+                .deletion_timestamp
+                .map(|time| timestamp(time.value())),
+            labels: metadata.labels.clone().unwrap_or_default(),
+            annotations: metadata.annotations.clone().unwrap_or_default(),
+            owner_references: metadata
+                .owner_references
+                .iter()
+                .flatten()
+                .map(|owner| proto::OwnerReference {
+                    kind: owner.kind.clone(),
+                    name: owner.name.clone(),
+                    uid: owner.uid.clone(),
+                    api_version: owner.api_version.clone(),
+                    controller: Some(owner.controller),
+                    block_owner_deletion: Some(owner.block_owner_deletion),
+                })
+                .collect(),
+            finalizers: metadata.finalizers.clone().unwrap_or_default(),
+        }
+    }
+}
+
+fn metadata(metadata: Option<proto::ObjectMeta>) -> Result<Metadata, String> {
+    metadata
+        .map(Metadata::try_from)
+        .unwrap_or(Ok(Metadata::default()))
+}
+
+fn env_source(source: Option<proto::EnvSource>) -> Option<LocalObjectReference> {
+    source.map(|source| LocalObjectReference {
+        name: source
+            .local_object_reference
+            .map(|reference| reference.name)
+            .unwrap_or_default(),
+    })
+}
+
+fn to_env_source(reference: &Option<LocalObjectReference>) -> Option<proto::EnvSource> {
+    reference.as_ref().map(|reference| proto::EnvSource {
+        local_object_reference: Some(proto::LocalObjectReference {
+            name: reference.name.clone(),
+        }),
+    })
+}
+
+impl TryFrom<proto::Container> for Container {
+    type Error = String;
+
+    fn try_from(container: proto::Container) -> Result<Self, String> {
+        let ports = container
+            .ports
+            .into_iter()
+            .map(|port| {
+                Ok(ContainerPort {
+                    container_port: port.container_port as u32,
+                    protocol: match port.protocol.as_str() {
+                        "" => Default::default(),
+                        protocol => from_name(protocol)?,
+                    },
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Container {
+            name: container.name,
+            image: container.image,
+            ports: non_empty_vec(ports),
+            env_from: container
+                .env_from
+                .into_iter()
+                .map(|source| EnvFromSource {
+                    config_map_ref: env_source(source.config_map_ref),
+                    secret_ref: env_source(source.secret_ref),
+                })
+                .collect(),
+        })
+    }
+}
+
+impl From<&Container> for proto::Container {
+    fn from(container: &Container) -> Self {
+        proto::Container {
+            name: container.name.clone(),
+            image: container.image.clone(),
+            ports: container
+                .ports
+                .iter()
+                .flatten()
+                .map(|port| proto::ContainerPort {
+                    container_port: port.container_port as i32,
+                    protocol: String::from(port.protocol.as_str()),
+                })
+                .collect(),
+            env_from: container
+                .env_from
+                .iter()
+                .map(|source| proto::EnvFromSource {
+                    config_map_ref: to_env_source(&source.config_map_ref),
+                    secret_ref: to_env_source(&source.secret_ref),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<proto::DeploymentSpec> for DeploymentSpec {
+    type Error = String;
+
+    fn try_from(spec: proto::DeploymentSpec) -> Result<Self, String> {
+        let template = required(spec.template, "spec.template")?;
+        let containers = required(template.spec, "spec.template.spec")?
+            .containers
+            .into_iter()
+            .map(Container::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(DeploymentSpec {
+            replicas: spec.replicas.unwrap_or(1) as u32,
+            template: PodTemplate {
+                metadata: metadata(template.metadata)?,
+                spec: PodSpec { containers },
+            },
+            selector: Selector {
+                match_labels: required(spec.selector, "spec.selector")?.match_labels,
+            },
+            strategy: spec.strategy.map(|strategy| DeploymentStrategy {
+                strategy_type: strategy.r#type,
+                rolling_update: strategy.rolling_update.map(|rolling_update| RollingUpdate {
+                    max_unavailable: rolling_update.max_unavailable.map(int_or_string),
+                }),
+            }),
+            progress_deadline_seconds: spec.progress_deadline_seconds.map(|s| s as u32),
+        })
+    }
+}
+
+impl TryFrom<proto::Deployment> for K8sListObject<Deployment> {
+    type Error = String;
+
+    fn try_from(deployment: proto::Deployment) -> Result<Self, String> {
+        Ok(K8sListObject {
+            metadata: metadata(deployment.metadata)?,
+            object: Deployment {
+                spec: required(deployment.spec, "spec")?.try_into()?,
+                status: deployment.status.map(|status| DeploymentStatus {
+                    observed_generation: (status.observed_generation > 0)
+                        .then_some(status.observed_generation as u64),
+                    updated_replicas: status.updated_replicas as u32,
+                    ready_replicas: status.ready_replicas as u32,
+                    conditions: status
+                        .conditions
+                        .into_iter()
+                        .map(|condition| DeploymentCondition {
+                            condition_type: condition.r#type,
+                            status: condition.status,
+                            reason: non_empty(condition.reason),
+                        })
+                        .collect(),
+                }),
+            },
+        })
+    }
+}
+
+impl From<&K8sListObject<Deployment>> for proto::Deployment {
+    fn from(deployment: &K8sListObject<Deployment>) -> Self {
+        let spec = &deployment.object.spec;
+        proto::Deployment {
+            metadata: Some((&deployment.metadata).into()),
+            spec: Some(proto::DeploymentSpec {
+                replicas: Some(spec.replicas as i32),
+                selector: Some(proto::LabelSelector {
+                    match_labels: spec.selector.match_labels.clone(),
+                }),
+                template: Some(proto::PodTemplateSpec {
+                    metadata: Some((&spec.template.metadata).into()),
+                    spec: Some(proto::PodSpec {
+                        containers: spec
+                            .template
+                            .spec
+                            .containers
+                            .iter()
+                            .map(Into::into)
+                            .collect(),
+                    }),
+                }),
+                strategy: spec
+                    .strategy
+                    .as_ref()
+                    .map(|strategy| proto::DeploymentStrategy {
+                        r#type: strategy.strategy_type.clone(),
+                        rolling_update: strategy.rolling_update.as_ref().map(|rolling_update| {
+                            proto::RollingUpdateDeployment {
+                                max_unavailable: rolling_update
+                                    .max_unavailable
+                                    .as_ref()
+                                    .map(Into::into),
+                            }
+                        }),
+                    }),
+                progress_deadline_seconds: spec.progress_deadline_seconds.map(|s| s as i32),
+            }),
+            status: deployment
+                .object
+                .status
+                .as_ref()
+                .map(|status| proto::DeploymentStatus {
+                    observed_generation: status.observed_generation.unwrap_or_default() as i64,
+                    updated_replicas: status.updated_replicas as i32,
+                    conditions: status
+                        .conditions
+                        .iter()
+                        .map(|condition| proto::DeploymentCondition {
+                            r#type: condition.condition_type.clone(),
+                            status: condition.status.clone(),
+                            reason: condition.reason.clone().unwrap_or_default(),
+                        })
+                        .collect(),
+                    ready_replicas: status.ready_replicas as i32,
+                }),
+        }
+    }
+}
+
+impl TryFrom<proto::ServicePort> for ServicePort {
+    type Error = String;
+
+    fn try_from(port: proto::ServicePort) -> Result<Self, String> {
+        let target_port = match port.target_port.map(int_or_string) {
+            Some(IntOrString::Int(target_port)) => target_port,
+            Some(IntOrString::String(name)) => {
+                return Err(format!("Named target port {} is not supported", name))
+            }
+            None => port.port as u32,
+        };
+        Ok(ServicePort {
+            name: non_empty(port.name),
+            protocol: match port.protocol.as_str() {
+                "" => Default::default(),
+                protocol => from_name(protocol)?,
+            },
+            port: port.port as u32,
+            target_port,
+            node_port: (port.node_port > 0).then_some(port.node_port as u32),
+        })
+    }
+}
+
+impl TryFrom<proto::Service> for K8sListObject<Service> {
+    type Error = String;
+
+    fn try_from(service: proto::Service) -> Result<Self, String> {
+        let spec = required(service.spec, "spec")?;
+        Ok(K8sListObject {
+            metadata: metadata(service.metadata)?,
+            object: Service {
+                spec: ServiceSpec {
+                    service_type: non_empty(spec.r#type)
+                        .map(|service_type| from_name(service_type.as_str()))
+                        .transpose()?,
+                    selector: (!spec.selector.is_empty()).then_some(spec.selector),
+                    ports: spec
+                        .ports
+                        .into_iter()
+                        .map(ServicePort::try_from)
+                        .collect::<Result<Vec<_>, _>>()?,
+                },
+            },
+        })
+    }
+}
+
+impl From<&K8sListObject<Service>> for proto::Service {
+    fn from(service: &K8sListObject<Service>) -> Self {
+        let spec = &service.object.spec;
+        proto::Service {
+            metadata: Some((&service.metadata).into()),
+            spec: Some(proto::ServiceSpec {
+                ports: spec
+                    .ports
+                    .iter()
+                    .map(|port| proto::ServicePort {
+                        name: port.name.clone().unwrap_or_default(),
+                        protocol: String::from(port.protocol.as_str()),
+                        port: port.port as i32,
+                        target_port: Some((&IntOrString::Int(port.target_port)).into()),
+                        node_port: port.node_port.unwrap_or_default() as i32,
+                    })
+                    .collect(),
+                selector: spec.selector.clone().unwrap_or_default(),
+                r#type: spec.service_type.as_ref().map(name_of).unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::Lease> for K8sListObject<Lease> {
+    type Error = String;
+
+    fn try_from(lease: proto::Lease) -> Result<Self, String> {
+        let spec = required(lease.spec, "spec")?;
+        let micro_time = |time: Option<proto::Time>| -> Result<Option<MicroTime>, String> {
+            Ok(time.map(datetime).transpose()?.map(MicroTime::new))
+        };
+        Ok(K8sListObject {
+            metadata: metadata(lease.metadata)?,
+            object: Lease {
+                spec: LeaseSpec {
+                    lease_duration_seconds: required(
+                        spec.lease_duration_seconds,
+                        "spec.leaseDurationSeconds",
+                    )? as u32,
+                    holder_identity: spec.holder_identity,
+                    acquire_time: micro_time(spec.acquire_time)?,
+                    renew_time: micro_time(spec.renew_time)?,
+                },
+            },
+        })
+    }
+}
+
+impl From<&K8sListObject<Lease>> for proto::Lease {
+    fn from(lease: &K8sListObject<Lease>) -> Self {
+        let spec = &lease.object.spec;
+        proto::Lease {
+            metadata: Some((&lease.metadata).into()),
+            spec: Some(proto::LeaseSpec {
+                holder_identity: spec.holder_identity.clone(),
+                lease_duration_seconds: Some(spec.lease_duration_seconds as i32),
+                acquire_time: spec.acquire_time.map(|time| timestamp(time.value())),
+                renew_time: spec.renew_time.map(|time| timestamp(time.value())),
+            }),
+        }
+    }
+}
+
+fn object_reference(reference: proto::ObjectReference) -> ObjectReference {
+    ObjectReference {
+        api_version: reference.api_version,
+        kind: reference.kind,
+        name: reference.name,
+        namespace: reference.namespace,
+        uid: reference.uid,
+    }
+}
+
+fn to_object_reference(reference: &ObjectReference) -> proto::ObjectReference {
+    proto::ObjectReference {
+        kind: reference.kind.clone(),
+        namespace: reference.namespace.clone(),
+        name: reference.name.clone(),
+        uid: reference.uid.clone(),
+        api_version: reference.api_version.clone(),
+    }
+}
+
+impl TryFrom<proto::Event> for K8sListObject<Event> {
+    type Error = String;
+
+    fn try_from(event: proto::Event) -> Result<Self, String> {
+        Ok(K8sListObject {
+            metadata: metadata(event.metadata)?,
+            object: Event {
+                event_time: MicroTime::new(datetime(required(event.event_time, "eventTime")?)?),
+                action: event.action,
+                note: non_empty(event.note),
+                reason: event.reason,
+                regarding: event.regarding.map(object_reference),
+                related: event.related.map(object_reference),
+                reporting_controller: event.reporting_controller,
+                reporting_instance: event.reporting_instance,
+                event_type: from_name(event.r#type.as_str())?,
+            },
+        })
+    }
+}
+
+impl From<&K8sListObject<Event>> for proto::Event {
+    fn from(event: &K8sListObject<Event>) -> Self {
+        let object = &event.object;
+        proto::Event {
+            metadata: Some((&event.metadata).into()),
+            event_time: Some(timestamp(object.event_time.value())),
+            reporting_controller: object.reporting_controller.clone(),
+            reporting_instance: object.reporting_instance.clone(),
+            action: object.action.clone(),
+            reason: object.reason.clone(),
+            regarding: object.regarding.as_ref().map(to_object_reference),
+            related: object.related.as_ref().map(to_object_reference),
+            note: object.note.clone().unwrap_or_default(),
+            r#type: name_of(&object.event_type),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::encoding::encode_varint;
+    use serde_json::json;
+
+    // Recorded from fake-api-server, see benches/decode.rs
+    fn payload(name: &str) -> Vec<u8> {
+        let path = format!("{}/benches/payloads/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::read(path).expect("Payload not found")
+    }
+
+    fn json_value<T: Serialize>(value: &T) -> Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    // Every field of the decoded items, reported by name when they differ
+    fn assert_same_fields(protobuf: Value, json: Value, path: &str) {
+        match (protobuf, json) {
+            (Value::Object(protobuf), Value::Object(json)) => {
+                let mut fields: Vec<&String> = protobuf.keys().chain(json.keys()).collect();
+                fields.sort();
+                fields.dedup();
+                for field in fields {
+                    assert_same_fields(
+                        protobuf.get(field).cloned().unwrap_or(Value::Null),
+                        json.get(field).cloned().unwrap_or(Value::Null),
+                        format!("{}.{}", path, field).as_str(),
+                    );
+                }
+            }
+            (Value::Array(protobuf), Value::Array(json)) => {
+                assert_eq!(protobuf.len(), json.len(), "{} length", path);
+                for (i, (protobuf, json)) in protobuf.into_iter().zip(json).enumerate() {
+                    assert_same_fields(protobuf, json, format!("{}[{}]", path, i).as_str());
+                }
+            }
+            (protobuf, json) => assert_eq!(protobuf, json, "{}", path),
+        }
+    }
+
+    fn assert_list_matches<T: Protobuf + Serialize + DeserializeOwned>(name: &str) {
+        let protobuf = decode_list(
+            payload(format!("{}.pb", name).as_str()).as_slice(),
+            T::decoder().unwrap(),
+        )
+        .unwrap();
+        let json: List<K8sListObject<T>> =
+            serde_json::from_slice(payload(format!("{}.json", name).as_str()).as_slice()).unwrap();
+        assert!(!json.items.is_empty());
+        assert_eq!(
+            protobuf.metadata.resource_version,
+            json.metadata.resource_version
+        );
+        assert_same_fields(
+            json_value(&protobuf.items),
+            json_value(&json.items),
+            "items",
+        );
+    }
+
+    fn assert_watch_matches<T: Protobuf + Serialize + DeserializeOwned>(name: &str) {
+        let mut frames = Frames::default();
+        frames.push(payload(format!("{}-watch.pb", name).as_str()).as_slice());
+        let mut protobuf = Vec::new();
+        while let Some(frame) = frames.next_frame().unwrap() {
+            protobuf.push(decode_watch_event(frame.as_slice(), T::decoder().unwrap()).unwrap());
+        }
+        let json = payload(format!("{}-watch.jsonl", name).as_str());
+        let json: Vec<Watch<K8sListObject<T>>> = json
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert!(!json.is_empty());
+        assert_same_fields(json_value(&protobuf), json_value(&json), "events");
+    }
+
+    fn deployment() -> K8sListObject<Deployment> {
+        let list: List<K8sListObject<Deployment>> =
+            serde_json::from_slice(payload("deployments.json").as_slice()).unwrap();
+        list.items.into_iter().next().unwrap()
+    }
+
+    fn max_unavailable(deployment: &K8sListObject<Deployment>) -> Option<Value> {
+        deployment
+            .object
+            .spec
+            .strategy
+            .as_ref()
+            .and_then(|strategy| strategy.rolling_update.as_ref())
+            .and_then(|rolling_update| rolling_update.max_unavailable.as_ref())
+            .map(json_value)
+    }
+
+    fn round_trip(deployment: &K8sListObject<Deployment>) -> K8sListObject<Deployment> {
+        let message = proto::Deployment::from(deployment).encode_to_vec();
+        decode::<proto::Deployment, Deployment>(message.as_slice()).unwrap()
+    }
+
+    fn with_max_unavailable(max_unavailable: Option<IntOrString>) -> K8sListObject<Deployment> {
+        let mut deployment = deployment();
+        let strategy = deployment.object.spec.strategy.as_mut().unwrap();
+        strategy.rolling_update.as_mut().unwrap().max_unavailable = max_unavailable;
+        deployment
+    }
+
+    #[test]
+    fn deployment_list_matches_json() {
+        assert_list_matches::<Deployment>("deployments");
+    }
+
+    #[test]
+    fn service_list_matches_json() {
+        assert_list_matches::<Service>("services");
+    }
+
+    #[test]
+    fn deployment_watch_matches_json() {
+        assert_watch_matches::<Deployment>("deployments");
+    }
+
+    #[test]
+    fn service_watch_matches_json() {
+        assert_watch_matches::<Service>("services");
+    }
+
+    #[test]
+    fn envelope_is_recognized_by_its_magic() {
+        let message = proto::Deployment::from(&deployment()).encode_to_vec();
+        let enveloped = envelope(Deployment::API_VERSION, Deployment::KIND, message.clone());
+        assert!(enveloped.starts_with(b"k8s\0"));
+        assert_eq!(unwrap(enveloped.as_slice()).unwrap(), message);
+        // Watch frames are not enveloped
+        assert_eq!(unwrap(message.as_slice()).unwrap(), message);
+        // Without the NUL byte it is not the magic, the body is returned as is
+        assert!(unwrap(b"k8s").is_ok());
+        let invalid = [MAGIC, &[0x0a, 0x05, 0x01]].concat();
+        assert!(unwrap(invalid.as_slice())
+            .unwrap_err()
+            .starts_with("Invalid envelope"));
+        let gzipped = proto::Unknown {
+            raw: message,
+            content_encoding: String::from("gzip"),
+            ..proto::Unknown::default()
+        };
+        let gzipped = [MAGIC, gzipped.encode_to_vec().as_slice()].concat();
+        assert_eq!(
+            unwrap(gzipped.as_slice()).unwrap_err(),
+            "Unsupported content encoding gzip"
+        );
+    }
+
+    #[test]
+    fn json_body_is_not_a_list() {
+        let json = payload("deployments.json");
+        assert!(decode_list(json.as_slice(), Deployment::decoder().unwrap()).is_err());
+    }
+
+    #[test]
+    fn frames_wait_for_the_whole_frame() {
+        let stream = payload("services-watch.pb");
+        let mut chunked = Frames::default();
+        let mut frames = 0;
+        // One byte at a time, the header and the event are both truncated on the way
+        for byte in stream.iter() {
+            chunked.push(&[*byte]);
+            while let Some(frame) = chunked.next_frame().unwrap() {
+                decode_watch_event(frame.as_slice(), Service::decoder().unwrap()).unwrap();
+                frames += 1;
+            }
+        }
+        assert_eq!(frames, 40);
+        assert_eq!(chunked.next_frame().unwrap(), None);
+
+        let mut truncated = Frames::default();
+        truncated.push(&stream[..3]);
+        assert_eq!(truncated.next_frame().unwrap(), None);
+        truncated.push(&stream[3..10]);
+        assert_eq!(truncated.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_frame_is_an_error() {
+        let mut frames = Frames::default();
+        frames.push(&((MAX_FRAME + 1) as u32).to_be_bytes());
+        assert!(frames.next_frame().unwrap_err().contains("exceeds"));
+        let mut frames = Frames::default();
+        frames.push(&(MAX_FRAME as u32).to_be_bytes());
+        assert_eq!(frames.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_event_is_an_error() {
+        let mut frames = Frames::default();
+        frames.push(payload("deployments-watch.pb").as_slice());
+        let frame = frames.next_frame().unwrap().unwrap();
+        let truncated = &frame[..frame.len() - 10];
+        assert!(decode_watch_event(truncated, Deployment::decoder().unwrap()).is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let deployment = deployment();
+        let message = proto::Deployment::from(&deployment);
+        // managedFields (17) in the metadata, then unknown varint (101) and bytes (100) fields
+        let mut metadata = message.metadata.clone().unwrap().encode_to_vec();
+        metadata.extend_from_slice(&[0x8a, 0x01, 0x03, b'a', b'b', b'c']);
+        let mut raw = vec![0x0a];
+        encode_varint(metadata.len() as u64, &mut raw);
+        raw.extend_from_slice(metadata.as_slice());
+        raw.extend(
+            proto::Deployment {
+                metadata: None,
+                ..message
+            }
+            .encode_to_vec(),
+        );
+        raw.extend_from_slice(&[0xa8, 0x06, 0x96, 0x01, 0xa2, 0x06, 0x02, 0x08, 0x01]);
+        let decoded = decode::<proto::Deployment, Deployment>(raw.as_slice()).unwrap();
+        assert_same_fields(json_value(&decoded), json_value(&deployment), "deployment");
+    }
+
+    #[test]
+    fn int_or_string_keeps_its_type() {
+        let int = proto::IntOrString {
+            int_val: 1,
+            ..proto::IntOrString::default()
+        };
+        assert_eq!(json_value(&int_or_string(int)), json!(1));
+        let string = proto::IntOrString {
+            r#type: 1,
+            str_val: String::from("25%"),
+            ..proto::IntOrString::default()
+        };
+        assert_eq!(json_value(&int_or_string(string)), json!("25%"));
+        assert_eq!(
+            proto::IntOrString::from(&IntOrString::String(String::from("50%"))),
+            proto::IntOrString {
+                r#type: 1,
+                int_val: 0,
+                str_val: String::from("50%"),
+            }
+        );
+        assert_eq!(
+            proto::IntOrString::from(&IntOrString::Int(2)),
+            proto::IntOrString {
+                r#type: 0,
+                int_val: 2,
+                str_val: String::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn max_unavailable_round_trips() {
+        let recorded = deployment();
+        assert_eq!(max_unavailable(&recorded), Some(json!("25%")));
+        assert_eq!(max_unavailable(&round_trip(&recorded)), Some(json!("25%")));
+        // Encoded as an empty message, still present once decoded
+        let zero = with_max_unavailable(Some(IntOrString::Int(0)));
+        assert_eq!(max_unavailable(&round_trip(&zero)), Some(json!(0)));
+        let one = with_max_unavailable(Some(IntOrString::Int(1)));
+        assert_eq!(max_unavailable(&round_trip(&one)), Some(json!(1)));
+        let unset = with_max_unavailable(None);
+        assert_eq!(max_unavailable(&round_trip(&unset)), None);
+    }
+
+    #[test]
+    fn named_target_port_is_rejected() {
+        let port = proto::ServicePort {
+            name: String::from("http"),
+            port: 80,
+            target_port: Some(proto::IntOrString {
+                r#type: 1,
+                str_val: String::from("http"),
+                ..proto::IntOrString::default()
+            }),
+            ..proto::ServicePort::default()
+        };
+        assert_eq!(
+            ServicePort::try_from(port.clone()).err().unwrap(),
+            "Named target port http is not supported"
+        );
+        let defaulted = ServicePort::try_from(proto::ServicePort {
+            target_port: None,
+            ..port
+        })
+        .unwrap();
+        assert_eq!(defaulted.target_port, 80);
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Instant};
+
+/*
+   Token bucket, refilled with `qps` tokens per second up to `burst` tokens.
+   Every request takes a token, callers that find the bucket empty reserve
+   a future token and sleep until it is available, so waiting requests are served in order.
+*/
+pub struct RateLimiter {
+    qps: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(qps: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            qps,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        if self.qps <= 0.0 {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.qps).min(self.burst);
+            bucket.last_refill = now;
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64(-bucket.tokens / self.qps)
+            }
+        };
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // Time is paused, sleeps advance the clock instantly
+    async fn elapsed(limiter: &RateLimiter, requests: usize) -> Duration {
+        let start = Instant::now();
+        for _ in 0..requests {
+            limiter.acquire().await;
+        }
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_served_without_waiting() {
+        let limiter = RateLimiter::new(10.0, 5);
+        assert_eq!(elapsed(&limiter, 5).await, Duration::ZERO);
+        assert_eq!(elapsed(&limiter, 1).await, Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_beyond_the_burst_are_spaced_by_qps() {
+        let limiter = RateLimiter::new(20.0, 1);
+        assert_eq!(elapsed(&limiter, 11).await, Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_up_to_the_burst() {
+        let limiter = RateLimiter::new(10.0, 3);
+        elapsed(&limiter, 3).await;
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(elapsed(&limiter, 3).await, Duration::ZERO);
+        assert_eq!(elapsed(&limiter, 1).await, Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_requests_reserve_consecutive_tokens() {
+        let limiter = Arc::new(RateLimiter::new(10.0, 1));
+        limiter.acquire().await;
+        let start = Instant::now();
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                tokio::spawn(async move {
+                    limiter.acquire().await;
+                    start.elapsed()
+                })
+            })
+            .collect();
+        let mut served = Vec::new();
+        for task in waiting {
+            served.push(task.await.unwrap());
+        }
+        served.sort();
+        assert_eq!(served, [100, 200, 300].map(Duration::from_millis).to_vec());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_qps_disables_the_limit() {
+        let limiter = RateLimiter::new(0.0, 1);
+        assert_eq!(elapsed(&limiter, 1000).await, Duration::ZERO);
+    }
+}
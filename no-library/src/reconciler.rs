@@ -1,31 +1,115 @@
 use crate::cache::{Cache, CacheEntry, NamespacedName};
+use crate::canary::{
+    canary_strategy, image, plan, progress_deadline_exceeded, RolloutInput, RolloutPlan,
+    ABORT_ANNOTATION, PROMOTE_ANNOTATION, TRACK_LABEL,
+};
+use crate::children::Children;
+use crate::config::Config;
+use crate::controller::{Action, Reconcile};
+use crate::diff::diff;
+use crate::discovery::with_query;
 use crate::k8s_client::client::{K8sClient, K8sClientError};
-use crate::k8s_types::EventType::Normal;
+use crate::k8s_time::MicroTime;
+use crate::k8s_types::EventType::{Normal, Warning};
 use crate::k8s_types::{
-    Container, ContainerPort, Deployment, DeploymentSpec, Event, EventType, ExposedApp,
-    ExposedAppStatus, K8sObject, Metadata, ObjectReference, OwnerReference, PodSpec, PodTemplate,
-    Selector, Service, ServicePort, ServiceSpec,
+    CanaryStatus, Condition, ConfigMap, ConfigMapMetadata, Container, ContainerPort, DeleteOptions,
+    Deployment, DeploymentSpec, DeploymentStrategy, Event, EventType, ExposedApp, ExposedAppStatus,
+    IntOrString, K8sObject, Metadata, ObjectReference, OwnerReference, Pod, PodSpec, PodTemplate,
+    ReconcilePhase, Resource, RollingUpdate, Secret, SecretMetadata, Selector, Service,
+    ServicePort, ServiceSpec, ServiceType,
 };
-use crate::offset_date_time_parser::format;
+use crate::outcome::{failed, failed_in, succeeded, Failure, STALLED};
+use crate::pause::{pause, resumed, Pause, PAUSED};
+use crate::pod_health::{summarize, PODS_HEALTHY};
+use crate::references::{Reference, SharedReferenceIndex, REFERENCED_LABEL};
+use crate::sharding::{Sharding, OWNER_ANNOTATION};
+use crate::telemetry::{traceparent, TRACEPARENT_ANNOTATION};
 use rand::distr::{Alphanumeric, SampleString};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, to_value, to_vec, Value};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use time::OffsetDateTime;
-use tracing::info;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, Span};
 
 pub struct Reconciler {
     client: K8sClient,
     cache: Cache,
+    children: Children,
     pod_name: String,
+    dry_run: bool,
+    // Children of every ExposedApp are left untouched
+    paused: bool,
+    // Consecutive failures after which an ExposedApp is Stalled
+    stall_after: u32,
+    sharding: Option<Arc<Sharding>>,
+    references: SharedReferenceIndex,
+    // Last PodsHealthy condition change per ExposedApp
+    pod_condition_updates: HashMap<NamespacedName, Instant>,
+}
+
+pub const INSTANCE_LABEL: &str = "app.kubernetes.io/instance";
+// Content hash of the referenced ConfigMaps and Secrets, a change rolls the Deployment
+const CONFIG_HASH_ANNOTATION: &str = "no-library.com/config-hash";
+const DEPLOYMENT_SUFFIX: &str = "-deployment";
+// A flapping Pod changes the PodsHealthy condition at most once per interval
+const POD_CONDITION_INTERVAL: Duration = Duration::from_secs(15);
+
+pub fn deployment_name(exposed_app: &str) -> String {
+    format!("{}{}", exposed_app, DEPLOYMENT_SUFFIX)
+}
+
+// Its pods carry the instance label of the stable Deployment, so the Service selects both
+fn canary_deployment_name(exposed_app: &str) -> String {
+    format!("{}-canary{}", exposed_app, DEPLOYMENT_SUFFIX)
+}
+
+// Inverse of deployment_name, Pods are labelled with their Deployment name
+pub fn exposed_app_name(deployment: &str) -> Option<&str> {
+    deployment.strip_suffix(DEPLOYMENT_SUFFIX)
 }
 
 type PodLabels = HashMap<String, String>;
 
+// Status of an ExposedApp reconciled for the first time
+fn initial_status(exposed_app: &str) -> ExposedAppStatus {
+    ExposedAppStatus {
+        deployment_name: deployment_name(exposed_app),
+        service_name: format!("{}-service", exposed_app),
+        conditions: Vec::new(),
+        canary: None,
+        last_reconcile_time: None,
+        last_error: None,
+        next_retry_time: None,
+    }
+}
+
 impl Reconciler {
-    pub fn new(client: K8sClient, cache: Cache, pod_name: String) -> Self {
+    pub fn new(
+        client: K8sClient,
+        cache: Cache,
+        children: Children,
+        config: &Config,
+        sharding: Option<Arc<Sharding>>,
+        references: SharedReferenceIndex,
+    ) -> Self {
         Reconciler {
-            client,
+            client: if config.dry_run {
+                client.dry_run()
+            } else {
+                client
+            },
             cache,
-            pod_name,
+            children,
+            pod_name: config.pod_name.clone(),
+            dry_run: config.dry_run,
+            paused: config.paused,
+            stall_after: config.stall_after,
+            sharding,
+            references,
+            pod_condition_updates: HashMap::new(),
         }
     }
 
@@ -49,6 +133,82 @@ impl Reconciler {
         }
     }
 
+    // The same container port may be exposed by many Service ports
+    fn container_ports(resource: &K8sObject<ExposedApp>) -> Vec<ContainerPort> {
+        let mut ports: Vec<ContainerPort> = Vec::new();
+        for port in &resource.object.spec.ports {
+            if !ports
+                .iter()
+                .any(|p| p.container_port == port.container_port && p.protocol == port.protocol)
+            {
+                ports.push(ContainerPort {
+                    container_port: port.container_port,
+                    protocol: port.protocol,
+                });
+            }
+        }
+        ports
+    }
+
+    // Node ports of other Service types are rejected, they are dropped when the type changes
+    fn allocates_node_ports(service_type: Option<ServiceType>) -> bool {
+        matches!(
+            service_type,
+            Some(ServiceType::NodePort) | Some(ServiceType::LoadBalancer)
+        )
+    }
+
+    fn service_ports(resource: &K8sObject<ExposedApp>) -> Vec<ServicePort> {
+        let ports = &resource.object.spec.ports;
+        let node_ports = Self::allocates_node_ports(resource.object.spec.service_type);
+        ports
+            .iter()
+            .map(|port| ServicePort {
+                // Service ports have to be named when there is more than one
+                name: port.name.clone().or_else(|| {
+                    (ports.len() > 1)
+                        .then(|| format!("{}-{}", port.protocol.as_str(), port.port).to_lowercase())
+                }),
+                protocol: port.protocol,
+                port: port.port,
+                target_port: port.container_port,
+                node_port: port.node_port.filter(|_| node_ports),
+            })
+            .collect()
+    }
+
+    /*
+       Dry-run only: compares the live child with the object returned by the dryRun request,
+       i.e. what the API server would store after defaulting and admission.
+    */
+    async fn report_diff<T: Serialize + DeserializeOwned>(
+        &mut self,
+        uri: &str,
+        dry_run_result: &K8sObject<T>,
+    ) {
+        let kind = dry_run_result.kind.as_str();
+        let name = dry_run_result.metadata.name.clone().unwrap_or_default();
+        let live = match self.client.get::<T>(uri).await {
+            Ok(live) => to_value(&live).unwrap(),
+            Err(K8sClientError::NotFound) => json!({}),
+            Err(e) => {
+                error!("Unable to get {} {} to diff: {:?}", kind, name, e);
+                return;
+            }
+        };
+        let changes = diff(&live, &to_value(dry_run_result).unwrap());
+        if changes.is_empty() {
+            info!("[dry-run] {} {} unchanged", kind, name);
+        } else {
+            info!(
+                "[dry-run] {} {} would change:\n{}",
+                kind,
+                name,
+                changes.join("\n")
+            );
+        }
+    }
+
     fn random_str(len: usize) -> String {
         Alphanumeric.sample_string(&mut rand::rng(), len)
     }
@@ -66,17 +226,19 @@ impl Reconciler {
         let namespace = resource.metadata.namespace.clone().unwrap();
         let suffix = Self::random_str(10).to_lowercase();
         let name = format!("{}-{}", resource_name, suffix);
-        let event_time = format(OffsetDateTime::now_utc()).unwrap();
         let event = K8sObject {
-            api_version: String::from("events.k8s.io/v1"),
+            api_version: String::from(Event::API_VERSION),
             kind: String::from("Event"),
             metadata: Metadata {
                 name: Some(name),
                 namespace: Some(namespace.clone()),
+                annotations: traceparent().map(|traceparent| {
+                    HashMap::from([(String::from(TRACEPARENT_ANNOTATION), traceparent)])
+                }),
                 ..Metadata::default()
             },
             object: Event {
-                event_time,
+                event_time: MicroTime::now(),
                 action: String::from(action),
                 note: Some(String::from(note)),
                 reason: String::from(reason),
@@ -90,20 +252,94 @@ impl Reconciler {
         self.client.post_event(namespace.as_str(), &event).await
     }
 
-    async fn save_deployment(
-        &mut self,
+    // Missing objects are hashed too, so creating them rolls the Deployment as well
+    async fn config_hash(&mut self, references: &[Reference]) -> Result<Option<String>, String> {
+        if references.is_empty() {
+            return Ok(None);
+        }
+        let mut hasher = Sha256::new();
+        for reference in references {
+            let uri = reference.uri(&self.client);
+            let content = match reference {
+                Reference::ConfigMap(_) => {
+                    self.client
+                        .get::<ConfigMap>(uri.as_str())
+                        .await
+                        .map(|config_map| {
+                            let content =
+                                json!([config_map.object.data, config_map.object.binary_data]);
+                            (config_map.metadata, content)
+                        })
+                }
+                Reference::Secret(_) => self
+                    .client
+                    .get::<Secret>(uri.as_str())
+                    .await
+                    .map(|secret| (secret.metadata, json!(secret.object.data))),
+            };
+            let content = match content {
+                Ok((metadata, content)) => {
+                    self.stamp(reference, &metadata).await?;
+                    content
+                }
+                Err(K8sClientError::NotFound) => Value::Null,
+                Err(e) => return Err(format!("Unable to get {}: {:?}", uri, e)),
+            };
+            hasher.update(to_vec(&json!([uri, content])).unwrap());
+        }
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+
+    // Only labelled objects are watched, see REFERENCED_LABEL
+    async fn stamp(&mut self, reference: &Reference, metadata: &Metadata) -> Result<(), String> {
+        let stamped = metadata
+            .labels
+            .as_ref()
+            .is_some_and(|labels| labels.contains_key(REFERENCED_LABEL));
+        if stamped {
+            return Ok(());
+        }
+        let patch = json!({"metadata": {"labels": {REFERENCED_LABEL: "true"}}});
+        let result = match reference {
+            Reference::ConfigMap(name) => self
+                .client
+                .merge_patch::<ConfigMapMetadata>(
+                    name.namespace.as_str(),
+                    name.name.as_str(),
+                    &patch,
+                )
+                .await
+                .map(|_| ()),
+            Reference::Secret(name) => self
+                .client
+                .merge_patch::<SecretMetadata>(name.namespace.as_str(), name.name.as_str(), &patch)
+                .await
+                .map(|_| ()),
+        };
+        result.map_err(|e| format!("Unable to label {}: {:?}", reference.uri(&self.client), e))
+    }
+
+    fn deployment(
+        resource: &K8sObject<ExposedApp>,
         name: &str,
-        namespace: &str,
         pod_labels: &PodLabels,
-        resource: &K8sObject<ExposedApp>,
-    ) -> Result<K8sObject<Deployment>, String> {
-        let deployment = K8sObject {
-            api_version: String::from("apps/v1"),
-            kind: String::from("Deployment"),
-            metadata: Self::metadata(name, namespace, resource),
+        image: &str,
+        replicas: u32,
+        config_hash: Option<String>,
+    ) -> K8sObject<Deployment> {
+        let namespace = resource.metadata.namespace.clone().unwrap();
+        let strategy = canary_strategy(&resource.object.spec);
+        K8sObject {
+            api_version: String::from(Deployment::API_VERSION),
+            kind: String::from(Deployment::KIND),
+            // Same labels as the pods, canary Deployments are deleted by selector
+            metadata: Metadata {
+                labels: Some(pod_labels.clone()),
+                ..Self::metadata(name, namespace.as_str(), resource)
+            },
             object: Deployment {
                 spec: DeploymentSpec {
-                    replicas: resource.object.spec.replicas,
+                    replicas,
                     selector: {
                         Selector {
                             match_labels: pod_labels.clone(),
@@ -112,28 +348,62 @@ impl Reconciler {
                     template: PodTemplate {
                         metadata: Metadata {
                             labels: Some(pod_labels.clone()),
+                            annotations: config_hash.map(|hash| {
+                                HashMap::from([(String::from(CONFIG_HASH_ANNOTATION), hash)])
+                            }),
                             name: Some(String::from(name)),
                             ..Metadata::default()
                         },
                         spec: PodSpec {
                             containers: vec![Container {
                                 name: String::from("main"),
-                                image: resource.object.spec.image.clone(),
-                                ports: Some(vec![ContainerPort {
-                                    container_port: resource.object.spec.container_port,
-                                }]),
+                                image: String::from(image),
+                                ports: Some(Self::container_ports(resource)),
+                                env_from: resource.object.spec.env_from.clone(),
                             }],
                         },
                     },
+                    strategy: strategy.and_then(|strategy| strategy.max_unavailable).map(
+                        |max_unavailable| DeploymentStrategy {
+                            strategy_type: String::from("RollingUpdate"),
+                            rolling_update: Some(RollingUpdate {
+                                max_unavailable: Some(IntOrString::Int(max_unavailable)),
+                            }),
+                        },
+                    ),
+                    progress_deadline_seconds: strategy
+                        .and_then(|strategy| strategy.progress_deadline_seconds),
                 },
+                status: None,
             },
-        };
+        }
+    }
+
+    async fn save_deployment(
+        &mut self,
+        deployment: &K8sObject<Deployment>,
+    ) -> Result<K8sObject<Deployment>, String> {
+        let name = deployment.metadata.name.clone().unwrap();
+        let namespace = deployment.metadata.namespace.clone().unwrap();
+        if self.dry_run {
+            let result = self
+                .client
+                .save_deployment(deployment)
+                .await
+                .map_err(|e| format!("Dry-run of deployment failed: {:?}", e))?;
+            let uri = self
+                .client
+                .api_resource::<Deployment>()
+                .object_uri(namespace.as_str(), name.as_str());
+            self.report_diff(uri.as_str(), &result).await;
+            return Ok(result);
+        }
         let mut map = self.cache.lock().await;
-        match self.client.save_deployment(&deployment).await {
+        match self.client.save_deployment(deployment).await {
             Ok(result) => {
                 info!("Deployment created/updated");
                 map.insert(
-                    NamespacedName::new(name, namespace),
+                    NamespacedName::new(name.as_str(), namespace.as_str()),
                     CacheEntry::new(
                         result.metadata.resource_version.clone().unwrap().as_str(),
                         result.metadata.generation.unwrap(),
@@ -153,22 +423,30 @@ impl Reconciler {
         resource: &K8sObject<ExposedApp>,
     ) -> Result<K8sObject<Service>, String> {
         let service = K8sObject {
-            api_version: String::from("v1"),
-            kind: String::from("Service"),
+            api_version: String::from(Service::API_VERSION),
+            kind: String::from(Service::KIND),
             metadata: Self::metadata(name, namespace, resource),
             object: Service {
                 spec: ServiceSpec {
-                    service_type: resource.object.spec.service_type.clone(),
+                    service_type: resource.object.spec.service_type,
                     selector: Some(pod_labels.clone()),
-                    ports: vec![ServicePort {
-                        protocol: resource.object.spec.protocol.clone(),
-                        port: resource.object.spec.port,
-                        target_port: resource.object.spec.container_port,
-                        node_port: resource.object.spec.node_port,
-                    }],
+                    ports: Self::service_ports(resource),
                 },
             },
         };
+        if self.dry_run {
+            let result = self
+                .client
+                .put_service(&service)
+                .await
+                .map_err(|e| format!("Dry-run of service failed: {:?}", e))?;
+            let uri = self
+                .client
+                .api_resource::<Service>()
+                .object_uri(namespace, name);
+            self.report_diff(uri.as_str(), &result).await;
+            return Ok(result);
+        }
         let mut map = self.cache.lock().await;
         /*
            Service does not provide generation,
@@ -189,47 +467,336 @@ impl Reconciler {
         }
     }
 
+    /*
+       A Service switched to a type without node ports would keep the allocated ones on a plain
+       update, the API server then rejects it. They are cleared explicitly, returns the note
+       reported once the Service is saved.
+    */
+    async fn released_node_ports(
+        &mut self,
+        name: &str,
+        namespace: &str,
+        resource: &K8sObject<ExposedApp>,
+    ) -> Result<Option<String>, String> {
+        let service_type = resource.object.spec.service_type;
+        if Self::allocates_node_ports(service_type) {
+            return Ok(None);
+        }
+        let uri = self
+            .client
+            .api_resource::<Service>()
+            .object_uri(namespace, name);
+        let live = match self.client.get::<Service>(uri.as_str()).await {
+            Ok(live) => live,
+            Err(K8sClientError::NotFound) => return Ok(None),
+            Err(e) => return Err(format!("Unable to get Service {}: {:?}", name, e)),
+        };
+        let node_ports: Vec<String> = live
+            .object
+            .spec
+            .ports
+            .iter()
+            .filter_map(|port| port.node_port)
+            .map(|node_port| node_port.to_string())
+            .collect();
+        if node_ports.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(format!(
+            "Service {} changed from {:?} to {:?}, node ports {} released",
+            name,
+            live.object
+                .spec
+                .service_type
+                .unwrap_or(ServiceType::ClusterIP),
+            service_type.unwrap_or(ServiceType::ClusterIP),
+            node_ports.join(", ")
+        )))
+    }
+
+    /*
+       Deletes the children of the ExposedApp that were not provisioned in this pass,
+       e.g. left behind by a naming change. Children come from the watch store,
+       only the kinds the operator provisions are touched.
+    */
+    async fn prune_children(
+        &mut self,
+        resource: &K8sObject<ExposedApp>,
+        desired: &[(&str, &str)],
+    ) -> Result<(), String> {
+        let uid = resource.metadata.uid.clone().unwrap_or_default();
+        for child in self.children.of(uid.as_str()) {
+            let (kind, name, namespace) = (
+                child.kind.as_str(),
+                child.name.as_str(),
+                child.namespace.as_str(),
+            );
+            if desired.contains(&(kind, name)) {
+                continue;
+            }
+            let uri = match kind {
+                Deployment::KIND => self.client.api_resource::<Deployment>(),
+                Service::KIND => self.client.api_resource::<Service>(),
+                _ => continue,
+            }
+            .object_uri(namespace, name);
+            if self.dry_run {
+                info!(
+                    "[dry-run] {} {} would be deleted, it is no longer provisioned",
+                    kind, name
+                );
+                continue;
+            }
+            // The store lags behind, the name may have been taken by an object it has not seen
+            let options = DeleteOptions::with_uid(child.uid.as_str());
+            match self.client.delete(uri.as_str(), &options).await {
+                Ok(()) => info!("Orphaned {} {} deleted", kind, name),
+                // e.g. a canary deleted earlier in this pass
+                Err(K8sClientError::NotFound) => continue,
+                Err(K8sClientError::Conflict) => {
+                    info!("{} {} was recreated, it is not pruned", kind, name);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "Error occurred while deleting orphaned {} {}: {:?}",
+                        kind, name, e
+                    ))
+                }
+            }
+            let note = format!("{} {} deleted, it is no longer provisioned", kind, name);
+            if let Err(e) = self
+                .send_event(
+                    resource,
+                    &child,
+                    Normal,
+                    "OrphanDeleted",
+                    note.as_str(),
+                    "Pruned",
+                )
+                .await
+            {
+                error!("Unable to send Pruned event: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    // Saves the Deployment and reports it, returns the reference to relate further events to
+    async fn provision_deployment(
+        &mut self,
+        resource: &K8sObject<ExposedApp>,
+        deployment: &K8sObject<Deployment>,
+    ) -> Result<Option<ObjectReference>, String> {
+        let deployment = self.save_deployment(deployment).await?;
+        if self.dry_run {
+            return Ok(None);
+        }
+        let note = format!(
+            "Deployment {} provisioned successfully with {} replicas",
+            deployment.metadata.name.clone().unwrap(),
+            deployment.object.spec.replicas
+        );
+        let reference = ObjectReference::from(&deployment);
+        self.send_event(
+            resource,
+            &reference,
+            Normal,
+            "DeploymentProvisioned",
+            note.as_str(),
+            "ProvisioningRequested",
+        )
+        .await
+        .unwrap();
+        Ok(Some(reference))
+    }
+
+    async fn get_deployment(&mut self, uri: &str) -> Result<Option<K8sObject<Deployment>>, String> {
+        match self.client.get::<Deployment>(uri).await {
+            Ok(deployment) => Ok(Some(deployment)),
+            Err(K8sClientError::NotFound) => Ok(None),
+            Err(e) => Err(format!("Unable to get {}: {:?}", uri, e)),
+        }
+    }
+
+    /*
+       Gathers what the canary rollout depends on: the live Deployments, the health of the
+       canary pods and the promote/abort annotations, which are removed once read.
+    */
+    async fn plan_rollout(
+        &mut self,
+        resource: &mut K8sObject<ExposedApp>,
+        deployment_name: &str,
+    ) -> Result<RolloutPlan, String> {
+        if canary_strategy(&resource.object.spec).is_none() {
+            return Ok(plan(RolloutInput {
+                spec: &resource.object.spec,
+                previous: None,
+                live_stable_image: None,
+                canary: None,
+                canary_failure: None,
+                promote: None,
+                abort: false,
+            }));
+        }
+        let name = resource.metadata.name.clone().unwrap();
+        let namespace = resource.metadata.namespace.clone().unwrap();
+        let annotations = resource.metadata.annotations.clone().unwrap_or_default();
+        for key in [PROMOTE_ANNOTATION, ABORT_ANNOTATION] {
+            if annotations.contains_key(key) {
+                let annotated = self
+                    .client
+                    .annotate_exposed_app(namespace.as_str(), name.as_str(), key, None)
+                    .await
+                    .map_err(|e| format!("Error occurred while removing {}: {:?}", key, e))?;
+                resource.metadata = annotated.metadata;
+            }
+        }
+        let deployments = self.client.api_resource::<Deployment>().clone();
+        let uri = |deployment: &str| deployments.object_uri(namespace.as_str(), deployment);
+        let stable = self.get_deployment(uri(deployment_name).as_str()).await?;
+        let canary = self
+            .get_deployment(uri(canary_deployment_name(name.as_str()).as_str()).as_str())
+            .await?;
+        let canary_failure = match &canary {
+            None => None,
+            Some(canary) if progress_deadline_exceeded(canary) => Some(String::from(
+                "canary Deployment exceeded its progress deadline",
+            )),
+            Some(_) => {
+                let uri = with_query(
+                    self.client
+                        .api_resource::<Pod>()
+                        .namespaced_uri(namespace.as_str())
+                        .as_str(),
+                    "labelSelector",
+                    format!(
+                        "{}={},{}=canary",
+                        INSTANCE_LABEL, deployment_name, TRACK_LABEL
+                    )
+                    .as_str(),
+                );
+                let pods = self
+                    .client
+                    .get_all::<Pod>(uri.as_str())
+                    .await
+                    .map_err(|e| format!("Error occurred while listing canary Pods: {:?}", e))?;
+                let health = summarize(&pods.items);
+                (!health.is_healthy()).then(|| health.condition(None).message)
+            }
+        };
+        Ok(plan(RolloutInput {
+            spec: &resource.object.spec,
+            previous: resource
+                .object
+                .status
+                .as_ref()
+                .and_then(|status| status.canary.as_ref()),
+            live_stable_image: stable
+                .as_ref()
+                .and_then(|stable| image(stable))
+                .map(String::from),
+            canary: canary.as_ref(),
+            canary_failure,
+            promote: annotations.get(PROMOTE_ANNOTATION).map(String::as_str),
+            abort: annotations.contains_key(ABORT_ANNOTATION),
+        }))
+    }
+
     async fn reconcile_resource(
         &mut self,
         resource: &mut K8sObject<ExposedApp>,
-    ) -> Result<(), String> {
+    ) -> Result<Action, Failure> {
         let name = resource.metadata.name.clone().unwrap();
         let namespace = resource.metadata.namespace.clone().unwrap();
         info!("Synchronizing resource {} namespace {}", name, namespace);
-        let deployment_name = format!("{}-deployment", name);
-        let pod_labels = HashMap::from([(
-            String::from("app.kubernetes.io/instance"),
-            deployment_name.clone(),
-        )]);
-        match self
-            .save_deployment(
-                deployment_name.as_str(),
-                namespace.as_str(),
-                &pod_labels,
-                resource,
-            )
+        if let Some(pause) = pause(&resource.metadata.annotations, self.paused) {
+            return self.reconcile_paused(resource, pause).await;
+        }
+        let deployment_name = deployment_name(name.as_str());
+        let pod_labels = HashMap::from([(String::from(INSTANCE_LABEL), deployment_name.clone())]);
+        let references = Reference::of_spec(&resource.object.spec, namespace.as_str());
+        let config_hash = self
+            .config_hash(&references)
             .await
-        {
-            Ok(deployment) => {
-                let note = format!(
-                    "Deployment {} provisioned successfully with {} replicas",
-                    deployment.metadata.name.clone().unwrap(),
-                    deployment.object.spec.replicas
-                );
-                self.send_event(
+            .map_err(failed_in(ReconcilePhase::Config))?;
+        let had_canary = resource
+            .object
+            .status
+            .as_ref()
+            .and_then(|status| status.canary.as_ref())
+            .is_some_and(CanaryStatus::is_in_progress);
+        let plan = self
+            .plan_rollout(resource, deployment_name.as_str())
+            .await
+            .map_err(failed_in(ReconcilePhase::Rollout))?;
+        let stable = Self::deployment(
+            resource,
+            deployment_name.as_str(),
+            &pod_labels,
+            plan.stable_image.as_str(),
+            plan.stable_replicas,
+            config_hash.clone(),
+        );
+        let deployment_reference = self
+            .provision_deployment(resource, &stable)
+            .await
+            .map_err(failed_in(ReconcilePhase::Deployment))?;
+        let canary_name = canary_deployment_name(name.as_str());
+        match plan.canary_replicas {
+            Some(replicas) => {
+                let mut canary_labels = pod_labels.clone();
+                canary_labels.insert(String::from(TRACK_LABEL), String::from("canary"));
+                let canary = Self::deployment(
                     resource,
-                    &deployment.into(),
-                    Normal,
-                    "DeploymentProvisioned",
-                    note.as_str(),
-                    "ProvisioningRequested",
-                )
+                    canary_name.as_str(),
+                    &canary_labels,
+                    resource.object.spec.image.as_str(),
+                    replicas,
+                    config_hash,
+                );
+                self.provision_deployment(resource, &canary)
+                    .await
+                    .map_err(failed_in(ReconcilePhase::Deployment))?;
+            }
+            None if had_canary => {
+                let uri = self
+                    .client
+                    .api_resource::<Deployment>()
+                    .namespaced_uri(namespace.as_str());
+                let selector = format!(
+                    "{}={},{}=canary",
+                    INSTANCE_LABEL, deployment_name, TRACK_LABEL
+                );
+                match self
+                    .client
+                    .delete_collection(uri.as_str(), selector.as_str(), &DeleteOptions::default())
+                    .await
+                {
+                    Ok(()) => info!("Canary Deployments of {} deleted", deployment_name),
+                    Err(K8sClientError::NotFound) => {}
+                    Err(e) => {
+                        let message = format!("Error occurred while deleting canary: {:?}", e);
+                        return Err(failed_in(ReconcilePhase::Deployment)(message));
+                    }
+                }
+            }
+            None => {}
+        }
+        if let (Some((event_type, reason, note)), false) = (&plan.event, self.dry_run) {
+            let regarding = ObjectReference::from(&*resource);
+            if let Err(e) = self
+                .send_event(resource, &regarding, *event_type, "Rollout", note, reason)
                 .await
-                .unwrap();
+            {
+                error!("Unable to send {} event: {:?}", reason, e);
             }
-            Err(e) => return Err(e),
         }
         let service_name = format!("{}-service", name);
+        let released = self
+            .released_node_ports(service_name.as_str(), namespace.as_str(), resource)
+            .await
+            .map_err(failed_in(ReconcilePhase::Service))?;
         match self
             .save_service(
                 service_name.as_str(),
@@ -239,14 +806,16 @@ impl Reconciler {
             )
             .await
         {
+            Ok(_) if self.dry_run => {}
             Ok(service) => {
                 let note = format!(
                     "Service {} successfully provisioned",
                     service.metadata.name.clone().unwrap()
                 );
+                let reference = ObjectReference::from(service);
                 self.send_event(
                     resource,
-                    &service.into(),
+                    &reference,
                     Normal,
                     "ServiceProvisioned",
                     note.as_str(),
@@ -254,41 +823,297 @@ impl Reconciler {
                 )
                 .await
                 .unwrap();
+                if let Some(note) = released {
+                    if let Err(e) = self
+                        .send_event(
+                            resource,
+                            &reference,
+                            Normal,
+                            "NodePortsReleased",
+                            note.as_str(),
+                            "Pruned",
+                        )
+                        .await
+                    {
+                        error!("Unable to send Pruned event: {:?}", e);
+                    }
+                }
             }
-            Err(e) => return Err(e),
+            Err(e) => return Err(failed_in(ReconcilePhase::Service)(e)),
+        }
+        let mut desired = vec![
+            (Deployment::KIND, deployment_name.as_str()),
+            (Service::KIND, service_name.as_str()),
+        ];
+        if plan.canary_replicas.is_some() {
+            desired.push((Deployment::KIND, canary_name.as_str()));
         }
-        resource.object.status = Some(ExposedAppStatus {
+        self.prune_children(resource, &desired)
+            .await
+            .map_err(failed_in(ReconcilePhase::Prune))?;
+        // Status and events are not written in dry-run mode
+        if self.dry_run {
+            return Ok(Action::Done);
+        }
+        if let Some(sharding) = &self.sharding {
+            let annotated = resource
+                .metadata
+                .annotations
+                .as_ref()
+                .and_then(|annotations| annotations.get(OWNER_ANNOTATION))
+                .is_some_and(|owner| owner == sharding.identity());
+            if !annotated {
+                let annotated = self
+                    .client
+                    .annotate_exposed_app(
+                        namespace.as_str(),
+                        name.as_str(),
+                        OWNER_ANNOTATION,
+                        Some(sharding.identity()),
+                    )
+                    .await
+                    .map_err(|e| {
+                        let message =
+                            format!("Error occurred while annotating shard owner: {:?}", e);
+                        failed_in(ReconcilePhase::Sharding)(message)
+                    })?;
+                // Status is written on top of the annotated version
+                resource.metadata = annotated.metadata;
+            }
+        }
+        let (condition, pods_action) = self
+            .pods_healthy_condition(resource, deployment_name.as_str(), deployment_reference)
+            .await
+            .map_err(failed_in(ReconcilePhase::Pods))?;
+        let mut conditions = vec![condition];
+        // Kept once reported, False from then on
+        if let Some(previous) = Self::previous_condition(resource, PAUSED) {
+            if previous.status == "True" {
+                self.send_pause_event(resource, "ReconciliationResumed", "Reconciliation resumed")
+                    .await;
+            }
+            conditions.push(resumed(Some(&previous)));
+        }
+        if let Some(previous) = Self::previous_condition(resource, STALLED) {
+            conditions.push(previous);
+        }
+        let mut status = ExposedAppStatus {
             deployment_name,
             service_name,
-        });
+            conditions,
+            canary: plan.status,
+            last_reconcile_time: None,
+            last_error: None,
+            next_retry_time: None,
+        };
+        succeeded(&mut status);
+        let action = match plan.requeue {
+            Some(delay) => pods_action.merge(Action::Requeue(delay)),
+            None => pods_action,
+        };
+        self.write_status(resource, status)
+            .await
+            .map_err(failed_in(ReconcilePhase::Status))?;
+        Ok(action)
+    }
+
+    async fn write_status(
+        &mut self,
+        resource: &mut K8sObject<ExposedApp>,
+        status: ExposedAppStatus,
+    ) -> Result<(), String> {
+        let name = resource.metadata.name.clone().unwrap();
+        let namespace = resource.metadata.namespace.clone().unwrap();
+        let namespaced_name = NamespacedName::new(name.as_str(), namespace.as_str());
         let mut map = self.cache.lock().await;
+        // Nothing changed, skip the write so Pod events don't bump the resourceVersion
+        let unchanged = resource
+            .object
+            .status
+            .clone()
+            .map(|previous| ExposedAppStatus {
+                last_reconcile_time: status.last_reconcile_time,
+                ..previous
+            });
+        if unchanged.as_ref() == Some(&status) {
+            map.insert(
+                namespaced_name,
+                CacheEntry::new(
+                    resource.metadata.resource_version.clone().unwrap().as_str(),
+                    resource.metadata.generation.unwrap(),
+                )
+                .with_annotations(resource.metadata.annotations.clone()),
+            );
+            return Ok(());
+        }
+        resource.object.status = Some(status);
         match self
             .client
-            .put_exposed_app_status(namespace.as_str(), name.as_str(), &resource)
+            .put_exposed_app_status(namespace.as_str(), name.as_str(), resource)
             .await
         {
             Ok(result) => {
                 info!("Successfully updated status of {}", name);
-                let namespaced_name = NamespacedName::new(name.as_str(), namespace.as_str());
                 map.insert(
                     namespaced_name,
                     CacheEntry::new(
                         result.metadata.resource_version.clone().unwrap().as_str(),
                         result.metadata.generation.unwrap(),
-                    ),
+                    )
+                    .with_annotations(result.metadata.annotations),
                 );
+                Ok(())
             }
-            Err(e) => {
-                return Err(format!(
-                    "Error occurred while updating ExposedApp status: {:?}",
-                    e
+            Err(e) => Err(format!(
+                "Error occurred while updating ExposedApp status: {:?}",
+                e
+            )),
+        }
+    }
+
+    fn previous_condition(
+        resource: &K8sObject<ExposedApp>,
+        condition_type: &str,
+    ) -> Option<Condition> {
+        resource
+            .object
+            .status
+            .as_ref()
+            .and_then(|status| {
+                status
+                    .conditions
+                    .iter()
+                    .find(|condition| condition.condition_type == condition_type)
+            })
+            .cloned()
+    }
+
+    async fn send_pause_event(
+        &mut self,
+        resource: &K8sObject<ExposedApp>,
+        reason: &str,
+        note: &str,
+    ) {
+        if self.dry_run {
+            return;
+        }
+        let regarding = ObjectReference::from(resource);
+        if let Err(e) = self
+            .send_event(resource, &regarding, Normal, "Reconcile", note, reason)
+            .await
+        {
+            error!("Unable to send {} event: {:?}", reason, e);
+        }
+    }
+
+    // Children are left as they are, only the Paused condition is reported
+    async fn reconcile_paused(
+        &mut self,
+        resource: &mut K8sObject<ExposedApp>,
+        pause: Pause,
+    ) -> Result<Action, Failure> {
+        let name = resource.metadata.name.clone().unwrap();
+        info!("Reconciliation of {} paused, children left untouched", name);
+        let previous = Self::previous_condition(resource, PAUSED);
+        let condition = pause.condition(previous.as_ref());
+        if previous.is_none_or(|previous| previous.status != "True") {
+            self.send_pause_event(resource, "ReconciliationPaused", condition.message.as_str())
+                .await;
+        }
+        let action = pause.remaining().map_or(Action::Done, Action::Requeue);
+        if self.dry_run {
+            return Ok(action);
+        }
+        let mut status = resource
+            .object
+            .status
+            .clone()
+            .unwrap_or_else(|| initial_status(name.as_str()));
+        status
+            .conditions
+            .retain(|existing| existing.condition_type != PAUSED);
+        status.conditions.push(condition);
+        succeeded(&mut status);
+        self.write_status(resource, status)
+            .await
+            .map_err(failed_in(ReconcilePhase::Status))?;
+        Ok(action)
+    }
+
+    /*
+       Summarizes the Pods of the Deployment into the PodsHealthy condition.
+       The condition changes at most once per POD_CONDITION_INTERVAL, until then the
+       previous one is kept and the ExposedApp is requeued for when the interval ends.
+       A Warning event is sent whenever Pods start failing for a new reason.
+    */
+    async fn pods_healthy_condition(
+        &mut self,
+        resource: &K8sObject<ExposedApp>,
+        deployment_name: &str,
+        deployment_reference: Option<ObjectReference>,
+    ) -> Result<(Condition, Action), String> {
+        let name = resource.metadata.name.clone().unwrap();
+        let namespace = resource.metadata.namespace.clone().unwrap();
+        let uri = with_query(
+            self.client
+                .api_resource::<Pod>()
+                .namespaced_uri(namespace.as_str())
+                .as_str(),
+            "labelSelector",
+            format!("{}={}", INSTANCE_LABEL, deployment_name).as_str(),
+        );
+        let pods = self
+            .client
+            .get_all::<Pod>(uri.as_str())
+            .await
+            .map_err(|e| format!("Error occurred while listing Pods: {:?}", e))?;
+        let previous = Self::previous_condition(resource, PODS_HEALTHY);
+        let health = summarize(&pods.items);
+        let condition = health.condition(previous.as_ref());
+        if let Some(previous) = previous.as_ref().filter(|previous| **previous == condition) {
+            return Ok((previous.clone(), Action::Done));
+        }
+        let namespaced_name = NamespacedName::new(name.as_str(), namespace.as_str());
+        if let (Some(previous), Some(updated)) =
+            (&previous, self.pod_condition_updates.get(&namespaced_name))
+        {
+            let elapsed = updated.elapsed();
+            if elapsed < POD_CONDITION_INTERVAL {
+                return Ok((
+                    previous.clone(),
+                    Action::Requeue(POD_CONDITION_INTERVAL - elapsed),
                 ));
             }
         }
-        Ok(())
+        self.pod_condition_updates
+            .insert(namespaced_name, Instant::now());
+        let failing_again = previous.is_some_and(|previous| {
+            previous.status == "False" && previous.reason == condition.reason
+        });
+        if condition.status == "False" && !failing_again {
+            let related = deployment_reference.unwrap_or_else(|| resource.into());
+            if let Err(e) = self
+                .send_event(
+                    resource,
+                    &related,
+                    Warning,
+                    "PodsFailing",
+                    condition.message.as_str(),
+                    condition.reason.as_str(),
+                )
+                .await
+            {
+                error!("Unable to send PodsFailing event: {:?}", e);
+            }
+        }
+        Ok((condition, Action::Done))
     }
+}
 
-    pub async fn reconcile(&mut self, namespaced_name: NamespacedName) -> Result<(), String> {
+impl Reconcile for Reconciler {
+    type Error = Failure;
+
+    async fn reconcile(&mut self, namespaced_name: NamespacedName) -> Result<Action, Failure> {
         let name = namespaced_name.name;
         let namespace = namespaced_name.namespace;
         match self
@@ -296,12 +1121,91 @@ impl Reconciler {
             .get_exposed_app(name.as_str(), namespace.as_str())
             .await
         {
-            Ok(mut resource) => self.reconcile_resource(&mut resource).await,
+            Ok(mut resource) => {
+                Span::current().record("generation", resource.metadata.generation);
+                self.references.lock().unwrap().update(
+                    &NamespacedName::new(name.as_str(), namespace.as_str()),
+                    Reference::of_spec(&resource.object.spec, namespace.as_str()),
+                );
+                let action = self.reconcile_resource(&mut resource).await?;
+                if let Some(sharding) = &self.sharding {
+                    sharding.record(&NamespacedName::new(name.as_str(), namespace.as_str()));
+                }
+                Ok(action)
+            }
             Err(K8sClientError::NotFound) => {
+                self.references
+                    .lock()
+                    .unwrap()
+                    .remove(&NamespacedName::new(name.as_str(), namespace.as_str()));
+                self.pod_condition_updates
+                    .remove(&NamespacedName::new(name.as_str(), namespace.as_str()));
+                if let Some(sharding) = &self.sharding {
+                    sharding.forget(&NamespacedName::new(name.as_str(), namespace.as_str()));
+                }
                 info!("ExposedApp not found, probably already deleted. It's fine");
-                Ok(())
+                Ok(Action::Done)
             }
-            Err(e) => Err(format!("Unable to get ExposedApp: {:?}", e)),
+            Err(e) => Err(failed_in(ReconcilePhase::Fetch)(format!(
+                "Unable to get ExposedApp: {:?}",
+                e
+            ))),
+        }
+    }
+
+    // Reported in the status of the ExposedApp, unless it is gone
+    async fn failed(
+        &mut self,
+        request: &NamespacedName,
+        failure: &Failure,
+        failures: u32,
+        retry: Option<Duration>,
+    ) {
+        if self.dry_run {
+            return;
+        }
+        let name = request.name.as_str();
+        let mut resource = match self
+            .client
+            .get_exposed_app(name, request.namespace.as_str())
+            .await
+        {
+            Ok(resource) => resource,
+            Err(K8sClientError::NotFound) => return,
+            Err(e) => {
+                error!("Unable to get ExposedApp to record failure: {:?}", e);
+                return;
+            }
+        };
+        let mut status = resource
+            .object
+            .status
+            .clone()
+            .unwrap_or_else(|| initial_status(name));
+        if failed(&mut status, failure, failures, retry, self.stall_after) {
+            let note = format!(
+                "Reconcile failed {} times in a row, retrying every {:?}: {}",
+                failures,
+                retry.unwrap_or_default(),
+                failure
+            );
+            let regarding = ObjectReference::from(&resource);
+            if let Err(e) = self
+                .send_event(
+                    &resource,
+                    &regarding,
+                    Warning,
+                    "Reconcile",
+                    note.as_str(),
+                    STALLED,
+                )
+                .await
+            {
+                error!("Unable to send {} event: {:?}", STALLED, e);
+            }
+        }
+        if let Err(e) = self.write_status(&mut resource, status).await {
+            error!("Unable to record failure: {}", e);
         }
     }
 }
@@ -0,0 +1,108 @@
+use crate::cache::NamespacedName;
+use crate::k8s_client::client::K8sClient;
+use crate::k8s_types::{ConfigMap, EnvFromSource, ExposedAppSpec, K8sListObject, Secret};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/*
+   Stamped by the reconciler on the ConfigMaps and Secrets an ExposedApp references,
+   the watches select it instead of every ConfigMap and Secret of the cluster.
+   Objects created after being referenced are stamped on the next reconcile of the ExposedApp.
+*/
+pub const REFERENCED_LABEL: &str = "no-library.com/referenced";
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Reference {
+    ConfigMap(NamespacedName),
+    Secret(NamespacedName),
+}
+
+impl Reference {
+    pub fn from_env_source(source: &EnvFromSource, namespace: &str) -> Option<Self> {
+        match (&source.config_map_ref, &source.secret_ref) {
+            (Some(config_map), _) => Some(Reference::ConfigMap(NamespacedName::new(
+                config_map.name.as_str(),
+                namespace,
+            ))),
+            (None, Some(secret)) => Some(Reference::Secret(NamespacedName::new(
+                secret.name.as_str(),
+                namespace,
+            ))),
+            (None, None) => None,
+        }
+    }
+
+    pub fn uri(&self, client: &K8sClient) -> String {
+        let (resource, name) = match self {
+            Reference::ConfigMap(name) => (client.api_resource::<ConfigMap>(), name),
+            Reference::Secret(name) => (client.api_resource::<Secret>(), name),
+        };
+        resource.object_uri(name.namespace.as_str(), name.name.as_str())
+    }
+
+    pub fn of_spec(spec: &ExposedAppSpec, namespace: &str) -> Vec<Self> {
+        spec.env_from
+            .iter()
+            .filter_map(|source| Self::from_env_source(source, namespace))
+            .collect()
+    }
+}
+
+/*
+   Reverse index from ConfigMaps and Secrets to the ExposedApps referencing them.
+   Kept up to date by the reconciler, read by the ConfigMap and Secret watch mappers,
+   so changes to objects no ExposedApp references are dropped before reaching the queue.
+*/
+#[derive(Default)]
+pub struct ReferenceIndex {
+    referenced_by: HashMap<Reference, HashSet<NamespacedName>>,
+    references: HashMap<NamespacedName, Vec<Reference>>,
+}
+
+pub type SharedReferenceIndex = Arc<Mutex<ReferenceIndex>>;
+
+impl ReferenceIndex {
+    pub fn update(&mut self, exposed_app: &NamespacedName, references: Vec<Reference>) {
+        self.remove(exposed_app);
+        for reference in &references {
+            self.referenced_by
+                .entry(reference.clone())
+                .or_default()
+                .insert(exposed_app.clone());
+        }
+        self.references.insert(exposed_app.clone(), references);
+    }
+
+    pub fn remove(&mut self, exposed_app: &NamespacedName) {
+        for reference in self.references.remove(exposed_app).unwrap_or_default() {
+            if let Some(exposed_apps) = self.referenced_by.get_mut(&reference) {
+                exposed_apps.remove(exposed_app);
+                if exposed_apps.is_empty() {
+                    self.referenced_by.remove(&reference);
+                }
+            }
+        }
+    }
+
+    pub fn referencing(&self, reference: &Reference) -> Vec<NamespacedName> {
+        self.referenced_by
+            .get(reference)
+            .map(|exposed_apps| exposed_apps.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+// Watch mapper for ConfigMaps or Secrets, depending on the constructor
+pub fn mapper<T>(
+    index: &SharedReferenceIndex,
+    reference: fn(NamespacedName) -> Reference,
+) -> impl Fn(&K8sListObject<T>) -> Vec<NamespacedName> + Send + Sync + 'static {
+    let index = Arc::clone(index);
+    move |object| match (&object.metadata.name, &object.metadata.namespace) {
+        (Some(name), Some(namespace)) => index
+            .lock()
+            .unwrap()
+            .referencing(&reference(NamespacedName::new(name, namespace))),
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,347 @@
+/*
+   Serves a cassette recorded with --record back to the operator, no cluster needed.
+   Run replay-api-server, then start the operator with KUBERNETES_API_URL pointing at the printed address.
+
+   Requests are matched on method and URI, repeated ones get the recorded responses in order,
+   the last one is served again once they are used up. Watch events are delivered in the order
+   they were recorded relative to the other requests, a recorded watch end closes the stream.
+   GET /replay/status lists what was served and what was not, for regression test assertions.
+   Request bodies are not matched on, the received ones are kept for assertions.
+   Secret data is redacted so e.g. config hashes differ.
+*/
+use crate::cassette::{parse_body, redact, Record};
+use async_stream::stream;
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::{Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{error, info, warn};
+
+// A diverging operator might never send the request an event waits for
+const ORDER_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Exchange {
+    sequence: u64,
+    status: u16,
+    response: Value,
+}
+
+enum Delivery {
+    Event(Value),
+    End,
+}
+
+struct Cassette {
+    exchanges: HashMap<(String, String), Vec<Exchange>>,
+    // Deliveries of every watch, with the sequence they were recorded at
+    watches: HashMap<u64, Vec<(u64, Delivery)>>,
+    // Sorted sequences of the exchanges, an event waits for the ones recorded before it
+    sequences: Vec<u64>,
+}
+
+#[derive(Default)]
+struct Progress {
+    next: HashMap<(String, String), usize>,
+    served: BTreeSet<u64>,
+    repeated: u64,
+    unmatched: Vec<String>,
+    // Body the operator sent for each served exchange, redacted like the recorded one
+    requests: HashMap<u64, Value>,
+}
+
+struct Replay {
+    cassette: Cassette,
+    progress: Mutex<Progress>,
+    // Highest exchange sequence served so far
+    clock: watch::Sender<u64>,
+}
+
+type SharedReplay = Arc<Replay>;
+
+// Query re-encoded, so requests match however their parameters were percent-encoded
+fn canonical(uri: &str) -> String {
+    match uri.split_once('?') {
+        Some((path, query)) => {
+            let query = form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(form_urlencoded::parse(query.as_bytes()))
+                .finish();
+            format!("{}?{}", path, query)
+        }
+        None => String::from(uri),
+    }
+}
+
+fn index(records: Vec<Record>) -> Cassette {
+    let mut cassette = Cassette {
+        exchanges: HashMap::new(),
+        watches: HashMap::new(),
+        sequences: Vec::new(),
+    };
+    for record in records {
+        match record {
+            Record::Exchange {
+                sequence,
+                method,
+                uri,
+                status,
+                response,
+                ..
+            } => {
+                cassette.sequences.push(sequence);
+                cassette
+                    .exchanges
+                    .entry((method, canonical(uri.as_str())))
+                    .or_default()
+                    .push(Exchange {
+                        sequence,
+                        status,
+                        response,
+                    });
+            }
+            Record::WatchEvent {
+                sequence,
+                watch,
+                event,
+                ..
+            } => cassette
+                .watches
+                .entry(watch)
+                .or_default()
+                .push((sequence, Delivery::Event(event))),
+            Record::WatchEnd {
+                sequence, watch, ..
+            } => cassette
+                .watches
+                .entry(watch)
+                .or_default()
+                .push((sequence, Delivery::End)),
+        }
+    }
+    cassette.sequences.sort_unstable();
+    cassette
+}
+
+impl Replay {
+    // Last exchange recorded before the given sequence
+    fn preceding(&self, sequence: u64) -> u64 {
+        let sequences = &self.cassette.sequences;
+        let position = sequences.partition_point(|recorded| *recorded < sequence);
+        position
+            .checked_sub(1)
+            .map(|position| sequences[position])
+            .unwrap_or(0)
+    }
+
+    async fn wait_for(&self, sequence: u64) {
+        let needed = self.preceding(sequence);
+        let mut clock = self.clock.subscribe();
+        let reached = timeout(ORDER_TIMEOUT, clock.wait_for(|served| *served >= needed)).await;
+        if reached.is_err() {
+            warn!(
+                "Exchange {} was never requested, delivering {} anyway",
+                needed, sequence
+            );
+        }
+    }
+
+    fn next(&self, method: &str, uri: &str) -> Option<&Exchange> {
+        let key = (String::from(method), String::from(uri));
+        let exchanges = self.cassette.exchanges.get(&key)?;
+        let mut progress = self.progress.lock().unwrap();
+        let next = progress.next.entry(key).or_insert(0);
+        let exchange = match exchanges.get(*next) {
+            Some(exchange) => {
+                *next += 1;
+                exchange
+            }
+            None => {
+                progress.repeated += 1;
+                exchanges.last().unwrap()
+            }
+        };
+        progress.served.insert(exchange.sequence);
+        self.clock.send_if_modified(|served| {
+            let advanced = exchange.sequence > *served;
+            *served = (*served).max(exchange.sequence);
+            advanced
+        });
+        Some(exchange)
+    }
+}
+
+fn watch_response(replay: SharedReplay, watch: u64) -> Response {
+    let body = stream! {
+        let deliveries = replay.cassette.watches.get(&watch).map(Vec::as_slice).unwrap_or_default();
+        for (sequence, delivery) in deliveries {
+            replay.wait_for(*sequence).await;
+            match delivery {
+                Delivery::Event(event) => {
+                    let mut line = event.to_string();
+                    line.push('\n');
+                    yield Ok::<Bytes, Infallible>(Bytes::from(line));
+                }
+                Delivery::End => return,
+            }
+        }
+        // Recording stopped while the watch was open
+        futures::future::pending::<()>().await;
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from_stream(body))
+        .unwrap()
+}
+
+async fn handle(
+    State(replay): State<SharedReplay>,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> Response {
+    let path = uri
+        .path_and_query()
+        .map(|path| path.to_string())
+        .unwrap_or_default();
+    let exchange = match replay.next(method.as_str(), canonical(path.as_str()).as_str()) {
+        Some(exchange) => exchange,
+        None => {
+            warn!("{} {} not in the cassette", method, path);
+            let request = format!("{} {}", method, path);
+            replay.progress.lock().unwrap().unmatched.push(request);
+            let status = json!({
+                "apiVersion": "v1",
+                "kind": "Status",
+                "status": "Failure",
+                "reason": "NotFound",
+                "code": 404,
+                "message": format!("{} {} was not recorded", method, path),
+            });
+            return (StatusCode::NOT_FOUND, Json(status)).into_response();
+        }
+    };
+    info!("{} {} replayed from {}", method, path, exchange.sequence);
+    if !body.is_empty() {
+        let mut request = parse_body(String::from_utf8_lossy(&body).as_ref());
+        redact(path.as_str(), &mut request);
+        let mut progress = replay.progress.lock().unwrap();
+        progress.requests.insert(exchange.sequence, request);
+    }
+    let status = StatusCode::from_u16(exchange.status).unwrap_or(StatusCode::OK);
+    let watched = uri
+        .query()
+        .is_some_and(|query| query.split('&').any(|param| param == "watch=1"));
+    if watched {
+        return watch_response(Arc::clone(&replay), exchange.sequence);
+    }
+    match &exchange.response {
+        Value::String(text) => (status, text.clone()).into_response(),
+        response => (status, Json(response.clone())).into_response(),
+    }
+}
+
+async fn replay_status(State(replay): State<SharedReplay>) -> Json<Value> {
+    Json(replay.status())
+}
+
+impl Replay {
+    fn status(&self) -> Value {
+        let progress = self.progress.lock().unwrap();
+        let unserved: Vec<u64> = self
+            .cassette
+            .sequences
+            .iter()
+            .filter(|sequence| !progress.served.contains(sequence))
+            .copied()
+            .collect();
+        json!({
+            "exchanges": self.cassette.sequences.len(),
+            "served": progress.served.len(),
+            "repeated": progress.repeated,
+            "unserved": unserved,
+            "unmatched": progress.unmatched,
+        })
+    }
+}
+
+/*
+   Replay server over HTTP, started by the replay-api-server binary and by integration tests.
+   Listens on 127.0.0.1, a random port unless one is set.
+*/
+pub struct ReplayApiServer {
+    records: Vec<Record>,
+    port: u16,
+}
+
+pub struct RunningReplayServer {
+    pub url: String,
+    replay: SharedReplay,
+    task: JoinHandle<()>,
+}
+
+impl ReplayApiServer {
+    pub fn new(records: Vec<Record>) -> Self {
+        ReplayApiServer { records, port: 0 }
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub async fn start(self) -> RunningReplayServer {
+        let replay = Arc::new(Replay {
+            cassette: index(self.records),
+            progress: Mutex::new(Progress::default()),
+            clock: watch::Sender::new(0),
+        });
+        let app = Router::new()
+            .route("/replay/status", axum::routing::get(replay_status))
+            .fallback(handle)
+            .with_state(Arc::clone(&replay));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", self.port))
+            .await
+            .expect("Unable to bind replay API server");
+        let address = listener.local_addr().unwrap();
+        RunningReplayServer {
+            url: format!("http://{}", address),
+            replay,
+            task: tokio::spawn(async move { axum::serve(listener, app).await.unwrap() }),
+        }
+    }
+}
+
+impl RunningReplayServer {
+    // Same document as GET /replay/status
+    pub fn status(&self) -> Value {
+        self.replay.status()
+    }
+
+    // Compared with the recorded request to check what the operator sent
+    pub fn request(&self, sequence: u64) -> Option<Value> {
+        let progress = self.replay.progress.lock().unwrap();
+        progress.requests.get(&sequence).cloned()
+    }
+
+    // Until the server fails, it never stops on its own
+    pub async fn join(mut self) {
+        if let Err(e) = (&mut self.task).await {
+            error!("Replay API server failed: {}", e);
+        }
+    }
+}
+
+impl Drop for RunningReplayServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
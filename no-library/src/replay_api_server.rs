@@ -0,0 +1,28 @@
+use no_library::cassette::load;
+use no_library::replay::ReplayApiServer;
+use std::env;
+use std::path::PathBuf;
+use tracing::info;
+
+/*
+   Usage: replay-api-server CASSETTE
+   PORT sets the port, see the replay module for how requests are matched.
+*/
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let path = env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .expect("Usage: replay-api-server CASSETTE");
+    let records = load(path.as_path()).unwrap_or_else(|e| panic!("Invalid cassette: {}", e));
+    info!(
+        "Replaying {} records from {}",
+        records.len(),
+        path.display()
+    );
+    let port = env::var("PORT").map_or(0, |port| port.parse().expect("Invalid PORT"));
+    let running = ReplayApiServer::new(records).port(port).start().await;
+    println!("KUBERNETES_API_URL={}", running.url);
+    running.join().await;
+}
@@ -0,0 +1,366 @@
+use crate::cache::NamespacedName;
+use crate::config::Config;
+use crate::discovery::with_query;
+use crate::k8s_client::client::{K8sClient, K8sClientError};
+use crate::k8s_time::MicroTime;
+use crate::k8s_types::{K8sObject, Lease, LeaseSpec, Metadata, Resource};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+const GROUP_LABEL: &str = "shard.no-library.com/group";
+pub const OWNER_ANNOTATION: &str = "shard.no-library.com/owner";
+
+/*
+   Splits ExposedApps between operator replicas.
+   Every replica keeps its own member Lease renewed, live members are the ones
+   whose Lease has not expired. An object belongs to the member with the highest
+   hash of (member, namespace/name) - rendezvous hashing, so when a member joins or leaves
+   only the objects it gains or loses move.
+*/
+pub struct Sharding {
+    client: K8sClient,
+    identity: String,
+    group: String,
+    namespace: String,
+    lease_duration: Duration,
+    members: RwLock<Vec<String>>,
+    changes: watch::Sender<Vec<String>>,
+    owned: Mutex<HashSet<NamespacedName>>,
+}
+
+// FNV-1a with a final mix, stable across builds unlike DefaultHasher
+fn hash(member: &str, request: &NamespacedName) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let key = format!("{}/{}/{}", member, request.namespace, request.name);
+    for byte in key.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^ (hash >> 33)
+}
+
+// Member with the highest hash, None without members
+fn owner_of<'a>(members: &'a [String], request: &NamespacedName) -> Option<&'a String> {
+    members.iter().max_by_key(|member| hash(member, request))
+}
+
+impl Sharding {
+    pub fn new(client: K8sClient, config: &Config) -> Self {
+        let members = vec![config.pod_name.clone()];
+        Sharding {
+            client,
+            identity: config.pod_name.clone(),
+            group: config.lease_name.clone(),
+            namespace: config.lease_namespace.clone(),
+            lease_duration: config.shard_lease_duration,
+            members: RwLock::new(members.clone()),
+            changes: watch::channel(members).0,
+            owned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn identity(&self) -> &str {
+        self.identity.as_str()
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        self.members.read().unwrap().clone()
+    }
+
+    pub fn owner(&self, request: &NamespacedName) -> String {
+        let members = self.members.read().unwrap();
+        owner_of(members.as_slice(), request)
+            .cloned()
+            .unwrap_or_else(|| self.identity.clone())
+    }
+
+    pub fn owns(&self, request: &NamespacedName) -> bool {
+        self.owner(request) == self.identity
+    }
+
+    // Notified with the new member list on every rebalance
+    pub fn subscribe(&self) -> watch::Receiver<Vec<String>> {
+        self.changes.subscribe()
+    }
+
+    pub fn record(&self, request: &NamespacedName) {
+        self.owned.lock().unwrap().insert(request.clone());
+    }
+
+    pub fn forget(&self, request: &NamespacedName) {
+        self.owned.lock().unwrap().remove(request);
+    }
+
+    // Prometheus text format
+    pub fn metrics(&self) -> String {
+        let members = self.members.read().unwrap().len();
+        let owned = self.owned.lock().unwrap().len();
+        let mut output = String::new();
+        writeln!(
+            output,
+            "# HELP no_library_shard_members Live operator replicas sharing ExposedApps"
+        )
+        .unwrap();
+        writeln!(output, "# TYPE no_library_shard_members gauge").unwrap();
+        writeln!(output, "no_library_shard_members {}", members).unwrap();
+        writeln!(
+            output,
+            "# HELP no_library_shard_owned_exposed_apps ExposedApps reconciled by this replica"
+        )
+        .unwrap();
+        writeln!(output, "# TYPE no_library_shard_owned_exposed_apps gauge").unwrap();
+        writeln!(
+            output,
+            "no_library_shard_owned_exposed_apps{{member=\"{}\"}} {}",
+            self.identity, owned
+        )
+        .unwrap();
+        output
+    }
+
+    fn lease_name(&self) -> String {
+        format!("{}-{}", self.group, self.identity)
+    }
+
+    async fn renew(&self) -> Result<(), K8sClientError> {
+        let mut client = self.client.clone();
+        let now = MicroTime::now();
+        let lease = K8sObject {
+            api_version: String::from(Lease::API_VERSION),
+            kind: String::from("Lease"),
+            metadata: Metadata {
+                name: Some(self.lease_name()),
+                namespace: Some(self.namespace.clone()),
+                labels: Some(HashMap::from([(
+                    String::from(GROUP_LABEL),
+                    self.group.clone(),
+                )])),
+                ..Metadata::default()
+            },
+            object: Lease {
+                spec: LeaseSpec {
+                    lease_duration_seconds: self.lease_duration.as_secs() as u32,
+                    holder_identity: Some(self.identity.clone()),
+                    acquire_time: None,
+                    renew_time: Some(now),
+                },
+            },
+        };
+        // PUT does not create Leases
+        match client.put(&lease).await {
+            Err(K8sClientError::NotFound) => {
+                info!("Creating shard member Lease {}", self.lease_name());
+                client.post(&lease).await.map(|_| ())
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    async fn refresh_members(&self) -> Result<(), K8sClientError> {
+        let uri = with_query(
+            self.client
+                .api_resource::<Lease>()
+                .namespaced_uri(self.namespace.as_str())
+                .as_str(),
+            "labelSelector",
+            format!("{}={}", GROUP_LABEL, self.group).as_str(),
+        );
+        let leases = self.client.clone().get_all::<Lease>(uri.as_str()).await?;
+        let now = MicroTime::now().value();
+        let mut members: Vec<String> = leases
+            .items
+            .iter()
+            .filter(|lease| {
+                let spec = &lease.object.spec;
+                let expiry = time::Duration::seconds(spec.lease_duration_seconds as i64);
+                spec.renew_time
+                    .is_some_and(|renewed| renewed.value() + expiry > now)
+            })
+            .filter_map(|lease| lease.object.spec.holder_identity.clone())
+            .collect();
+        if !members.contains(&self.identity) {
+            members.push(self.identity.clone());
+        }
+        members.sort();
+        members.dedup();
+        let changed = *self.members.read().unwrap() != members;
+        if changed {
+            info!("Shard members changed: {:?}", members);
+            *self.members.write().unwrap() = members.clone();
+            self.owned
+                .lock()
+                .unwrap()
+                .retain(|request| self.owns(request));
+            self.changes.send_replace(members);
+        }
+        Ok(())
+    }
+
+    async fn heartbeat(&self) {
+        if let Err(e) = self.renew().await {
+            error!("Unable to renew shard member Lease: {:?}", e);
+        }
+        if let Err(e) = self.refresh_members().await {
+            error!("Unable to list shard members: {:?}", e);
+        }
+    }
+
+    // First renew and rebalance, the controllers start with the live members known
+    pub async fn join(&self) {
+        self.heartbeat().await;
+    }
+
+    // Renews the member Lease and rebalances after join, runs for the lifetime of the operator
+    pub async fn run(&self) {
+        loop {
+            sleep(self.lease_duration / 3).await;
+            self.heartbeat().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::{Discovery, Requirement};
+    use crate::fake_api::{FakeApiServer, RunningApiServer};
+
+    fn requests() -> Vec<NamespacedName> {
+        (0..200)
+            .map(|i| NamespacedName::new(format!("app-{}", i).as_str(), "team"))
+            .collect()
+    }
+
+    fn members(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    async fn sharding(server: &RunningApiServer, pod_name: &str) -> Sharding {
+        let config = Config::parse_from([
+            "no-library",
+            "--pod-name",
+            pod_name,
+            "--api-server-url",
+            server.url.as_str(),
+            "--sharding",
+        ])
+        .unwrap();
+        let mut client = K8sClient::new(&config.client).await;
+        let discovery = Discovery::load(&mut client, &[Requirement::of::<Lease>(&["list"])])
+            .await
+            .unwrap();
+        Sharding::new(client.with_discovery(discovery), &config)
+    }
+
+    // Member Lease of another replica, renewed the given number of seconds ago
+    async fn lease(sharding: &Sharding, identity: &str, renewed_ago: i64) {
+        let renewed = MicroTime::now().value() - time::Duration::seconds(renewed_ago);
+        let lease = K8sObject {
+            api_version: String::from(Lease::API_VERSION),
+            kind: String::from("Lease"),
+            metadata: Metadata {
+                name: Some(format!("{}-{}", sharding.group, identity)),
+                namespace: Some(sharding.namespace.clone()),
+                labels: Some(HashMap::from([(
+                    String::from(GROUP_LABEL),
+                    sharding.group.clone(),
+                )])),
+                ..Metadata::default()
+            },
+            object: Lease {
+                spec: LeaseSpec {
+                    lease_duration_seconds: 30,
+                    holder_identity: Some(String::from(identity)),
+                    acquire_time: None,
+                    renew_time: Some(MicroTime::new(renewed)),
+                },
+            },
+        };
+        let mut client = sharding.client.clone();
+        match client.put(&lease).await {
+            Err(K8sClientError::NotFound) => client.post(&lease).await.map(|_| ()),
+            result => result.map(|_| ()),
+        }
+        .unwrap();
+    }
+
+    #[test]
+    // Replicas of different versions must agree on the owners during a rolling upgrade
+    fn hash_is_stable_across_builds() {
+        let request = NamespacedName::new("demo", "example");
+        assert_eq!(hash("no-library-0", &request), 0x12ea_657c_a4a4_3def);
+        assert_eq!(hash("no-library-1", &request), 0x7873_0293_0dfc_25fe);
+    }
+
+    #[test]
+    fn only_the_keys_of_a_joining_or_leaving_member_move() {
+        let before = members(&["a", "b", "c"]);
+        let joined = members(&["a", "b", "c", "d"]);
+        let left = members(&["a", "c"]);
+        let mut gained = 0;
+        for request in requests() {
+            let owner = owner_of(&before, &request).unwrap();
+            let after_join = owner_of(&joined, &request).unwrap();
+            if after_join != owner {
+                assert_eq!(after_join, "d");
+                gained += 1;
+            }
+            let after_leave = owner_of(&left, &request).unwrap();
+            if owner != "b" {
+                assert_eq!(after_leave, owner);
+            }
+        }
+        // Roughly a quarter of the keys
+        assert!((20..80).contains(&gained), "{} keys moved", gained);
+        assert_eq!(owner_of(&[], &NamespacedName::new("demo", "example")), None);
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_dropped_and_self_is_kept() {
+        let server = FakeApiServer::new().start().await;
+        let sharding = sharding(&server, "a").await;
+        lease(&sharding, "b", 5).await;
+        lease(&sharding, "c", 31).await;
+        // No Lease of its own yet
+        sharding.refresh_members().await.unwrap();
+        assert_eq!(sharding.members(), members(&["a", "b"]));
+
+        lease(&sharding, "b", 60).await;
+        sharding.refresh_members().await.unwrap();
+        assert_eq!(sharding.members(), members(&["a"]));
+        let request = NamespacedName::new("demo", "example");
+        assert!(sharding.owns(&request));
+    }
+
+    #[tokio::test]
+    async fn rebalance_prunes_the_owned_requests() {
+        let server = FakeApiServer::new().start().await;
+        let sharding = sharding(&server, "a").await;
+        let mut changes = sharding.subscribe();
+        for request in requests() {
+            sharding.record(&request);
+        }
+        lease(&sharding, "b", 0).await;
+        sharding.refresh_members().await.unwrap();
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(*changes.borrow_and_update(), members(&["a", "b"]));
+        let owned = sharding.owned.lock().unwrap().clone();
+        let expected: HashSet<NamespacedName> = requests()
+            .into_iter()
+            .filter(|request| owner_of(&members(&["a", "b"]), request).unwrap() == "a")
+            .collect();
+        assert!(!expected.is_empty() && expected.len() < requests().len());
+        assert!(owned == expected);
+
+        // Unchanged members are not a rebalance
+        sharding.refresh_members().await.unwrap();
+        assert!(!changes.has_changed().unwrap());
+    }
+}
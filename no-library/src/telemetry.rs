@@ -0,0 +1,88 @@
+use crate::config::{Config, LogFormat};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::Context;
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use tracing::{info, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+// W3C traceparent of the reconcile that emitted an Event
+pub const TRACEPARENT_ANNOTATION: &str = "no-library.com/traceparent";
+
+// Flushes the spans still buffered by the batch exporter when dropped
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Unable to flush spans: {}", e);
+            }
+        }
+    }
+}
+
+fn tracer_provider(endpoint: &str) -> Result<SdkTracerProvider, String> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpJson)
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+        .map_err(|e| format!("Unable to create OTLP exporter: {}", e))?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("no-library").build())
+        .build())
+}
+
+/*
+   Log lines are filtered with RUST_LOG, info by default.
+   Spans are exported over OTLP/HTTP only when an endpoint is configured,
+   log lines carry the fields of their spans in both formats.
+*/
+pub fn init(config: &Config) -> Result<Telemetry, String> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let output = match config.log_format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let provider = config
+        .otlp_endpoint
+        .as_deref()
+        .map(tracer_provider)
+        .transpose()?;
+    let export = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("no-library")));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .with(export)
+        .try_init()
+        .map_err(|e| format!("Unable to initialize logging: {}", e))?;
+    if let Some(endpoint) = &config.otlp_endpoint {
+        info!("Exporting spans to {}", endpoint);
+    }
+    Ok(Telemetry { provider })
+}
+
+// None when spans are not exported, there is no trace to refer to
+pub fn traceparent() -> Option<String> {
+    let context: Context = Span::current().context();
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&context, &mut carrier);
+    carrier.remove("traceparent")
+}
@@ -0,0 +1,504 @@
+use crate::cache::{Cache, CacheEntry, NamespacedName};
+use crate::children::Children;
+use crate::config::Config;
+use crate::controller::{Action, Reconcile};
+use crate::k8s_client::client::{K8sClient, K8sClientError};
+use crate::k8s_time::Time;
+use crate::k8s_types::{
+    DeleteOptions, ExposedApp, ExposedAppSpec, ExposedAppTemplate, ExposedAppTemplateSpec,
+    ExposedAppTemplateStatus, K8sListObject, K8sObject, LabelSelector, LabelSelectorOperator,
+    Metadata, Namespace, NamespaceRolloutPhase, NamespaceRolloutStatus, OwnerReference,
+    PropagationPolicy, Resource,
+};
+use crate::pod_health::PODS_HEALTHY;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, Span};
+
+// Set on the generated ExposedApps, the value is the name of the template
+pub const TEMPLATE_LABEL: &str = "no-library.com/template";
+// A pruned ExposedApp still there after this is retried with the template
+const PRUNE_WAIT: Duration = Duration::from_secs(60);
+
+pub fn selects(selector: &LabelSelector, labels: &HashMap<String, String>) -> bool {
+    let labelled = selector
+        .match_labels
+        .iter()
+        .all(|(key, value)| labels.get(key) == Some(value));
+    labelled
+        && selector.match_expressions.iter().all(|requirement| {
+            let value = labels.get(&requirement.key);
+            match requirement.operator {
+                LabelSelectorOperator::In => value.is_some_and(|v| requirement.values.contains(v)),
+                LabelSelectorOperator::NotIn => {
+                    value.is_none_or(|v| !requirement.values.contains(v))
+                }
+                LabelSelectorOperator::Exists => value.is_some(),
+                LabelSelectorOperator::DoesNotExist => value.is_none(),
+            }
+        })
+}
+
+/*
+   Namespace selector of every template and the namespaces it stamped into.
+   Kept up to date by the template reconciler, read by the Namespace watch mapper,
+   so a label change reaches the templates selecting the namespace now or before it.
+*/
+#[derive(Default)]
+pub struct TemplateIndex {
+    templates: HashMap<String, (LabelSelector, BTreeSet<String>)>,
+}
+
+pub type SharedTemplateIndex = Arc<Mutex<TemplateIndex>>;
+
+impl TemplateIndex {
+    pub fn update(&mut self, template: &str, selector: LabelSelector, stamped: BTreeSet<String>) {
+        self.templates
+            .insert(String::from(template), (selector, stamped));
+    }
+
+    pub fn remove(&mut self, template: &str) {
+        self.templates.remove(template);
+    }
+
+    pub fn selecting(
+        &self,
+        namespace: &str,
+        labels: &HashMap<String, String>,
+    ) -> Vec<NamespacedName> {
+        self.templates
+            .iter()
+            .filter(|(_, (selector, stamped))| {
+                stamped.contains(namespace) || selects(selector, labels)
+            })
+            .map(|(template, _)| NamespacedName::new(template, ""))
+            .collect()
+    }
+}
+
+pub fn namespace_mapper(
+    index: &SharedTemplateIndex,
+) -> impl Fn(&K8sListObject<Namespace>) -> Vec<NamespacedName> + Send + Sync + 'static {
+    let index = Arc::clone(index);
+    move |namespace| match &namespace.metadata.name {
+        Some(name) => index
+            .lock()
+            .unwrap()
+            .selecting(name, &namespace.metadata.labels.clone().unwrap_or_default()),
+        None => Vec::new(),
+    }
+}
+
+/*
+   Template spec with the overrides of the namespace applied.
+   Node ports are cluster wide, fixed ones would clash between namespaces and are dropped.
+*/
+fn desired_spec(spec: &ExposedAppTemplateSpec, namespace: &str) -> ExposedAppSpec {
+    let mut desired = spec.template.clone();
+    for port in desired.ports.iter_mut() {
+        port.node_port = None;
+    }
+    for namespace_override in spec.overrides.iter().filter(|o| o.namespace == namespace) {
+        if let Some(replicas) = namespace_override.replicas {
+            desired.replicas = replicas;
+        }
+        if let Some(image) = &namespace_override.image {
+            desired.image = image.clone();
+        }
+        if let Some(service_type) = namespace_override.service_type {
+            desired.service_type = Some(service_type);
+        }
+        if let Some(env_from) = &namespace_override.env_from {
+            desired.env_from = env_from.clone();
+        }
+    }
+    desired
+}
+
+fn controlled_by(metadata: &Metadata, uid: &str) -> bool {
+    metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .any(|owner| owner.controller && owner.uid == uid)
+}
+
+fn rollout(namespace: &str, phase: NamespaceRolloutPhase, message: &str) -> NamespaceRolloutStatus {
+    NamespaceRolloutStatus {
+        namespace: String::from(namespace),
+        phase,
+        message: String::from(message),
+    }
+}
+
+// Summarizes the status the ExposedApp reconciler reported
+fn rollout_of(namespace: &str, exposed_app: &ExposedApp) -> NamespaceRolloutStatus {
+    let status = match &exposed_app.status {
+        Some(status) => status,
+        None => {
+            return rollout(
+                namespace,
+                NamespaceRolloutPhase::Pending,
+                "Waiting for the first reconcile",
+            )
+        }
+    };
+    if let Some(error) = &status.last_error {
+        let message = format!("{:?} failed: {}", error.phase, error.message);
+        return rollout(namespace, NamespaceRolloutPhase::Failed, message.as_str());
+    }
+    if let Some(canary) = status.canary.as_ref().filter(|c| c.is_in_progress()) {
+        return rollout(
+            namespace,
+            NamespaceRolloutPhase::Progressing,
+            canary.message.as_str(),
+        );
+    }
+    let pods = status
+        .conditions
+        .iter()
+        .find(|condition| condition.condition_type == PODS_HEALTHY);
+    match pods {
+        Some(pods) if pods.status == "True" => rollout(
+            namespace,
+            NamespaceRolloutPhase::Ready,
+            pods.message.as_str(),
+        ),
+        Some(pods) if pods.status == "False" => rollout(
+            namespace,
+            NamespaceRolloutPhase::Failed,
+            pods.message.as_str(),
+        ),
+        _ => rollout(
+            namespace,
+            NamespaceRolloutPhase::Progressing,
+            "Waiting for the Pods",
+        ),
+    }
+}
+
+/*
+   Stamps the ExposedApp of a template into the selected namespaces, updates the ones
+   that drifted from the template and deletes the ones in namespaces no longer selected.
+   The generated ExposedApps are then reconciled by the ExposedApp controller,
+   their status is summarized per namespace in the status of the template.
+*/
+pub struct TemplateReconciler {
+    client: K8sClient,
+    cache: Cache,
+    children: Children,
+    dry_run: bool,
+    // Generated ExposedApps are left untouched
+    paused: bool,
+    index: SharedTemplateIndex,
+}
+
+impl TemplateReconciler {
+    pub fn new(
+        client: K8sClient,
+        cache: Cache,
+        children: Children,
+        config: &Config,
+        index: SharedTemplateIndex,
+    ) -> Self {
+        TemplateReconciler {
+            client: if config.dry_run {
+                client.dry_run()
+            } else {
+                client
+            },
+            cache,
+            children,
+            dry_run: config.dry_run,
+            paused: config.paused,
+            index,
+        }
+    }
+
+    // Terminating namespaces reject new objects, they are not selected
+    async fn selected_namespaces(
+        &mut self,
+        selector: &LabelSelector,
+    ) -> Result<BTreeSet<String>, String> {
+        let uri = self.client.api_resource::<Namespace>().list_uri();
+        let namespaces = self
+            .client
+            .get_all::<Namespace>(uri.as_str())
+            .await
+            .map_err(|e| format!("Unable to list Namespaces: {:?}", e))?;
+        Ok(namespaces
+            .items
+            .into_iter()
+            .filter(|namespace| {
+                let phase = namespace
+                    .object
+                    .status
+                    .as_ref()
+                    .and_then(|status| status.phase.as_deref());
+                phase != Some("Terminating")
+            })
+            .filter(|namespace| {
+                selects(
+                    selector,
+                    &namespace.metadata.labels.clone().unwrap_or_default(),
+                )
+            })
+            .filter_map(|namespace| namespace.metadata.name)
+            .collect())
+    }
+
+    fn exposed_app(
+        template: &K8sObject<ExposedAppTemplate>,
+        namespace: &str,
+        spec: ExposedAppSpec,
+    ) -> K8sObject<ExposedApp> {
+        let name = template.metadata.name.clone().unwrap();
+        K8sObject {
+            api_version: String::from(ExposedApp::API_VERSION),
+            kind: String::from(ExposedApp::KIND),
+            metadata: Metadata {
+                name: Some(name.clone()),
+                namespace: Some(String::from(namespace)),
+                labels: Some(HashMap::from([(
+                    String::from(TEMPLATE_LABEL),
+                    name.clone(),
+                )])),
+                owner_references: Some(vec![OwnerReference {
+                    api_version: template.api_version.clone(),
+                    kind: template.kind.clone(),
+                    name,
+                    uid: template.metadata.uid.clone().unwrap(),
+                    block_owner_deletion: true,
+                    controller: true,
+                }]),
+                ..Metadata::default()
+            },
+            object: ExposedApp { spec, status: None },
+        }
+    }
+
+    // ExposedApps created by someone else are reported, never taken over
+    async fn stamp(
+        &mut self,
+        template: &K8sObject<ExposedAppTemplate>,
+        namespace: &str,
+    ) -> Result<NamespaceRolloutStatus, String> {
+        let name = template.metadata.name.clone().unwrap();
+        let uid = template.metadata.uid.clone().unwrap_or_default();
+        let spec = desired_spec(&template.object.spec, namespace);
+        match self.client.get_exposed_app(name.as_str(), namespace).await {
+            Ok(mut existing) => {
+                if !controlled_by(&existing.metadata, uid.as_str()) {
+                    let message = format!(
+                        "ExposedApp {} exists and is not managed by the template",
+                        name
+                    );
+                    return Ok(rollout(
+                        namespace,
+                        NamespaceRolloutPhase::Conflict,
+                        message.as_str(),
+                    ));
+                }
+                if existing.object.spec == spec {
+                    return Ok(rollout_of(namespace, &existing.object));
+                }
+                existing.object.spec = spec;
+                self.client
+                    .put(&existing)
+                    .await
+                    .map_err(|e| format!("Unable to update ExposedApp: {:?}", e))?;
+                info!("ExposedApp {} updated in namespace {}", name, namespace);
+                Ok(rollout(
+                    namespace,
+                    NamespaceRolloutPhase::Progressing,
+                    "Spec updated, waiting for the ExposedApp to be reconciled",
+                ))
+            }
+            Err(K8sClientError::NotFound) => {
+                let exposed_app = Self::exposed_app(template, namespace, spec);
+                self.client
+                    .post(&exposed_app)
+                    .await
+                    .map_err(|e| format!("Unable to create ExposedApp: {:?}", e))?;
+                info!("ExposedApp {} created in namespace {}", name, namespace);
+                Ok(rollout(
+                    namespace,
+                    NamespaceRolloutPhase::Pending,
+                    "Waiting for the first reconcile",
+                ))
+            }
+            Err(e) => Err(format!("Unable to get ExposedApp: {:?}", e)),
+        }
+    }
+
+    // Children come from the ExposedApp watch store, like the ones of an ExposedApp
+    async fn prune(
+        &mut self,
+        template: &K8sObject<ExposedAppTemplate>,
+        selected: &BTreeSet<String>,
+    ) -> Result<(), String> {
+        let uid = template.metadata.uid.clone().unwrap_or_default();
+        for child in self.children.of(uid.as_str()) {
+            let (name, namespace) = (child.name.as_str(), child.namespace.as_str());
+            if child.kind != ExposedApp::KIND || selected.contains(namespace) {
+                continue;
+            }
+            if self.dry_run {
+                info!(
+                    "[dry-run] ExposedApp {} would be deleted from namespace {}, it is no longer selected",
+                    name, namespace
+                );
+                continue;
+            }
+            // Gone with its Deployment and Service before the namespace leaves the status
+            let options = DeleteOptions {
+                propagation_policy: Some(PropagationPolicy::Foreground),
+                ..DeleteOptions::with_uid(child.uid.as_str())
+            };
+            match self
+                .client
+                .delete_and_wait::<ExposedApp>(namespace, name, &options, PRUNE_WAIT)
+                .await
+            {
+                Ok(()) => info!(
+                    "ExposedApp {} deleted from namespace {}, it is no longer selected",
+                    name, namespace
+                ),
+                // Recreated by hand since the store saw it
+                Err(K8sClientError::NotFound) | Err(K8sClientError::Conflict) => {}
+                Err(e) => {
+                    return Err(format!(
+                        "Error occurred while deleting ExposedApp {} from namespace {}: {:?}",
+                        name, namespace, e
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_status(
+        &mut self,
+        template: &mut K8sObject<ExposedAppTemplate>,
+        mut status: ExposedAppTemplateStatus,
+    ) -> Result<(), String> {
+        let name = template.metadata.name.clone().unwrap();
+        let namespaced_name = NamespacedName::new(name.as_str(), "");
+        let mut map = self.cache.lock().await;
+        // Nothing changed, skip the write so ExposedApp events don't bump the resourceVersion
+        let unchanged = template
+            .object
+            .status
+            .clone()
+            .map(|previous| ExposedAppTemplateStatus {
+                last_reconcile_time: None,
+                ..previous
+            });
+        if unchanged.as_ref() == Some(&status) {
+            map.insert(
+                namespaced_name,
+                CacheEntry::new(
+                    template.metadata.resource_version.clone().unwrap().as_str(),
+                    template.metadata.generation.unwrap_or_default(),
+                ),
+            );
+            return Ok(());
+        }
+        status.last_reconcile_time = Some(Time::now());
+        template.object.status = Some(status);
+        match self.client.put_status(template).await {
+            Ok(result) => {
+                info!("Successfully updated status of template {}", name);
+                map.insert(
+                    namespaced_name,
+                    CacheEntry::new(
+                        result.metadata.resource_version.clone().unwrap().as_str(),
+                        result.metadata.generation.unwrap_or_default(),
+                    ),
+                );
+                Ok(())
+            }
+            Err(e) => Err(format!(
+                "Error occurred while updating ExposedAppTemplate status: {:?}",
+                e
+            )),
+        }
+    }
+}
+
+impl Reconcile for TemplateReconciler {
+    type Error = String;
+
+    async fn reconcile(&mut self, request: NamespacedName) -> Result<Action, String> {
+        let name = request.name;
+        let uri = self
+            .client
+            .api_resource::<ExposedAppTemplate>()
+            .object_uri("", name.as_str());
+        let mut template = match self.client.get::<ExposedAppTemplate>(uri.as_str()).await {
+            Ok(template) => {
+                Span::current().record("generation", template.metadata.generation);
+                template
+            }
+            // Generated ExposedApps are garbage collected with it
+            Err(K8sClientError::NotFound) => {
+                self.index.lock().unwrap().remove(name.as_str());
+                info!("ExposedAppTemplate not found, probably already deleted. It's fine");
+                return Ok(Action::Done);
+            }
+            Err(e) => return Err(format!("Unable to get ExposedAppTemplate: {:?}", e)),
+        };
+        if template.metadata.deletion_timestamp.is_some() || self.paused {
+            return Ok(Action::Done);
+        }
+        info!("Synchronizing template {}", name);
+        let selector = template.object.spec.namespace_selector.clone();
+        let selected = self.selected_namespaces(&selector).await?;
+        self.index
+            .lock()
+            .unwrap()
+            .update(name.as_str(), selector, selected.clone());
+        // A failing namespace doesn't hold back the others, it is retried with the template
+        let mut namespaces = Vec::new();
+        let mut errors = Vec::new();
+        for namespace in &selected {
+            match self.stamp(&template, namespace).await {
+                Ok(status) => namespaces.push(status),
+                Err(e) => {
+                    namespaces.push(rollout(
+                        namespace,
+                        NamespaceRolloutPhase::Failed,
+                        e.as_str(),
+                    ));
+                    errors.push(format!("{}: {}", namespace, e));
+                }
+            }
+        }
+        self.prune(&template, &selected).await?;
+        // Status is not written in dry-run mode
+        if !self.dry_run {
+            let ready = namespaces
+                .iter()
+                .filter(|namespace| namespace.phase == NamespaceRolloutPhase::Ready)
+                .count();
+            let status = ExposedAppTemplateStatus {
+                observed_generation: template.metadata.generation,
+                selected_namespaces: selected.len() as u32,
+                ready_namespaces: ready as u32,
+                namespaces,
+                last_reconcile_time: None,
+            };
+            self.write_status(&mut template, status).await?;
+        }
+        if errors.is_empty() {
+            Ok(Action::Done)
+        } else {
+            Err(format!(
+                "Unable to stamp {} namespaces: {}",
+                errors.len(),
+                errors.join(", ")
+            ))
+        }
+    }
+}
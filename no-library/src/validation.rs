@@ -0,0 +1,718 @@
+use crate::admission_review::{AdmissionReview, AdmissionStatus, StatusCause, StatusDetails};
+use crate::canary::{ABORT_ANNOTATION, PROMOTE_ANNOTATION};
+use crate::k8s_types::CanaryStatus;
+use axum::Json;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use tracing::{error, info};
+
+const GROUP: &str = "stable.no-library.com";
+const PROTOCOLS: [&str; 3] = ["TCP", "UDP", "SCTP"];
+// ExternalName Services need an externalName the spec has no field for
+const SERVICE_TYPES: [&str; 3] = ["ClusterIP", "NodePort", "LoadBalancer"];
+// Service types allocating node ports
+const NODE_PORT_SERVICE_TYPES: [&str; 2] = ["NodePort", "LoadBalancer"];
+const DEFAULT_SERVICE_TYPE: &str = "ClusterIP";
+// Service port names are DNS labels
+const MAX_PORT_NAME_LENGTH: usize = 63;
+
+// https://pkg.go.dev/k8s.io/apimachinery/pkg/util/validation/field#ErrorType
+enum ErrorType {
+    Required,
+    Invalid,
+    NotSupported,
+    Duplicate,
+    Forbidden,
+}
+
+impl ErrorType {
+    fn reason(&self) -> &'static str {
+        match self {
+            ErrorType::Required => "FieldValueRequired",
+            ErrorType::Invalid => "FieldValueInvalid",
+            ErrorType::NotSupported => "FieldValueNotSupported",
+            ErrorType::Duplicate => "FieldValueDuplicate",
+            ErrorType::Forbidden => "FieldValueForbidden",
+        }
+    }
+}
+
+pub struct FieldError {
+    field: String,
+    error_type: ErrorType,
+    value: Option<Value>,
+    detail: String,
+}
+
+impl FieldError {
+    fn new(field: &str, error_type: ErrorType, value: Option<&Value>, detail: &str) -> Self {
+        FieldError {
+            field: String::from(field),
+            error_type,
+            value: value.cloned(),
+            detail: String::from(detail),
+        }
+    }
+
+    // Same wording as the API server, without the field path
+    fn message(&self) -> String {
+        let value = self
+            .value
+            .as_ref()
+            .map(Value::to_string)
+            .unwrap_or_default();
+        match self.error_type {
+            ErrorType::Required => format!("Required value: {}", self.detail),
+            ErrorType::Invalid => format!("Invalid value: {}: {}", value, self.detail),
+            ErrorType::NotSupported => format!("Unsupported value: {}: {}", value, self.detail),
+            ErrorType::Duplicate => format!("Duplicate value: {}", value),
+            ErrorType::Forbidden => format!("Forbidden: {}", self.detail),
+        }
+    }
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message())
+    }
+}
+
+fn service_type(spec: &Value) -> &str {
+    spec.get("serviceType")
+        .and_then(Value::as_str)
+        .unwrap_or(DEFAULT_SERVICE_TYPE)
+}
+
+fn is_dns_label(name: &str) -> bool {
+    let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    !name.is_empty()
+        && name.len() <= MAX_PORT_NAME_LENGTH
+        && name.chars().all(|c| alphanumeric(c) || c == '-')
+        && name.starts_with(alphanumeric)
+        && name.ends_with(alphanumeric)
+}
+
+fn validate_port_number(field: &str, value: Option<&Value>, errors: &mut Vec<FieldError>) {
+    match value {
+        None => errors.push(FieldError::new(field, ErrorType::Required, None, "")),
+        Some(number) if !number.as_u64().is_some_and(|n| (1..=65535).contains(&n)) => {
+            errors.push(FieldError::new(
+                field,
+                ErrorType::Invalid,
+                Some(number),
+                "must be between 1 and 65535, inclusive",
+            ))
+        }
+        Some(_) => {}
+    }
+}
+
+fn validate_ports(spec: &Value, errors: &mut Vec<FieldError>) {
+    let ports = match spec.get("ports") {
+        None => {
+            errors.push(FieldError::new("spec.ports", ErrorType::Required, None, ""));
+            return;
+        }
+        Some(Value::Array(ports)) if !ports.is_empty() => ports,
+        Some(ports) => {
+            errors.push(FieldError::new(
+                "spec.ports",
+                ErrorType::Invalid,
+                Some(ports),
+                "must contain at least one port",
+            ));
+            return;
+        }
+    };
+    let allocates_node_ports = NODE_PORT_SERVICE_TYPES.contains(&service_type(spec));
+    let mut names = HashSet::new();
+    let mut service_ports = HashSet::new();
+    let mut node_ports = HashSet::new();
+    for (index, port) in ports.iter().enumerate() {
+        let path = format!("spec.ports[{}]", index);
+        let field = |name: &str| format!("{}.{}", path, name);
+        match port.get("name") {
+            None if ports.len() > 1 => errors.push(FieldError::new(
+                field("name").as_str(),
+                ErrorType::Required,
+                None,
+                "must be set when more than one port is exposed",
+            )),
+            None => {}
+            Some(name) if !name.as_str().is_some_and(is_dns_label) => {
+                errors.push(FieldError::new(
+                    field("name").as_str(),
+                    ErrorType::Invalid,
+                    Some(name),
+                    "must consist of lower case alphanumeric characters or '-', start and end with an alphanumeric character and be at most 63 characters",
+                ))
+            }
+            Some(name) if !names.insert(name) => errors.push(FieldError::new(
+                field("name").as_str(),
+                ErrorType::Duplicate,
+                Some(name),
+                "",
+            )),
+            Some(_) => {}
+        }
+        validate_port_number(field("port").as_str(), port.get("port"), errors);
+        validate_port_number(
+            field("containerPort").as_str(),
+            port.get("containerPort"),
+            errors,
+        );
+        let protocol = match port.get("protocol") {
+            None => "TCP",
+            Some(protocol) => match protocol.as_str().filter(|p| PROTOCOLS.contains(p)) {
+                Some(protocol) => protocol,
+                None => {
+                    errors.push(FieldError::new(
+                        field("protocol").as_str(),
+                        ErrorType::NotSupported,
+                        Some(protocol),
+                        "supported values: \"TCP\", \"UDP\", \"SCTP\"",
+                    ));
+                    continue;
+                }
+            },
+        };
+        if let Some(number) = port.get("port").and_then(Value::as_u64) {
+            if !service_ports.insert((number, protocol)) {
+                errors.push(FieldError::new(
+                    path.as_str(),
+                    ErrorType::Duplicate,
+                    Some(&json!({"port": number, "protocol": protocol})),
+                    "",
+                ));
+            }
+        }
+        if let Some(node_port) = port.get("nodePort") {
+            if !allocates_node_ports {
+                errors.push(FieldError::new(
+                    field("nodePort").as_str(),
+                    ErrorType::Forbidden,
+                    None,
+                    "may only be set when serviceType is NodePort or LoadBalancer",
+                ));
+                continue;
+            }
+            validate_port_number(field("nodePort").as_str(), Some(node_port), errors);
+            if !node_ports.insert((node_port, protocol)) {
+                errors.push(FieldError::new(
+                    field("nodePort").as_str(),
+                    ErrorType::Duplicate,
+                    Some(node_port),
+                    "",
+                ));
+            }
+        }
+    }
+}
+
+fn validate_integer(field: &str, value: &Value, min: u64, max: u64, errors: &mut Vec<FieldError>) {
+    if !value.as_u64().is_some_and(|n| (min..=max).contains(&n)) {
+        errors.push(FieldError::new(
+            field,
+            ErrorType::Invalid,
+            Some(value),
+            format!("must be between {} and {}, inclusive", min, max).as_str(),
+        ));
+    }
+}
+
+fn validate_canary(canary: &Value, errors: &mut Vec<FieldError>) {
+    let path = "spec.rollout.canary";
+    match canary.get("steps") {
+        Some(Value::Array(steps)) if !steps.is_empty() => {
+            for (index, step) in steps.iter().enumerate() {
+                let field = |name: &str| format!("{}.steps[{}].{}", path, index, name);
+                match step.get("weight") {
+                    Some(weight) => {
+                        validate_integer(field("weight").as_str(), weight, 1, 100, errors)
+                    }
+                    None => errors.push(FieldError::new(
+                        field("weight").as_str(),
+                        ErrorType::Required,
+                        None,
+                        "",
+                    )),
+                }
+                if let Some(pause) = step.get("pauseSeconds") {
+                    validate_integer(
+                        field("pauseSeconds").as_str(),
+                        pause,
+                        0,
+                        u32::MAX as u64,
+                        errors,
+                    );
+                }
+            }
+        }
+        Some(steps) => errors.push(FieldError::new(
+            format!("{}.steps", path).as_str(),
+            ErrorType::Invalid,
+            Some(steps),
+            "must contain at least one step",
+        )),
+        None => errors.push(FieldError::new(
+            format!("{}.steps", path).as_str(),
+            ErrorType::Required,
+            None,
+            "",
+        )),
+    }
+    if let Some(max_unavailable) = canary.get("maxUnavailable") {
+        let field = format!("{}.maxUnavailable", path);
+        validate_integer(field.as_str(), max_unavailable, 0, i32::MAX as u64, errors);
+    }
+    if let Some(deadline) = canary.get("progressDeadlineSeconds") {
+        let field = format!("{}.progressDeadlineSeconds", path);
+        validate_integer(field.as_str(), deadline, 1, i32::MAX as u64, errors);
+    }
+}
+
+fn validate_env_from(spec: &Value, errors: &mut Vec<FieldError>) {
+    let sources = match spec.get("envFrom") {
+        None => return,
+        Some(Value::Array(sources)) => sources,
+        Some(sources) => {
+            errors.push(FieldError::new(
+                "spec.envFrom",
+                ErrorType::Invalid,
+                Some(sources),
+                "must be a list",
+            ));
+            return;
+        }
+    };
+    for (index, source) in sources.iter().enumerate() {
+        let path = format!("spec.envFrom[{}]", index);
+        let references: Vec<&str> = ["configMapRef", "secretRef"]
+            .into_iter()
+            .filter(|reference| source.get(reference).is_some())
+            .collect();
+        match references.as_slice() {
+            [reference] => {
+                let field = format!("{}.{}.name", path, reference);
+                match source[reference].get("name") {
+                    Some(Value::String(name)) if !name.is_empty() => {}
+                    Some(name) => errors.push(FieldError::new(
+                        field.as_str(),
+                        ErrorType::Invalid,
+                        Some(name),
+                        "must be a non empty string",
+                    )),
+                    None => errors.push(FieldError::new(
+                        field.as_str(),
+                        ErrorType::Required,
+                        None,
+                        "",
+                    )),
+                }
+            }
+            [] => errors.push(FieldError::new(
+                path.as_str(),
+                ErrorType::Required,
+                None,
+                "must specify one of: `configMapRef` or `secretRef`",
+            )),
+            _ => errors.push(FieldError::new(
+                path.as_str(),
+                ErrorType::Forbidden,
+                None,
+                "may not specify more than 1 source type",
+            )),
+        }
+    }
+}
+
+/*
+   Semantic checks the CRD schema is not able to express, so invalid specs are
+   rejected when they are submitted instead of failing every reconciliation.
+*/
+pub fn validate_spec(spec: &Value) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    match spec.get("image") {
+        Some(Value::String(image)) if !image.is_empty() => {}
+        Some(image) => errors.push(FieldError::new(
+            "spec.image",
+            ErrorType::Invalid,
+            Some(image),
+            "must be a non empty string",
+        )),
+        None => errors.push(FieldError::new("spec.image", ErrorType::Required, None, "")),
+    }
+    if let Some(replicas) = spec.get("replicas").filter(|replicas| !replicas.is_u64()) {
+        errors.push(FieldError::new(
+            "spec.replicas",
+            ErrorType::Invalid,
+            Some(replicas),
+            "must be greater than or equal to 0",
+        ));
+    }
+    if let Some(service_type) = spec.get("serviceType") {
+        if !service_type
+            .as_str()
+            .is_some_and(|t| SERVICE_TYPES.contains(&t))
+        {
+            errors.push(FieldError::new(
+                "spec.serviceType",
+                ErrorType::NotSupported,
+                Some(service_type),
+                "supported values: \"ClusterIP\", \"NodePort\", \"LoadBalancer\"",
+            ));
+        }
+    }
+    validate_ports(spec, &mut errors);
+    validate_env_from(spec, &mut errors);
+    if let Some(canary) = spec.pointer("/rollout/canary") {
+        validate_canary(canary, &mut errors);
+    }
+    errors
+}
+
+fn canary_in_progress(status: Option<&Value>) -> bool {
+    status
+        .and_then(|status| status.get("canary"))
+        .and_then(|canary| serde_json::from_value::<CanaryStatus>(canary.clone()).ok())
+        .is_some_and(|canary| canary.is_in_progress())
+}
+
+/*
+   Changes the reconciler is not able to apply safely, serviceType changes are fine,
+   the node ports are released by the reconciler.
+   A named port keeps its protocol: the Service and the v1 ports annotation identify ports
+   by name, and v1 clients, which do not see names, would flip the protocol behind it.
+   The canary strategy stays while a rollout is in progress, removing it would skip the
+   remaining steps and move every replica to the new image at once.
+*/
+pub fn validate_update(
+    spec: &Value,
+    old_spec: &Value,
+    old_status: Option<&Value>,
+) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let ports = |spec: &Value| spec.get("ports").and_then(Value::as_array).cloned();
+    let protocol = |port: &Value| {
+        port.get("protocol")
+            .and_then(Value::as_str)
+            .unwrap_or("TCP")
+            .to_string()
+    };
+    let old_protocols: HashMap<String, String> = ports(old_spec)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|port| Some((port.get("name")?.as_str()?.to_string(), protocol(port))))
+        .collect();
+    for (index, port) in ports(spec).unwrap_or_default().iter().enumerate() {
+        let name = port.get("name").and_then(Value::as_str);
+        let old_protocol = name.and_then(|name| old_protocols.get(name));
+        if let Some(old_protocol) = old_protocol.filter(|old| **old != protocol(port)) {
+            errors.push(FieldError::new(
+                format!("spec.ports[{}].protocol", index).as_str(),
+                ErrorType::Invalid,
+                Some(&Value::from(protocol(port))),
+                format!(
+                    "may not change from {} while the port keeps its name, rename the port",
+                    old_protocol
+                )
+                .as_str(),
+            ));
+        }
+    }
+    let has_canary = |spec: &Value| spec.pointer("/rollout/canary").is_some();
+    if has_canary(old_spec) && !has_canary(spec) && canary_in_progress(old_status) {
+        errors.push(FieldError::new(
+            "spec.rollout.canary",
+            ErrorType::Forbidden,
+            None,
+            format!(
+                "may not be removed while a canary rollout is in progress, promote or abort it with the {} or {} annotation first",
+                PROMOTE_ANNOTATION, ABORT_ANNOTATION
+            )
+            .as_str(),
+        ));
+    }
+    errors
+}
+
+fn denial(name: Option<String>, errors: &[FieldError]) -> AdmissionStatus {
+    let listed: Vec<String> = errors.iter().map(FieldError::to_string).collect();
+    let listed = match listed.as_slice() {
+        [single] => single.clone(),
+        _ => format!("[{}]", listed.join(", ")),
+    };
+    AdmissionStatus {
+        code: 422,
+        reason: String::from("Invalid"),
+        message: format!(
+            "ExposedApp.{} \"{}\" is invalid: {}",
+            GROUP,
+            name.clone().unwrap_or_default(),
+            listed
+        ),
+        details: Some(StatusDetails {
+            name,
+            group: String::from(GROUP),
+            kind: String::from("ExposedApp"),
+            causes: errors
+                .iter()
+                .map(|e| StatusCause {
+                    reason: String::from(e.error_type.reason()),
+                    message: e.message(),
+                    field: e.field.clone(),
+                })
+                .collect(),
+        }),
+    }
+}
+
+pub async fn validate(Json(review): Json<AdmissionReview>) -> Json<AdmissionReview> {
+    let request = match review.request {
+        Some(request) => request,
+        None => {
+            error!("AdmissionReview request missing");
+            return Json(AdmissionReview::denied(
+                "",
+                AdmissionStatus {
+                    code: 400,
+                    reason: String::from("BadRequest"),
+                    message: String::from("AdmissionReview request missing"),
+                    details: None,
+                },
+            ));
+        }
+    };
+    let spec = |object: &Option<Value>| object.as_ref().and_then(|o| o.get("spec")).cloned();
+    let mut errors = match spec(&request.object) {
+        Some(spec) => validate_spec(&spec),
+        None => vec![FieldError::new("spec", ErrorType::Required, None, "")],
+    };
+    if request.operation == "UPDATE" {
+        if let (Some(spec), Some(old_spec)) = (spec(&request.object), spec(&request.old_object)) {
+            let old_status = request.old_object.as_ref().and_then(|o| o.get("status"));
+            errors.extend(validate_update(&spec, &old_spec, old_status));
+        }
+    }
+    let name = request.name.or_else(|| {
+        request
+            .object
+            .as_ref()
+            .and_then(|object| object.pointer("/metadata/name"))
+            .and_then(Value::as_str)
+            .map(String::from)
+    });
+    if errors.is_empty() {
+        return Json(AdmissionReview::allowed(request.uid.as_str()));
+    }
+    let status = denial(name, &errors);
+    info!(
+        "Denied {} of ExposedApp in namespace {}: {}",
+        request.operation,
+        request.namespace.unwrap_or_default(),
+        status.message
+    );
+    Json(AdmissionReview::denied(request.uid.as_str(), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admission_review::AdmissionRequest;
+
+    fn spec(ports: Value) -> Value {
+        json!({"image": "nginx:alpine", "serviceType": "NodePort", "ports": ports})
+    }
+
+    fn fields(errors: &[FieldError]) -> Vec<&str> {
+        errors.iter().map(|error| error.field.as_str()).collect()
+    }
+
+    fn canary_spec() -> Value {
+        json!({
+            "image": "nginx:1.27",
+            "ports": [{"port": 80, "containerPort": 80}],
+            "rollout": {"canary": {"steps": [{"weight": 20}]}},
+        })
+    }
+
+    fn canary_status(phase: &str) -> Value {
+        json!({"canary": {
+            "image": "nginx:1.27",
+            "stableImage": "nginx:1.26",
+            "step": 0,
+            "weight": 20,
+            "phase": phase,
+            "message": "",
+        }})
+    }
+
+    // Field path and message of every error, as listed in the denial
+    fn denials(spec: &Value) -> Vec<String> {
+        validate_spec(spec)
+            .iter()
+            .map(FieldError::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn valid_spec_is_allowed() {
+        let spec = spec(json!([
+            {"name": "http", "port": 80, "containerPort": 8080, "nodePort": 30080},
+            {"name": "dns", "protocol": "UDP", "port": 53, "containerPort": 53},
+        ]));
+        assert!(denials(&spec).is_empty());
+        assert!(denials(&canary_spec()).is_empty());
+    }
+
+    #[test]
+    fn node_port_needs_a_node_port_service_type() {
+        let port = json!([{"port": 80, "containerPort": 80, "nodePort": 30080}]);
+        let cluster_ip = json!({"image": "nginx:alpine", "ports": port});
+        assert_eq!(
+            denials(&cluster_ip),
+            ["spec.ports[0].nodePort: Forbidden: may only be set when serviceType is NodePort or LoadBalancer"]
+        );
+        let load_balancer =
+            json!({"image": "nginx:alpine", "serviceType": "LoadBalancer", "ports": port});
+        assert!(denials(&load_balancer).is_empty());
+    }
+
+    #[test]
+    fn protocol_is_tcp_udp_or_sctp() {
+        let spec = spec(json!([{"port": 80, "containerPort": 80, "protocol": "HTTP"}]));
+        assert_eq!(
+            denials(&spec),
+            ["spec.ports[0].protocol: Unsupported value: \"HTTP\": supported values: \"TCP\", \"UDP\", \"SCTP\""]
+        );
+    }
+
+    #[test]
+    fn port_numbers_are_in_range() {
+        let spec = spec(json!([
+            {"name": "zero", "port": 0, "containerPort": 65536, "nodePort": 30080},
+            {"name": "negative", "port": 81, "containerPort": 81, "nodePort": -1},
+            {"name": "large", "port": 82, "containerPort": 82, "nodePort": 65536},
+        ]));
+        assert_eq!(
+            denials(&spec),
+            [
+                "spec.ports[0].port: Invalid value: 0: must be between 1 and 65535, inclusive",
+                "spec.ports[0].containerPort: Invalid value: 65536: must be between 1 and 65535, inclusive",
+                "spec.ports[1].nodePort: Invalid value: -1: must be between 1 and 65535, inclusive",
+                "spec.ports[2].nodePort: Invalid value: 65536: must be between 1 and 65535, inclusive",
+            ]
+        );
+    }
+
+    #[test]
+    fn replicas_are_a_non_negative_integer() {
+        for replicas in [json!(-1), json!(1.5), json!("2")] {
+            let mut spec = spec(json!([{"port": 80, "containerPort": 80}]));
+            spec["replicas"] = replicas.clone();
+            assert_eq!(
+                denials(&spec),
+                [format!(
+                    "spec.replicas: Invalid value: {}: must be greater than or equal to 0",
+                    replicas
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn canary_max_unavailable_is_a_non_negative_int32() {
+        for max_unavailable in [json!(-1), json!("25%"), json!(2147483648u64)] {
+            let mut spec = canary_spec();
+            spec["rollout"]["canary"]["maxUnavailable"] = max_unavailable.clone();
+            assert_eq!(
+                denials(&spec),
+                [format!(
+                    "spec.rollout.canary.maxUnavailable: Invalid value: {}: must be between 0 and 2147483647, inclusive",
+                    max_unavailable
+                )]
+            );
+        }
+        let mut spec = canary_spec();
+        spec["rollout"]["canary"]["maxUnavailable"] = json!(0);
+        assert!(denials(&spec).is_empty());
+    }
+
+    #[test]
+    fn service_type_changes_are_allowed() {
+        let old =
+            spec(json!([{"name": "http", "port": 80, "containerPort": 80, "nodePort": 30080}]));
+        let new = json!({"image": "nginx:alpine", "serviceType": "ClusterIP", "ports": [{"name": "http", "port": 80, "containerPort": 80}]});
+        assert!(validate_update(&new, &old, None).is_empty());
+    }
+
+    #[test]
+    fn named_ports_keep_their_protocol() {
+        let old = spec(json!([
+            {"name": "dns", "port": 53, "containerPort": 53},
+            {"name": "metrics", "port": 9090, "containerPort": 9090},
+        ]));
+        let flipped = spec(json!([
+            {"name": "dns", "protocol": "UDP", "port": 53, "containerPort": 53},
+            {"name": "metrics", "protocol": "TCP", "port": 9090, "containerPort": 9090},
+        ]));
+        let errors = validate_update(&flipped, &old, None);
+        assert_eq!(fields(&errors), ["spec.ports[0].protocol"]);
+        assert_eq!(
+            errors[0].message(),
+            "Invalid value: \"UDP\": may not change from TCP while the port keeps its name, rename the port"
+        );
+        let renamed = spec(json!([
+            {"name": "dns-udp", "protocol": "UDP", "port": 53, "containerPort": 53},
+            {"name": "metrics", "port": 9090, "containerPort": 9090},
+        ]));
+        assert!(validate_update(&renamed, &old, None).is_empty());
+    }
+
+    #[test]
+    fn canary_stays_while_the_rollout_is_in_progress() {
+        let old = canary_spec();
+        let mut removed = canary_spec();
+        removed.as_object_mut().unwrap().remove("rollout");
+        for phase in ["Progressing", "Paused"] {
+            let errors = validate_update(&removed, &old, Some(&canary_status(phase)));
+            assert_eq!(fields(&errors), ["spec.rollout.canary"]);
+        }
+        for phase in ["Promoted", "Aborted"] {
+            assert!(validate_update(&removed, &old, Some(&canary_status(phase))).is_empty());
+        }
+        assert!(validate_update(&removed, &old, None).is_empty());
+        let mut steps_changed = canary_spec();
+        steps_changed["rollout"]["canary"]["steps"] = json!([{"weight": 50}, {"weight": 100}]);
+        let status = canary_status("Progressing");
+        assert!(validate_update(&steps_changed, &old, Some(&status)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn updates_are_checked_against_the_old_object() {
+        let review = |operation: &str| AdmissionReview {
+            api_version: String::from("admission.k8s.io/v1"),
+            kind: String::from("AdmissionReview"),
+            request: Some(AdmissionRequest {
+                uid: String::from("uid"),
+                operation: String::from(operation),
+                name: Some(String::from("demo")),
+                namespace: Some(String::from("example")),
+                object: Some(
+                    json!({"spec": {"image": "nginx:1.27", "ports": [{"port": 80, "containerPort": 80}]}}),
+                ),
+                old_object: Some(json!({"spec": canary_spec(), "status": canary_status("Paused")})),
+            }),
+            response: None,
+        };
+        let Json(created) = validate(Json(review("CREATE"))).await;
+        assert!(created.response.unwrap().allowed);
+        let Json(updated) = validate(Json(review("UPDATE"))).await;
+        let response = updated.response.unwrap();
+        assert!(!response.allowed);
+        let causes = response.status.unwrap().details.unwrap().causes;
+        assert_eq!(causes[0].field, "spec.rollout.canary");
+        assert_eq!(causes[0].reason, "FieldValueForbidden");
+    }
+}
@@ -0,0 +1,116 @@
+/*
+   Shared by the integration tests, every test starts its own fake API server
+   and talks to it over HTTPS like the operator does to a real one.
+*/
+use no_library::config::Config;
+use no_library::discovery::Discovery;
+use no_library::fake_api::{FakeApiServer, RunningApiServer};
+use no_library::introspection::Introspection;
+use no_library::k8s_client::client::K8sClient;
+use no_library::k8s_types::{K8sObject, Resource};
+use no_library::operator::operator::{handle_owned_resources, required_resources};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Cluster {
+    pub server: RunningApiServer,
+    pub config: Config,
+    // Discovered, like the one handed to the controllers
+    pub client: K8sClient,
+}
+
+// None when it does not exist
+pub async fn get<T: Resource + DeserializeOwned>(
+    client: &K8sClient,
+    namespace: &str,
+    name: &str,
+) -> Option<K8sObject<T>> {
+    let uri = client.api_resource::<T>().object_uri(namespace, name);
+    client.clone().get::<T>(uri.as_str()).await.ok()
+}
+
+// Manifests relative to the crate root, e.g. examples/exposed_app.yaml
+pub fn seeded(files: &[&str]) -> FakeApiServer {
+    files
+        .iter()
+        .fold(FakeApiServer::new().tls(), |server, file| {
+            server.seed_file(Path::new(env!("CARGO_MANIFEST_DIR")).join(file).as_path())
+        })
+}
+
+pub async fn start(server: FakeApiServer, args: &[&str]) -> Cluster {
+    let server = server.start().await;
+    let (config, client) = connect(server.url.as_str(), server.ca_file.as_deref(), args).await;
+    Cluster {
+        server,
+        config,
+        client,
+    }
+}
+
+// Configured from the command line like the operator, the client is discovered
+pub async fn connect(url: &str, ca_file: Option<&Path>, args: &[&str]) -> (Config, K8sClient) {
+    let mut command_line = vec![
+        String::from("no-library"),
+        String::from("--api-server-url"),
+        String::from(url),
+        String::from("--pod-name"),
+        String::from("test"),
+    ];
+    if let Some(ca_file) = ca_file {
+        command_line.push(String::from("--ca-file"));
+        command_line.push(ca_file.display().to_string());
+    }
+    command_line.extend(args.iter().map(|arg| String::from(*arg)));
+    let config = Config::parse_from(command_line).expect("Invalid test configuration");
+    let mut client = K8sClient::new(&config.client).await;
+    let discovery = Discovery::load(&mut client, &required_resources())
+        .await
+        .expect("Discovery failed");
+    (config, client.with_discovery(discovery))
+}
+
+// Both controllers, started as if this replica was elected
+pub fn run_operator(client: &K8sClient, config: &Config) -> JoinHandle<()> {
+    let notify = Arc::new(Notify::new());
+    notify.notify_one();
+    tokio::spawn(handle_owned_resources(
+        client.clone(),
+        notify,
+        config.clone(),
+        None,
+        Introspection::default(),
+    ))
+}
+
+impl Cluster {
+    pub fn run_operator(&self) -> JoinHandle<()> {
+        run_operator(&self.client, &self.config)
+    }
+}
+
+// Polls until check returns Some, panics after TIMEOUT
+pub async fn eventually<T, F, Fut>(what: &str, mut check: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(value) = check().await {
+            return value;
+        }
+        if Instant::now() > deadline {
+            panic!("Timed out waiting for {}", what);
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+}
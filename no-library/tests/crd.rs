@@ -0,0 +1,16 @@
+use no_library::crd;
+
+// The manifest is applied by kustomize and installed by --bootstrap, it must match the types
+#[test]
+fn checked_in_crd_matches_the_types() {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/config/custom_resource_definition.yaml"
+    );
+    let checked_in = std::fs::read_to_string(path).unwrap();
+    assert!(
+        checked_in == crd::generate(),
+        "{} is out of date, regenerate it with just crd",
+        path
+    );
+}
@@ -0,0 +1,280 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{eventually, get, seeded, start, Cluster};
+use no_library::k8s_client::client::K8sClientError;
+use no_library::k8s_types::{ConfigMap, DeleteOptions, Preconditions, PropagationPolicy};
+use serde_json::json;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::time::sleep;
+
+const MANIFESTS: &str = r#"
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: owner
+  namespace: example
+  labels:
+    track: stable
+---
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: dependent
+  namespace: example
+  labels:
+    track: canary
+---
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: unrelated
+  namespace: example
+  labels:
+    track: canary
+"#;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn cluster() -> Cluster {
+    start(seeded(&[]).seed(MANIFESTS), &[]).await
+}
+
+async fn uid(cluster: &Cluster, name: &str) -> String {
+    get::<ConfigMap>(&cluster.client, "example", name)
+        .await
+        .and_then(|config_map| config_map.metadata.uid)
+        .unwrap()
+}
+
+// dependent is owned by owner
+async fn owned(cluster: &Cluster) {
+    let owner_uid = uid(cluster, "owner").await;
+    let patch = json!({
+        "metadata": {
+            "ownerReferences": [{
+                "apiVersion": "v1",
+                "kind": "ConfigMap",
+                "name": "owner",
+                "uid": owner_uid,
+                "blockOwnerDeletion": true,
+                "controller": true,
+            }]
+        }
+    });
+    cluster
+        .client
+        .clone()
+        .merge_patch::<ConfigMap>("example", "dependent", &patch)
+        .await
+        .unwrap();
+}
+
+async fn exists(cluster: &Cluster, name: &str) -> bool {
+    get::<ConfigMap>(&cluster.client, "example", name)
+        .await
+        .is_some()
+}
+
+#[tokio::test]
+async fn foreground_deletes_the_dependents_first() {
+    let cluster = cluster().await;
+    owned(&cluster).await;
+    let options = DeleteOptions {
+        propagation_policy: Some(PropagationPolicy::Foreground),
+        ..DeleteOptions::with_uid(uid(&cluster, "owner").await.as_str())
+    };
+    cluster
+        .client
+        .clone()
+        .delete_and_wait::<ConfigMap>("example", "owner", &options, WAIT)
+        .await
+        .unwrap();
+    assert!(!exists(&cluster, "owner").await);
+    assert!(!exists(&cluster, "dependent").await);
+    assert!(exists(&cluster, "unrelated").await);
+}
+
+#[tokio::test]
+async fn orphan_keeps_the_dependents() {
+    let cluster = cluster().await;
+    owned(&cluster).await;
+    let options = DeleteOptions {
+        propagation_policy: Some(PropagationPolicy::Orphan),
+        ..Default::default()
+    };
+    cluster
+        .client
+        .clone()
+        .delete_and_wait::<ConfigMap>("example", "owner", &options, WAIT)
+        .await
+        .unwrap();
+    assert!(!exists(&cluster, "owner").await);
+    let dependent = get::<ConfigMap>(&cluster.client, "example", "dependent")
+        .await
+        .expect("Orphaned dependent deleted");
+    assert!(dependent
+        .metadata
+        .owner_references
+        .unwrap_or_default()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn failed_preconditions_are_conflicts() {
+    let cluster = cluster().await;
+    let mut client = cluster.client.clone();
+    let other_uid = uid(&cluster, "unrelated").await;
+    let result = client
+        .delete_and_wait::<ConfigMap>(
+            "example",
+            "owner",
+            &DeleteOptions::with_uid(other_uid.as_str()),
+            WAIT,
+        )
+        .await;
+    assert!(matches!(result, Err(K8sClientError::Conflict)));
+
+    let read = get::<ConfigMap>(&client, "example", "owner").await.unwrap();
+    client
+        .merge_patch::<ConfigMap>("example", "owner", &json!({"data": {"LEVEL": "debug"}}))
+        .await
+        .unwrap();
+    let stale = DeleteOptions {
+        preconditions: Some(Preconditions {
+            uid: None,
+            resource_version: read.metadata.resource_version,
+        }),
+        ..Default::default()
+    };
+    let result = client
+        .delete_and_wait::<ConfigMap>("example", "owner", &stale, WAIT)
+        .await;
+    assert!(matches!(result, Err(K8sClientError::Conflict)));
+    assert!(exists(&cluster, "owner").await);
+}
+
+#[tokio::test]
+async fn dry_run_deletes_nothing() {
+    let cluster = cluster().await;
+    let mut client = cluster.client.clone();
+    let options = DeleteOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    client
+        .delete_and_wait::<ConfigMap>("example", "owner", &options, WAIT)
+        .await
+        .unwrap();
+    let uri = client.api_resource::<ConfigMap>().namespaced_uri("example");
+    client
+        .delete_collection(uri.as_str(), "", &options)
+        .await
+        .unwrap();
+    for name in ["owner", "dependent", "unrelated"] {
+        assert!(exists(&cluster, name).await, "{} deleted", name);
+    }
+}
+
+#[tokio::test]
+async fn delete_collection_deletes_the_selected_objects() {
+    let cluster = cluster().await;
+    let mut client = cluster.client.clone();
+    let uri = client.api_resource::<ConfigMap>().namespaced_uri("example");
+    client
+        .delete_collection(uri.as_str(), "track=canary", &DeleteOptions::default())
+        .await
+        .unwrap();
+    assert!(exists(&cluster, "owner").await);
+    assert!(!exists(&cluster, "dependent").await);
+    assert!(!exists(&cluster, "unrelated").await);
+}
+
+#[tokio::test]
+async fn waits_until_the_finalizers_are_removed() {
+    let cluster = cluster().await;
+    let mut client = cluster.client.clone();
+    client
+        .merge_patch::<ConfigMap>(
+            "example",
+            "owner",
+            &json!({"metadata": {"finalizers": ["example.com/cleanup"]}}),
+        )
+        .await
+        .unwrap();
+    let mut waiting = client.clone();
+    let deleted = spawn(async move {
+        waiting
+            .delete_and_wait::<ConfigMap>("example", "owner", &DeleteOptions::default(), WAIT)
+            .await
+    });
+    eventually("deletionTimestamp", || async {
+        get::<ConfigMap>(&cluster.client, "example", "owner")
+            .await
+            .and_then(|config_map| config_map.metadata.deletion_timestamp)
+    })
+    .await;
+    // Changes to other objects of the namespace do not end the wait
+    client
+        .merge_patch::<ConfigMap>("example", "unrelated", &json!({"data": {"A": "b"}}))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+    assert!(!deleted.is_finished());
+    client
+        .merge_patch::<ConfigMap>(
+            "example",
+            "owner",
+            &json!({"metadata": {"finalizers": null}}),
+        )
+        .await
+        .unwrap();
+    deleted.await.unwrap().unwrap();
+    assert!(!exists(&cluster, "owner").await);
+}
+
+#[tokio::test]
+async fn times_out_while_finalizers_remain() {
+    let cluster = cluster().await;
+    let mut client = cluster.client.clone();
+    client
+        .merge_patch::<ConfigMap>(
+            "example",
+            "owner",
+            &json!({"metadata": {"finalizers": ["example.com/cleanup"]}}),
+        )
+        .await
+        .unwrap();
+    let result = client
+        .delete_and_wait::<ConfigMap>(
+            "example",
+            "owner",
+            &DeleteOptions::default(),
+            Duration::from_millis(500),
+        )
+        .await;
+    assert!(
+        matches!(&result, Err(K8sClientError::Error(message)) if message.contains("still exists")),
+        "{:?}",
+        result.err()
+    );
+    assert!(exists(&cluster, "owner").await);
+}
+
+#[tokio::test]
+async fn field_selector_selects_by_name() {
+    let cluster = cluster().await;
+    let mut client = cluster.client.clone();
+    let uri = format!(
+        "{}?fieldSelector=metadata.name%3Ddependent",
+        client.api_resource::<ConfigMap>().namespaced_uri("example")
+    );
+    let list = client.get_all::<ConfigMap>(uri.as_str()).await.unwrap();
+    let names: Vec<_> = list
+        .items
+        .into_iter()
+        .filter_map(|config_map| config_map.metadata.name)
+        .collect();
+    assert_eq!(names, vec![String::from("dependent")]);
+}
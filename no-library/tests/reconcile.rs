@@ -0,0 +1,68 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{eventually, get, seeded, start};
+use no_library::k8s_types::{Deployment, ExposedApp, Protocol, Service, ServiceType};
+
+#[tokio::test]
+async fn exposed_app_is_reconciled_into_deployment_and_service() {
+    let cluster = start(
+        seeded(&[
+            "config/lease.yaml",
+            "examples/namespace.yaml",
+            "examples/exposed_app.yaml",
+        ]),
+        &[],
+    )
+    .await;
+    let client = &cluster.client;
+    let app = get::<ExposedApp>(client, "example", "demo-exposed-app")
+        .await
+        .unwrap();
+    let uid = app.metadata.uid.clone().unwrap();
+    let operator = cluster.run_operator();
+
+    let deployment = eventually("the Deployment", || {
+        get::<Deployment>(client, "example", "demo-exposed-app-deployment")
+    })
+    .await;
+    let owner = &deployment.metadata.owner_references.as_ref().unwrap()[0];
+    assert_eq!((owner.uid.as_str(), owner.controller), (uid.as_str(), true));
+    assert_eq!(deployment.object.spec.replicas, 2);
+    let container = &deployment.object.spec.template.spec.containers[0];
+    assert_eq!(container.image, "nginx:alpine");
+    let container_port = &container.ports.as_ref().unwrap()[0];
+    assert_eq!(container_port.container_port, 80);
+    assert_eq!(container_port.protocol, Protocol::Tcp);
+
+    let service = eventually("the Service", || {
+        get::<Service>(client, "example", "demo-exposed-app-service")
+    })
+    .await;
+    let owner = &service.metadata.owner_references.as_ref().unwrap()[0];
+    assert_eq!(owner.uid, uid);
+    assert_eq!(
+        service.object.spec.service_type,
+        Some(ServiceType::NodePort)
+    );
+    let pod_labels = deployment.object.spec.template.metadata.labels.unwrap();
+    let selector = service.object.spec.selector.unwrap();
+    assert!(!selector.is_empty());
+    assert!(selector
+        .iter()
+        .all(|(key, value)| pod_labels.get(key) == Some(value)));
+    let port = &service.object.spec.ports[0];
+    assert_eq!(port.name.as_deref(), Some("http"));
+    assert_eq!((port.port, port.target_port), (3000, 80));
+    assert_eq!(port.node_port, Some(30950));
+
+    // Written once both children are saved
+    eventually("the ExposedApp status", || async {
+        get::<ExposedApp>(client, "example", "demo-exposed-app")
+            .await?
+            .object
+            .status
+    })
+    .await;
+    operator.abort();
+}
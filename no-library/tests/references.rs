@@ -0,0 +1,117 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{eventually, get, seeded, start};
+use no_library::k8s_types::{ConfigMap, Deployment, Secret};
+use no_library::references::REFERENCED_LABEL;
+use serde_json::json;
+
+const MANIFESTS: &str = r#"
+apiVersion: stable.no-library.com/v2
+kind: ExposedApp
+metadata:
+  name: configured
+  namespace: example
+spec:
+  replicas: 1
+  image: "nginx:alpine"
+  ports:
+    - name: http
+      protocol: TCP
+      port: 80
+      containerPort: 80
+  serviceType: "ClusterIP"
+  envFrom:
+    - configMapRef:
+        name: settings
+    - secretRef:
+        name: credentials
+---
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: settings
+  namespace: example
+data:
+  LEVEL: info
+---
+apiVersion: v1
+kind: Secret
+metadata:
+  name: credentials
+  namespace: example
+data:
+  PASSWORD: b2xk
+---
+apiVersion: v1
+kind: Secret
+metadata:
+  name: unrelated
+  namespace: example
+data:
+  TOKEN: c2VjcmV0
+"#;
+
+fn config_hash(deployment: &Deployment) -> Option<String> {
+    deployment
+        .spec
+        .template
+        .metadata
+        .annotations
+        .as_ref()?
+        .get("no-library.com/config-hash")
+        .cloned()
+}
+
+#[tokio::test]
+async fn referenced_objects_are_labelled_and_their_changes_roll_the_deployment() {
+    let cluster = start(
+        seeded(&["config/lease.yaml", "examples/namespace.yaml"]).seed(MANIFESTS),
+        &[],
+    )
+    .await;
+    let client = &cluster.client;
+    let operator = cluster.run_operator();
+
+    let labelled = |labels: Option<&std::collections::HashMap<String, String>>| {
+        labels.is_some_and(|labels| labels.contains_key(REFERENCED_LABEL))
+    };
+    eventually("the labelled ConfigMap", || async {
+        let config_map = get::<ConfigMap>(client, "example", "settings").await?;
+        labelled(config_map.metadata.labels.as_ref()).then_some(())
+    })
+    .await;
+    eventually("the labelled Secret", || async {
+        let secret = get::<Secret>(client, "example", "credentials").await?;
+        labelled(secret.metadata.labels.as_ref()).then_some(())
+    })
+    .await;
+    let unrelated = get::<Secret>(client, "example", "unrelated").await.unwrap();
+    assert!(!labelled(unrelated.metadata.labels.as_ref()));
+
+    let initial = eventually("the configured Deployment", || async {
+        config_hash(
+            &get::<Deployment>(client, "example", "configured-deployment")
+                .await?
+                .object,
+        )
+    })
+    .await;
+
+    // Seen through the labelled Secret watch, not a resync
+    client
+        .clone()
+        .merge_patch::<Secret>(
+            "example",
+            "credentials",
+            &json!({"data": {"PASSWORD": "bmV3"}}),
+        )
+        .await
+        .unwrap();
+    eventually("the rolled Deployment", || async {
+        let deployment = get::<Deployment>(client, "example", "configured-deployment").await?;
+        config_hash(&deployment.object).filter(|hash| *hash != initial)
+    })
+    .await;
+    operator.abort();
+}
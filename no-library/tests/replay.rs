@@ -0,0 +1,94 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{connect, eventually, get, run_operator, seeded, start};
+use no_library::cassette::{load, Record};
+use no_library::k8s_types::ExposedApp;
+use no_library::replay::ReplayApiServer;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+
+fn cassette() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/cassettes/exposed_app.jsonl")
+}
+
+// Recorded exchange of the given method whose path ends with suffix
+fn recorded(records: &[Record], method: &str, suffix: &str) -> (u64, Value) {
+    records
+        .iter()
+        .find_map(|record| match record {
+            Record::Exchange {
+                sequence,
+                method: recorded,
+                uri,
+                request: Some(request),
+                ..
+            } if recorded == method && uri.split('?').next().unwrap().ends_with(suffix) => {
+                Some((*sequence, request.clone()))
+            }
+            _ => None,
+        })
+        .unwrap_or_else(|| panic!("{} *{} is not in the cassette", method, suffix))
+}
+
+/*
+   Rewrites tests/cassettes/exposed_app.jsonl from the fake API server after reconciler changes:
+   cargo test --test replay -- --ignored
+*/
+#[tokio::test]
+#[ignore]
+async fn record_exposed_app_cassette() {
+    let path = cassette();
+    let cluster = start(
+        seeded(&["examples/namespace.yaml", "examples/exposed_app.yaml"]),
+        &["--record", path.to_str().unwrap()],
+    )
+    .await;
+    let operator = cluster.run_operator();
+    // Not recorded, the replay has no one to serve these to
+    let (_, client) = connect(
+        cluster.server.url.as_str(),
+        cluster.server.ca_file.as_deref(),
+        &[],
+    )
+    .await;
+    let client = &client;
+    eventually("the ExposedApp status", || async {
+        get::<ExposedApp>(client, "example", "demo-exposed-app")
+            .await?
+            .object
+            .status
+    })
+    .await;
+    // Lets the watches deliver the events of the reconcile
+    sleep(Duration::from_secs(1)).await;
+    operator.abort();
+}
+
+#[tokio::test]
+async fn recorded_exposed_app_is_reconciled_the_same_way() {
+    let records = load(cassette().as_path()).unwrap();
+    let server = ReplayApiServer::new(records.clone()).start().await;
+    let (config, client) = connect(server.url.as_str(), None, &[]).await;
+    let operator = run_operator(&client, &config);
+
+    let expected = [
+        recorded(&records, "POST", "/namespaces/example/deployments"),
+        recorded(&records, "PUT", "/services/demo-exposed-app-service"),
+        recorded(&records, "PUT", "/exposedapps/demo-exposed-app/status"),
+    ];
+    for (sequence, request) in expected {
+        let sent = eventually("the recorded request", || async {
+            server.request(sequence)
+        })
+        .await;
+        assert_eq!(sent["kind"], request["kind"]);
+        assert_eq!(sent["metadata"]["name"], request["metadata"]["name"]);
+        assert_eq!(sent["spec"], request["spec"]);
+    }
+    let status = server.status();
+    assert_eq!(status["unmatched"], Value::Array(Vec::new()), "{}", status);
+    operator.abort();
+}
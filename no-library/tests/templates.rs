@@ -0,0 +1,50 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{eventually, get, seeded, start};
+use no_library::k8s_types::{Deployment, ExposedApp, Namespace};
+use serde_json::json;
+
+const NAME: &str = "tenant-exposed-app";
+
+#[tokio::test]
+async fn exposed_app_is_pruned_when_the_namespace_is_no_longer_selected() {
+    let cluster = start(
+        seeded(&["config/lease.yaml", "examples/exposed_app_template.yaml"]),
+        &[],
+    )
+    .await;
+    let client = &cluster.client;
+    let operator = cluster.run_operator();
+    for namespace in ["team-a", "team-b"] {
+        eventually("the Deployment of the stamped ExposedApp", || {
+            get::<Deployment>(client, namespace, "tenant-exposed-app-deployment")
+        })
+        .await;
+    }
+
+    client
+        .clone()
+        .merge_patch::<Namespace>(
+            "",
+            "team-a",
+            &json!({"metadata": {"labels": {"no-library.com/tenant": null}}}),
+        )
+        .await
+        .unwrap();
+    // Foreground deletion, the Deployment is gone no later than the ExposedApp
+    eventually("the pruned ExposedApp", || async {
+        get::<ExposedApp>(client, "team-a", NAME)
+            .await
+            .is_none()
+            .then_some(())
+    })
+    .await;
+    assert!(
+        get::<Deployment>(client, "team-a", "tenant-exposed-app-deployment")
+            .await
+            .is_none()
+    );
+    assert!(get::<ExposedApp>(client, "team-b", NAME).await.is_some());
+    operator.abort();
+}